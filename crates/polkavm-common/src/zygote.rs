@@ -307,6 +307,11 @@ pub struct VmCtx {
     pub message_length: UnsafeCell<u32>,
     /// A buffer used to marshal error messages.
     pub message_buffer: UnsafeCell<[u8; MESSAGE_BUFFER_SIZE]>,
+
+    /// The number of basic blocks which can still be executed before the execution is interrupted.
+    ///
+    /// Only used when execution limits are enabled.
+    pub basic_block_budget: AtomicI64,
 }
 
 // Make sure it fits within a single page on amd64.
@@ -398,6 +403,8 @@ impl VmCtx {
 
             message_length: UnsafeCell::new(0),
             message_buffer: UnsafeCell::new([0; MESSAGE_BUFFER_SIZE]),
+
+            basic_block_budget: AtomicI64::new(0),
         }
     }
 
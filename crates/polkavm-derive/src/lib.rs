@@ -92,6 +92,93 @@ unsafe impl core::alloc::GlobalAlloc for LeakingAllocator {
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {}
 }
 
+/// The symbol of the import through which [`report_panic`] reports panics to the host.
+///
+/// The import takes six arguments: a pointer to and the length of the panic message, a pointer to and the length of
+/// the path of the source file in which the panic happened, and its line and column numbers. It never returns.
+pub const PANIC_IMPORT_SYMBOL: &str = "polkavm_guest_panic";
+
+#[cfg(any(all(any(target_arch = "riscv32", target_arch = "riscv64"), target_feature = "e"), doc))]
+mod panic_import {
+    #[crate::polkavm_import(abi = crate::default_abi)]
+    extern "C" {
+        // This must be kept in sync with `PANIC_IMPORT_SYMBOL`.
+        #[polkavm_import(symbol = "polkavm_guest_panic")]
+        pub fn report_panic(message: *const u8, message_length: usize, file: *const u8, file_length: usize, line: u32, column: u32);
+    }
+}
+
+/// A fixed size buffer used to format the panic message without allocating.
+#[cfg(any(all(any(target_arch = "riscv32", target_arch = "riscv64"), target_feature = "e"), doc))]
+struct PanicMessageBuffer {
+    buffer: [u8; 512],
+    length: usize,
+    skip_first_line: bool,
+}
+
+#[cfg(any(all(any(target_arch = "riscv32", target_arch = "riscv64"), target_feature = "e"), doc))]
+impl core::fmt::Write for PanicMessageBuffer {
+    fn write_str(&mut self, mut string: &str) -> core::fmt::Result {
+        if self.skip_first_line {
+            let Some(index) = string.find('\n') else { return Ok(()) };
+            string = &string[index + 1..];
+            self.skip_first_line = false;
+        }
+
+        // Silently truncate the message if it's too long; a partial message is better than no message.
+        let remaining = self.buffer.len() - self.length;
+        let mut length = core::cmp::min(string.len(), remaining);
+        while !string.is_char_boundary(length) {
+            length -= 1;
+        }
+
+        self.buffer[self.length..self.length + length].copy_from_slice(&string.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
+
+/// Reports a panic to the host through the [`PANIC_IMPORT_SYMBOL`] import and traps.
+///
+/// The message is truncated if it's longer than 512 bytes. Hosts using `polkavm`'s `Linker` will see this as a `CallError::GuestPanic`.
+///
+/// This is meant to be called from the guest program's `#[panic_handler]`; see also [`panic_handler!`].
+#[cfg(any(all(any(target_arch = "riscv32", target_arch = "riscv64"), target_feature = "e"), doc))]
+pub fn report_panic(info: &core::panic::PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    // `PanicInfo`'s `Display` impl prints "panicked at {location}:" on the first line, followed by the message.
+    let mut message = PanicMessageBuffer {
+        buffer: [0; 512],
+        length: 0,
+        skip_first_line: true,
+    };
+    let _ = write!(&mut message, "{info}");
+
+    let (file, line, column) = match info.location() {
+        Some(location) => (location.file(), location.line(), location.column()),
+        None => ("", 0, 0),
+    };
+
+    // SAFETY: The import only reads from the given buffers.
+    unsafe {
+        panic_import::report_panic(message.buffer.as_ptr(), message.length, file.as_ptr(), file.len(), line, column);
+        core::arch::asm!("unimp", options(noreturn));
+    }
+}
+
+/// Defines a `#[panic_handler]` which reports panics to the host with [`report_panic`].
+#[cfg(any(all(any(target_arch = "riscv32", target_arch = "riscv64"), target_feature = "e"), doc))]
+#[macro_export]
+macro_rules! panic_handler {
+    () => {
+        #[panic_handler]
+        fn panic(info: &::core::panic::PanicInfo) -> ! {
+            $crate::report_panic(info)
+        }
+    };
+}
+
 /// Sets the minimum stack size.
 #[cfg(any(all(any(target_arch = "riscv32", target_arch = "riscv64"), target_feature = "e"), doc))]
 #[macro_export]
@@ -1,3 +1,4 @@
+use alloc::borrow::Cow;
 use alloc::boxed::Box;
 use alloc::format;
 use alloc::sync::Arc;
@@ -5,8 +6,9 @@ use alloc::vec::Vec;
 
 use polkavm_common::abi::{MemoryMap, MemoryMapBuilder, VM_ADDR_RETURN_TO_HOST};
 use polkavm_common::cast::cast;
+use polkavm_common::hasher::Hash;
 use polkavm_common::program::{
-    FrameKind, ISA32_V1_NoSbrk, Imports, InstructionSet, Instructions, JumpTable, Opcode, ProgramBlob, Reg, ISA32_V1, ISA64_V1,
+    ISA32_V1_NoSbrk, Imports, InstructionSet, Instructions, JumpTable, Opcode, ProgramBlob, Reg, ISA32_V1, ISA64_V1,
 };
 use polkavm_common::utils::{ArcBytes, AsUninitSliceMut};
 
@@ -16,19 +18,24 @@ if_compiler_is_supported! {
     };
 }
 
-use crate::config::{BackendKind, Config, GasMeteringKind, ModuleConfig, SandboxKind};
+use crate::config::{BackendKind, Config, GasMeteringKind, InstanceLimits, ModuleConfig, SandboxKind};
 use crate::error::{bail, bail_static, Error};
 use crate::gas::CostModelRef;
 use crate::interpreter::{InterpretedInstance, InterpretedModule};
-use crate::utils::{GuestInit, InterruptKind};
+use crate::snapshot::{Snapshot, SnapshotMemoryMap, SnapshotPage};
+use crate::utils::{GuestInit, InterruptHandle, InterruptKind, InterruptState, LimitKind, MemoryDiagnostic, MemoryUsage, WatchpointKind};
 use crate::{Gas, ProgramCounter};
 
+#[cfg(target_endian = "little")]
+use crate::utils::Pod;
+
 #[cfg(feature = "module-cache")]
 use crate::module_cache::{ModuleCache, ModuleKey};
 
 if_compiler_is_supported! {
     {
         use crate::sandbox::{Sandbox, SandboxInstance};
+        use crate::code_cache::{CodeCache, CodeCacheEntry};
         use crate::compiler::{CompiledModule, CompilerCache, B32, B64};
 
         #[cfg(target_os = "linux")]
@@ -41,14 +48,25 @@ if_compiler_is_supported! {
             pub(crate) sandbox_global: Option<crate::sandbox::GlobalStateKind>,
             pub(crate) sandbox_cache: Option<crate::sandbox::WorkerCacheKind>,
             compiler_cache: CompilerCache,
+            code_cache: Option<CodeCache>,
             #[cfg(feature = "module-cache")]
             module_cache: ModuleCache,
         }
+
+        /// The native code going in or out of a precompiled module.
+        enum Precompiled {
+            /// The module is being precompiled; the compiler's output will be stored here.
+            Capture(Option<CodeCacheEntry>),
+            /// The module is being loaded from a precompiled artifact; the code will be taken from here.
+            Load(Option<CodeCacheEntry>),
+        }
     } else {
         pub(crate) struct EngineState {
             #[cfg(feature = "module-cache")]
             module_cache: ModuleCache,
         }
+
+        enum Precompiled {}
     }
 }
 
@@ -175,11 +193,17 @@ impl Engine {
                         sandbox_cache.spawn(&sandbox_global)?;
                     }
 
+                    let code_cache = match config.code_cache_dir {
+                        Some(ref path) => Some(CodeCache::new(path.clone())?),
+                        None => None,
+                    };
+
                     let state = Arc::new(EngineState {
                         sandboxing_enabled: config.sandboxing_enabled,
                         sandbox_global: Some(sandbox_global),
                         sandbox_cache: Some(sandbox_cache),
                         compiler_cache: Default::default(),
+                        code_cache,
 
                         #[cfg(feature = "module-cache")]
                         module_cache,
@@ -192,6 +216,7 @@ impl Engine {
                         sandbox_global: None,
                         sandbox_cache: None,
                         compiler_cache: Default::default(),
+                        code_cache: None,
 
                         #[cfg(feature = "module-cache")]
                         module_cache
@@ -216,6 +241,19 @@ impl Engine {
         })
     }
 
+    #[cfg(feature = "std")]
+    fn clone_handle(&self) -> Engine {
+        Engine {
+            selected_backend: self.selected_backend,
+            selected_sandbox: self.selected_sandbox,
+            interpreter_enabled: self.interpreter_enabled,
+            crosscheck: self.crosscheck,
+            state: Arc::clone(&self.state),
+            allow_dynamic_paging: self.allow_dynamic_paging,
+            allow_experimental: self.allow_experimental,
+        }
+    }
+
     /// Returns the backend used by the engine.
     pub fn backend(&self) -> BackendKind {
         self.selected_backend
@@ -261,21 +299,73 @@ pub(crate) struct ModulePrivate {
     crosscheck: bool,
 
     blob: ProgramBlob,
+    unique_hash: crate::mutex::Mutex<Option<Hash>>,
     compiled_module: CompiledModuleKind,
     interpreted_module: Option<InterpretedModule>,
     memory_map: MemoryMap,
     gas_metering: Option<GasMeteringKind>,
     is_strict: bool,
     step_tracing: bool,
+    memory_sanitizer: bool,
+    execution_limits: bool,
     dynamic_paging: bool,
     page_size_mask: u32,
     page_shift: u32,
     instruction_set: RuntimeInstructionSet,
     cost_model: CostModelRef,
+    #[cfg(feature = "std")]
+    tiered_compilation: Option<TieredCompilation>,
     #[cfg(feature = "module-cache")]
     pub(crate) module_key: Option<ModuleKey>,
 }
 
+/// The state of a module which is interpreted until it gets compiled in the background.
+#[cfg(feature = "std")]
+struct TieredCompilation {
+    engine: Engine,
+    config: ModuleConfig,
+    threshold: usize,
+    instantiation_count: core::sync::atomic::AtomicUsize,
+    compiled_module: Arc<crate::mutex::Mutex<Option<Module>>>,
+}
+
+#[cfg(feature = "std")]
+impl TieredCompilation {
+    fn compiled_module(&self) -> Option<Module> {
+        self.compiled_module.lock().clone()
+    }
+
+    fn on_instantiate(&self, blob: &ProgramBlob) {
+        let count = self.instantiation_count.fetch_add(1, core::sync::atomic::Ordering::Relaxed) + 1;
+        if count == self.threshold {
+            self.start(blob);
+        }
+    }
+
+    fn start(&self, blob: &ProgramBlob) {
+        let engine = self.engine.clone_handle();
+        let config = self.config.clone();
+        let blob = blob.clone();
+        let compiled_module = Arc::clone(&self.compiled_module);
+        let result = std::thread::Builder::new().name("polkavm-compiler".into()).spawn(move || {
+            log::debug!("Compiling module in the background...");
+            match Module::from_blob(&engine, &config, blob) {
+                Ok(module) => {
+                    log::debug!("Background compilation finished");
+                    *compiled_module.lock() = Some(module);
+                }
+                Err(error) => {
+                    log::warn!("Background compilation failed: {error}");
+                }
+            }
+        });
+
+        if let Err(error) = result {
+            log::warn!("Failed to spawn a thread for background compilation: {error}");
+        }
+    }
+}
+
 /// A compiled PolkaVM program module.
 #[derive(Clone)]
 pub struct Module(pub(crate) Option<Arc<ModulePrivate>>);
@@ -310,6 +400,14 @@ impl Module {
         self.state().step_tracing
     }
 
+    pub(crate) fn is_memory_sanitizer_enabled(&self) -> bool {
+        self.state().memory_sanitizer
+    }
+
+    pub(crate) fn has_execution_limits(&self) -> bool {
+        self.state().execution_limits
+    }
+
     pub(crate) fn is_dynamic_paging(&self) -> bool {
         self.state().dynamic_paging
     }
@@ -328,10 +426,21 @@ impl Module {
         &self.state().blob
     }
 
+    /// Returns a hash which uniquely identifies the program, excluding its debug info.
+    pub(crate) fn unique_hash(&self) -> Hash {
+        let mut unique_hash = self.state().unique_hash.lock();
+        *unique_hash.get_or_insert_with(|| self.state().blob.unique_hash(false))
+    }
+
     pub(crate) fn code_len(&self) -> u32 {
         cast(self.state().blob.code().len()).assert_always_fits_in_u32()
     }
 
+    #[cfg(feature = "std")]
+    pub(crate) fn instructions(&self) -> Instructions<'_, RuntimeInstructionSet> {
+        self.state().blob.instructions(self.state().instruction_set)
+    }
+
     pub(crate) fn instructions_bounded_at(&self, offset: ProgramCounter) -> Instructions<RuntimeInstructionSet> {
         self.state().blob.instructions_bounded_at(self.state().instruction_set, offset)
     }
@@ -398,10 +507,113 @@ impl Module {
 
     /// Creates a new module from a deserialized program `blob`.
     pub fn from_blob(engine: &Engine, config: &ModuleConfig, blob: ProgramBlob) -> Result<Self, Error> {
+        Self::from_blob_impl(engine, config, blob, None)
+    }
+
+    /// Compiles the program from the given `bytes` ahead-of-time and returns a precompiled artifact.
+    ///
+    /// The artifact can later be loaded with [`Module::from_precompiled`] to skip the compilation.
+    /// It can only be loaded by the same version of PolkaVM running on the same architecture,
+    /// using an engine with the same sandbox and crosscheck setting, and with an identical `config`.
+    ///
+    /// This requires the compiler backend with the Linux sandbox.
+    pub fn precompile(engine: &Engine, config: &ModuleConfig, bytes: ArcBytes) -> Result<Vec<u8>, Error> {
+        if_compiler_is_supported! {
+            {{
+                if engine.selected_backend != BackendKind::Compiler {
+                    bail_static!("failed to precompile module: precompilation requires the compiler backend");
+                }
+
+                if engine.selected_sandbox != Some(SandboxKind::Linux) {
+                    bail_static!("failed to precompile module: precompilation requires the Linux sandbox");
+                }
+
+                if config.watchpoints || config.memory_sanitizer {
+                    bail_static!("failed to precompile module: watchpoints and the memory sanitizer are only supported by the interpreter");
+                }
+
+                let Some(config_hash) = config.hash() else {
+                    bail_static!("failed to precompile module: modules using custom codegen cannot be precompiled");
+                };
+
+                let blob = match ProgramBlob::parse(bytes.clone()) {
+                    Ok(blob) => blob,
+                    Err(error) => {
+                        bail!("failed to parse blob: {}", error);
+                    }
+                };
+
+                let mut precompiled = Precompiled::Capture(None);
+                Self::from_blob_impl(engine, config, blob, Some(&mut precompiled))?;
+                let Precompiled::Capture(Some(entry)) = precompiled else {
+                    bail_static!("failed to precompile module: the module was not compiled");
+                };
+
+                crate::code_cache::serialize_precompiled(&bytes, config_hash, SandboxKind::Linux, engine.crosscheck, &entry)
+            }} else {{
+                let _ = (engine, config, bytes);
+                bail_static!("failed to precompile module: precompilation is not supported on this platform");
+            }}
+        }
+    }
+
+    /// Creates a new module from an `artifact` previously returned by [`Module::precompile`].
+    ///
+    /// The artifact is checked for compatibility with this engine and with the given `config`, and is rejected if it isn't.
+    ///
+    /// # Safety
+    ///
+    /// The artifact contains native code which will be executed as-is. It is only checked for accidental corruption,
+    /// not validated, so loading a maliciously crafted artifact can execute arbitrary code outside of the guest's
+    /// sandbox. The `artifact` must have been produced by [`Module::precompile`] and come from a trusted source.
+    pub unsafe fn from_precompiled(engine: &Engine, config: &ModuleConfig, artifact: &[u8]) -> Result<Self, Error> {
+        if_compiler_is_supported! {
+            {{
+                if engine.selected_backend != BackendKind::Compiler || engine.selected_sandbox != Some(SandboxKind::Linux) {
+                    bail_static!("failed to load the precompiled module: precompiled modules require the compiler backend with the Linux sandbox");
+                }
+
+                let Some(config_hash) = config.hash() else {
+                    bail_static!("failed to load the precompiled module: modules using custom codegen cannot be precompiled");
+                };
+
+                let (bytes, entry) =
+                    crate::code_cache::deserialize_precompiled(artifact, config_hash, SandboxKind::Linux, engine.crosscheck)?;
+                let blob = match ProgramBlob::parse(ArcBytes::from(bytes)) {
+                    Ok(blob) => blob,
+                    Err(error) => {
+                        bail!("failed to parse blob: {}", error);
+                    }
+                };
+
+                let mut precompiled = Precompiled::Load(Some(entry));
+                let module = Self::from_blob_impl(engine, config, blob, Some(&mut precompiled))?;
+                if !matches!(precompiled, Precompiled::Load(None)) {
+                    bail_static!("failed to load the precompiled module: the module's native code was not used");
+                }
+
+                Ok(module)
+            }} else {{
+                let _ = (engine, config, artifact);
+                bail_static!("failed to load the precompiled module: precompilation is not supported on this platform");
+            }}
+        }
+    }
+
+    fn from_blob_impl(
+        engine: &Engine,
+        config: &ModuleConfig,
+        blob: ProgramBlob,
+        precompiled: Option<&mut Precompiled>,
+    ) -> Result<Self, Error> {
         if config.dynamic_paging() && !engine.allow_dynamic_paging {
             bail!("dynamic paging was not enabled; use `Config::set_allow_dynamic_paging` to enable it");
         }
 
+        if config.dynamic_paging() && config.memory_sanitizer {
+            bail!("the memory sanitizer cannot be used together with dynamic paging");
+        }
+
         if config.custom_codegen.is_some() && !engine.allow_experimental {
             bail!("cannot use custom codegen: `set_allow_experimental`/`POLKAVM_ALLOW_EXPERIMENTAL` is not enabled");
         }
@@ -412,7 +624,10 @@ impl Module {
         );
 
         #[cfg(feature = "module-cache")]
-        let module_key = {
+        let module_key = if precompiled.is_some() {
+            // The native code must always go through the precompiled artifact.
+            None
+        } else {
             let (module_key, module) = engine.state.module_cache.get(config, &blob);
             if let Some(module) = module {
                 return Ok(module);
@@ -492,9 +707,59 @@ impl Module {
             is_64_bit: blob.is_64_bit(),
         };
 
+        if_compiler_is_supported! {
+            let mut precompiled = precompiled;
+        }
+
+        let is_tiered = config.tier_up_threshold.is_some()
+            && cfg!(feature = "std")
+            && engine.selected_backend == BackendKind::Compiler
+            && !config.watchpoints
+            && !config.memory_sanitizer
+            && precompiled.is_none();
+
         #[allow(unused_macros)]
         macro_rules! compile_module {
             ($sandbox_kind:ident, $bitness_kind:ident, $isa:ident, $isa_no_sbrk:ident, $visitor_name:ident, $module_kind:ident) => {{
+                let global = $sandbox_kind::downcast_global_state(engine.state.sandbox_global.as_ref().unwrap());
+                let (cached_entry, code_cache) = match precompiled.as_deref_mut() {
+                    Some(Precompiled::Load(entry)) => (entry.take(), None),
+                    Some(Precompiled::Capture(..)) => (None, None),
+                    None => {
+                        // Only the Linux sandbox always loads the code at the same address, which the cached code requires.
+                        let code_cache = engine.state.code_cache.as_ref().filter(|_| $sandbox_kind::KIND == SandboxKind::Linux);
+                        let code_cache = code_cache.and_then(|code_cache| {
+                            let key = CodeCache::key(config, &blob, $sandbox_kind::KIND, engine.crosscheck)?;
+                            Some((code_cache, key))
+                        });
+
+                        // SAFETY: The user has vouched for the cache's directory in `Config::set_code_cache_dir`.
+                        (code_cache.and_then(|(code_cache, key)| unsafe { code_cache.load(key) }), code_cache)
+                    }
+                };
+
+                let cached_module = match cached_entry {
+                    Some(entry) => crate::compiler::CompilerVisitor::<$sandbox_kind, $bitness_kind>::from_code_cache(
+                        global,
+                        &engine.state.compiler_cache,
+                        init,
+                        blob.jump_table(),
+                        &exports,
+                        entry,
+                    )?,
+                    None => None,
+                };
+
+                if let Some(module) = cached_module {
+                    Some(CompiledModuleKind::$module_kind(module))
+                } else if matches!(precompiled.as_deref(), Some(Precompiled::Load(..))) {
+                    bail_static!("failed to load the precompiled module: the module cannot be loaded in this process");
+                } else {
+                    compile_module!(@compile $sandbox_kind, $bitness_kind, $isa, $isa_no_sbrk, $visitor_name, $module_kind, global, code_cache)
+                }
+            }};
+
+            (@compile $sandbox_kind:ident, $bitness_kind:ident, $isa:ident, $isa_no_sbrk:ident, $visitor_name:ident, $module_kind:ident, $global:ident, $code_cache:ident) => {{
                 type VisitorTy<'a> = crate::compiler::CompilerVisitor<'a, $sandbox_kind, $bitness_kind>;
                 let (mut visitor, aux) = crate::compiler::CompilerVisitor::<$sandbox_kind, $bitness_kind>::new(
                     &engine.state.compiler_cache,
@@ -519,15 +784,22 @@ impl Module {
                     );
                 }
 
-                let global = $sandbox_kind::downcast_global_state(engine.state.sandbox_global.as_ref().unwrap());
-                let module = visitor.finish_compilation(global, &engine.state.compiler_cache, aux)?;
+                let create_code_cache_entry = $code_cache.is_some() || matches!(precompiled.as_deref(), Some(Precompiled::Capture(..)));
+                let (module, code_cache_entry) =
+                    visitor.finish_compilation($global, &engine.state.compiler_cache, aux, create_code_cache_entry)?;
+                match (precompiled.as_deref_mut(), $code_cache, code_cache_entry) {
+                    (Some(Precompiled::Capture(output)), _, entry) => *output = entry,
+                    (_, Some((code_cache, key)), Some(entry)) => code_cache.store(key, &entry),
+                    _ => {}
+                }
+
                 Some(CompiledModuleKind::$module_kind(module))
             }};
         }
 
         let compiled_module: Option<CompiledModuleKind> = if_compiler_is_supported! {
-            {
-                if engine.selected_backend == BackendKind::Compiler {
+            {{
+                if engine.selected_backend == BackendKind::Compiler && !config.watchpoints && !config.memory_sanitizer && !is_tiered {
                     if let Some(selected_sandbox) = engine.selected_sandbox {
                         match selected_sandbox {
                             SandboxKind::Linux => {
@@ -569,12 +841,13 @@ impl Module {
                 } else {
                     None
                 }
-            } else {{
+            }} else {{
+                let _ = precompiled;
                 None
             }}
         };
 
-        let interpreted_module = if engine.interpreter_enabled {
+        let interpreted_module = if engine.interpreter_enabled || config.watchpoints || config.memory_sanitizer || is_tiered {
             Some(InterpretedModule::new(init)?)
         } else {
             None
@@ -625,22 +898,49 @@ impl Module {
         let page_shift = memory_map.page_size().ilog2();
         let page_size_mask = (1 << page_shift) - 1;
 
+        #[cfg(feature = "std")]
+        let tiered_compilation = if is_tiered {
+            log::debug!("The module will be compiled in the background");
+            let mut compiled_config = config.clone();
+            compiled_config.tier_up_threshold = None;
+            let tiered_compilation = TieredCompilation {
+                engine: engine.clone_handle(),
+                config: compiled_config,
+                threshold: cast(config.tier_up_threshold.unwrap_or(0)).to_usize(),
+                instantiation_count: core::sync::atomic::AtomicUsize::new(0),
+                compiled_module: Arc::new(crate::mutex::Mutex::new(None)),
+            };
+
+            if tiered_compilation.threshold == 0 {
+                tiered_compilation.start(&blob);
+            }
+
+            Some(tiered_compilation)
+        } else {
+            None
+        };
+
         let module = Arc::new(ModulePrivate {
             engine_state: Some(Arc::clone(&engine.state)),
 
             blob,
+            unique_hash: crate::mutex::Mutex::new(None),
             compiled_module,
             interpreted_module,
             memory_map,
             gas_metering: config.gas_metering,
             is_strict: config.is_strict,
             step_tracing: config.step_tracing,
+            memory_sanitizer: config.memory_sanitizer,
+            execution_limits: config.execution_limits,
             dynamic_paging: config.dynamic_paging,
             instruction_set,
             crosscheck: engine.crosscheck,
             page_size_mask,
             page_shift,
             cost_model: config.cost_model().clone(),
+            #[cfg(feature = "std")]
+            tiered_compilation,
 
             #[cfg(feature = "module-cache")]
             module_key,
@@ -674,6 +974,15 @@ impl Module {
 
     /// Instantiates a new module.
     pub fn instantiate(&self) -> Result<RawInstance, Error> {
+        #[cfg(feature = "std")]
+        if let Some(ref tiered_compilation) = self.state().tiered_compilation {
+            if let Some(module) = tiered_compilation.compiled_module() {
+                return module.instantiate();
+            }
+
+            tiered_compilation.on_instantiate(&self.state().blob);
+        }
+
         let compiled_module = &self.state().compiled_module;
         let backend = if_compiler_is_supported! {
             {{
@@ -716,6 +1025,9 @@ impl Module {
             module: self.clone(),
             backend,
             crosscheck_instance,
+            limits: InstanceLimits::default(),
+            interrupt_state: None,
+            deferred_interruption: None,
         })
     }
 
@@ -823,35 +1135,13 @@ impl Module {
 
     pub(crate) fn debug_print_location(&self, log_level: log::Level, pc: ProgramCounter) {
         log::log!(log_level, "  At #{pc}:");
-
-        let Ok(Some(mut line_program)) = self.state().blob.get_debug_line_program_at(pc) else {
+        let Some(frames) = crate::frames::location_frames(self, pc) else {
             log::log!(log_level, "    (no location available)");
             return;
         };
 
-        for _ in 0..128 {
-            // Have an upper bound on the number of iterations, just in case.
-            let Ok(Some(region_info)) = line_program.run() else { break };
-
-            if !region_info.instruction_range().contains(&pc) {
-                continue;
-            }
-
-            for frame in region_info.frames() {
-                let kind = match frame.kind() {
-                    FrameKind::Enter => 'f',
-                    FrameKind::Call => 'c',
-                    FrameKind::Line => 'l',
-                };
-
-                if let Ok(full_name) = frame.full_name() {
-                    if let Ok(Some(location)) = frame.location() {
-                        log::log!(log_level, "    ({kind}) '{full_name}' [{location}]");
-                    } else {
-                        log::log!(log_level, "    ({kind}) '{full_name}'");
-                    }
-                }
-            }
+        for frame in frames {
+            log::log!(log_level, "    {}", frame.describe());
         }
     }
 }
@@ -876,6 +1166,7 @@ if_compiler_is_supported! {
 #[derive(Debug)]
 pub enum MemoryAccessError {
     OutOfRangeAccess { address: u32, length: u64 },
+    MemoryLimitReached { address: u32, length: u64 },
     Error(Error),
 }
 
@@ -894,6 +1185,15 @@ impl core::fmt::Display for MemoryAccessError {
                     length
                 )
             }
+            MemoryAccessError::MemoryLimitReached { address, length } => {
+                write!(
+                    fmt,
+                    "memory limit reached when accessing 0x{:x}-0x{:x} ({} bytes)",
+                    address,
+                    u64::from(*address) + length,
+                    length
+                )
+            }
             MemoryAccessError::Error(error) => {
                 write!(fmt, "memory access failed: {error}")
             }
@@ -907,6 +1207,64 @@ impl From<MemoryAccessError> for alloc::string::String {
     }
 }
 
+/// A mutable view into the VM's memory, returned by [`RawInstance::memory_slice_mut`].
+///
+/// This is only zero-copy when [`MemorySliceMut::is_borrowed`] returns `true`. Otherwise this holds a copy of the memory,
+/// which is written back into the VM in its entirety when dropped, even if it wasn't modified.
+pub struct MemorySliceMut<'a>(MemorySliceMutInner<'a>);
+
+enum MemorySliceMutInner<'a> {
+    Borrowed(&'a mut [u8]),
+    Copied {
+        instance: &'a mut RawInstance,
+        address: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl<'a> MemorySliceMut<'a> {
+    /// Returns whether the memory is borrowed directly instead of being copied.
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.0, MemorySliceMutInner::Borrowed(..))
+    }
+}
+
+impl<'a> core::ops::Deref for MemorySliceMut<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self.0 {
+            MemorySliceMutInner::Borrowed(ref slice) => slice,
+            MemorySliceMutInner::Copied { ref data, .. } => data,
+        }
+    }
+}
+
+impl<'a> core::ops::DerefMut for MemorySliceMut<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self.0 {
+            MemorySliceMutInner::Borrowed(ref mut slice) => slice,
+            MemorySliceMutInner::Copied { ref mut data, .. } => data,
+        }
+    }
+}
+
+impl<'a> Drop for MemorySliceMut<'a> {
+    fn drop(&mut self) {
+        if let MemorySliceMutInner::Copied {
+            ref mut instance,
+            address,
+            ref data,
+        } = self.0
+        {
+            // This was already checked to be writable when the slice was created, so this should never fail.
+            if let Err(error) = instance.write_memory(address, data) {
+                log::error!("Failed to write back a copied memory slice: {error}");
+            }
+        }
+    }
+}
+
 if_compiler_is_supported! {
     {
         macro_rules! access_backend {
@@ -963,6 +1321,9 @@ pub struct RawInstance {
     module: Module,
     backend: InstanceBackend,
     crosscheck_instance: Option<Box<InterpretedInstance>>,
+    limits: InstanceLimits,
+    interrupt_state: Option<Arc<InterruptState>>,
+    deferred_interruption: Option<InterruptKind>,
 }
 
 impl RawInstance {
@@ -977,7 +1338,24 @@ impl RawInstance {
     }
 
     /// Starts or resumes the execution.
+    ///
+    /// If the execution runs out of gas then [`InterruptKind::NotEnoughGas`] is returned and the instance
+    /// is left in a resumable state: more gas can be added with [`RawInstance::set_gas`] and calling this again
+    /// will continue the execution exactly where it stopped.
+    ///
+    /// With [`GasMeteringKind::Async`] running out of gas is only noticed at the next point where the execution
+    /// would have been interrupted anyway. That interruption is then held back and is returned from the next call
+    /// to this function once the gas is no longer negative.
     pub fn run(&mut self) -> Result<InterruptKind, Error> {
+        if let Some(interruption) = self.deferred_interruption.take() {
+            if self.gas() < 0 {
+                self.deferred_interruption = Some(interruption);
+                return Ok(InterruptKind::NotEnoughGas);
+            }
+
+            return Ok(interruption);
+        }
+
         if self.next_program_counter().is_none() {
             return Err(Error::from_static_str("failed to run: next program counter is not set"));
         }
@@ -986,6 +1364,15 @@ impl RawInstance {
             return Ok(InterruptKind::NotEnoughGas);
         }
 
+        #[cfg(feature = "std")]
+        if self.limits.deadline().is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+            return Ok(InterruptKind::LimitReached(LimitKind::Deadline));
+        }
+
+        if self.interrupt_state.as_ref().is_some_and(|state| state.take_interrupt()) {
+            return Ok(InterruptKind::Interrupted);
+        }
+
         loop {
             let interruption = access_backend!(self.backend, |mut backend| backend
                 .run()
@@ -998,7 +1385,12 @@ impl RawInstance {
                 }
             }
 
-            if let Some(ref mut crosscheck) = self.crosscheck_instance {
+            let is_nondeterministic = matches!(
+                interruption,
+                InterruptKind::LimitReached(LimitKind::Deadline) | InterruptKind::Interrupted
+            );
+
+            if let Some(crosscheck) = self.crosscheck_instance.as_mut().filter(|_| !is_nondeterministic) {
                 let is_step = matches!(interruption, InterruptKind::Step);
                 let expected_interruption = crosscheck.run().expect("crosscheck failed");
                 if interruption != expected_interruption {
@@ -1034,6 +1426,10 @@ impl RawInstance {
             }
 
             if self.gas() < 0 {
+                if !matches!(interruption, InterruptKind::NotEnoughGas) {
+                    self.deferred_interruption = Some(interruption);
+                }
+
                 return Ok(InterruptKind::NotEnoughGas);
             }
 
@@ -1072,6 +1468,220 @@ impl RawInstance {
         access_backend!(self.backend, |mut backend| backend.set_gas(gas))
     }
 
+    /// Returns the execution limits currently set for this instance.
+    pub fn limits(&self) -> &InstanceLimits {
+        &self.limits
+    }
+
+    /// Sets the limits for this instance.
+    ///
+    /// The limits apply to every subsequent call to [`RawInstance::run`] until they're changed again,
+    /// and setting them resets the count of executed basic blocks.
+    ///
+    /// Limiting the number of executed basic blocks or setting a deadline requires execution limits
+    /// to be enabled with [`ModuleConfig::set_execution_limits`].
+    pub fn set_limits(&mut self, limits: InstanceLimits) -> Result<(), Error> {
+        #[cfg(feature = "std")]
+        let has_deadline = limits.deadline().is_some();
+        #[cfg(not(feature = "std"))]
+        let has_deadline = false;
+
+        if (limits.basic_block_limit().is_some() || has_deadline) && !self.module.has_execution_limits() {
+            bail_static!("failed to set limits: execution limits are not enabled for this module");
+        }
+
+        let budget = limits
+            .basic_block_limit()
+            .map_or(i64::MAX, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+
+        #[cfg(feature = "std")]
+        let deadline = limits.deadline();
+
+        // The deadline is deliberately not set for the crosscheck instance as it's inherently nondeterministic.
+        if let Some(ref mut crosscheck) = self.crosscheck_instance {
+            crosscheck.set_basic_block_budget(budget);
+        }
+
+        access_backend!(self.backend, |mut backend| backend.set_basic_block_budget(budget));
+
+        #[cfg(feature = "std")]
+        access_backend!(self.backend, |mut backend| backend.set_deadline(deadline));
+
+        self.limits = limits;
+        self.apply_memory_limit();
+        Ok(())
+    }
+
+    /// Returns the amount of memory currently used by this instance, broken down by region.
+    ///
+    /// When dynamic paging is not enabled this is the memory which is currently accessible to the program,
+    /// otherwise it's the memory of every page which is currently mapped in.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let map = self.module.memory_map();
+        if !self.module.is_dynamic_paging() {
+            let heap_top = self.module.round_to_page_size_up(map.heap_base() + self.heap_size());
+            MemoryUsage {
+                ro_data: map.ro_data_size(),
+                rw_data: map.rw_data_size(),
+                heap: heap_top.saturating_sub(map.rw_data_range().end),
+                stack: map.stack_size(),
+                aux_data: access_backend!(self.backend, |backend| backend.accessible_aux_size()),
+            }
+        } else {
+            let count = |start: u32, end: u64| -> u32 {
+                let length = cast(end - u64::from(start)).assert_always_fits_in_u32();
+                if length == 0 {
+                    return 0;
+                }
+
+                access_backend!(self.backend, |backend| backend.count_mapped_pages(start, length)) * map.page_size()
+            };
+
+            MemoryUsage {
+                ro_data: count(map.ro_data_address(), u64::from(map.rw_data_address())),
+                rw_data: count(map.rw_data_address(), u64::from(map.rw_data_range().end)),
+                heap: count(map.rw_data_range().end, u64::from(map.stack_address_low())),
+                stack: count(map.stack_address_low(), u64::from(map.aux_data_address())),
+                aux_data: count(map.aux_data_address(), 0x100000000),
+            }
+        }
+    }
+
+    /// Limits how much the heap can grow so that the memory limit isn't exceeded.
+    fn apply_memory_limit(&mut self) {
+        if self.module.is_dynamic_paging() {
+            return;
+        }
+
+        let max_heap_size = match self.limits.memory_limit() {
+            None => u32::MAX,
+            Some(limit) => {
+                let map = self.module.memory_map();
+                let usage = self.memory_usage();
+                let available = limit.saturating_sub(usage.total() - u64::from(usage.heap));
+                let available = available & !(u64::from(map.page_size()) - 1);
+                let max_heap_top = u64::from(map.rw_data_range().end) + available;
+                let max_heap_size = max_heap_top.saturating_sub(u64::from(map.heap_base()));
+                u32::try_from(max_heap_size).unwrap_or(u32::MAX)
+            }
+        };
+
+        if let Some(ref mut crosscheck) = self.crosscheck_instance {
+            crosscheck.set_max_heap_size(max_heap_size);
+        }
+
+        access_backend!(self.backend, |mut backend| backend.set_max_heap_size(max_heap_size));
+    }
+
+    /// Checks whether mapping in the pages of a given non-empty memory region would exceed the memory limit.
+    fn check_memory_limit(&self, address: u32, length: u32) -> Result<(), MemoryAccessError> {
+        let Some(limit) = self.limits.memory_limit() else {
+            return Ok(());
+        };
+
+        if !self.module.is_dynamic_paging() {
+            return Ok(());
+        }
+
+        let page_size = self.module.memory_map().page_size();
+        let first_page_address = self.module.round_to_page_size_down(address);
+        let last_page_address = self.module.round_to_page_size_down(address + (length - 1));
+        let page_count = (last_page_address - first_page_address) / page_size + 1;
+        let mapped_page_count = access_backend!(self.backend, |backend| backend.count_mapped_pages(address, length));
+        let new_memory = u64::from(page_count - mapped_page_count) * u64::from(page_size);
+        if new_memory > 0 && self.memory_usage().total() + new_memory > limit {
+            return Err(MemoryAccessError::MemoryLimitReached {
+                address,
+                length: u64::from(length),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns a handle which can be used to interrupt the execution of this instance from another thread.
+    ///
+    /// Requires execution limits to be enabled with [`ModuleConfig::set_execution_limits`].
+    pub fn interrupt_handle(&mut self) -> Result<InterruptHandle, Error> {
+        if !self.module.has_execution_limits() {
+            bail_static!("failed to create an interrupt handle: execution limits are not enabled for this module");
+        }
+
+        if let Some(ref state) = self.interrupt_state {
+            return Ok(state.handle());
+        }
+
+        // The crosscheck instance deliberately doesn't get the state as interruptions are inherently nondeterministic.
+        let state = InterruptState::new();
+        access_backend!(self.backend, |mut backend| backend.set_interrupt_state(Some(Arc::clone(&state))));
+        let handle = state.handle();
+        self.interrupt_state = Some(state);
+        Ok(handle)
+    }
+
+    /// Returns how many more basic blocks can be executed before the limit set with [`RawInstance::set_limits`] is reached.
+    ///
+    /// Returns `None` if no limit on the number of basic blocks is set.
+    pub fn basic_blocks_remaining(&self) -> Option<u64> {
+        self.limits.basic_block_limit()?;
+        let budget = access_backend!(self.backend, |backend| backend.basic_block_budget());
+        Some(cast(core::cmp::max(budget, 0)).to_unsigned())
+    }
+
+    /// Adds a watchpoint on the given range of the guest's memory.
+    ///
+    /// Whenever the guest accesses memory within the range in a way which matches the `kind` of the watchpoint
+    /// the execution will be interrupted with [`InterruptKind::Watchpoint`].
+    ///
+    /// Watchpoints are only supported by the interpreter; use [`ModuleConfig::set_watchpoints`] to make sure the module is interpreted.
+    pub fn add_watchpoint(&mut self, address: u32, length: u32, kind: WatchpointKind) -> Result<(), Error> {
+        if length == 0 {
+            bail_static!("failed to add a watchpoint: the length must not be zero");
+        }
+
+        #[allow(irrefutable_let_patterns)]
+        let InstanceBackend::Interpreted(ref mut backend) = self.backend
+        else {
+            bail_static!("failed to add a watchpoint: watchpoints are only supported by the interpreter");
+        };
+
+        backend.add_watchpoint(address, length, kind);
+        Ok(())
+    }
+
+    /// Removes a watchpoint previously added with [`RawInstance::add_watchpoint`].
+    ///
+    /// Returns whether the watchpoint was found.
+    pub fn remove_watchpoint(&mut self, address: u32, length: u32, kind: WatchpointKind) -> bool {
+        #[allow(irrefutable_let_patterns)]
+        let InstanceBackend::Interpreted(ref mut backend) = self.backend
+        else {
+            return false;
+        };
+
+        backend.remove_watchpoint(address, length, kind)
+    }
+
+    /// Removes all of the watchpoints.
+    pub fn clear_watchpoints(&mut self) {
+        #[allow(irrefutable_let_patterns)]
+        if let InstanceBackend::Interpreted(ref mut backend) = self.backend {
+            backend.clear_watchpoints();
+        }
+    }
+
+    /// Takes the diagnostics recorded by the memory sanitizer since the last time this was called.
+    ///
+    /// Always returns an empty list if the memory sanitizer wasn't enabled with [`ModuleConfig::set_memory_sanitizer`].
+    pub fn take_memory_diagnostics(&mut self) -> Vec<MemoryDiagnostic> {
+        #[allow(irrefutable_let_patterns)]
+        if let InstanceBackend::Interpreted(ref mut backend) = self.backend {
+            backend.take_memory_diagnostics()
+        } else {
+            Vec::new()
+        }
+    }
+
     /// Gets the current program counter.
     pub fn program_counter(&self) -> Option<ProgramCounter> {
         access_backend!(self.backend, |backend| backend.program_counter())
@@ -1085,7 +1695,10 @@ impl RawInstance {
     }
 
     /// Sets the next program counter.
+    ///
+    /// This discards any interruption which was held back after running out of gas.
     pub fn set_next_program_counter(&mut self, pc: ProgramCounter) {
+        self.deferred_interruption = None;
         if let Some(ref mut crosscheck) = self.crosscheck_instance {
             crosscheck.set_next_program_counter(pc);
         }
@@ -1116,13 +1729,23 @@ impl RawInstance {
         }
 
         let size = self.module.round_to_page_size_up(size);
+        if let Some(limit) = self.limits.memory_limit() {
+            let usage = self.memory_usage();
+            if usage.total() - u64::from(usage.aux_data) + u64::from(size) > limit {
+                return Err(format!("cannot set accessible aux size: the memory limit of {limit} bytes would be exceeded").into());
+            }
+        }
+
         if let Some(ref mut crosscheck) = self.crosscheck_instance {
             crosscheck.set_accessible_aux_size(size);
         }
 
         access_backend!(self.backend, |mut backend| backend
             .set_accessible_aux_size(size)
-            .into_result("failed to set accessible aux size"))
+            .into_result("failed to set accessible aux size"))?;
+
+        self.apply_memory_limit();
+        Ok(())
     }
 
     /// Resets the VM's memory to its initial state.
@@ -1133,7 +1756,10 @@ impl RawInstance {
 
         access_backend!(self.backend, |mut backend| backend
             .reset_memory()
-            .into_result("failed to reset the instance's memory"))
+            .into_result("failed to reset the instance's memory"))?;
+
+        self.apply_memory_limit();
+        Ok(())
     }
 
     /// Returns whether a given chunk of memory is accessible through [`read_memory_into`](Self::read_memory_into)/[`write_memory`](Self::write_memory).
@@ -1277,6 +1903,8 @@ impl RawInstance {
             });
         }
 
+        self.check_memory_limit(address, cast(data.len()).assert_always_fits_in_u32())?;
+
         let result = access_backend!(self.backend, |mut backend| backend.write_memory(address, data));
         if let Some(ref mut crosscheck) = self.crosscheck_instance {
             let expected_result = crosscheck.write_memory(address, data);
@@ -1327,6 +1955,61 @@ impl RawInstance {
         Ok(buffer)
     }
 
+    /// Returns a view into the VM's memory.
+    ///
+    /// If possible the memory is borrowed directly without making a copy, otherwise this is equivalent
+    /// to calling [`RawInstance::read_memory`]. Currently the memory can be borrowed with the interpreter
+    /// (except for ranges spanning multiple pages when dynamic paging is enabled), and with the Linux sandbox
+    /// when dynamic paging is enabled or when reading the auxiliary data region.
+    pub fn memory_slice(&self, address: u32, length: u32) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        if length == 0 {
+            return Ok(Cow::Borrowed(&[]));
+        }
+
+        if self.crosscheck_instance.is_none() {
+            if let Some(slice) = access_backend!(self.backend, |backend| backend.memory_slice(address, length)) {
+                return Ok(Cow::Borrowed(slice));
+            }
+        }
+
+        self.read_memory(address, length).map(Cow::Owned)
+    }
+
+    /// Returns a mutable view into the VM's memory.
+    ///
+    /// The memory is borrowed directly whenever [`RawInstance::memory_slice`] would do so, otherwise it is copied
+    /// and written back into the VM when the returned [`MemorySliceMut`] is dropped. In both cases the whole region
+    /// must be both readable and writable.
+    pub fn memory_slice_mut(&mut self, address: u32, length: u32) -> Result<MemorySliceMut<'_>, MemoryAccessError> {
+        if length == 0 {
+            return Ok(MemorySliceMut(MemorySliceMutInner::Borrowed(&mut [])));
+        }
+
+        if !self.is_memory_accessible(address, length, true) {
+            return Err(MemoryAccessError::OutOfRangeAccess {
+                address,
+                length: u64::from(length),
+            });
+        }
+
+        let is_borrowable =
+            self.crosscheck_instance.is_none() && access_backend!(self.backend, |backend| backend.memory_slice(address, length).is_some());
+
+        if is_borrowable {
+            let slice = access_backend!(self.backend, |mut backend| backend.memory_slice_mut(address, length))
+                .ok_or_else(|| MemoryAccessError::Error(Error::from_static_str("internal error: failed to borrow memory")))?;
+
+            return Ok(MemorySliceMut(MemorySliceMutInner::Borrowed(slice)));
+        }
+
+        let data = self.read_memory(address, length)?;
+        Ok(MemorySliceMut(MemorySliceMutInner::Copied {
+            instance: self,
+            address,
+            data,
+        }))
+    }
+
     /// A convenience function to read an `u64` from the VM's memory.
     ///
     /// This is equivalent to calling [`RawInstance::read_memory_into`].
@@ -1395,6 +2078,83 @@ impl RawInstance {
         self.write_memory(address, &[value])
     }
 
+    /// Reads a value of a plain old data type from the VM's memory.
+    ///
+    /// This is equivalent to calling [`RawInstance::read_memory_into`].
+    #[cfg(target_endian = "little")]
+    pub fn read_pod<T>(&self, address: u32) -> Result<T, MemoryAccessError>
+    where
+        T: Pod,
+    {
+        let mut value = core::mem::MaybeUninit::<T>::uninit();
+
+        // SAFETY: The pointer is valid for `size_of::<T>()` bytes, and `MaybeUninit<u8>` has no validity requirements.
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr().cast::<core::mem::MaybeUninit<u8>>(), core::mem::size_of::<T>()) };
+        self.read_memory_into(address, buffer)?;
+
+        // SAFETY: Every byte was initialized, and `T` is `Pod` so any bit pattern is valid.
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Reads `count` consecutive values of a plain old data type from the VM's memory.
+    ///
+    /// This is equivalent to calling [`RawInstance::read_memory_into`].
+    #[cfg(target_endian = "little")]
+    pub fn read_pod_slice<T>(&self, address: u32, count: u32) -> Result<Vec<T>, MemoryAccessError>
+    where
+        T: Pod,
+    {
+        let length = cast(core::mem::size_of::<T>()).to_u64().saturating_mul(u64::from(count));
+
+        if u64::from(address).saturating_add(length) > 0x100000000 {
+            return Err(MemoryAccessError::OutOfRangeAccess { address, length });
+        }
+
+        let mut values: Vec<T> = Vec::with_capacity(cast(count).to_usize());
+        let spare = &mut values.spare_capacity_mut()[..cast(count).to_usize()];
+
+        // SAFETY: The spare capacity is valid for `length` bytes, and `MaybeUninit<u8>` has no validity requirements.
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(
+                spare.as_mut_ptr().cast::<core::mem::MaybeUninit<u8>>(),
+                core::mem::size_of_val(spare),
+            )
+        };
+        self.read_memory_into(address, buffer)?;
+
+        // SAFETY: Every byte was initialized, and `T` is `Pod` so any bit pattern is valid.
+        unsafe {
+            values.set_len(cast(count).to_usize());
+        }
+
+        Ok(values)
+    }
+
+    /// Writes a value of a plain old data type into the VM's memory.
+    ///
+    /// This is equivalent to calling [`RawInstance::write_memory`].
+    #[cfg(target_endian = "little")]
+    pub fn write_pod<T>(&mut self, address: u32, value: &T) -> Result<(), MemoryAccessError>
+    where
+        T: Pod,
+    {
+        self.write_pod_slice(address, core::slice::from_ref(value))
+    }
+
+    /// Writes consecutive values of a plain old data type into the VM's memory.
+    ///
+    /// This is equivalent to calling [`RawInstance::write_memory`].
+    #[cfg(target_endian = "little")]
+    pub fn write_pod_slice<T>(&mut self, address: u32, values: &[T]) -> Result<(), MemoryAccessError>
+    where
+        T: Pod,
+    {
+        // SAFETY: `T` is `Pod` so it has no padding, which means every byte is initialized.
+        let data = unsafe { core::slice::from_raw_parts(values.as_ptr().cast::<u8>(), core::mem::size_of_val(values)) };
+        self.write_memory(address, data)
+    }
+
     /// Fills the given memory region with zeros.
     ///
     /// `address` must be greater or equal to 0x10000 and `address + length` cannot be greater than 0x100000000.
@@ -1421,6 +2181,8 @@ impl RawInstance {
             });
         }
 
+        self.check_memory_limit(address, length)?;
+
         let result = access_backend!(self.backend, |mut backend| backend.zero_memory(address, length));
         if let Some(ref mut crosscheck) = self.crosscheck_instance {
             let expected_result = crosscheck.zero_memory(address, length);
@@ -1508,6 +2270,192 @@ impl RawInstance {
         Ok(result)
     }
 
+    /// Captures the current state of the instance.
+    ///
+    /// This includes the registers, the remaining gas, the next program counter, the size of the heap
+    /// and the contents of every writable page of memory which is currently accessible.
+    /// The snapshot can be applied back to this (or any other) instance of the same module with [`RawInstance::restore`].
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        if self.deferred_interruption.is_some() {
+            bail_static!("failed to snapshot: the instance ran out of gas and has an interruption pending");
+        }
+
+        let page_size = self.module.memory_map().page_size();
+        let mut regs = [0; Reg::ALL.len()];
+        for reg in Reg::ALL {
+            regs[reg as usize] = self.reg(reg);
+        }
+
+        let mut pages = Vec::new();
+        let mut capture_page = |address: u32| -> Result<(), Error> {
+            let data = self
+                .read_memory(address, page_size)
+                .map_err(|error| format!("failed to snapshot memory: {error}"))?;
+
+            let data = if data.iter().all(|&byte| byte == 0) {
+                None
+            } else {
+                Some(data.into())
+            };
+
+            pages.push(SnapshotPage { address, data });
+            Ok(())
+        };
+
+        let (heap_size, accessible_aux_size) = if !self.module.is_dynamic_paging() {
+            let map = self.module.memory_map();
+            let heap_size = self.heap_size();
+            let heap_top = self.module.round_to_page_size_up(map.heap_base() + heap_size);
+            let accessible_aux_size = access_backend!(self.backend, |backend| backend.accessible_aux_size());
+            let regions = [
+                map.rw_data_address()..heap_top,
+                map.stack_range(),
+                map.aux_data_address()..map.aux_data_address() + accessible_aux_size,
+            ];
+
+            for region in regions {
+                for address in (u64::from(region.start)..u64::from(region.end)).step_by(cast(page_size).to_usize()) {
+                    capture_page(cast(address).assert_always_fits_in_u32())?;
+                }
+            }
+
+            (heap_size, accessible_aux_size)
+        } else {
+            for address in access_backend!(self.backend, |backend| backend.mapped_pages()) {
+                capture_page(address)?;
+            }
+
+            (0, 0)
+        };
+
+        Ok(Snapshot {
+            program_hash: self.module.unique_hash(),
+            memory_map: SnapshotMemoryMap::new(self.module.memory_map()),
+            regs,
+            gas: self.gas(),
+            next_program_counter: self.next_program_counter(),
+            heap_size,
+            accessible_aux_size,
+            page_size,
+            is_dynamic_paging: self.module.is_dynamic_paging(),
+            is_64_bit: self.module.is_64_bit(),
+            pages,
+        })
+    }
+
+    /// Restores the state of the instance from a snapshot previously created with [`RawInstance::snapshot`].
+    ///
+    /// The snapshot must have been taken of an instance of the same program with the same memory map,
+    /// otherwise an error is returned. Any memory which was not accessible when the snapshot was taken
+    /// is reset to its initial state.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        if snapshot.page_size != self.module.memory_map().page_size() {
+            bail!(
+                "failed to restore snapshot: page size mismatch (expected {}, got {})",
+                self.module.memory_map().page_size(),
+                snapshot.page_size
+            );
+        }
+
+        if snapshot.program_hash != self.module.unique_hash() {
+            bail_static!("failed to restore snapshot: the snapshot was taken of a different program");
+        }
+
+        if snapshot.memory_map != SnapshotMemoryMap::new(self.module.memory_map()) {
+            bail_static!("failed to restore snapshot: memory map mismatch");
+        }
+
+        if snapshot.is_dynamic_paging != self.module.is_dynamic_paging() {
+            bail_static!("failed to restore snapshot: dynamic paging mismatch");
+        }
+
+        if snapshot.is_64_bit != self.module.is_64_bit() {
+            bail_static!("failed to restore snapshot: bitness mismatch");
+        }
+
+        self.reset_memory()?;
+        if !self.module.is_dynamic_paging() {
+            self.set_accessible_aux_size(snapshot.accessible_aux_size)?;
+            if snapshot.heap_size > 0 && self.sbrk(snapshot.heap_size)?.is_none() {
+                bail!(
+                    "failed to restore snapshot: failed to grow the heap to {} bytes",
+                    snapshot.heap_size
+                );
+            }
+        }
+
+        for page in &snapshot.pages {
+            let result = if let Some(ref data) = page.data {
+                self.write_memory(page.address, data)
+            } else {
+                self.zero_memory(page.address, snapshot.page_size)
+            };
+
+            result.map_err(|error| format!("failed to restore snapshot: {error}"))?;
+        }
+
+        for reg in Reg::ALL {
+            self.set_reg(reg, snapshot.regs[reg as usize]);
+        }
+
+        self.set_gas(snapshot.gas);
+        if let Some(pc) = snapshot.next_program_counter {
+            self.set_next_program_counter(pc);
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new instance of the same module with exactly the same state as this one.
+    ///
+    /// The new instance is completely independent; changes made to one of them are not visible in the other.
+    ///
+    /// With the Linux sandbox the guest's memory is shared copy-on-write, so forking is cheap regardless of how much
+    /// memory the guest uses; its current memory is copied once and then reused by every fork made until either of
+    /// the instances modifies it. Otherwise (or with dynamic paging, or when crosschecking) this is equivalent
+    /// to calling [`Module::instantiate`] and restoring a [`RawInstance::snapshot`] into it.
+    ///
+    /// The new instance inherits this instance's [limits](RawInstance::limits), including the number of basic blocks
+    /// which can still be executed, but it gets its own interrupt state, so it can only be interrupted through
+    /// a handle obtained from its own [`RawInstance::interrupt_handle`].
+    pub fn fork(&self) -> Result<RawInstance, Error> {
+        let mut instance = self.fork_state()?;
+        instance.set_limits(self.limits.clone())?;
+
+        // Setting the limits resets the count of executed basic blocks, so carry over what's left of ours.
+        let budget = access_backend!(self.backend, |backend| backend.basic_block_budget());
+        if let Some(ref mut crosscheck) = instance.crosscheck_instance {
+            crosscheck.set_basic_block_budget(budget);
+        }
+
+        access_backend!(instance.backend, |mut backend| backend.set_basic_block_budget(budget));
+        Ok(instance)
+    }
+
+    /// Creates a new instance with the same memory, registers, gas and program counter as this one.
+    fn fork_state(&self) -> Result<RawInstance, Error> {
+        if_compiler_is_supported! {
+            #[cfg(target_os = "linux")]
+            if let InstanceBackend::CompiledLinux(ref backend) = self.backend {
+                if !self.module.is_dynamic_paging() && self.crosscheck_instance.is_none() {
+                    return Ok(RawInstance {
+                        module: self.module.clone(),
+                        backend: InstanceBackend::CompiledLinux(backend.fork()?),
+                        crosscheck_instance: None,
+                        limits: InstanceLimits::default(),
+                        interrupt_state: None,
+                        deferred_interruption: None,
+                    });
+                }
+            }
+        }
+
+        let snapshot = self.snapshot()?;
+        let mut instance = self.module.instantiate()?;
+        instance.restore(&snapshot)?;
+        Ok(instance)
+    }
+
     /// A convenience function which sets up a fuction call according to the default ABI.
     ///
     /// This function will:
@@ -0,0 +1,237 @@
+//! Symbolized backtraces of guest programs.
+//!
+//! A [`Backtrace`] is captured from a [`RawInstance`] after it was interrupted (typically with
+//! [`InterruptKind::Trap`](crate::InterruptKind::Trap) or [`InterruptKind::Segfault`](crate::InterruptKind::Segfault)),
+//! and every one of its frames is resolved through the debug info embedded in the program blob, including inlined functions.
+//!
+//! There are no unwind tables in a program blob, so the frames are walked by analyzing the prologue of every function
+//! on the stack according to the RISC-V calling convention which is used by the linker: a function first reserves
+//! its stack frame by decrementing `sp` and then saves `ra` somewhere within it. This is best-effort; if a frame
+//! can't be walked through the backtrace simply ends there.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::fmt;
+use std::collections::HashMap;
+
+use polkavm_common::abi::VM_ADDR_RETURN_TO_HOST;
+use polkavm_common::cast::cast;
+use polkavm_common::program::{Instruction, ParsedInstruction, ProgramCounter, Reg};
+
+use crate::frames::{resolve_frames, Frame};
+use crate::{Module, RawInstance};
+
+/// The maximum number of frames which will be walked, just in case the stack is corrupted.
+const MAX_FRAMES: usize = 256;
+
+/// A single frame of a guest's call stack.
+#[derive(Clone, Debug)]
+pub struct BacktraceFrame {
+    program_counter: ProgramCounter,
+    functions: Vec<Frame>,
+}
+
+impl BacktraceFrame {
+    /// The program counter of the instruction which was being executed in this frame.
+    ///
+    /// For every frame except the innermost one this is the instruction which made the call.
+    pub fn program_counter(&self) -> ProgramCounter {
+        self.program_counter
+    }
+
+    /// The functions which were active at this frame's program counter, starting with the innermost one.
+    ///
+    /// Every function except the last one was inlined into the function after it. Always contains at least one function.
+    pub fn functions(&self) -> &[Frame] {
+        &self.functions
+    }
+}
+
+/// A symbolized backtrace of a guest program.
+#[derive(Clone, Debug)]
+pub struct Backtrace {
+    frames: Vec<BacktraceFrame>,
+}
+
+impl Backtrace {
+    /// Captures a backtrace of the guest program which is currently running in the given instance.
+    ///
+    /// The instance must have been interrupted at an instruction, otherwise the backtrace will be empty.
+    pub fn capture(instance: &RawInstance) -> Self {
+        let mut frames = Vec::new();
+        let Some(mut pc) = instance.program_counter() else {
+            return Backtrace { frames };
+        };
+
+        let unwinder = Unwinder::new(instance.module());
+        let mut sp = instance.reg(Reg::SP);
+        let mut ra = Some(instance.reg(Reg::RA));
+        loop {
+            // The debug info can contain multiple entries for the same function (e.g. for its declaration and the current line),
+            // so only keep the innermost one.
+            let mut functions: Vec<Frame> = resolve_frames(instance.module(), pc).into_iter().rev().collect();
+            functions.dedup_by(|outer, inner| outer.function == inner.function);
+            frames.push(BacktraceFrame {
+                program_counter: pc,
+                functions,
+            });
+
+            if frames.len() >= MAX_FRAMES {
+                break;
+            }
+
+            let Some((caller_pc, caller_sp)) = unwinder.unwind(instance, pc, sp, ra) else {
+                break;
+            };
+
+            pc = caller_pc;
+            sp = caller_sp;
+
+            // Only the innermost function can still have its return address in a register.
+            ra = None;
+        }
+
+        Backtrace { frames }
+    }
+
+    /// Returns the frames of this backtrace, starting with the innermost one.
+    pub fn frames(&self) -> &[BacktraceFrame] {
+        &self.frames
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for (index, frame) in self.frames.iter().enumerate() {
+            for (nth, function) in frame.functions.iter().enumerate() {
+                if nth == 0 {
+                    write!(fmt, "#{index:<3} #{} in {}", frame.program_counter, function.function)?;
+                } else {
+                    write!(fmt, "     inlined into {}", function.function)?;
+                }
+
+                match (&function.path, function.line) {
+                    (Some(path), Some(line)) => writeln!(fmt, " at {path}:{line}")?,
+                    (Some(path), None) => writeln!(fmt, " at {path}")?,
+                    (None, _) => writeln!(fmt)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Unwinder {
+    instructions: Vec<ParsedInstruction>,
+    function_starts: BTreeSet<ProgramCounter>,
+    call_sites: HashMap<u32, ProgramCounter>,
+    return_targets: BTreeMap<ProgramCounter, ProgramCounter>,
+    is_64_bit: bool,
+}
+
+impl Unwinder {
+    fn new(module: &Module) -> Self {
+        let ra = Reg::RA.into();
+        let instructions: Vec<_> = module.instructions().collect();
+        let mut function_starts = BTreeSet::new();
+        let mut call_sites = HashMap::new();
+        for (index, instruction) in instructions.iter().enumerate() {
+            match instruction.kind {
+                Instruction::load_imm_and_jump(dst, return_address, target) if dst == ra => {
+                    call_sites.insert(return_address, instruction.offset);
+                    function_starts.insert(ProgramCounter(target));
+                }
+                Instruction::load_imm_and_jump_indirect(dst, _, return_address, _) if dst == ra => {
+                    call_sites.insert(return_address, instruction.offset);
+                }
+                Instruction::load_imm(dst, return_address) if dst == ra => {
+                    // The linker can also lay out the callee right after the return address is loaded.
+                    call_sites.insert(return_address, instruction.offset);
+                    match instructions.get(index + 1).map(|next| next.kind) {
+                        Some(Instruction::jump(target)) => function_starts.insert(ProgramCounter(target)),
+                        Some(_) => function_starts.insert(instruction.next_offset),
+                        None => false,
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        function_starts.extend(module.exports().map(|export| export.program_counter()));
+
+        // Anything in the jump table which isn't a return address is most likely a function called through a pointer.
+        let jump_table = module.blob().jump_table();
+        let return_targets: BTreeMap<ProgramCounter, ProgramCounter> = call_sites
+            .iter()
+            .filter_map(|(&address, &call_site)| Some((jump_table.get_by_address(address)?, call_site)))
+            .collect();
+        function_starts.extend(jump_table.iter().filter(|pc| !return_targets.contains_key(pc)));
+
+        Unwinder {
+            instructions,
+            function_starts,
+            call_sites,
+            return_targets,
+            is_64_bit: module.is_64_bit(),
+        }
+    }
+
+    /// Walks a single frame, returning the caller's program counter and stack pointer.
+    fn unwind(&self, instance: &RawInstance, pc: ProgramCounter, sp: u64, ra: Option<u64>) -> Option<(ProgramCounter, u64)> {
+        // The code after a call belongs to the same function as the call itself, even if the callee was laid out in between.
+        let mut pc = pc;
+        let mut start = *self.function_starts.range(..=pc).next_back()?;
+        for _ in 0..16 {
+            match self.return_targets.range(..=pc).next_back() {
+                Some((&return_target, &call_site)) if return_target > start => {
+                    pc = call_site;
+                    start = *self.function_starts.range(..=pc).next_back()?;
+                }
+                _ => break,
+            }
+        }
+
+        let first = self.instructions.partition_point(|instruction| instruction.offset < start);
+        let sp_reg = Reg::SP.into();
+        let ra_reg = Reg::RA.into();
+
+        let mut frame_size = None;
+        let mut return_address_offset = None;
+        for instruction in self.instructions[first..].iter().take_while(|instruction| instruction.offset < pc) {
+            match instruction.kind {
+                Instruction::add_imm_32(dst, src, imm) | Instruction::add_imm_64(dst, src, imm)
+                    if dst == sp_reg && src == sp_reg && frame_size.is_none() && cast(imm).to_signed() < 0 =>
+                {
+                    frame_size = Some(imm.wrapping_neg());
+                }
+                Instruction::store_indirect_u32(src, base, offset) | Instruction::store_indirect_u64(src, base, offset)
+                    if src == ra_reg && base == sp_reg && frame_size.is_some() && return_address_offset.is_none() =>
+                {
+                    return_address_offset = Some(offset);
+                }
+                _ => {}
+            }
+        }
+
+        let return_address = match return_address_offset {
+            Some(offset) => {
+                let address = cast(sp).truncate_to_u32().wrapping_add(offset);
+                if self.is_64_bit {
+                    cast(instance.read_u64(address).ok()?).truncate_to_u32()
+                } else {
+                    instance.read_u32(address).ok()?
+                }
+            }
+            None => cast(ra?).truncate_to_u32(),
+        };
+
+        if return_address == VM_ADDR_RETURN_TO_HOST {
+            return None;
+        }
+
+        let caller_pc = *self.call_sites.get(&return_address)?;
+        let caller_sp = sp.wrapping_add(cast(frame_size.unwrap_or(0)).to_u64());
+        Some((caller_pc, caller_sp))
+    }
+}
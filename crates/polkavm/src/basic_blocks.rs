@@ -0,0 +1,46 @@
+//! Helpers for the tools which gather statistics for every basic block of a module.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use std::collections::HashMap;
+
+use polkavm_common::program::ProgramCounter;
+
+use crate::{InterruptKind, Module, RawInstance};
+
+/// Returns the ranges of every basic block of the module, in order.
+pub(crate) fn basic_blocks(module: &Module) -> Vec<Range<ProgramCounter>> {
+    let mut blocks = Vec::new();
+    let mut block_start = None;
+    for instruction in module.instructions() {
+        let start = *block_start.get_or_insert(instruction.offset);
+        if instruction.starts_new_basic_block() {
+            block_start = None;
+            blocks.push(start..instruction.next_offset);
+        }
+    }
+
+    blocks
+}
+
+/// Finds out which basic blocks are executed from the interruptions of instances with step tracing enabled.
+pub(crate) struct BlockCounter {
+    block_by_start: HashMap<ProgramCounter, usize>,
+}
+
+impl BlockCounter {
+    pub(crate) fn new(blocks: impl IntoIterator<Item = ProgramCounter>) -> Self {
+        BlockCounter {
+            block_by_start: blocks.into_iter().enumerate().map(|(index, start)| (start, index)).collect(),
+        }
+    }
+
+    /// Returns the index of the basic block which is about to be executed, if any.
+    pub(crate) fn on_interrupt(&self, instance: &RawInstance, interrupt: &InterruptKind) -> Option<usize> {
+        if !matches!(interrupt, InterruptKind::Step) {
+            return None;
+        }
+
+        self.block_by_start.get(&instance.program_counter()?).copied()
+    }
+}
@@ -0,0 +1,357 @@
+use std::path::PathBuf;
+
+use polkavm_common::cast::cast;
+use polkavm_common::hasher::{Hash, Hasher};
+use polkavm_common::program::{ProgramBlob, ProgramCounter};
+
+use crate::config::{ModuleConfig, SandboxKind};
+use crate::error::{bail, Error};
+use crate::utils::Reader;
+
+const MAGIC: [u8; 8] = *b"PVMCODE\0";
+
+// Bump this whenever the layout of the cache entries changes.
+const FORMAT_VERSION: u32 = 2;
+
+/// The output of the recompiler which is needed to load a module without recompiling it.
+pub(crate) struct CodeCacheEntry {
+    pub(crate) native_code_origin: u64,
+    pub(crate) invalid_code_offset_address: u64,
+    pub(crate) sysenter_address: u64,
+    pub(crate) sysreturn_address: u64,
+    pub(crate) memset_trampoline_start: u64,
+    pub(crate) memset_trampoline_end: u64,
+    pub(crate) code: Vec<u8>,
+    pub(crate) jump_table: Vec<u64>,
+    pub(crate) program_counter_to_machine_code_offset_list: Vec<(ProgramCounter, u32)>,
+    pub(crate) program_counter_to_deoptimized_machine_code_offset_list: Vec<(ProgramCounter, u32)>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct CodeCacheKey(Hash);
+
+impl core::fmt::Display for CodeCacheKey {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        self.0.fmt(fmt)
+    }
+}
+
+/// A persistent on-disk cache of the recompiler's output.
+pub(crate) struct CodeCache {
+    path: PathBuf,
+}
+
+impl CodeCache {
+    pub(crate) fn new(path: PathBuf) -> Result<Self, Error> {
+        std::fs::create_dir_all(&path)
+            .map_err(|error| Error::from(format!("failed to create the code cache directory '{}': {error}", path.display())))?;
+
+        log::debug!("Using on-disk code cache: {}", path.display());
+        Ok(CodeCache { path })
+    }
+
+    /// Returns the cache key for the given module, or `None` if the module cannot be cached.
+    pub(crate) fn key(config: &ModuleConfig, blob: &ProgramBlob, sandbox: SandboxKind, crosscheck: bool) -> Option<CodeCacheKey> {
+        let config_hash = config.hash()?;
+        let mut hasher = Hasher::new();
+        hasher.update(&MAGIC);
+        hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher.update(std::env::consts::ARCH.as_bytes());
+        hasher.update_u32_array([FORMAT_VERSION, sandbox_kind_to_u32(sandbox), u32::from(crosscheck)]);
+        hasher.update(&config_hash.0);
+        hasher.update(&blob.unique_hash(true).0);
+        Some(CodeCacheKey(hasher.finalize()))
+    }
+
+    fn entry_path(&self, key: CodeCacheKey) -> PathBuf {
+        self.path.join(format!("{key}.pvmcode"))
+    }
+
+    /// Loads the entry with the given key, if it exists and isn't corrupted.
+    ///
+    /// # Safety
+    ///
+    /// The entry's native code will be executed as-is, and it's only checked for accidental corruption,
+    /// so the cache's directory must only be writable by trusted users.
+    pub(crate) unsafe fn load(&self, key: CodeCacheKey) -> Option<CodeCacheEntry> {
+        let path = self.entry_path(key);
+        let blob = match std::fs::read(&path) {
+            Ok(blob) => blob,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                log::debug!("Code cache miss: {key}");
+                return None;
+            }
+            Err(error) => {
+                log::warn!("Failed to read code cache entry {}: {error}", path.display());
+                return None;
+            }
+        };
+
+        let Some(entry) = deserialize(key, &blob) else {
+            log::warn!("Ignoring corrupted code cache entry: {}", path.display());
+            return None;
+        };
+
+        log::debug!("Code cache hit: {key}");
+        Some(entry)
+    }
+
+    pub(crate) fn store(&self, key: CodeCacheKey, entry: &CodeCacheEntry) {
+        let Some(blob) = serialize(key, entry) else {
+            log::warn!("Failed to store code cache entry {key}: the entry is too big");
+            return;
+        };
+
+        // Write into a temporary file first so that other processes never see a partially written entry.
+        let path = self.entry_path(key);
+        let tmp_path = self.path.join(format!("{key}.{}.tmp", std::process::id()));
+        let result = std::fs::write(&tmp_path, blob).and_then(|()| std::fs::rename(&tmp_path, &path));
+        if let Err(error) = result {
+            log::warn!("Failed to store code cache entry {}: {error}", path.display());
+            let _ = std::fs::remove_file(&tmp_path);
+            return;
+        }
+
+        log::debug!("Stored code cache entry: {key}");
+    }
+}
+
+fn write_length(blob: &mut Vec<u8>, length: usize) -> Option<()> {
+    blob.extend_from_slice(&u32::try_from(length).ok()?.to_le_bytes());
+    Some(())
+}
+
+fn write_entry(blob: &mut Vec<u8>, entry: &CodeCacheEntry) -> Option<()> {
+    for value in [
+        entry.native_code_origin,
+        entry.invalid_code_offset_address,
+        entry.sysenter_address,
+        entry.sysreturn_address,
+        entry.memset_trampoline_start,
+        entry.memset_trampoline_end,
+    ] {
+        blob.extend_from_slice(&value.to_le_bytes());
+    }
+
+    write_length(blob, entry.code.len())?;
+    blob.extend_from_slice(&entry.code);
+
+    write_length(blob, entry.jump_table.len())?;
+    for address in &entry.jump_table {
+        blob.extend_from_slice(&address.to_le_bytes());
+    }
+
+    for list in [
+        &entry.program_counter_to_machine_code_offset_list,
+        &entry.program_counter_to_deoptimized_machine_code_offset_list,
+    ] {
+        write_length(blob, list.len())?;
+        for &(program_counter, native_offset) in list {
+            blob.extend_from_slice(&program_counter.0.to_le_bytes());
+            blob.extend_from_slice(&native_offset.to_le_bytes());
+        }
+    }
+
+    Some(())
+}
+
+fn append_checksum(blob: &mut Vec<u8>) {
+    // The code is going to be executed, so make sure it wasn't truncated or otherwise damaged.
+    let mut hasher = Hasher::new();
+    hasher.update(blob);
+    blob.extend_from_slice(&hasher.finalize().0);
+}
+
+fn verify_checksum(blob: &[u8]) -> Option<&[u8]> {
+    let (payload, checksum) = blob.split_at(blob.len().checked_sub(32)?);
+    let mut hasher = Hasher::new();
+    hasher.update(payload);
+    if hasher.finalize().0 != checksum {
+        return None;
+    }
+
+    Some(payload)
+}
+
+fn serialize(key: CodeCacheKey, entry: &CodeCacheEntry) -> Option<Vec<u8>> {
+    let mut blob = Vec::with_capacity(entry.code.len() + entry.jump_table.len() * 8 + 256);
+    blob.extend_from_slice(&MAGIC);
+    blob.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    blob.extend_from_slice(&key.0 .0);
+    write_entry(&mut blob, entry)?;
+    append_checksum(&mut blob);
+    Some(blob)
+}
+
+fn read_slice_with_length<'a>(reader: &mut Reader<'a>) -> Result<&'a [u8], Error> {
+    let length = cast(reader.read_u32()?).to_usize();
+    reader.read_slice(length)
+}
+
+fn read_entry(reader: &mut Reader) -> Result<CodeCacheEntry, Error> {
+    let native_code_origin = reader.read_u64()?;
+    let invalid_code_offset_address = reader.read_u64()?;
+    let sysenter_address = reader.read_u64()?;
+    let sysreturn_address = reader.read_u64()?;
+    let memset_trampoline_start = reader.read_u64()?;
+    let memset_trampoline_end = reader.read_u64()?;
+
+    let code = read_slice_with_length(reader)?.to_vec();
+
+    let jump_table_length = cast(reader.read_u32()?).to_usize();
+    // Don't let a damaged length trigger a huge allocation.
+    let mut jump_table = Vec::with_capacity(jump_table_length.min(reader.remaining() / 8));
+    for _ in 0..jump_table_length {
+        jump_table.push(reader.read_u64()?);
+    }
+
+    let program_counter_to_machine_code_offset_list = read_offset_list(reader)?;
+    let program_counter_to_deoptimized_machine_code_offset_list = read_offset_list(reader)?;
+
+    Ok(CodeCacheEntry {
+        native_code_origin,
+        invalid_code_offset_address,
+        sysenter_address,
+        sysreturn_address,
+        memset_trampoline_start,
+        memset_trampoline_end,
+        code,
+        jump_table,
+        program_counter_to_machine_code_offset_list,
+        program_counter_to_deoptimized_machine_code_offset_list,
+    })
+}
+
+fn read_offset_list(reader: &mut Reader) -> Result<Vec<(ProgramCounter, u32)>, Error> {
+    let length = cast(reader.read_u32()?).to_usize();
+    let mut list = Vec::with_capacity(length.min(reader.remaining() / 8));
+    for _ in 0..length {
+        list.push((ProgramCounter(reader.read_u32()?), reader.read_u32()?));
+    }
+
+    Ok(list)
+}
+
+fn deserialize(key: CodeCacheKey, blob: &[u8]) -> Option<CodeCacheEntry> {
+    let mut reader = Reader::new(verify_checksum(blob)?, "unexpected end of a code cache entry");
+    if reader.read_slice(MAGIC.len()).ok()? != MAGIC || reader.read_u32().ok()? != FORMAT_VERSION || reader.read_slice(32).ok()? != key.0 .0
+    {
+        return None;
+    }
+
+    let entry = read_entry(&mut reader).ok()?;
+    if reader.remaining() != 0 {
+        return None;
+    }
+
+    Some(entry)
+}
+
+const PRECOMPILED_MAGIC: [u8; 8] = *b"PVMAOT\0\0";
+
+fn sandbox_kind_to_u32(sandbox: SandboxKind) -> u32 {
+    match sandbox {
+        SandboxKind::Linux => 0,
+        SandboxKind::Generic => 1,
+    }
+}
+
+/// Serializes a precompiled module, which is the original program blob bundled together with its native code.
+pub(crate) fn serialize_precompiled(
+    program_blob: &[u8],
+    config_hash: Hash,
+    sandbox: SandboxKind,
+    crosscheck: bool,
+    entry: &CodeCacheEntry,
+) -> Result<Vec<u8>, Error> {
+    fn serialize_impl(
+        program_blob: &[u8],
+        config_hash: Hash,
+        sandbox: SandboxKind,
+        crosscheck: bool,
+        entry: &CodeCacheEntry,
+    ) -> Option<Vec<u8>> {
+        let mut blob = Vec::with_capacity(program_blob.len() + entry.code.len() + entry.jump_table.len() * 8 + 256);
+        blob.extend_from_slice(&PRECOMPILED_MAGIC);
+        blob.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        for string in [env!("CARGO_PKG_VERSION"), std::env::consts::ARCH] {
+            write_length(&mut blob, string.len())?;
+            blob.extend_from_slice(string.as_bytes());
+        }
+
+        blob.extend_from_slice(&sandbox_kind_to_u32(sandbox).to_le_bytes());
+        blob.extend_from_slice(&u32::from(crosscheck).to_le_bytes());
+        blob.extend_from_slice(&config_hash.0);
+        write_length(&mut blob, program_blob.len())?;
+        blob.extend_from_slice(program_blob);
+        write_entry(&mut blob, entry)?;
+        append_checksum(&mut blob);
+        Some(blob)
+    }
+
+    serialize_impl(program_blob, config_hash, sandbox, crosscheck, entry)
+        .ok_or_else(|| Error::from_static_str("failed to serialize the precompiled module: the module is too big"))
+}
+
+/// Deserializes a precompiled module and checks whether it is compatible with the current environment.
+///
+/// Returns the original program blob and its native code.
+pub(crate) fn deserialize_precompiled(
+    blob: &[u8],
+    config_hash: Hash,
+    sandbox: SandboxKind,
+    crosscheck: bool,
+) -> Result<(&[u8], CodeCacheEntry), Error> {
+    const CORRUPTED: &str = "failed to load the precompiled module: the module is corrupted";
+
+    if !blob.starts_with(&PRECOMPILED_MAGIC) {
+        return Err(Error::from_static_str(
+            "failed to load the precompiled module: the data is not a precompiled PolkaVM module",
+        ));
+    }
+
+    let mut reader = Reader::new(verify_checksum(blob).ok_or(Error::from_static_str(CORRUPTED))?, CORRUPTED);
+    reader.read_slice(PRECOMPILED_MAGIC.len())?;
+    let format_version = reader.read_u32()?;
+    if format_version != FORMAT_VERSION {
+        bail!("failed to load the precompiled module: unsupported format version {format_version} (expected {FORMAT_VERSION})");
+    }
+
+    let version = read_slice_with_length(&mut reader)?;
+    if version != env!("CARGO_PKG_VERSION").as_bytes() {
+        bail!(
+            "failed to load the precompiled module: the module was compiled with PolkaVM {}, but this is PolkaVM {}",
+            String::from_utf8_lossy(version),
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+
+    let arch = read_slice_with_length(&mut reader)?;
+    if arch != std::env::consts::ARCH.as_bytes() {
+        bail!(
+            "failed to load the precompiled module: the module was compiled for '{}', but this is '{}'",
+            String::from_utf8_lossy(arch),
+            std::env::consts::ARCH
+        );
+    }
+
+    if reader.read_u32()? != sandbox_kind_to_u32(sandbox) {
+        bail!("failed to load the precompiled module: the module was compiled for a different sandbox");
+    }
+
+    if reader.read_u32()? != u32::from(crosscheck) {
+        bail!("failed to load the precompiled module: the module was compiled with a different crosscheck setting");
+    }
+
+    if reader.read_slice(32)? != config_hash.0 {
+        bail!("failed to load the precompiled module: the module was compiled with a different module config");
+    }
+
+    let program_blob = read_slice_with_length(&mut reader)?;
+    let entry = read_entry(&mut reader)?;
+    if reader.remaining() != 0 {
+        return Err(Error::from_static_str(CORRUPTED));
+    }
+
+    Ok((program_blob, entry))
+}
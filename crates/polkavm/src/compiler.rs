@@ -5,13 +5,15 @@ use std::sync::Arc;
 use polkavm_assembler::{Assembler, Label};
 use polkavm_common::abi::VM_CODE_ADDRESS_ALIGNMENT;
 use polkavm_common::program::{
-    is_jump_target_valid, InstructionVisitor, Instructions, JumpTable, ParsedInstruction, ProgramCounter, ProgramExport, RawReg,
+    is_jump_target_valid, Instruction, InstructionVisitor, Instructions, JumpTable, ParsedInstruction, ProgramCounter, ProgramExport,
+    RawReg,
 };
 use polkavm_common::zygote::VM_COMPILER_MAXIMUM_INSTRUCTION_LENGTH;
 
 use crate::error::Error;
 
 use crate::api::RuntimeInstructionSet;
+use crate::code_cache::CodeCacheEntry;
 use crate::config::{CustomCodegen, GasMeteringKind, ModuleConfig, SandboxKind};
 use crate::gas::{CostModelRef, GasVisitor};
 use crate::mutex::Mutex;
@@ -21,6 +23,10 @@ use crate::utils::{FlatMap, GuestInit};
 #[cfg(target_arch = "x86_64")]
 mod amd64;
 
+mod known_state;
+
+use known_state::KnownState;
+
 /// The address to which to jump to for invalid dynamic jumps.
 ///
 /// This needs to be at least 0x800000000000 on modern CPUs, but ideally should have
@@ -49,11 +55,15 @@ struct CachePerCompilation {
     gas_metering_stub_offsets: Vec<usize>,
     gas_cost_for_basic_block: Vec<u32>,
     export_to_label: HashMap<u32, Label>,
+    optimized_run: Vec<(u32, bool)>,
+    deoptimized_program_counters: Vec<u32>,
+    deoptimized_runs: Vec<(usize, u32)>,
 }
 
 struct CachePerModule {
     program_counter_to_machine_code_offset_list: Vec<(ProgramCounter, u32)>,
     program_counter_to_machine_code_offset_map: HashMap<ProgramCounter, u32>,
+    program_counter_to_deoptimized_machine_code_offset_list: Vec<(ProgramCounter, u32)>,
 }
 
 #[derive(Default)]
@@ -97,6 +107,7 @@ where
     asm: Assembler,
     program_counter_to_label: FlatMap<Label>,
     step_tracing: bool,
+    execution_limits: bool,
     ecall_label: Label,
     export_to_label: HashMap<u32, Label>,
     exports: &'a [ProgramExport<&'a [u8]>],
@@ -105,11 +116,20 @@ where
     jump_table_label: Label,
     program_counter_to_machine_code_offset_list: Vec<(ProgramCounter, u32)>,
     program_counter_to_machine_code_offset_map: HashMap<ProgramCounter, u32>,
+    program_counter_to_deoptimized_machine_code_offset_list: Vec<(ProgramCounter, u32)>,
     gas_metering_stub_offsets: Vec<usize>,
     gas_cost_for_basic_block: Vec<u32>,
+    known_state: KnownState,
+    // The instructions compiled since the known state was last cleared, and whether they depend on it.
+    optimized_run: Vec<(u32, bool)>,
+    // The instructions which need an unoptimized copy; split into runs by `deoptimized_runs`.
+    deoptimized_program_counters: Vec<u32>,
+    // The end of each run in `deoptimized_program_counters` and the program counter to continue at afterwards.
+    deoptimized_runs: Vec<(usize, u32)>,
     code_length: u32,
     sbrk_label: Label,
     step_label: Label,
+    execution_limit_label: Label,
     trap_label: Label,
     memset_label: Label,
     invalid_jump_label: Label,
@@ -192,6 +212,9 @@ where
         let mut gas_cost_for_basic_block: Vec<u32>;
         let program_counter_to_label;
         let export_to_label;
+        let optimized_run;
+        let deoptimized_program_counters;
+        let deoptimized_runs;
 
         if let Some(per_compilation_cache) = per_compilation_cache {
             asm = per_compilation_cache.assembler;
@@ -199,28 +222,39 @@ where
             gas_metering_stub_offsets = per_compilation_cache.gas_metering_stub_offsets;
             gas_cost_for_basic_block = per_compilation_cache.gas_cost_for_basic_block;
             export_to_label = per_compilation_cache.export_to_label;
+            optimized_run = per_compilation_cache.optimized_run;
+            deoptimized_program_counters = per_compilation_cache.deoptimized_program_counters;
+            deoptimized_runs = per_compilation_cache.deoptimized_runs;
         } else {
             asm = Assembler::new();
             program_counter_to_label = FlatMap::new(code_length + 2);
             gas_metering_stub_offsets = Vec::new();
             gas_cost_for_basic_block = Vec::new();
             export_to_label = HashMap::new();
+            optimized_run = Vec::new();
+            deoptimized_program_counters = Vec::new();
+            deoptimized_runs = Vec::new();
         }
 
         let program_counter_to_machine_code_offset_list: Vec<(ProgramCounter, u32)>;
         let program_counter_to_machine_code_offset_map: HashMap<ProgramCounter, u32>;
+        let program_counter_to_deoptimized_machine_code_offset_list: Vec<(ProgramCounter, u32)>;
         if let Some(per_module_cache) = per_module_cache {
             program_counter_to_machine_code_offset_list = per_module_cache.program_counter_to_machine_code_offset_list;
             program_counter_to_machine_code_offset_map = per_module_cache.program_counter_to_machine_code_offset_map;
+            program_counter_to_deoptimized_machine_code_offset_list =
+                per_module_cache.program_counter_to_deoptimized_machine_code_offset_list;
         } else {
             program_counter_to_machine_code_offset_list = Vec::with_capacity(code_length as usize);
             program_counter_to_machine_code_offset_map = HashMap::with_capacity(exports.len());
+            program_counter_to_deoptimized_machine_code_offset_list = Vec::new();
         }
 
         let ecall_label = asm.forward_declare_label();
         let trap_label = asm.forward_declare_label();
         let invalid_jump_label = asm.forward_declare_label();
         let step_label = asm.forward_declare_label();
+        let execution_limit_label = asm.forward_declare_label();
         let jump_table_label = asm.forward_declare_label();
         let sbrk_label = asm.forward_declare_label();
         let memset_label = asm.forward_declare_label();
@@ -248,15 +282,23 @@ where
             trap_label,
             invalid_jump_label,
             step_label,
+            execution_limit_label,
             jump_table_label,
             sbrk_label,
             memset_label,
             gas_metering: config.gas_metering,
             step_tracing,
+            execution_limits: config.execution_limits,
             program_counter_to_machine_code_offset_list,
             program_counter_to_machine_code_offset_map,
+            program_counter_to_deoptimized_machine_code_offset_list,
             gas_metering_stub_offsets,
             gas_cost_for_basic_block,
+            // The state can't be tracked when tracing, since then every instruction can be an entry point.
+            known_state: KnownState::new(!step_tracing, B::BITNESS == Bitness::B64),
+            optimized_run,
+            deoptimized_program_counters,
+            deoptimized_runs,
             code_length,
             instruction_set,
             memset_trampoline_start: 0,
@@ -279,6 +321,10 @@ where
             ArchVisitor(&mut visitor).emit_step_trampoline();
         }
 
+        if config.execution_limits {
+            ArchVisitor(&mut visitor).emit_execution_limit_trampoline();
+        }
+
         log::trace!("Emitting code...");
         visitor
             .program_counter_to_machine_code_offset_list
@@ -297,14 +343,17 @@ where
         global: &S::GlobalState,
         cache: &CompilerCache,
         address_space: S::AddressSpace,
-    ) -> Result<CompiledModule<S>, Error>
+        create_code_cache_entry: bool,
+    ) -> Result<(CompiledModule<S>, Option<CodeCacheEntry>), Error>
     where
         S: Sandbox,
     {
         log::trace!("Finishing compilation...");
         let invalid_code_offset_address = self.asm.origin() + self.asm.len() as u64;
         self.emit_trap_epilogue();
+        self.emit_deoptimized_code();
         self.program_counter_to_machine_code_offset_list.shrink_to_fit();
+        self.program_counter_to_deoptimized_machine_code_offset_list.shrink_to_fit();
 
         let mut gas_metering_stub_offsets = core::mem::take(&mut self.gas_metering_stub_offsets);
         let mut gas_cost_for_basic_block = core::mem::take(&mut self.gas_cost_for_basic_block);
@@ -341,25 +390,12 @@ where
         }
 
         assert!(self.program_counter_to_machine_code_offset_map.is_empty());
-        for export in self.exports {
-            let native_offset = if let Ok(index) = self
-                .program_counter_to_machine_code_offset_list
-                .binary_search_by_key(&export.program_counter(), |&(code_offset, _)| code_offset)
-            {
-                self.program_counter_to_machine_code_offset_list[index].1
-            } else {
-                self.program_counter_to_machine_code_offset_list.last().unwrap().1
-            };
-
-            log::trace!(
-                "Export at {}: {} => 0x{:08x}",
-                export.program_counter(),
-                export.symbol(),
-                native_code_origin + u64::from(native_offset)
-            );
-            self.program_counter_to_machine_code_offset_map
-                .insert(export.program_counter(), native_offset);
-        }
+        fill_export_map(
+            &mut self.program_counter_to_machine_code_offset_map,
+            &self.program_counter_to_machine_code_offset_list,
+            self.exports,
+            native_code_origin,
+        );
 
         let sysenter_address = native_code_origin
             .checked_add_signed(self.asm.get_label_origin_offset_or_panic(label_sysenter) as i64)
@@ -380,26 +416,52 @@ where
         }
 
         let module = {
+            let code = self.asm.finalize();
+            let code_cache_entry = if create_code_cache_entry {
+                Some(CodeCacheEntry {
+                    native_code_origin,
+                    invalid_code_offset_address,
+                    sysenter_address,
+                    sysreturn_address,
+                    memset_trampoline_start: polkavm_common::cast::cast(self.memset_trampoline_start).to_u64(),
+                    memset_trampoline_end: polkavm_common::cast::cast(self.memset_trampoline_end).to_u64(),
+                    code: code.to_vec(),
+                    jump_table: native_jump_table.as_mut()[..jump_table_length]
+                        .iter()
+                        .map(|&address| polkavm_common::cast::cast(address).to_u64())
+                        .collect(),
+                    program_counter_to_machine_code_offset_list: self.program_counter_to_machine_code_offset_list.clone(),
+                    program_counter_to_deoptimized_machine_code_offset_list: self
+                        .program_counter_to_deoptimized_machine_code_offset_list
+                        .clone(),
+                })
+            } else {
+                None
+            };
+
             let init = SandboxInit {
                 guest_init: self.init,
-                code: &self.asm.finalize(),
+                code: &code,
                 jump_table: native_jump_table,
                 sysenter_address,
                 sysreturn_address,
             };
 
             let sandbox_program = S::prepare_program(global, init, address_space).map_err(Error::from_display)?;
-            CompiledModule {
+            let module = CompiledModule {
                 sandbox_program,
                 native_code_origin,
                 program_counter_to_machine_code_offset_list: self.program_counter_to_machine_code_offset_list,
                 program_counter_to_machine_code_offset_map: self.program_counter_to_machine_code_offset_map,
+                program_counter_to_deoptimized_machine_code_offset_list: self.program_counter_to_deoptimized_machine_code_offset_list,
                 cache: cache.clone(),
                 invalid_code_offset_address,
                 bitness: B::BITNESS,
                 memset_trampoline_start: polkavm_common::cast::cast(self.memset_trampoline_start).to_u64(),
                 memset_trampoline_end: polkavm_common::cast::cast(self.memset_trampoline_end).to_u64(),
-            }
+            };
+
+            (module, code_cache_entry)
         };
 
         {
@@ -410,6 +472,9 @@ where
                 self.export_to_label.clear();
                 gas_metering_stub_offsets.clear();
                 gas_cost_for_basic_block.clear();
+                self.optimized_run.clear();
+                self.deoptimized_program_counters.clear();
+                self.deoptimized_runs.clear();
 
                 cache.per_compilation.push(CachePerCompilation {
                     assembler: self.asm,
@@ -417,6 +482,9 @@ where
                     export_to_label: self.export_to_label,
                     gas_metering_stub_offsets,
                     gas_cost_for_basic_block,
+                    optimized_run: self.optimized_run,
+                    deoptimized_program_counters: self.deoptimized_program_counters,
+                    deoptimized_runs: self.deoptimized_runs,
                 });
             }
         }
@@ -424,6 +492,76 @@ where
         Ok(module)
     }
 
+    /// Loads a module which was previously compiled and stored in the on-disk code cache.
+    ///
+    /// Returns `None` if the cached code cannot be used in this process.
+    pub(crate) fn from_code_cache(
+        global: &S::GlobalState,
+        cache: &CompilerCache,
+        init: GuestInit<'a>,
+        jump_table: JumpTable<'a>,
+        exports: &'a [ProgramExport<&'a [u8]>],
+        entry: CodeCacheEntry,
+    ) -> Result<Option<CompiledModule<S>>, Error> {
+        let address_space = S::reserve_address_space().map_err(Error::from_display)?;
+
+        // The machine code contains absolute addresses, so it can only be reused at the same address.
+        let native_code_origin = crate::sandbox::SandboxAddressSpace::native_code_origin(&address_space);
+        if entry.native_code_origin != native_code_origin {
+            log::debug!(
+                "Cannot use the cached code: it was compiled for 0x{:x}, but the code will be loaded at 0x{:x}",
+                entry.native_code_origin,
+                native_code_origin
+            );
+            return Ok(None);
+        }
+
+        let jump_table_length = (jump_table.len() as usize + 1) * VM_CODE_ADDRESS_ALIGNMENT as usize;
+        if entry.jump_table.len() != jump_table_length {
+            log::warn!("Cannot use the cached code: jump table length mismatch");
+            return Ok(None);
+        }
+
+        let mut native_jump_table = S::allocate_jump_table(global, jump_table_length).map_err(Error::from_display)?;
+        {
+            let native_jump_table = native_jump_table.as_mut();
+            for (target, &address) in native_jump_table.iter_mut().zip(entry.jump_table.iter()) {
+                *target = usize::try_from(address).expect("overflow");
+            }
+            native_jump_table[jump_table_length..].fill(JUMP_TABLE_INVALID_ADDRESS);
+        }
+
+        let mut program_counter_to_machine_code_offset_map = HashMap::with_capacity(exports.len());
+        fill_export_map(
+            &mut program_counter_to_machine_code_offset_map,
+            &entry.program_counter_to_machine_code_offset_list,
+            exports,
+            native_code_origin,
+        );
+
+        let init = SandboxInit {
+            guest_init: init,
+            code: &entry.code,
+            jump_table: native_jump_table,
+            sysenter_address: entry.sysenter_address,
+            sysreturn_address: entry.sysreturn_address,
+        };
+
+        let sandbox_program = S::prepare_program(global, init, address_space).map_err(Error::from_display)?;
+        Ok(Some(CompiledModule {
+            sandbox_program,
+            native_code_origin,
+            program_counter_to_machine_code_offset_list: entry.program_counter_to_machine_code_offset_list,
+            program_counter_to_machine_code_offset_map,
+            program_counter_to_deoptimized_machine_code_offset_list: entry.program_counter_to_deoptimized_machine_code_offset_list,
+            cache: cache.clone(),
+            invalid_code_offset_address: entry.invalid_code_offset_address,
+            bitness: B::BITNESS,
+            memset_trampoline_start: entry.memset_trampoline_start,
+            memset_trampoline_end: entry.memset_trampoline_end,
+        }))
+    }
+
     #[inline(always)]
     fn force_start_new_basic_block(&mut self, program_counter: u32, is_valid_jump_target: bool) {
         log::trace!("Starting new basic block at: {program_counter}");
@@ -442,16 +580,31 @@ where
             self.step(program_counter);
         }
 
+        if self.execution_limits && (program_counter as usize) < self.code.len() {
+            ArchVisitor(self).emit_execution_limit_stub(program_counter);
+        }
+
         if let Some(gas_metering) = self.gas_metering {
             self.gas_metering_stub_offsets.push(self.asm.len());
             ArchVisitor(self).emit_gas_metering_stub(gas_metering);
         }
     }
 
-    fn before_instruction(&self, program_counter: u32) {
+    fn before_instruction(&mut self, program_counter: u32) {
+        if log::log_enabled!(log::Level::Trace) {
+            self.trace_compiled_instruction(program_counter);
+        }
+
+        self.known_state.begin_instruction(false);
+    }
+
+    /// Same as `before_instruction`, but for instructions which can be optimized based on the known state.
+    fn before_optimized_instruction(&mut self, program_counter: u32) {
         if log::log_enabled!(log::Level::Trace) {
             self.trace_compiled_instruction(program_counter);
         }
+
+        self.known_state.begin_instruction(true);
     }
 
     fn after_instruction<const KIND: usize>(&mut self, program_counter: u32, args_length: u32) {
@@ -465,11 +618,19 @@ where
             }
         }
 
+        if self.known_state.is_tracked() {
+            self.optimized_run.push((program_counter, self.known_state.was_used()));
+        } else {
+            self.end_optimized_run(program_counter);
+        }
+
         let next_program_counter = program_counter + args_length + 1;
         self.program_counter_to_machine_code_offset_list
             .push((ProgramCounter(next_program_counter), self.asm.len() as u32));
 
         if KIND == END_BASIC_BLOCK || KIND == END_BASIC_BLOCK_INVALID {
+            self.end_optimized_run(next_program_counter);
+
             if self.gas_metering.is_some() {
                 let cost = self.gas_visitor.take_block_cost().unwrap();
                 self.gas_cost_for_basic_block.push(cost);
@@ -483,6 +644,142 @@ where
         }
     }
 
+    /// Forgets everything that was known, and schedules an unoptimized copy of the instructions
+    /// which depended on it, so that the execution can also start in the middle of the run.
+    fn end_optimized_run(&mut self, resume_program_counter: u32) {
+        // The first instruction of a run never depends on anything, so it doesn't need a copy.
+        if let Some(end) = self
+            .optimized_run
+            .iter()
+            .rposition(|&(_, is_dependent)| is_dependent)
+            .filter(|&end| end > 0)
+        {
+            let resume_program_counter = self.optimized_run.get(end + 1).map_or(resume_program_counter, |&(pc, _)| pc);
+            self.deoptimized_program_counters
+                .extend(self.optimized_run[1..=end].iter().map(|&(program_counter, _)| program_counter));
+            self.deoptimized_runs
+                .push((self.deoptimized_program_counters.len(), resume_program_counter));
+        }
+
+        self.optimized_run.clear();
+        self.known_state.clear();
+    }
+
+    fn emit_deoptimized_code(&mut self) {
+        if self.deoptimized_runs.is_empty() {
+            return;
+        }
+
+        log::trace!("Emitting deoptimized code...");
+        self.known_state.set_enabled(false);
+
+        let deoptimized_program_counters = core::mem::take(&mut self.deoptimized_program_counters);
+        let deoptimized_runs = core::mem::take(&mut self.deoptimized_runs);
+        let mut start = 0;
+        for &(end, resume_program_counter) in &deoptimized_runs {
+            for &program_counter in &deoptimized_program_counters[start..end] {
+                self.program_counter_to_deoptimized_machine_code_offset_list
+                    .push((ProgramCounter(program_counter), self.asm.len() as u32));
+
+                let instruction = Instructions::new_bounded(self.instruction_set, self.code, self.bitmask, program_counter)
+                    .next()
+                    .expect("internal error: failed to parse an already compiled instruction");
+
+                self.before_instruction(program_counter);
+                self.emit_deoptimized_instruction(instruction.kind);
+            }
+
+            start = end;
+
+            // Continue in the optimized code once nothing depends on the known state anymore.
+            let index = self
+                .program_counter_to_machine_code_offset_list
+                .binary_search_by_key(&ProgramCounter(resume_program_counter), |&(program_counter, _)| program_counter)
+                .expect("internal error: no machine code for the resume point of a deoptimized run");
+
+            let resume_offset = self.program_counter_to_machine_code_offset_list[index].1;
+            self.program_counter_to_deoptimized_machine_code_offset_list
+                .push((ProgramCounter(resume_program_counter), self.asm.len() as u32));
+
+            let label = self.asm.forward_declare_label();
+            self.asm.set_label_origin_offset(label, resume_offset as isize);
+            ArchVisitor(self).jump_to_label(label);
+        }
+
+        self.deoptimized_program_counters = deoptimized_program_counters;
+        self.deoptimized_runs = deoptimized_runs;
+    }
+
+    fn emit_deoptimized_instruction(&mut self, instruction: Instruction) {
+        macro_rules! emit {
+            ($($name:ident($($arg:ident),*))+) => {
+                match instruction {
+                    $(Instruction::$name($($arg),*) => ArchVisitor(self).$name($($arg),*),)+
+                    _ => unreachable!("internal error: unexpected deoptimized instruction: {instruction:?}"),
+                }
+            };
+        }
+
+        emit! {
+            load_imm(d, value)
+            load_imm64(d, value)
+            move_reg(d, s)
+            add_32(d, s1, s2)
+            add_64(d, s1, s2)
+            sub_32(d, s1, s2)
+            sub_64(d, s1, s2)
+            and(d, s1, s2)
+            or(d, s1, s2)
+            xor(d, s1, s2)
+            add_imm_32(d, s, imm)
+            add_imm_64(d, s, imm)
+            and_imm(d, s, imm)
+            or_imm(d, s, imm)
+            xor_imm(d, s, imm)
+            shift_logical_left_imm_32(d, s, imm)
+            shift_logical_left_imm_64(d, s, imm)
+            zero_extend_16(d, s)
+            set_less_than_unsigned(d, s1, s2)
+            set_less_than_signed(d, s1, s2)
+            set_less_than_unsigned_imm(d, s, imm)
+            set_less_than_signed_imm(d, s, imm)
+            set_greater_than_unsigned_imm(d, s, imm)
+            set_greater_than_signed_imm(d, s, imm)
+            branch_eq_imm(s, imm, target)
+            branch_not_eq_imm(s, imm, target)
+            load_u8(d, offset)
+            load_i8(d, offset)
+            load_u16(d, offset)
+            load_i16(d, offset)
+            load_u32(d, offset)
+            load_i32(d, offset)
+            load_u64(d, offset)
+            load_indirect_u8(d, base, offset)
+            load_indirect_i8(d, base, offset)
+            load_indirect_u16(d, base, offset)
+            load_indirect_i16(d, base, offset)
+            load_indirect_u32(d, base, offset)
+            load_indirect_i32(d, base, offset)
+            load_indirect_u64(d, base, offset)
+            store_u8(s, offset)
+            store_u16(s, offset)
+            store_u32(s, offset)
+            store_u64(s, offset)
+            store_indirect_u8(s, base, offset)
+            store_indirect_u16(s, base, offset)
+            store_indirect_u32(s, base, offset)
+            store_indirect_u64(s, base, offset)
+            store_imm_u8(value, offset)
+            store_imm_u16(value, offset)
+            store_imm_u32(value, offset)
+            store_imm_u64(value, offset)
+            store_imm_indirect_u8(base, offset, value)
+            store_imm_indirect_u16(base, offset, value)
+            store_imm_indirect_u32(base, offset, value)
+            store_imm_indirect_u64(base, offset, value)
+        }
+    }
+
     #[inline(never)]
     #[cold]
     fn step(&mut self, program_counter: u32) {
@@ -698,7 +995,7 @@ where
 
     #[inline(always)]
     fn set_less_than_unsigned(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: RawReg) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.set_less_than_unsigned(d, s1, s2);
         ArchVisitor(self).set_less_than_unsigned(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -706,7 +1003,7 @@ where
 
     #[inline(always)]
     fn set_less_than_signed(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: RawReg) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.set_less_than_signed(d, s1, s2);
         ArchVisitor(self).set_less_than_signed(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -765,7 +1062,7 @@ where
 
     #[inline(always)]
     fn xor(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: RawReg) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.xor(d, s1, s2);
         ArchVisitor(self).xor(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -773,7 +1070,7 @@ where
 
     #[inline(always)]
     fn and(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: RawReg) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.and(d, s1, s2);
         ArchVisitor(self).and(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -781,7 +1078,7 @@ where
 
     #[inline(always)]
     fn or(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: RawReg) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.or(d, s1, s2);
         ArchVisitor(self).or(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -789,7 +1086,7 @@ where
 
     #[inline(always)]
     fn add_32(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: RawReg) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.add_32(d, s1, s2);
         ArchVisitor(self).add_32(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -798,7 +1095,7 @@ where
     #[inline(always)]
     fn add_64(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: RawReg) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.add_64(d, s1, s2);
         ArchVisitor(self).add_64(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -806,7 +1103,7 @@ where
 
     #[inline(always)]
     fn sub_32(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: RawReg) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.sub_32(d, s1, s2);
         ArchVisitor(self).sub_32(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -815,7 +1112,7 @@ where
     #[inline(always)]
     fn sub_64(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: RawReg) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.sub_64(d, s1, s2);
         ArchVisitor(self).sub_64(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -949,7 +1246,7 @@ where
 
     #[inline(always)]
     fn set_less_than_unsigned_imm(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.set_less_than_unsigned_imm(d, s1, s2);
         ArchVisitor(self).set_less_than_unsigned_imm(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -957,7 +1254,7 @@ where
 
     #[inline(always)]
     fn set_less_than_signed_imm(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.set_less_than_signed_imm(d, s1, s2);
         ArchVisitor(self).set_less_than_signed_imm(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -965,7 +1262,7 @@ where
 
     #[inline(always)]
     fn set_greater_than_unsigned_imm(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.set_greater_than_unsigned_imm(d, s1, s2);
         ArchVisitor(self).set_greater_than_unsigned_imm(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -973,7 +1270,7 @@ where
 
     #[inline(always)]
     fn set_greater_than_signed_imm(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.set_greater_than_signed_imm(d, s1, s2);
         ArchVisitor(self).set_greater_than_signed_imm(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1015,7 +1312,7 @@ where
 
     #[inline(always)]
     fn shift_logical_left_imm_32(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.shift_logical_left_imm_32(d, s1, s2);
         ArchVisitor(self).shift_logical_left_imm_32(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1024,7 +1321,7 @@ where
     #[inline(always)]
     fn shift_logical_left_imm_64(&mut self, code_offset: u32, args_length: u32, d: RawReg, s1: RawReg, s2: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.shift_logical_left_imm_64(d, s1, s2);
         ArchVisitor(self).shift_logical_left_imm_64(d, s1, s2);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1083,7 +1380,7 @@ where
 
     #[inline(always)]
     fn or_imm(&mut self, code_offset: u32, args_length: u32, d: RawReg, s: RawReg, imm: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.or_imm(d, s, imm);
         ArchVisitor(self).or_imm(d, s, imm);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1091,7 +1388,7 @@ where
 
     #[inline(always)]
     fn and_imm(&mut self, code_offset: u32, args_length: u32, d: RawReg, s: RawReg, imm: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.and_imm(d, s, imm);
         ArchVisitor(self).and_imm(d, s, imm);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1099,7 +1396,7 @@ where
 
     #[inline(always)]
     fn xor_imm(&mut self, code_offset: u32, args_length: u32, d: RawReg, s: RawReg, imm: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.xor_imm(d, s, imm);
         ArchVisitor(self).xor_imm(d, s, imm);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1107,7 +1404,7 @@ where
 
     #[inline(always)]
     fn move_reg(&mut self, code_offset: u32, args_length: u32, d: RawReg, s: RawReg) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.move_reg(d, s);
         ArchVisitor(self).move_reg(d, s);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1170,7 +1467,7 @@ where
     }
 
     fn zero_extend_16(&mut self, code_offset: u32, args_length: u32, d: RawReg, s: RawReg) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.zero_extend_16(d, s);
         ArchVisitor(self).zero_extend_16(d, s);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1249,7 +1546,7 @@ where
 
     #[inline(always)]
     fn add_imm_32(&mut self, code_offset: u32, args_length: u32, d: RawReg, s: RawReg, imm: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.add_imm_32(d, s, imm);
         ArchVisitor(self).add_imm_32(d, s, imm);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1258,7 +1555,7 @@ where
     #[inline(always)]
     fn add_imm_64(&mut self, code_offset: u32, args_length: u32, d: RawReg, s: RawReg, imm: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.add_imm_64(d, s, imm);
         ArchVisitor(self).add_imm_64(d, s, imm);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1283,7 +1580,7 @@ where
 
     #[inline(always)]
     fn store_imm_indirect_u8(&mut self, code_offset: u32, args_length: u32, base: RawReg, offset: u32, value: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_imm_indirect_u8(base, offset, value);
         ArchVisitor(self).store_imm_indirect_u8(base, offset, value);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1291,7 +1588,7 @@ where
 
     #[inline(always)]
     fn store_imm_indirect_u16(&mut self, code_offset: u32, args_length: u32, base: RawReg, offset: u32, value: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_imm_indirect_u16(base, offset, value);
         ArchVisitor(self).store_imm_indirect_u16(base, offset, value);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1299,7 +1596,7 @@ where
 
     #[inline(always)]
     fn store_imm_indirect_u32(&mut self, code_offset: u32, args_length: u32, base: RawReg, offset: u32, value: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_imm_indirect_u32(base, offset, value);
         ArchVisitor(self).store_imm_indirect_u32(base, offset, value);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1308,7 +1605,7 @@ where
     #[inline(always)]
     fn store_imm_indirect_u64(&mut self, code_offset: u32, args_length: u32, base: RawReg, offset: u32, value: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_imm_indirect_u64(base, offset, value);
         ArchVisitor(self).store_imm_indirect_u64(base, offset, value);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1316,7 +1613,7 @@ where
 
     #[inline(always)]
     fn store_indirect_u8(&mut self, code_offset: u32, args_length: u32, src: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_indirect_u8(src, base, offset);
         ArchVisitor(self).store_indirect_u8(src, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1324,7 +1621,7 @@ where
 
     #[inline(always)]
     fn store_indirect_u16(&mut self, code_offset: u32, args_length: u32, src: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_indirect_u16(src, base, offset);
         ArchVisitor(self).store_indirect_u16(src, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1332,7 +1629,7 @@ where
 
     #[inline(always)]
     fn store_indirect_u32(&mut self, code_offset: u32, args_length: u32, src: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_indirect_u32(src, base, offset);
         ArchVisitor(self).store_indirect_u32(src, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1341,7 +1638,7 @@ where
     #[inline(always)]
     fn store_indirect_u64(&mut self, code_offset: u32, args_length: u32, src: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_indirect_u64(src, base, offset);
         ArchVisitor(self).store_indirect_u64(src, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1349,7 +1646,7 @@ where
 
     #[inline(always)]
     fn store_imm_u8(&mut self, code_offset: u32, args_length: u32, value: u32, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_imm_u8(value, offset);
         ArchVisitor(self).store_imm_u8(value, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1357,7 +1654,7 @@ where
 
     #[inline(always)]
     fn store_imm_u16(&mut self, code_offset: u32, args_length: u32, value: u32, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_imm_u16(value, offset);
         ArchVisitor(self).store_imm_u16(value, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1365,7 +1662,7 @@ where
 
     #[inline(always)]
     fn store_imm_u32(&mut self, code_offset: u32, args_length: u32, value: u32, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_imm_u32(value, offset);
         ArchVisitor(self).store_imm_u32(value, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1374,7 +1671,7 @@ where
     #[inline(always)]
     fn store_imm_u64(&mut self, code_offset: u32, args_length: u32, value: u32, offset: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_imm_u64(value, offset);
         ArchVisitor(self).store_imm_u64(value, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1382,7 +1679,7 @@ where
 
     #[inline(always)]
     fn store_u8(&mut self, code_offset: u32, args_length: u32, src: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_u8(src, offset);
         ArchVisitor(self).store_u8(src, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1390,7 +1687,7 @@ where
 
     #[inline(always)]
     fn store_u16(&mut self, code_offset: u32, args_length: u32, src: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_u16(src, offset);
         ArchVisitor(self).store_u16(src, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1398,7 +1695,7 @@ where
 
     #[inline(always)]
     fn store_u32(&mut self, code_offset: u32, args_length: u32, src: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_u32(src, offset);
         ArchVisitor(self).store_u32(src, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1407,7 +1704,7 @@ where
     #[inline(always)]
     fn store_u64(&mut self, code_offset: u32, args_length: u32, src: RawReg, offset: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.store_u64(src, offset);
         ArchVisitor(self).store_u64(src, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1415,7 +1712,7 @@ where
 
     #[inline(always)]
     fn load_indirect_u8(&mut self, code_offset: u32, args_length: u32, dst: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_indirect_u8(dst, base, offset);
         ArchVisitor(self).load_indirect_u8(dst, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1423,7 +1720,7 @@ where
 
     #[inline(always)]
     fn load_indirect_i8(&mut self, code_offset: u32, args_length: u32, dst: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_indirect_i8(dst, base, offset);
         ArchVisitor(self).load_indirect_i8(dst, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1431,7 +1728,7 @@ where
 
     #[inline(always)]
     fn load_indirect_u16(&mut self, code_offset: u32, args_length: u32, dst: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_indirect_u16(dst, base, offset);
         ArchVisitor(self).load_indirect_u16(dst, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1439,7 +1736,7 @@ where
 
     #[inline(always)]
     fn load_indirect_i16(&mut self, code_offset: u32, args_length: u32, dst: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_indirect_i16(dst, base, offset);
         ArchVisitor(self).load_indirect_i16(dst, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1448,7 +1745,7 @@ where
     #[inline(always)]
     fn load_indirect_u32(&mut self, code_offset: u32, args_length: u32, dst: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_indirect_u32(dst, base, offset);
         ArchVisitor(self).load_indirect_u32(dst, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1456,7 +1753,7 @@ where
 
     #[inline(always)]
     fn load_indirect_i32(&mut self, code_offset: u32, args_length: u32, dst: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_indirect_i32(dst, base, offset);
         ArchVisitor(self).load_indirect_i32(dst, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1465,7 +1762,7 @@ where
     #[inline(always)]
     fn load_indirect_u64(&mut self, code_offset: u32, args_length: u32, dst: RawReg, base: RawReg, offset: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_indirect_u64(dst, base, offset);
         ArchVisitor(self).load_indirect_u64(dst, base, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1473,7 +1770,7 @@ where
 
     #[inline(always)]
     fn load_u8(&mut self, code_offset: u32, args_length: u32, dst: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_u8(dst, offset);
         ArchVisitor(self).load_u8(dst, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1481,7 +1778,7 @@ where
 
     #[inline(always)]
     fn load_i8(&mut self, code_offset: u32, args_length: u32, dst: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_i8(dst, offset);
         ArchVisitor(self).load_i8(dst, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1489,7 +1786,7 @@ where
 
     #[inline(always)]
     fn load_u16(&mut self, code_offset: u32, args_length: u32, dst: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_u16(dst, offset);
         ArchVisitor(self).load_u16(dst, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1497,7 +1794,7 @@ where
 
     #[inline(always)]
     fn load_i16(&mut self, code_offset: u32, args_length: u32, dst: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_i16(dst, offset);
         ArchVisitor(self).load_i16(dst, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1506,7 +1803,7 @@ where
     #[inline(always)]
     fn load_u32(&mut self, code_offset: u32, args_length: u32, dst: RawReg, offset: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_u32(dst, offset);
         ArchVisitor(self).load_u32(dst, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1514,7 +1811,7 @@ where
 
     #[inline(always)]
     fn load_i32(&mut self, code_offset: u32, args_length: u32, dst: RawReg, offset: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_i32(dst, offset);
         ArchVisitor(self).load_i32(dst, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1523,7 +1820,7 @@ where
     #[inline(always)]
     fn load_u64(&mut self, code_offset: u32, args_length: u32, dst: RawReg, offset: u32) -> Self::ReturnTy {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_u64(dst, offset);
         ArchVisitor(self).load_u64(dst, offset);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1579,7 +1876,7 @@ where
 
     #[inline(always)]
     fn branch_eq_imm(&mut self, code_offset: u32, args_length: u32, s1: RawReg, s2: u32, imm: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.branch_eq_imm(s1, s2, imm);
         ArchVisitor(self).branch_eq_imm(s1, s2, imm);
         self.after_instruction::<END_BASIC_BLOCK>(code_offset, args_length);
@@ -1587,7 +1884,7 @@ where
 
     #[inline(always)]
     fn branch_not_eq_imm(&mut self, code_offset: u32, args_length: u32, s1: RawReg, s2: u32, imm: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.branch_not_eq_imm(s1, s2, imm);
         ArchVisitor(self).branch_not_eq_imm(s1, s2, imm);
         self.after_instruction::<END_BASIC_BLOCK>(code_offset, args_length);
@@ -1666,7 +1963,7 @@ where
 
     #[inline(always)]
     fn load_imm(&mut self, code_offset: u32, args_length: u32, dst: RawReg, value: u32) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_imm(dst, value);
         ArchVisitor(self).load_imm(dst, value);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1674,7 +1971,7 @@ where
 
     #[inline(always)]
     fn load_imm64(&mut self, code_offset: u32, args_length: u32, dst: RawReg, value: u64) -> Self::ReturnTy {
-        self.before_instruction(code_offset);
+        self.before_optimized_instruction(code_offset);
         self.gas_visitor.load_imm64(dst, value);
         ArchVisitor(self).load_imm64(dst, value);
         self.after_instruction::<CONTINUE_BASIC_BLOCK>(code_offset, args_length);
@@ -1721,6 +2018,31 @@ where
     }
 }
 
+fn fill_export_map(
+    map: &mut HashMap<ProgramCounter, u32>,
+    program_counter_to_machine_code_offset_list: &[(ProgramCounter, u32)],
+    exports: &[ProgramExport<&[u8]>],
+    native_code_origin: u64,
+) {
+    for export in exports {
+        let native_offset = if let Ok(index) =
+            program_counter_to_machine_code_offset_list.binary_search_by_key(&export.program_counter(), |&(code_offset, _)| code_offset)
+        {
+            program_counter_to_machine_code_offset_list[index].1
+        } else {
+            program_counter_to_machine_code_offset_list.last().unwrap().1
+        };
+
+        log::trace!(
+            "Export at {}: {} => 0x{:08x}",
+            export.program_counter(),
+            export.symbol(),
+            native_code_origin + u64::from(native_offset)
+        );
+        map.insert(export.program_counter(), native_offset);
+    }
+}
+
 pub(crate) struct CompiledModule<S>
 where
     S: Sandbox,
@@ -1732,6 +2054,9 @@ where
     // Maps guest code offsets for exports to native code offsets.
     // Used to make sure calls into exports are always O(1) instead of O(log n).
     program_counter_to_machine_code_offset_map: HashMap<ProgramCounter, u32>,
+    // A sorted list which maps guest code offsets to native code offsets of the unoptimized copies
+    // of instructions which were optimized based on what was executed before them.
+    program_counter_to_deoptimized_machine_code_offset_list: Vec<(ProgramCounter, u32)>,
     cache: CompilerCache,
     pub(crate) invalid_code_offset_address: u64,
     pub(crate) bitness: Bitness,
@@ -1752,10 +2077,15 @@ where
         &self.program_counter_to_machine_code_offset_list
     }
 
+    /// Returns the address at which the execution can start at the given program counter.
+    ///
+    /// If the instruction was optimized based on the instructions executed before it then this
+    /// returns the address of its unoptimized copy.
     pub fn lookup_native_code_address(&self, program_counter: ProgramCounter) -> Option<u64> {
         self.program_counter_to_machine_code_offset_map
             .get(&program_counter)
             .copied()
+            .or_else(|| self.lookup_deoptimized_native_code_offset(program_counter))
             .or_else(|| {
                 let index = self
                     .program_counter_to_machine_code_offset_list
@@ -1766,14 +2096,33 @@ where
             .map(|native_offset| self.native_code_origin + u64::from(native_offset))
     }
 
+    /// Returns the address of the unoptimized copy of the instruction at the given program counter, if it has one.
+    pub fn lookup_deoptimized_native_code_address(&self, program_counter: ProgramCounter) -> Option<u64> {
+        self.lookup_deoptimized_native_code_offset(program_counter)
+            .map(|native_offset| self.native_code_origin + u64::from(native_offset))
+    }
+
+    fn lookup_deoptimized_native_code_offset(&self, program_counter: ProgramCounter) -> Option<u32> {
+        let index = self
+            .program_counter_to_deoptimized_machine_code_offset_list
+            .binary_search_by_key(&program_counter, |&(pc, _)| pc)
+            .ok()?;
+        Some(self.program_counter_to_deoptimized_machine_code_offset_list[index].1)
+    }
+
     pub fn program_counter_by_native_code_offset(&self, offset: u64, strict: bool) -> Option<ProgramCounter> {
-        let index = match self
-            .program_counter_to_machine_code_offset_list
-            .binary_search_by_key(&offset, |&(_, native_offset)| u64::from(native_offset))
-        {
+        // The unoptimized copies are emitted after all of the other instructions.
+        let list = match self.program_counter_to_deoptimized_machine_code_offset_list.first() {
+            Some(&(_, deoptimized_code_offset)) if offset >= u64::from(deoptimized_code_offset) => {
+                &self.program_counter_to_deoptimized_machine_code_offset_list
+            }
+            _ => &self.program_counter_to_machine_code_offset_list,
+        };
+
+        let index = match list.binary_search_by_key(&offset, |&(_, native_offset)| u64::from(native_offset)) {
             Ok(index) => index,
             Err(index) => {
-                if !strict && index > 0 && index < self.program_counter_to_machine_code_offset_list.len() {
+                if !strict && index > 0 && index < list.len() {
                     index - 1
                 } else {
                     return None;
@@ -1781,7 +2130,7 @@ where
             }
         };
 
-        Some(self.program_counter_to_machine_code_offset_list[index].0)
+        Some(list[index].0)
     }
 }
 
@@ -1792,14 +2141,18 @@ where
     fn drop(&mut self) {
         let mut program_counter_to_machine_code_offset_list = core::mem::take(&mut self.program_counter_to_machine_code_offset_list);
         let mut program_counter_to_machine_code_offset_map = core::mem::take(&mut self.program_counter_to_machine_code_offset_map);
+        let mut program_counter_to_deoptimized_machine_code_offset_list =
+            core::mem::take(&mut self.program_counter_to_deoptimized_machine_code_offset_list);
         {
             let mut cache = self.cache.0.lock();
             if cache.per_module.is_empty() {
                 program_counter_to_machine_code_offset_list.clear();
                 program_counter_to_machine_code_offset_map.clear();
+                program_counter_to_deoptimized_machine_code_offset_list.clear();
                 cache.per_module.push(CachePerModule {
                     program_counter_to_machine_code_offset_list,
                     program_counter_to_machine_code_offset_map,
+                    program_counter_to_deoptimized_machine_code_offset_list,
                 });
             }
         }
@@ -203,6 +203,10 @@ where
 
 const GAS_METERING_TRAP_OFFSET: u64 = 9;
 const GAS_COST_OFFSET: usize = 3;
+
+/// Stored in the `arg` field of the VM context when the execution is interrupted due to an execution limit being reached.
+const EXECUTION_LIMIT_REACHED_MARKER: u32 = 1;
+
 const REP_STOSB_MACHINE_CODE: &[u8] = &[0xf3, 0xaa];
 
 #[derive(Copy, Clone)]
@@ -228,6 +232,28 @@ where
     }
 }
 
+fn invert_condition(condition: Condition) -> Condition {
+    use Condition::*;
+    match condition {
+        Overflow => NotOverflow,
+        NotOverflow => Overflow,
+        Below => AboveOrEqual,
+        AboveOrEqual => Below,
+        Equal => NotEqual,
+        NotEqual => Equal,
+        BelowOrEqual => Above,
+        Above => BelowOrEqual,
+        Sign => NotSign,
+        NotSign => Sign,
+        Parity => NotParity,
+        NotParity => Parity,
+        Less => GreaterOrEqual,
+        GreaterOrEqual => Less,
+        LessOrEqual => Greater,
+        Greater => LessOrEqual,
+    }
+}
+
 fn set_program_counter_after_interruption<S>(
     compiled_module: &crate::compiler::CompiledModule<S>,
     machine_code_offset: u64,
@@ -307,7 +333,19 @@ where
 
     #[cfg_attr(not(debug_assertions), inline(always))]
     fn store(&mut self, src: impl Into<RegImm>, base: Option<RawReg>, offset: u32, kind: Size) {
-        let src = src.into();
+        let mut src = src.into();
+        if let RegImm::Reg(reg) = src {
+            let value = match kind {
+                Size::U64 => self.known_state.constant_imm(reg),
+                Size::U8 | Size::U16 | Size::U32 => self.known_state.constant(reg).map(|value| cast(value).truncate_to_u32()),
+            };
+
+            if let Some(value) = value {
+                src = RegImm::Imm(value);
+            }
+        }
+
+        let (base, offset) = self.fold_address(base, offset);
         load_store_operand!(self, S::KIND, base, offset, |dst| {
             match src {
                 RegImm::Reg(src) => self.push(store(kind, dst, conv_reg(src))),
@@ -326,9 +364,37 @@ where
 
     #[cfg_attr(not(debug_assertions), inline(always))]
     fn load(&mut self, dst: RawReg, base: Option<RawReg>, offset: u32, kind: LoadKind) {
+        let (base, offset) = self.fold_address(base, offset);
         load_store_operand!(self, S::KIND, base, offset, |src| {
             self.push(load(kind, conv_reg(dst), src));
         });
+
+        match kind {
+            LoadKind::U8 => self.known_state.set_bits(dst, 8),
+            LoadKind::U16 => self.known_state.set_bits(dst, 16),
+            LoadKind::U32 => self.known_state.set_bits(dst, 32),
+            LoadKind::U64 | LoadKind::I8 | LoadKind::I16 | LoadKind::I32 => self.known_state.set_unknown(dst),
+        }
+    }
+
+    /// Turns an access through a base register whose value is known into an access to an absolute address.
+    #[cfg_attr(not(debug_assertions), inline(always))]
+    fn fold_address(&mut self, base: Option<RawReg>, offset: u32) -> (Option<RawReg>, u32) {
+        match base.and_then(|base| self.known_state.constant(base)) {
+            Some(address) => (None, cast(address).truncate_to_u32().wrapping_add(offset)),
+            None => (base, offset),
+        }
+    }
+
+    /// Loads a value which was calculated at compile time into `d`.
+    #[cfg_attr(not(debug_assertions), inline(always))]
+    fn load_constant(&mut self, d: RawReg, value: u64) {
+        let imm = cast(value).truncate_to_u32();
+        if B::BITNESS == Bitness::B32 || cast(imm).to_u64_sign_extend() == value {
+            self.load_imm(d, imm);
+        } else {
+            self.load_imm64(d, value);
+        }
     }
 
     #[cfg_attr(not(debug_assertions), inline(always))]
@@ -352,24 +418,30 @@ where
     #[cfg_attr(not(debug_assertions), inline(always))]
     fn compare_reg_reg(&mut self, d: RawReg, s1: RawReg, s2: RawReg, condition: Condition) {
         let reg_size = self.reg_size();
+        let d_raw = d;
         let s1 = conv_reg(s1);
         let s2 = conv_reg(s2);
         let d = conv_reg(d);
         let asm = self.asm.reserve::<U3>();
-        if d == s1 || d == s2 {
+        let flags = if d == s1 || d == s2 {
             let asm = asm.push(cmp((reg_size, s1, s2)));
             let asm = asm.push(setcc(condition, d));
             asm.push(and((d, imm32(1))));
+            Condition::NotEqual
         } else {
             let asm = asm.push(xor((RegSize::R32, d, d)));
             let asm = asm.push(cmp((reg_size, s1, s2)));
             asm.push(setcc(condition, d));
-        }
+            condition
+        };
+
+        self.known_state.set_comparison(d_raw, flags);
     }
 
     #[cfg_attr(not(debug_assertions), inline(always))]
     fn compare_reg_imm(&mut self, d: RawReg, s1: RawReg, s2: u32, condition: Condition) {
         let reg_size = self.reg_size();
+        let d_raw = d;
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
 
@@ -380,24 +452,32 @@ where
             asm.push_none()
         };
 
-        let asm = if condition == Condition::Below && s2 == 1 {
+        let (asm, condition) = if condition == Condition::Below && s2 == 1 {
             // d = s1 <u 1  =>  d = s1 == 0
             let asm = asm.push(test((reg_size, s1, s1)));
-            asm.push(setcc(Condition::Equal, d))
+            (asm.push(setcc(Condition::Equal, d)), Condition::Equal)
         } else if condition == Condition::Above && s2 == 0 {
             // d = s1 >u 0  =>  d = s1 != 0
             let asm = asm.push(test((reg_size, s1, s1)));
-            asm.push(setcc(Condition::NotEqual, d))
+            (asm.push(setcc(Condition::NotEqual, d)), Condition::NotEqual)
+        } else if s2 == 0 {
+            // `test` sets the flags exactly like `cmp` with zero would, but is shorter.
+            let asm = asm.push(test((reg_size, s1, s1)));
+            (asm.push(setcc(condition, d)), condition)
         } else {
             let asm = match reg_size {
                 RegSize::R32 => asm.push(cmp((s1, imm32(s2)))),
                 RegSize::R64 => asm.push(cmp((s1, imm64(s2 as i32)))),
             };
-            asm.push(setcc(condition, d))
+            (asm.push(setcc(condition, d)), condition)
         };
 
         let asm = asm.push_if(d == s1, and((d, imm32(1))));
         asm.assert_reserved_exactly_as_needed();
+
+        // The flags either hold the result of the comparison, or the result of the `and`.
+        let flags = if d == s1 { Condition::NotEqual } else { condition };
+        self.known_state.set_comparison(d_raw, flags);
     }
 
     #[cfg_attr(not(debug_assertions), inline(always))]
@@ -420,7 +500,8 @@ where
             ShiftKind::ArithmeticRight => asm.push(sar_imm(reg_size, d, s2 as u8)),
         };
 
-        let asm = if (B::BITNESS, reg_size) == (Bitness::B64, RegSize::R32) {
+        // A logical right shift by a non-zero amount always clears the sign bit, so the value is already correctly extended.
+        let asm = if (B::BITNESS, reg_size) == (Bitness::B64, RegSize::R32) && !matches!(kind, ShiftKind::LogicalRight if s2 != 0) {
             asm.push(movsxd_32_to_64(d, d))
         } else {
             asm.push_none()
@@ -470,7 +551,7 @@ where
     }
 
     #[cfg_attr(not(debug_assertions), inline(always))]
-    fn jump_to_label(&mut self, label: Label) {
+    pub(crate) fn jump_to_label(&mut self, label: Label) {
         let asm = self.asm.reserve::<U1>();
         jump_to_label(asm, label).assert_reserved_exactly_as_needed();
     }
@@ -483,12 +564,40 @@ where
     #[cfg_attr(not(debug_assertions), inline(always))]
     fn branch(&mut self, s1: RawReg, s2: impl Into<RegImm>, target: u32, condition: Condition) {
         let reg_size = self.reg_size();
-        let s1 = conv_reg(s1);
+        let s2 = s2.into();
         let label = self.get_or_forward_declare_label(target).unwrap_or(self.invalid_jump_label);
 
+        if let (RegImm::Imm(imm), Condition::Equal | Condition::NotEqual) = (s2, condition) {
+            if let Some(is_equal) = self.known_state.fold(s1, imm, |s1, s2| u64::from(s1 == s2)) {
+                if (is_equal == 1) == (condition == Condition::Equal) {
+                    self.jump_to_label(label);
+                }
+
+                return;
+            }
+
+            if imm == 0 {
+                if let Some(is_non_zero) = self.known_state.comparison(s1) {
+                    // The flags were already set by the comparison whose result is in `s1`.
+                    let condition = if condition == Condition::Equal {
+                        invert_condition(is_non_zero)
+                    } else {
+                        is_non_zero
+                    };
+
+                    let asm = self.asm.reserve::<U1>();
+                    branch_to_label(asm, condition, label).assert_reserved_exactly_as_needed();
+                    return;
+                }
+            }
+        }
+
+        let s1 = conv_reg(s1);
         let asm = self.asm.reserve::<U2>();
-        let asm = match s2.into() {
+        let asm = match s2 {
             RegImm::Reg(s2) => asm.push(cmp((reg_size, s1, conv_reg(s2)))),
+            // `test` sets the flags exactly like `cmp` with zero would, but is shorter.
+            RegImm::Imm(0) => asm.push(test((reg_size, s1, s1))),
             RegImm::Imm(s2) => match reg_size {
                 RegSize::R32 => asm.push(cmp((s1, imm32(s2)))),
                 RegSize::R64 => asm.push(cmp((s1, imm64(cast(s2).to_signed())))),
@@ -747,6 +856,26 @@ where
 
         self.save_return_address_to_vmctx();
         self.save_registers_to_vmctx();
+        if self.execution_limits {
+            self.push(mov_imm(Self::vmctx_field(S::offset_table().arg), imm32(0)));
+        }
+        self.push(mov_imm64(TMP_REG, S::address_table().syscall_step));
+        self.push(jmp(TMP_REG));
+    }
+
+    pub(crate) fn emit_execution_limit_trampoline(&mut self) {
+        log::trace!("Emitting trampoline: execution limit");
+        let label = self.execution_limit_label;
+        self.define_label(label);
+
+        self.save_return_address_to_vmctx();
+        self.save_registers_to_vmctx();
+
+        // This reuses the step syscall, so mark that this is not a real step.
+        self.push(mov_imm(
+            Self::vmctx_field(S::offset_table().arg),
+            imm32(EXECUTION_LIMIT_REACHED_MARKER),
+        ));
         self.push(mov_imm64(TMP_REG, S::address_table().syscall_step));
         self.push(jmp(TMP_REG));
     }
@@ -822,6 +951,42 @@ where
         asm.assert_reserved_exactly_as_needed();
     }
 
+    pub(crate) fn emit_execution_limit_stub(&mut self, program_counter: u32) {
+        let origin = self.asm.len();
+        let execution_limit_label = self.execution_limit_label;
+        let label_continue = self.asm.forward_declare_label();
+
+        self.push(sub((Self::vmctx_field(S::offset_table().basic_block_budget), imm64(1))));
+        self.push(jcc_label8(Condition::NotSign, label_continue));
+        self.push(mov_imm(
+            Self::vmctx_field(S::offset_table().program_counter),
+            imm32(program_counter),
+        ));
+        self.push(mov_imm(
+            Self::vmctx_field(S::offset_table().next_program_counter),
+            imm32(program_counter),
+        ));
+        self.push(call_label32(execution_limit_label));
+        debug_assert_eq!(cast(self.asm.len() - origin).to_u64(), Self::execution_limit_stub_length());
+
+        self.define_label(label_continue);
+    }
+
+    fn execution_limit_stub_length() -> u64
+    where
+        'a: 'r,
+        S: 'r,
+        B: 'r,
+    {
+        let length = sub((Self::vmctx_field(S::offset_table().basic_block_budget), imm64(1))).len()
+            + jcc_rel8(Condition::NotSign, 0).len()
+            + mov_imm(Self::vmctx_field(S::offset_table().program_counter), imm32(0)).len()
+            + mov_imm(Self::vmctx_field(S::offset_table().next_program_counter), imm32(0)).len()
+            + call_rel32(0).len();
+
+        cast(length).to_u64()
+    }
+
     pub(crate) fn emit_gas_metering_stub(&mut self, kind: GasMeteringKind) {
         let origin = self.asm.len();
 
@@ -1193,7 +1358,7 @@ where
 
     pub fn on_signal_trap(
         compiled_module: &crate::compiler::CompiledModule<S>,
-        is_gas_metering_enabled: bool,
+        gas_metering: Option<GasMeteringKind>,
         machine_code_offset: u64,
         vmctx: &VmCtx,
     ) -> Result<bool, &'static str> {
@@ -1203,9 +1368,12 @@ where
             Trap,
         }
 
+        let is_gas_metering_enabled = gas_metering.is_some();
         let trap_kind = if let Some(kind) = are_we_executing_memset(compiled_module, machine_code_offset) {
             TrapKind::Memset { kind }
-        } else if is_gas_metering_enabled && vmctx.gas.load(Ordering::Relaxed) < 0 {
+        } else if gas_metering == Some(GasMeteringKind::Sync) && vmctx.gas.load(Ordering::Relaxed) < 0 {
+            // Only the synchronous gas metering stub traps; with asynchronous metering the gas
+            // counter can be negative here when the program traps for an unrelated reason.
             TrapKind::NotEnoughGas
         } else {
             TrapKind::Trap
@@ -1261,6 +1429,30 @@ where
         }
     }
 
+    /// Checks whether the execution was interrupted by an execution limit stub, and if so prepares it to be resumed.
+    pub fn on_step(vmctx: &VmCtx) -> Result<bool, &'static str>
+    where
+        'a: 'r,
+        S: 'r,
+        B: 'r,
+    {
+        if vmctx.arg.load(Ordering::Relaxed) != EXECUTION_LIMIT_REACHED_MARKER {
+            return Ok(false);
+        }
+
+        // If we restart we want to check the limit again, so set the address to point to the start of the stub.
+        let return_address = vmctx.next_native_program_counter.load(Ordering::Relaxed);
+        let Some(address) = return_address.checked_sub(Self::execution_limit_stub_length()) else {
+            return Err("internal error: address underflow after an execution limit was reached");
+        };
+
+        vmctx.next_native_program_counter.store(address, Ordering::Relaxed);
+
+        // The budget is now negative; refund the basic block which wasn't executed.
+        vmctx.basic_block_budget.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+
     pub fn on_page_fault(
         compiled_module: &crate::compiler::CompiledModule<S>,
         is_gas_metering_enabled: bool,
@@ -1271,8 +1463,14 @@ where
         if let Some(kind) = are_we_executing_memset(compiled_module, machine_code_offset) {
             handle_interruption_during_memset(kind, compiled_module, is_gas_metering_enabled, machine_code_offset, vmctx)?;
         } else {
-            set_program_counter_after_interruption(compiled_module, machine_code_offset, vmctx)?;
-            vmctx.next_native_program_counter.store(machine_code_address, Ordering::Relaxed);
+            let program_counter = set_program_counter_after_interruption(compiled_module, machine_code_offset, vmctx)?;
+
+            // The registers can be modified before the execution is resumed, so anything the faulting
+            // instruction assumed about their values might not hold anymore.
+            let address = compiled_module
+                .lookup_deoptimized_native_code_address(program_counter)
+                .unwrap_or(machine_code_address);
+            vmctx.next_native_program_counter.store(address, Ordering::Relaxed);
         }
 
         Ok(())
@@ -1390,6 +1588,18 @@ where
 
     #[inline(always)]
     pub fn xor(&mut self, d: RawReg, s1: RawReg, s2: RawReg) {
+        if let Some(value) = self.known_state.fold(s1, s2, |s1, s2| s1 ^ s2) {
+            self.load_constant(d, value);
+            return;
+        } else if let Some(s2) = self.known_state.constant_imm(s2) {
+            self.xor_imm(d, s1, s2);
+            return;
+        } else if let Some(s1) = self.known_state.constant_imm(s1) {
+            self.xor_imm(d, s2, s1);
+            return;
+        }
+
+        let (d_raw, s1_raw, s2_raw) = (d, s1, s2);
         let reg_size = self.reg_size();
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
@@ -1408,10 +1618,24 @@ where
             }
         }
         .assert_reserved_exactly_as_needed();
+
+        self.known_state.set_or(d_raw, s1_raw, s2_raw, |s1, s2| s1 ^ s2);
     }
 
     #[inline(always)]
     pub fn and(&mut self, d: RawReg, s1: RawReg, s2: RawReg) {
+        if let Some(value) = self.known_state.fold(s1, s2, |s1, s2| s1 & s2) {
+            self.load_constant(d, value);
+            return;
+        } else if let Some(s2) = self.known_state.constant_imm(s2) {
+            self.and_imm(d, s1, s2);
+            return;
+        } else if let Some(s1) = self.known_state.constant_imm(s1) {
+            self.and_imm(d, s2, s1);
+            return;
+        }
+
+        let (d_raw, s1_raw, s2_raw) = (d, s1, s2);
         let reg_size = self.reg_size();
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
@@ -1430,10 +1654,24 @@ where
             }
         }
         .assert_reserved_exactly_as_needed();
+
+        self.known_state.set_and(d_raw, s1_raw, s2_raw);
     }
 
     #[inline(always)]
     pub fn or(&mut self, d: RawReg, s1: RawReg, s2: RawReg) {
+        if let Some(value) = self.known_state.fold(s1, s2, |s1, s2| s1 | s2) {
+            self.load_constant(d, value);
+            return;
+        } else if let Some(s2) = self.known_state.constant_imm(s2) {
+            self.or_imm(d, s1, s2);
+            return;
+        } else if let Some(s1) = self.known_state.constant_imm(s1) {
+            self.or_imm(d, s2, s1);
+            return;
+        }
+
+        let (d_raw, s1_raw, s2_raw) = (d, s1, s2);
         let reg_size = self.reg_size();
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
@@ -1452,10 +1690,21 @@ where
             }
         }
         .assert_reserved_exactly_as_needed();
+
+        self.known_state.set_or(d_raw, s1_raw, s2_raw, |s1, s2| s1 | s2);
     }
 
     #[inline(always)]
     fn add_generic(&mut self, reg_size: RegSize, d: RawReg, s1: RawReg, s2: RawReg) {
+        // If both of the operands are small enough the sum is never negative, so it doesn't need to be sign extended.
+        let is_sign_extension_needed =
+            (B::BITNESS, reg_size) == (Bitness::B64, RegSize::R32) && !self.known_state.is_sum_32_non_negative(s1, s2);
+
+        match reg_size {
+            RegSize::R32 => self.known_state.set_sum_32(d, s1, s2),
+            RegSize::R64 => self.known_state.set_unknown(d),
+        }
+
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
         let s2 = conv_reg(s2);
@@ -1473,7 +1722,7 @@ where
             }
         };
 
-        let asm = if (B::BITNESS, reg_size) == (Bitness::B64, RegSize::R32) {
+        let asm = if is_sign_extension_needed {
             asm.push(movsxd_32_to_64(d, d))
         } else {
             asm.push_none()
@@ -1484,17 +1733,34 @@ where
 
     #[inline(always)]
     pub fn add_32(&mut self, d: RawReg, s1: RawReg, s2: RawReg) {
-        self.add_generic(RegSize::R32, d, s1, s2);
+        if let Some(value) = self.known_state.fold_32(s1, s2, u32::wrapping_add) {
+            self.load_constant(d, value);
+        } else if let Some(s2) = self.known_state.constant(s2) {
+            self.add_imm_32(d, s1, cast(s2).truncate_to_u32());
+        } else if let Some(s1) = self.known_state.constant(s1) {
+            self.add_imm_32(d, s2, cast(s1).truncate_to_u32());
+        } else {
+            self.add_generic(RegSize::R32, d, s1, s2);
+        }
     }
 
     #[inline(always)]
     pub fn add_64(&mut self, d: RawReg, s1: RawReg, s2: RawReg) {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.add_generic(RegSize::R64, d, s1, s2);
+        if let Some(value) = self.known_state.fold(s1, s2, u64::wrapping_add) {
+            self.load_constant(d, value);
+        } else if let Some(s2) = self.known_state.constant_imm(s2) {
+            self.add_imm_64(d, s1, s2);
+        } else if let Some(s1) = self.known_state.constant_imm(s1) {
+            self.add_imm_64(d, s2, s1);
+        } else {
+            self.add_generic(RegSize::R64, d, s1, s2);
+        }
     }
 
     #[inline(always)]
     fn sub_generic(&mut self, reg_size: RegSize, d: RawReg, s1: RawReg, s2: RawReg) {
+        self.known_state.set_unknown(d);
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
         let s2 = conv_reg(s2);
@@ -1526,17 +1792,35 @@ where
 
     #[inline(always)]
     pub fn sub_32(&mut self, d: RawReg, s1: RawReg, s2: RawReg) {
-        self.sub_generic(RegSize::R32, d, s1, s2);
+        if let Some(value) = self.known_state.fold_32(s1, s2, u32::wrapping_sub) {
+            self.load_constant(d, value);
+        } else if let Some(s2) = self.known_state.constant(s2) {
+            self.add_imm_32(d, s1, cast(s2).truncate_to_u32().wrapping_neg());
+        } else if let Some(s1) = self.known_state.constant(s1) {
+            self.negate_and_add_imm_32(d, s2, cast(s1).truncate_to_u32());
+        } else {
+            self.sub_generic(RegSize::R32, d, s1, s2);
+        }
     }
 
     #[inline(always)]
     pub fn sub_64(&mut self, d: RawReg, s1: RawReg, s2: RawReg) {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.sub_generic(RegSize::R64, d, s1, s2);
+        if let Some(value) = self.known_state.fold(s1, s2, u64::wrapping_sub) {
+            self.load_constant(d, value);
+        } else if let Some(s2) = self.known_state.constant_imm(s2).filter(|&s2| s2 != 0x80000000) {
+            // The negated immediate must still fit in a sign extended 32-bit immediate.
+            self.add_imm_64(d, s1, s2.wrapping_neg());
+        } else if let Some(s1) = self.known_state.constant_imm(s1) {
+            self.negate_and_add_imm_64(d, s2, s1);
+        } else {
+            self.sub_generic(RegSize::R64, d, s1, s2);
+        }
     }
 
     #[inline(always)]
     fn negate_and_add_imm_generic(&mut self, reg_size: RegSize, d: RawReg, s1: RawReg, s2: u32) {
+        self.known_state.set_unknown(d);
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
 
@@ -1627,6 +1911,12 @@ where
 
     #[inline(always)]
     pub fn mul_imm_32(&mut self, d: RawReg, s1: RawReg, s2: u32) {
+        if s2.is_power_of_two() {
+            // d = s1 << log2(s2)
+            self.shift_imm(RegSize::R32, d, s1, s2.trailing_zeros(), ShiftKind::LogicalLeft);
+            return;
+        }
+
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
 
@@ -1644,6 +1934,13 @@ where
 
     #[inline(always)]
     pub fn mul_imm_64(&mut self, d: RawReg, s1: RawReg, s2: u32) {
+        // The immediate is sign extended, so only the powers of two which are still positive can be turned into a shift.
+        if s2.is_power_of_two() && s2 <= 0x7fffffff {
+            // d = s1 << log2(s2)
+            self.shift_imm(RegSize::R64, d, s1, s2.trailing_zeros(), ShiftKind::LogicalLeft);
+            return;
+        }
+
         self.push(imul_imm(RegSize::R64, conv_reg(d), conv_reg(s1), s2 as i32));
     }
 
@@ -1864,16 +2161,32 @@ where
 
     #[inline(always)]
     pub fn shift_logical_left_imm_32(&mut self, d: RawReg, s1: RawReg, s2: u32) {
-        self.shift_imm(RegSize::R32, d, s1, s2, ShiftKind::LogicalLeft);
+        if let Some(value) = self.known_state.fold_32(s1, s2, u32::wrapping_shl) {
+            self.load_constant(d, value);
+        } else {
+            self.shift_imm(RegSize::R32, d, s1, s2, ShiftKind::LogicalLeft);
+            self.known_state.set_unknown(d);
+        }
     }
 
     #[inline(always)]
     pub fn shift_logical_left_imm_64(&mut self, d: RawReg, s1: RawReg, s2: u32) {
-        self.shift_imm(RegSize::R64, d, s1, s2, ShiftKind::LogicalLeft);
+        if let Some(value) = self.known_state.fold(s1, s2, |s1, s2| s1.wrapping_shl(cast(s2).truncate_to_u32())) {
+            self.load_constant(d, value);
+        } else {
+            self.shift_imm(RegSize::R64, d, s1, s2, ShiftKind::LogicalLeft);
+            self.known_state.set_unknown(d);
+        }
     }
 
     #[inline(always)]
     pub fn or_imm(&mut self, d: RawReg, s1: RawReg, s2: u32) {
+        if let Some(value) = self.known_state.fold(s1, s2, |s1, s2| s1 | s2) {
+            self.load_constant(d, value);
+            return;
+        }
+
+        self.known_state.set_or(d, s1, s2, |s1, s2| s1 | s2);
         let reg_size = self.reg_size();
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
@@ -1891,7 +2204,21 @@ where
 
     #[inline(always)]
     pub fn and_imm(&mut self, d: RawReg, s1: RawReg, s2: u32) {
-        let reg_size = self.reg_size();
+        if let Some(value) = self.known_state.fold(s1, s2, |s1, s2| s1 & s2) {
+            self.load_constant(d, value);
+            return;
+        } else if self.known_state.fits_in_mask(s1, s2) {
+            // None of the bits which could be set are masked out.
+            if d != s1 {
+                self.move_reg(d, s1);
+            }
+            return;
+        }
+
+        self.known_state.set_and(d, s1, s2);
+        // If the sign bit of the mask is clear then the upper bits of the result are always going to be zero,
+        // so we can use a 32-bit operation which implicitly clears them and has a shorter encoding.
+        let reg_size = if s2 <= 0x7fffffff { RegSize::R32 } else { self.reg_size() };
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
 
@@ -1908,6 +2235,12 @@ where
 
     #[inline(always)]
     pub fn xor_imm(&mut self, d: RawReg, s1: RawReg, s2: u32) {
+        if let Some(value) = self.known_state.fold(s1, s2, |s1, s2| s1 ^ s2) {
+            self.load_constant(d, value);
+            return;
+        }
+
+        self.known_state.set_or(d, s1, s2, |s1, s2| s1 ^ s2);
         let reg_size = self.reg_size();
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
@@ -1929,8 +2262,12 @@ where
 
     #[inline(always)]
     pub fn load_imm(&mut self, dst: RawReg, s2: u32) {
+        self.known_state.set_constant_imm(dst, s2);
         match B::BITNESS {
+            _ if s2 == 0 => self.clear_reg(dst),
             Bitness::B32 => self.push(mov_imm(conv_reg(dst), imm32(s2))),
+            // A 32-bit move implicitly clears the upper bits and has a shorter encoding.
+            Bitness::B64 if s2 <= 0x7fffffff => self.push(mov_imm(conv_reg(dst), imm32(s2))),
             Bitness::B64 => self.push(mov_imm(conv_reg(dst), imm64(cast(s2).to_signed()))),
         }
     }
@@ -1938,11 +2275,13 @@ where
     #[inline(always)]
     pub fn load_imm64(&mut self, dst: RawReg, s2: u64) {
         assert_eq!(B::BITNESS, Bitness::B64);
+        self.known_state.set_constant(dst, s2);
         self.push(mov_imm64(conv_reg(dst), s2));
     }
 
     #[inline(always)]
     pub fn move_reg(&mut self, d: RawReg, s: RawReg) {
+        self.known_state.copy(d, s);
         self.mov(d, s);
     }
 
@@ -1988,7 +2327,17 @@ where
 
     #[inline(always)]
     pub fn zero_extend_16(&mut self, d: RawReg, s: RawReg) {
-        self.push(movzx_16_to_64(self.reg_size(), conv_reg(d), conv_reg(s)))
+        if let Some(value) = self.known_state.fold(s, 0xffff_u32, |s, mask| s & mask) {
+            self.load_constant(d, value);
+        } else if self.known_state.fits_in_mask(s, 0xffff) {
+            // The value is already zero extended.
+            if d != s {
+                self.move_reg(d, s);
+            }
+        } else {
+            self.known_state.set_bits(d, 16);
+            self.push(movzx_16_to_64(self.reg_size(), conv_reg(d), conv_reg(s)))
+        }
     }
 
     #[inline(always)]
@@ -2082,13 +2431,25 @@ where
 
     #[inline(always)]
     fn add_imm_generic(&mut self, reg_size: RegSize, d: RawReg, s1: RawReg, s2: u32) {
+        let is_sign_extension_needed =
+            (B::BITNESS, reg_size) == (Bitness::B64, RegSize::R32) && !self.known_state.is_sum_32_non_negative(s1, s2);
+
+        match reg_size {
+            RegSize::R32 => self.known_state.set_sum_32(d, s1, s2),
+            RegSize::R64 => self.known_state.set_unknown(d),
+        }
+
         let d = conv_reg(d);
         let s1 = conv_reg(s1);
 
         let asm = self.asm.reserve::<U2>();
         let asm = if d == s1 {
-            if s2 == 1 {
+            if s2 == 0 {
+                asm.push_none()
+            } else if s2 == 1 {
                 asm.push(inc(reg_size, d))
+            } else if s2 == !0 {
+                asm.push(dec(reg_size, d))
             } else {
                 match reg_size {
                     RegSize::R32 => asm.push(add((d, imm32(s2)))),
@@ -2099,7 +2460,7 @@ where
             asm.push(lea(reg_size, d, reg_indirect(reg_size, s1 + s2 as i32)))
         };
 
-        let asm = if (B::BITNESS, reg_size) == (Bitness::B64, RegSize::R32) {
+        let asm = if is_sign_extension_needed {
             asm.push(movsxd_32_to_64(d, d))
         } else {
             asm.push_none()
@@ -2110,13 +2471,21 @@ where
 
     #[inline(always)]
     pub fn add_imm_32(&mut self, d: RawReg, s1: RawReg, s2: u32) {
-        self.add_imm_generic(RegSize::R32, d, s1, s2);
+        if let Some(value) = self.known_state.fold_32(s1, s2, u32::wrapping_add) {
+            self.load_constant(d, value);
+        } else {
+            self.add_imm_generic(RegSize::R32, d, s1, s2);
+        }
     }
 
     #[inline(always)]
     pub fn add_imm_64(&mut self, d: RawReg, s1: RawReg, s2: u32) {
         assert_eq!(B::BITNESS, Bitness::B64);
-        self.add_imm_generic(RegSize::R64, d, s1, s2);
+        if let Some(value) = self.known_state.fold(s1, s2, u64::wrapping_add) {
+            self.load_constant(d, value);
+        } else {
+            self.add_imm_generic(RegSize::R64, d, s1, s2);
+        }
     }
 
     #[inline(always)]
@@ -0,0 +1,299 @@
+use polkavm_assembler::amd64::Condition;
+use polkavm_common::cast::cast;
+use polkavm_common::program::{RawReg, Reg};
+
+use crate::utils::RegImm;
+
+const REG_COUNT: usize = Reg::ALL.len();
+
+/// What the recompiler knows about the guest's registers at the current point of the emitted code.
+///
+/// This is only tracked across a run of consecutive instructions inside of a single basic block, and
+/// it's only valid if the run is executed from its beginning. Every entry into the middle of a run
+/// which is not a fallthrough from the previous instruction goes into an unoptimized copy of the run.
+pub(crate) struct KnownState {
+    is_enabled: bool,
+    is_64_bit: bool,
+    // Whether the instruction which is currently being compiled can make use of what is known.
+    is_tracked: bool,
+    constants: [Option<u64>; REG_COUNT],
+    // How many of the lowest bits of a register can be non-zero.
+    bits: [u32; REG_COUNT],
+    // Set by the current instruction if the flags hold the result of a comparison which was stored in a register.
+    flags: Option<(Reg, Condition)>,
+    // The flags set by the previous instruction.
+    incoming_flags: Option<(Reg, Condition)>,
+    was_used: bool,
+}
+
+impl KnownState {
+    pub(crate) fn new(is_enabled: bool, is_64_bit: bool) -> Self {
+        let width = if is_64_bit { 64 } else { 32 };
+        KnownState {
+            is_enabled,
+            is_64_bit,
+            is_tracked: false,
+            constants: [None; REG_COUNT],
+            bits: [width; REG_COUNT],
+            flags: None,
+            incoming_flags: None,
+            was_used: false,
+        }
+    }
+
+    /// Enables or disables the tracking, forgetting everything that was known.
+    pub(crate) fn set_enabled(&mut self, is_enabled: bool) {
+        self.is_enabled = is_enabled;
+        self.clear();
+    }
+
+    fn width(&self) -> u32 {
+        if self.is_64_bit {
+            64
+        } else {
+            32
+        }
+    }
+
+    /// Forgets everything.
+    pub(crate) fn clear(&mut self) {
+        *self = Self::new(self.is_enabled, self.is_64_bit);
+    }
+
+    /// Starts compiling a new instruction; `is_tracked` says whether the instruction keeps the state up-to-date.
+    pub(crate) fn begin_instruction(&mut self, is_tracked: bool) {
+        self.is_tracked = is_tracked && self.is_enabled;
+        self.incoming_flags = self.flags.take();
+        self.was_used = false;
+    }
+
+    /// Returns whether the current instruction keeps the state up-to-date.
+    pub(crate) fn is_tracked(&self) -> bool {
+        self.is_tracked
+    }
+
+    /// Returns whether the code emitted for the current instruction depends on anything that was known.
+    pub(crate) fn was_used(&self) -> bool {
+        self.was_used
+    }
+
+    fn peek_constant(&self, operand: impl Into<RegImm>) -> Option<u64> {
+        match operand.into() {
+            RegImm::Reg(reg) if self.is_tracked => self.constants[reg_index(reg)],
+            RegImm::Reg(..) => None,
+            RegImm::Imm(value) => Some(self.sign_extend(value)),
+        }
+    }
+
+    fn peek_bits(&self, operand: impl Into<RegImm>) -> u32 {
+        match operand.into() {
+            RegImm::Reg(reg) if self.is_tracked => self.bits[reg_index(reg)],
+            RegImm::Reg(..) => self.width(),
+            RegImm::Imm(value) => 64 - self.sign_extend(value).leading_zeros(),
+        }
+    }
+
+    fn mark_as_used(&mut self, operand: impl Into<RegImm>) {
+        if let RegImm::Reg(..) = operand.into() {
+            self.was_used = true;
+        }
+    }
+
+    /// Returns the value of `reg` if it's known.
+    pub(crate) fn constant(&mut self, reg: RawReg) -> Option<u64> {
+        let value = self.peek_constant(reg);
+        self.was_used |= value.is_some();
+        value
+    }
+
+    /// Returns the value of `reg` if it's known and it can be encoded as a sign extended 32-bit immediate.
+    pub(crate) fn constant_imm(&mut self, reg: RawReg) -> Option<u32> {
+        let value = self.peek_constant(reg)?;
+        let imm = cast(value).truncate_to_u32();
+        if self.sign_extend(imm) != value {
+            return None;
+        }
+
+        self.was_used = true;
+        Some(imm)
+    }
+
+    /// Returns the result of a 32-bit operation whose result is sign extended, if both of its operands are known.
+    pub(crate) fn fold_32(&mut self, s1: impl Into<RegImm>, s2: impl Into<RegImm>, operation: fn(u32, u32) -> u32) -> Option<u64> {
+        let (s1, s2) = (s1.into(), s2.into());
+        let value = self.peek_result_32(s1, s2, operation)?;
+        self.mark_as_used(s1);
+        self.mark_as_used(s2);
+        Some(value)
+    }
+
+    /// Returns the result of an operation on whole registers, if both of its operands are known.
+    pub(crate) fn fold(&mut self, s1: impl Into<RegImm>, s2: impl Into<RegImm>, operation: fn(u64, u64) -> u64) -> Option<u64> {
+        let (s1, s2) = (s1.into(), s2.into());
+        let value = self.peek_result(s1, s2, operation)?;
+        self.mark_as_used(s1);
+        self.mark_as_used(s2);
+        Some(value)
+    }
+
+    /// Returns whether `reg & mask == reg` is guaranteed to hold, where `mask` is sign extended.
+    pub(crate) fn fits_in_mask(&mut self, reg: RawReg, mask: u32) -> bool {
+        let mask = self.sign_extend(mask);
+        let bits = self.peek_bits(reg);
+        if low_bits_mask(bits) & !mask != 0 {
+            return false;
+        }
+
+        self.was_used |= bits < self.width();
+        true
+    }
+
+    /// Returns whether the sum of `s1` and `s2` is guaranteed to be a non-negative 32-bit number.
+    pub(crate) fn is_sum_32_non_negative(&mut self, s1: impl Into<RegImm>, s2: impl Into<RegImm>) -> bool {
+        let (s1, s2) = (s1.into(), s2.into());
+        if self.peek_bits(s1).max(self.peek_bits(s2)) > 30 {
+            return false;
+        }
+
+        self.mark_as_used(s1);
+        self.mark_as_used(s2);
+        true
+    }
+
+    /// Returns the condition which is true if `reg` is non-zero, if the flags were set by the previous
+    /// instruction based on the value which it stored in `reg`.
+    pub(crate) fn comparison(&mut self, reg: RawReg) -> Option<Condition> {
+        if !self.is_tracked {
+            return None;
+        }
+
+        let (flags_reg, condition) = self.incoming_flags?;
+        if flags_reg != reg.get() {
+            return None;
+        }
+
+        self.was_used = true;
+        Some(condition)
+    }
+
+    fn sign_extend(&self, value: u32) -> u64 {
+        if self.is_64_bit {
+            cast(value).to_u64_sign_extend()
+        } else {
+            cast(value).to_u64()
+        }
+    }
+
+    fn peek_result_32(&self, s1: RegImm, s2: RegImm, operation: fn(u32, u32) -> u32) -> Option<u64> {
+        let s1 = cast(self.peek_constant(s1)?).truncate_to_u32();
+        let s2 = cast(self.peek_constant(s2)?).truncate_to_u32();
+        Some(self.sign_extend(operation(s1, s2)))
+    }
+
+    fn peek_result(&self, s1: RegImm, s2: RegImm, operation: fn(u64, u64) -> u64) -> Option<u64> {
+        let value = operation(self.peek_constant(s1)?, self.peek_constant(s2)?);
+        if self.is_64_bit {
+            Some(value)
+        } else {
+            Some(value & u64::from(u32::MAX))
+        }
+    }
+
+    pub(crate) fn set_unknown(&mut self, reg: RawReg) {
+        self.set_bits(reg, 64);
+    }
+
+    pub(crate) fn set_constant(&mut self, reg: RawReg, value: u64) {
+        if !self.is_tracked {
+            return;
+        }
+
+        let value = if self.is_64_bit { value } else { value & u64::from(u32::MAX) };
+
+        let reg = reg_index(reg);
+        self.constants[reg] = Some(value);
+        self.bits[reg] = 64 - value.leading_zeros();
+    }
+
+    /// Marks `reg` as holding the given immediate after it was sign extended.
+    pub(crate) fn set_constant_imm(&mut self, reg: RawReg, value: u32) {
+        self.set_constant(reg, self.sign_extend(value));
+    }
+
+    /// Marks `reg` as having only its lowest `bits` bits possibly set.
+    pub(crate) fn set_bits(&mut self, reg: RawReg, bits: u32) {
+        if !self.is_tracked {
+            return;
+        }
+
+        let width = self.width();
+        let reg = reg_index(reg);
+        self.constants[reg] = None;
+        self.bits[reg] = bits.min(width);
+    }
+
+    pub(crate) fn set_comparison(&mut self, reg: RawReg, condition: Condition) {
+        if !self.is_tracked {
+            return;
+        }
+
+        self.set_bits(reg, 1);
+        self.flags = Some((reg.get(), condition));
+    }
+
+    pub(crate) fn copy(&mut self, dst: RawReg, src: RawReg) {
+        if !self.is_tracked {
+            return;
+        }
+
+        let (dst, src) = (reg_index(dst), reg_index(src));
+        self.constants[dst] = self.constants[src];
+        self.bits[dst] = self.bits[src];
+    }
+
+    /// Updates `dst` after a 32-bit addition, whose result is sign extended.
+    pub(crate) fn set_sum_32(&mut self, dst: RawReg, s1: impl Into<RegImm>, s2: impl Into<RegImm>) {
+        let (s1, s2) = (s1.into(), s2.into());
+        if let Some(value) = self.peek_result_32(s1, s2, u32::wrapping_add) {
+            self.set_constant(dst, value);
+            return;
+        }
+
+        let bits = self.peek_bits(s1).max(self.peek_bits(s2));
+        if bits <= 30 {
+            self.set_bits(dst, bits + 1);
+        } else {
+            self.set_unknown(dst);
+        }
+    }
+
+    /// Updates `dst` after an `and` of `s1` and `s2`.
+    pub(crate) fn set_and(&mut self, dst: RawReg, s1: impl Into<RegImm>, s2: impl Into<RegImm>) {
+        let (s1, s2) = (s1.into(), s2.into());
+        match self.peek_result(s1, s2, |s1, s2| s1 & s2) {
+            Some(value) => self.set_constant(dst, value),
+            None => self.set_bits(dst, self.peek_bits(s1).min(self.peek_bits(s2))),
+        }
+    }
+
+    /// Updates `dst` after an `or` or a `xor` of `s1` and `s2`.
+    pub(crate) fn set_or(&mut self, dst: RawReg, s1: impl Into<RegImm>, s2: impl Into<RegImm>, operation: fn(u64, u64) -> u64) {
+        let (s1, s2) = (s1.into(), s2.into());
+        match self.peek_result(s1, s2, operation) {
+            Some(value) => self.set_constant(dst, value),
+            None => self.set_bits(dst, self.peek_bits(s1).max(self.peek_bits(s2))),
+        }
+    }
+}
+
+fn reg_index(reg: RawReg) -> usize {
+    reg.get() as usize
+}
+
+fn low_bits_mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
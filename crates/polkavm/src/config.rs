@@ -108,6 +108,8 @@ pub struct Config {
     pub(crate) cache_enabled: bool,
     pub(crate) lru_cache_size: u32,
     pub(crate) sandboxing_enabled: bool,
+    #[cfg(feature = "std")]
+    pub(crate) code_cache_dir: Option<std::path::PathBuf>,
 }
 
 impl Default for Config {
@@ -161,10 +163,14 @@ impl Config {
             cache_enabled: cfg!(feature = "module-cache"),
             lru_cache_size: 0,
             sandboxing_enabled: true,
+            #[cfg(feature = "std")]
+            code_cache_dir: None,
         }
     }
 
     /// Creates a new default configuration and seeds it from the environment variables.
+    ///
+    /// The on-disk code cache is never enabled through here; use [`Config::from_env_with_code_cache`] for that.
     pub fn from_env() -> Result<Self, Error> {
         let mut config = Self::new();
 
@@ -206,6 +212,25 @@ impl Config {
         Ok(config)
     }
 
+    /// Same as [`Config::from_env`], but also enables the on-disk code cache if `POLKAVM_CODE_CACHE_DIR` is set.
+    ///
+    /// # Safety
+    ///
+    /// The directory from `POLKAVM_CODE_CACHE_DIR` is subject to the same requirements as one set with
+    /// [`Config::set_code_cache_dir`], so the environment of the process must be controlled by a trusted user.
+    #[cfg(feature = "std")]
+    pub unsafe fn from_env_with_code_cache() -> Result<Self, Error> {
+        let mut config = Self::from_env()?;
+        if let Some(value) = std::env::var_os("POLKAVM_CODE_CACHE_DIR") {
+            // SAFETY: The caller guarantees that the directory can be trusted.
+            unsafe {
+                config.set_code_cache_dir(Some(value.into()));
+            }
+        }
+
+        Ok(config)
+    }
+
     /// Forces the use of a given backend.
     ///
     /// Default: `None` (automatically pick the best available backend)
@@ -364,6 +389,36 @@ impl Config {
     pub fn sandboxing_enabled(&self) -> bool {
         self.sandboxing_enabled
     }
+
+    /// Sets the directory of the on-disk code cache.
+    ///
+    /// When set the recompiler's output will be persisted in this directory and reused
+    /// across engines and process restarts instead of recompiling the same program again.
+    /// The cache is keyed by the program blob, the [`ModuleConfig`] (including the cost model)
+    /// and the version of PolkaVM.
+    ///
+    /// Only the Linux sandbox can reuse the cached code. Modules with custom codegen are never cached.
+    ///
+    /// Default: `None`
+    ///
+    /// Corresponding environment variable: `POLKAVM_CODE_CACHE_DIR` (only read by [`Config::from_env_with_code_cache`])
+    ///
+    /// # Safety
+    ///
+    /// The cached machine code is loaded and executed as-is, similarly to [`Module::from_precompiled`](crate::Module::from_precompiled).
+    /// The cache entries are only checked for accidental corruption, so anyone who can write into this directory
+    /// can make the engine execute arbitrary native code. The directory must only be writable by trusted users.
+    #[cfg(feature = "std")]
+    pub unsafe fn set_code_cache_dir(&mut self, value: Option<std::path::PathBuf>) -> &mut Self {
+        self.code_cache_dir = value;
+        self
+    }
+
+    /// Returns the directory of the on-disk code cache, if any.
+    #[cfg(feature = "std")]
+    pub fn code_cache_dir(&self) -> Option<&std::path::Path> {
+        self.code_cache_dir.as_deref()
+    }
 }
 
 /// The type of gas metering.
@@ -378,6 +433,8 @@ pub enum GasMeteringKind {
     /// With asynchronous gas metering the program can run slightly longer than it would otherwise,
     /// and the exact point *when* it is interrupted is not deterministic, but whether the computation
     /// as a whole finishes under a given gas limit will still be strictly enforced and deterministic.
+    /// Refilling the gas and resuming the execution after running out produces the same final state
+    /// as running with enough gas from the start.
     ///
     /// This is only a hint, and the VM might still fall back to using synchronous gas metering
     /// if asynchronous metering is not available.
@@ -395,9 +452,13 @@ pub struct ModuleConfig {
     pub(crate) gas_metering: Option<GasMeteringKind>,
     pub(crate) is_strict: bool,
     pub(crate) step_tracing: bool,
+    pub(crate) watchpoints: bool,
+    pub(crate) memory_sanitizer: bool,
+    pub(crate) execution_limits: bool,
     pub(crate) dynamic_paging: bool,
     pub(crate) aux_data_size: u32,
     pub(crate) allow_sbrk: bool,
+    pub(crate) tier_up_threshold: Option<u32>,
     cache_by_hash: bool,
     pub(crate) custom_codegen: Option<Arc<dyn CustomCodegen>>,
     pub(crate) cost_model: CostModelRef,
@@ -417,9 +478,13 @@ impl ModuleConfig {
             gas_metering: None,
             is_strict: false,
             step_tracing: false,
+            watchpoints: false,
+            memory_sanitizer: false,
+            execution_limits: false,
             dynamic_paging: false,
             aux_data_size: 0,
             allow_sbrk: true,
+            tier_up_threshold: None,
             cache_by_hash: false,
             custom_codegen: None,
             cost_model: CostModel::naive_ref(),
@@ -483,6 +548,79 @@ impl ModuleConfig {
         self
     }
 
+    /// Sets whether watchpoints can be set on instances of this module.
+    ///
+    /// Watchpoints are only supported by the interpreter, so when enabled the module will always be interpreted
+    /// regardless of which backend was selected.
+    ///
+    /// Should only be used for debugging.
+    ///
+    /// Default: `false`
+    pub fn set_watchpoints(&mut self, enabled: bool) -> &mut Self {
+        self.watchpoints = enabled;
+        self
+    }
+
+    /// Sets whether the guest's memory accesses will be checked by a memory sanitizer.
+    ///
+    /// When enabled every load and store is checked, and a [`MemoryDiagnostic`](crate::MemoryDiagnostic) is recorded when the guest:
+    ///   - reads a byte of its stack or heap which it has never written to, or
+    ///   - accesses memory between the current top of its heap (as grown with `sbrk`) and the bottom of its stack.
+    ///
+    /// The diagnostics don't interrupt the execution and can be retrieved with
+    /// [`RawInstance::take_memory_diagnostics`](crate::RawInstance::take_memory_diagnostics).
+    ///
+    /// The sanitizer is only supported by the interpreter, so when enabled the module will always be interpreted
+    /// regardless of which backend was selected. Cannot be used together with dynamic paging.
+    ///
+    /// Should only be used for debugging and testing.
+    ///
+    /// Default: `false`
+    pub fn set_memory_sanitizer(&mut self, enabled: bool) -> &mut Self {
+        self.memory_sanitizer = enabled;
+        self
+    }
+
+    /// Sets whether execution limits are enabled.
+    ///
+    /// When enabled the execution can be limited with [`RawInstance::set_limits`](crate::RawInstance::set_limits)
+    /// independently of gas metering, in which case [`InterruptKind::LimitReached`](crate::InterruptKind::LimitReached)
+    /// will be returned by [`RawInstance::run`](crate::RawInstance::run) when a limit is reached.
+    ///
+    /// This is also required to interrupt the execution from another thread through [`RawInstance::interrupt_handle`](crate::RawInstance::interrupt_handle).
+    ///
+    /// This adds a small overhead to every executed basic block.
+    ///
+    /// Default: `false`
+    pub fn set_execution_limits(&mut self, enabled: bool) -> &mut Self {
+        self.execution_limits = enabled;
+        self
+    }
+
+    /// Sets whether the module should be compiled lazily in the background.
+    ///
+    /// When set to `Some(threshold)` the module will initially be executed by the interpreter, and will be
+    /// compiled in a background thread once it was instantiated `threshold` times. (A `threshold` of zero
+    /// starts the compilation right away.) Once the compilation finishes every subsequent
+    /// [`Module::instantiate`](crate::Module::instantiate) will transparently create an instance
+    /// of the compiled module instead; existing instances will keep running on the interpreter.
+    ///
+    /// This is useful for modules which are usually executed only once, for which the compilation
+    /// would take longer than the execution itself.
+    ///
+    /// Has no effect unless the compiler backend is used, and is ignored for precompiled modules.
+    ///
+    /// Default: `None`
+    pub fn set_tiered_compilation(&mut self, threshold: Option<u32>) -> &mut Self {
+        self.tier_up_threshold = threshold;
+        self
+    }
+
+    /// Returns whether the module should be compiled lazily in the background.
+    pub fn tiered_compilation(&self) -> Option<u32> {
+        self.tier_up_threshold
+    }
+
     /// Sets the strict mode. When disabled it's guaranteed that the semantics
     /// of lazy execution match the semantics of eager execution.
     ///
@@ -540,7 +678,7 @@ impl ModuleConfig {
         self
     }
 
-    #[cfg(feature = "module-cache")]
+    #[cfg_attr(not(feature = "module-cache"), allow(dead_code))]
     pub(crate) fn hash(&self) -> Option<polkavm_common::hasher::Hash> {
         if self.custom_codegen.is_some() {
             return None;
@@ -552,8 +690,12 @@ impl ModuleConfig {
             gas_metering,
             is_strict,
             step_tracing,
+            watchpoints,
+            memory_sanitizer,
+            execution_limits,
             dynamic_paging,
             allow_sbrk,
+            tier_up_threshold,
             ref cost_model,
             // Deliberately ignored.
             cache_by_hash: _,
@@ -571,8 +713,13 @@ impl ModuleConfig {
             },
             u32::from(is_strict),
             u32::from(step_tracing),
+            u32::from(watchpoints),
+            u32::from(memory_sanitizer),
+            u32::from(execution_limits),
             u32::from(dynamic_paging),
             u32::from(allow_sbrk),
+            u32::from(tier_up_threshold.is_some()),
+            tier_up_threshold.unwrap_or(0),
         ]);
 
         use core::hash::Hash;
@@ -581,3 +728,81 @@ impl ModuleConfig {
         Some(hasher.finalize())
     }
 }
+
+/// Limits on how long an instance can execute and how much memory it can use, independent of gas metering.
+///
+/// Limiting the execution requires execution limits to be enabled with [`ModuleConfig::set_execution_limits`].
+#[derive(Clone, Default, Debug)]
+pub struct InstanceLimits {
+    basic_block_limit: Option<u64>,
+    #[cfg(feature = "std")]
+    deadline: Option<std::time::Instant>,
+    memory_limit: Option<u64>,
+}
+
+impl InstanceLimits {
+    /// Creates a new set of limits with no limits set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the maximum number of basic blocks which can be executed.
+    pub fn basic_block_limit(&self) -> Option<u64> {
+        self.basic_block_limit
+    }
+
+    /// Sets the maximum number of basic blocks which can be executed.
+    ///
+    /// Once this many basic blocks were executed [`InterruptKind::LimitReached`](crate::InterruptKind::LimitReached)
+    /// with [`LimitKind::BasicBlocks`](crate::LimitKind::BasicBlocks) will be returned.
+    ///
+    /// Default: `None`
+    pub fn set_basic_block_limit(&mut self, limit: Option<u64>) -> &mut Self {
+        self.basic_block_limit = limit;
+        self
+    }
+
+    /// Returns the deadline after which the execution will be interrupted.
+    #[cfg(feature = "std")]
+    pub fn deadline(&self) -> Option<std::time::Instant> {
+        self.deadline
+    }
+
+    /// Sets the deadline after which the execution will be interrupted.
+    ///
+    /// Once the deadline passes [`InterruptKind::LimitReached`](crate::InterruptKind::LimitReached)
+    /// with [`LimitKind::Deadline`](crate::LimitKind::Deadline) will be returned. The deadline is
+    /// not enforced precisely; the execution will be interrupted shortly after it passes.
+    ///
+    /// Default: `None`
+    #[cfg(feature = "std")]
+    pub fn set_deadline(&mut self, deadline: Option<std::time::Instant>) -> &mut Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Returns the maximum amount of memory, in bytes, which the instance can use.
+    pub fn memory_limit(&self) -> Option<u64> {
+        self.memory_limit
+    }
+
+    /// Sets the maximum amount of memory, in bytes, which the instance can use.
+    ///
+    /// This applies to the total reported by [`RawInstance::memory_usage`](crate::RawInstance::memory_usage).
+    /// Once the limit is reached any attempt to grow the heap with `sbrk` will fail, and when dynamic paging
+    /// is enabled any attempt to map in new pages will fail with [`MemoryAccessError::MemoryLimitReached`](crate::MemoryAccessError::MemoryLimitReached).
+    ///
+    /// With dynamic paging the guest can't map in any pages by itself; every page fault is returned to the host
+    /// as [`InterruptKind::Segfault`](crate::InterruptKind::Segfault), and the host resolves it through
+    /// [`RawInstance::write_memory`](crate::RawInstance::write_memory) or [`RawInstance::zero_memory`](crate::RawInstance::zero_memory),
+    /// which are what enforce the limit. The [`Linker`](crate::Linker) reports a page fault which couldn't be resolved
+    /// due to the limit as [`LimitKind::Memory`](crate::LimitKind::Memory).
+    ///
+    /// Setting a limit which is lower than the current usage doesn't free any memory which is already in use.
+    ///
+    /// Default: `None`
+    pub fn set_memory_limit(&mut self, limit: Option<u64>) -> &mut Self {
+        self.memory_limit = limit;
+        self
+    }
+}
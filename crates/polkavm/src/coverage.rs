@@ -0,0 +1,399 @@
+//! Code coverage collection.
+//!
+//! A [`Coverage`] is fed every interruption of instances created from a module with
+//! [`ModuleConfig::set_step_tracing`](crate::ModuleConfig::set_step_tracing) enabled, and counts how many
+//! times each basic block of that module was executed. The same collector can be used for any number of
+//! runs and instances of the module, and collectors for the same module can be merged together.
+//!
+//! The blocks are mapped back to source files and lines through the debug info embedded in the program blob,
+//! which can then be written out in the `lcov` or the Cobertura format.
+//!
+//! A basic block is considered to be executed in its entirety as soon as its first instruction is executed.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use std::collections::HashMap;
+use std::io::Write;
+
+use polkavm_common::program::ProgramCounter;
+
+use crate::basic_blocks::{basic_blocks, BlockCounter};
+use crate::error::bail;
+use crate::frames::resolve_frames;
+use crate::{Error, InterruptKind, Module, RawInstance};
+
+/// The coverage of a single basic block.
+#[derive(Clone, Debug)]
+pub struct BlockCoverage {
+    start: ProgramCounter,
+    end: ProgramCounter,
+    hits: u64,
+}
+
+impl BlockCoverage {
+    /// Returns the program counter of the first instruction of this block.
+    pub fn start(&self) -> ProgramCounter {
+        self.start
+    }
+
+    /// Returns the program counter right after the last instruction of this block.
+    pub fn end(&self) -> ProgramCounter {
+        self.end
+    }
+
+    /// Returns how many times this block was executed.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+/// The coverage of a single source line.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct LineCoverage {
+    /// The line number.
+    pub line: u32,
+    /// How many times this line was executed.
+    pub hits: u64,
+}
+
+/// The coverage of a single function.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct FunctionCoverage {
+    /// The name of the function.
+    pub name: String,
+    /// The first line of the function.
+    pub line: u32,
+    /// How many times the function was called.
+    pub hits: u64,
+}
+
+/// The coverage of a single source file.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct FileCoverage {
+    /// The path to the source file.
+    pub path: String,
+    /// The coverage of every line for which code was emitted, sorted by line number.
+    pub lines: Vec<LineCoverage>,
+    /// The coverage of every function defined in this file, sorted by line number.
+    pub functions: Vec<FunctionCoverage>,
+}
+
+impl FileCoverage {
+    fn lines_hit(&self) -> usize {
+        self.lines.iter().filter(|line| line.hits > 0).count()
+    }
+
+    fn line_rate(&self) -> f64 {
+        line_rate(self.lines_hit(), self.lines.len())
+    }
+}
+
+struct SourceFunction {
+    name: String,
+    path: String,
+    line: u32,
+}
+
+/// A collector of code coverage for a single module.
+pub struct Coverage {
+    blocks: Vec<BlockCoverage>,
+    block_counter: BlockCounter,
+    /// The source lines of every instruction of a given block.
+    block_lines: Vec<Vec<(usize, u32)>>,
+    /// The (non-inlined) function to which a given block belongs.
+    block_function: Vec<Option<usize>>,
+    paths: Vec<String>,
+    functions: Vec<SourceFunction>,
+}
+
+impl Coverage {
+    /// Creates a new coverage collector for the given module.
+    pub fn new(module: &Module) -> Result<Self, Error> {
+        if !module.is_step_tracing() {
+            bail!("failed to create a coverage collector: the module was not compiled with step tracing enabled");
+        }
+
+        let blocks = basic_blocks(module);
+        let mut coverage = Coverage {
+            blocks: Vec::with_capacity(blocks.len()),
+            block_counter: BlockCounter::new(blocks.iter().map(|block| block.start)),
+            block_lines: Vec::with_capacity(blocks.len()),
+            block_function: Vec::with_capacity(blocks.len()),
+            paths: Vec::new(),
+            functions: Vec::new(),
+        };
+
+        let mut path_to_index = HashMap::new();
+        let mut function_to_index = HashMap::new();
+        let mut instructions = module.instructions().peekable();
+        for block in blocks {
+            let mut lines = Vec::new();
+            let mut function = None;
+            while let Some(instruction) = instructions.next_if(|instruction| instruction.offset < block.end) {
+                let frames = resolve_frames(module, instruction.offset);
+                for frame in &frames {
+                    let (Some(path), Some(line)) = (&frame.path, frame.line) else {
+                        continue;
+                    };
+
+                    let path_index = *path_to_index.entry(path.clone()).or_insert_with(|| {
+                        coverage.paths.push(path.clone());
+                        coverage.paths.len() - 1
+                    });
+
+                    lines.push((path_index, line));
+                }
+
+                if function.is_none() {
+                    // The outermost frame is the function which wasn't inlined.
+                    if let Some(frame) = frames.first() {
+                        if let (Some(path), Some(line)) = (&frame.path, frame.line) {
+                            let index = *function_to_index.entry(frame.function.clone()).or_insert_with(|| {
+                                coverage.functions.push(SourceFunction {
+                                    name: frame.function.clone(),
+                                    path: path.clone(),
+                                    line,
+                                });
+                                coverage.functions.len() - 1
+                            });
+
+                            let source_function = &mut coverage.functions[index];
+                            if source_function.path == *path {
+                                source_function.line = source_function.line.min(line);
+                            }
+
+                            function = Some(index);
+                        }
+                    }
+                }
+            }
+
+            lines.sort_unstable();
+            lines.dedup();
+
+            coverage.blocks.push(BlockCoverage {
+                start: block.start,
+                end: block.end,
+                hits: 0,
+            });
+            coverage.block_lines.push(lines);
+            coverage.block_function.push(function);
+        }
+
+        Ok(coverage)
+    }
+
+    /// Records an interruption returned by [`RawInstance::run`], counting the executed basic blocks.
+    pub fn on_interrupt(&mut self, instance: &RawInstance, interrupt: &InterruptKind) {
+        if let Some(index) = self.block_counter.on_interrupt(instance, interrupt) {
+            self.blocks[index].hits += 1;
+        }
+    }
+
+    /// Adds the execution counts gathered by another collector for the same module.
+    pub fn merge(&mut self, other: &Coverage) -> Result<(), Error> {
+        let is_same_module = self.blocks.len() == other.blocks.len()
+            && self
+                .blocks
+                .iter()
+                .zip(other.blocks.iter())
+                .all(|(lhs, rhs)| lhs.start == rhs.start && lhs.end == rhs.end);
+
+        if !is_same_module {
+            bail!("failed to merge coverage: the coverage was collected for a different module");
+        }
+
+        for (lhs, rhs) in self.blocks.iter_mut().zip(other.blocks.iter()) {
+            lhs.hits += rhs.hits;
+        }
+
+        Ok(())
+    }
+
+    /// Returns every basic block of the module, in order.
+    pub fn blocks(&self) -> &[BlockCoverage] {
+        &self.blocks
+    }
+
+    /// Returns the coverage of every source file, sorted by path.
+    ///
+    /// The hit count of a line is the highest hit count of the basic blocks which contain code for that line,
+    /// and the hit count of a function is the hit count of its first basic block.
+    pub fn files(&self) -> Vec<FileCoverage> {
+        let mut lines_for_path: Vec<BTreeMap<u32, u64>> = self.paths.iter().map(|_| BTreeMap::new()).collect();
+        for (block, lines) in self.blocks.iter().zip(self.block_lines.iter()) {
+            for &(path_index, line) in lines {
+                let hits = lines_for_path[path_index].entry(line).or_insert(0);
+                *hits = (*hits).max(block.hits);
+            }
+        }
+
+        let mut function_hits: Vec<Option<u64>> = self.functions.iter().map(|_| None).collect();
+        for (block, function) in self.blocks.iter().zip(self.block_function.iter()) {
+            if let Some(function) = *function {
+                function_hits[function].get_or_insert(block.hits);
+            }
+        }
+
+        let mut files: BTreeMap<&str, FileCoverage> = BTreeMap::new();
+        for (path, lines) in self.paths.iter().zip(lines_for_path) {
+            files.insert(
+                path,
+                FileCoverage {
+                    path: path.clone(),
+                    lines: lines.into_iter().map(|(line, hits)| LineCoverage { line, hits }).collect(),
+                    functions: Vec::new(),
+                },
+            );
+        }
+
+        for (function, hits) in self.functions.iter().zip(function_hits) {
+            let Some(file) = files.get_mut(function.path.as_str()) else {
+                continue;
+            };
+
+            file.functions.push(FunctionCoverage {
+                name: function.name.clone(),
+                line: function.line,
+                hits: hits.unwrap_or(0),
+            });
+        }
+
+        let mut files: Vec<_> = files.into_values().collect();
+        for file in &mut files {
+            file.functions.sort_by(|lhs, rhs| (lhs.line, &lhs.name).cmp(&(rhs.line, &rhs.name)));
+        }
+
+        files
+    }
+
+    /// Writes out the coverage in the `lcov` tracefile format.
+    pub fn write_lcov(&self, mut output: impl Write, test_name: &str) -> std::io::Result<()> {
+        for file in self.files() {
+            writeln!(output, "TN:{test_name}")?;
+            writeln!(output, "SF:{}", file.path)?;
+            for function in &file.functions {
+                writeln!(output, "FN:{},{}", function.line, function.name)?;
+            }
+
+            for function in &file.functions {
+                writeln!(output, "FNDA:{},{}", function.hits, function.name)?;
+            }
+
+            writeln!(output, "FNF:{}", file.functions.len())?;
+            writeln!(output, "FNH:{}", file.functions.iter().filter(|function| function.hits > 0).count())?;
+            for line in &file.lines {
+                writeln!(output, "DA:{},{}", line.line, line.hits)?;
+            }
+
+            writeln!(output, "LF:{}", file.lines.len())?;
+            writeln!(output, "LH:{}", file.lines_hit())?;
+            writeln!(output, "end_of_record")?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes out the coverage in the Cobertura XML format.
+    pub fn write_cobertura(&self, mut output: impl Write) -> std::io::Result<()> {
+        let files = self.files();
+        let lines_valid: usize = files.iter().map(|file| file.lines.len()).sum();
+        let lines_covered: usize = files.iter().map(FileCoverage::lines_hit).sum();
+        let line_rate = line_rate(lines_covered, lines_valid);
+
+        writeln!(output, r#"<?xml version="1.0" ?>"#)?;
+        writeln!(
+            output,
+            r#"<!DOCTYPE coverage SYSTEM "http://cobertura.sourceforge.net/xml/coverage-04.dtd">"#
+        )?;
+        writeln!(
+            output,
+            r#"<coverage line-rate="{line_rate:.4}" branch-rate="0" lines-covered="{lines_covered}" lines-valid="{lines_valid}" branches-covered="0" branches-valid="0" complexity="0" version="{}" timestamp="0">"#,
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(output, "  <sources>")?;
+        writeln!(output, "    <source>.</source>")?;
+        writeln!(output, "  </sources>")?;
+        writeln!(output, "  <packages>")?;
+        writeln!(
+            output,
+            r#"    <package name="" line-rate="{line_rate:.4}" branch-rate="0" complexity="0">"#
+        )?;
+        writeln!(output, "      <classes>")?;
+        for file in &files {
+            let path = XmlEscaped(&file.path);
+            writeln!(
+                output,
+                r#"        <class name="{path}" filename="{path}" line-rate="{:.4}" branch-rate="0" complexity="0">"#,
+                file.line_rate()
+            )?;
+            writeln!(output, "          <methods>")?;
+            for function in &file.functions {
+                writeln!(
+                    output,
+                    r#"            <method name="{}" signature="" line-rate="{}" branch-rate="0" complexity="0">"#,
+                    XmlEscaped(&function.name),
+                    if function.hits > 0 { 1 } else { 0 }
+                )?;
+                writeln!(output, "              <lines>")?;
+                writeln!(
+                    output,
+                    r#"                <line number="{}" hits="{}" branch="false"/>"#,
+                    function.line, function.hits
+                )?;
+                writeln!(output, "              </lines>")?;
+                writeln!(output, "            </method>")?;
+            }
+            writeln!(output, "          </methods>")?;
+            writeln!(output, "          <lines>")?;
+            for line in &file.lines {
+                writeln!(
+                    output,
+                    r#"            <line number="{}" hits="{}" branch="false"/>"#,
+                    line.line, line.hits
+                )?;
+            }
+            writeln!(output, "          </lines>")?;
+            writeln!(output, "        </class>")?;
+        }
+        writeln!(output, "      </classes>")?;
+        writeln!(output, "    </package>")?;
+        writeln!(output, "  </packages>")?;
+        writeln!(output, "</coverage>")?;
+
+        Ok(())
+    }
+}
+
+fn line_rate(hit: usize, total: usize) -> f64 {
+    if total == 0 {
+        return 1.0;
+    }
+
+    let hit = u32::try_from(hit).unwrap_or(u32::MAX);
+    let total = u32::try_from(total).unwrap_or(u32::MAX);
+    f64::from(hit) / f64::from(total)
+}
+
+struct XmlEscaped<'a>(&'a str);
+
+impl<'a> core::fmt::Display for XmlEscaped<'a> {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for ch in self.0.chars() {
+            match ch {
+                '&' => fmt.write_str("&amp;")?,
+                '<' => fmt.write_str("&lt;")?,
+                '>' => fmt.write_str("&gt;")?,
+                '"' => fmt.write_str("&quot;")?,
+                '\'' => fmt.write_str("&apos;")?,
+                ch => core::fmt::Write::write_char(fmt, ch)?,
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,958 @@
+//! A GDB remote serial protocol server for debugging guest programs.
+//!
+//! The [`GdbStub`] drives a [`RawInstance`] whose module was compiled with
+//! [`ModuleConfig::set_step_tracing`](crate::ModuleConfig::set_step_tracing) enabled, and allows
+//! a stock `gdb` (or anything else which speaks its remote protocol) to connect to it with e.g.:
+//!
+//! ```text
+//! (gdb) set architecture riscv:rv32
+//! (gdb) target remote localhost:1234
+//! ```
+//!
+//! The guest's registers are exposed as the RISC-V registers they were originally translated from,
+//! so `ra`, `sp`, `t0`-`t2`, `s0`, `s1` and `a0`-`a5` are all available under their usual names.
+//!
+//! Program counters are exposed as-is, so e.g. `break *0x1234` will set a breakpoint at the instruction
+//! at offset `0x1234` in the program's code. Breakpoints on source lines can be set through the
+//! `monitor break <path>:<line>` command, which uses the debug info embedded in the program blob.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use std::io::{Read, Write};
+
+use polkavm_common::cast::cast;
+use polkavm_common::program::{ProgramCounter, Reg};
+
+use crate::error::bail;
+use crate::{Error, InterruptKind, Module, RawInstance, WatchpointKind};
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGSEGV: u8 = 11;
+const SIGXCPU: u8 = 24;
+
+const MAX_PACKET_SIZE: usize = 0x4000;
+
+/// The byte sent by the debugger when it wants to interrupt the running program.
+const INTERRUPT_REQUEST: u8 = 0x03;
+
+/// How many instructions are executed between checks whether the debugger wants to interrupt the program.
+const INTERRUPT_POLL_INTERVAL: u32 = 1024;
+
+/// The remote register number of the program counter, as used by GDB's RISC-V target description.
+const PC_REGNUM: u32 = 32;
+
+/// Maps the RISC-V `x0`-`x15` registers to the guest's registers.
+const RISCV_REGS: [Option<Reg>; 16] = [
+    None,
+    Some(Reg::RA),
+    Some(Reg::SP),
+    None,
+    None,
+    Some(Reg::T0),
+    Some(Reg::T1),
+    Some(Reg::T2),
+    Some(Reg::S0),
+    Some(Reg::S1),
+    Some(Reg::A0),
+    Some(Reg::A1),
+    Some(Reg::A2),
+    Some(Reg::A3),
+    Some(Reg::A4),
+    Some(Reg::A5),
+];
+
+type InterruptHandler<'a> = Box<dyn FnMut(&mut RawInstance, &InterruptKind) -> bool + 'a>;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum StopReason {
+    Step,
+    Breakpoint,
+    Watchpoint { address: u32, is_write: bool },
+    Signal(u8),
+    Exited,
+    Terminated(u8),
+}
+
+impl StopReason {
+    fn reply(self) -> String {
+        match self {
+            StopReason::Step => format!("T{SIGTRAP:02x}thread:01;"),
+            StopReason::Breakpoint => format!("T{SIGTRAP:02x}swbreak:;thread:01;"),
+            StopReason::Watchpoint { address, is_write } => {
+                let kind = if is_write { "watch" } else { "rwatch" };
+                format!("T{SIGTRAP:02x}{kind}:{address:x};thread:01;")
+            }
+            StopReason::Signal(signal) => format!("T{signal:02x}thread:01;"),
+            StopReason::Exited => "W00".into(),
+            StopReason::Terminated(signal) => format!("X{signal:02x}"),
+        }
+    }
+
+    fn is_final(self) -> bool {
+        matches!(self, StopReason::Exited | StopReason::Terminated(..))
+    }
+}
+
+/// A stream over which a debugger session can be served.
+pub trait DebuggerStream: Read + Write {
+    /// Moves the stream into or out of nonblocking mode.
+    ///
+    /// This is used to check whether the debugger wants to interrupt the program while it's running.
+    fn set_nonblocking(&mut self, value: bool) -> std::io::Result<()>;
+}
+
+impl DebuggerStream for std::net::TcpStream {
+    fn set_nonblocking(&mut self, value: bool) -> std::io::Result<()> {
+        std::net::TcpStream::set_nonblocking(self, value)
+    }
+}
+
+#[cfg(unix)]
+impl DebuggerStream for std::os::unix::net::UnixStream {
+    fn set_nonblocking(&mut self, value: bool) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, value)
+    }
+}
+
+impl<S> DebuggerStream for &mut S
+where
+    S: DebuggerStream,
+{
+    fn set_nonblocking(&mut self, value: bool) -> std::io::Result<()> {
+        S::set_nonblocking(self, value)
+    }
+}
+
+struct Connection<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    position: usize,
+    no_ack_mode: bool,
+}
+
+impl<S> Connection<S>
+where
+    S: DebuggerStream,
+{
+    /// Reads more data from the stream once everything which was already read was consumed.
+    fn fill_buffer(&mut self) -> std::io::Result<()> {
+        if self.position < self.buffer.len() {
+            return Ok(());
+        }
+
+        self.buffer.resize(1024, 0);
+        let result = loop {
+            match self.stream.read(&mut self.buffer) {
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+
+        self.buffer.truncate(*result.as_ref().unwrap_or(&0));
+        self.position = 0;
+        result.map(|_| ())
+    }
+
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        self.fill_buffer()?;
+        let Some(&byte) = self.buffer.get(self.position) else {
+            return Ok(None);
+        };
+
+        self.position += 1;
+        Ok(Some(byte))
+    }
+
+    /// Checks, without blocking, whether the debugger has requested for the program to be interrupted.
+    fn poll_interrupt_request(&mut self) -> std::io::Result<bool> {
+        if self.position >= self.buffer.len() {
+            self.stream.set_nonblocking(true)?;
+            let result = self.fill_buffer();
+            self.stream.set_nonblocking(false)?;
+            match result {
+                Ok(()) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => return Ok(false),
+                Err(error) => return Err(error),
+            }
+        }
+
+        if self.buffer.get(self.position) == Some(&INTERRUPT_REQUEST) {
+            self.position += 1;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Reads the next packet, returning `None` once the connection is closed.
+    fn read_packet(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        loop {
+            let Some(byte) = self.read_byte()? else { return Ok(None) };
+            if byte != b'$' {
+                // Stray acks and interrupt requests (0x03) received while we're stopped anyway.
+                continue;
+            }
+
+            let mut payload = Vec::new();
+            let mut checksum = 0_u8;
+            loop {
+                let Some(byte) = self.read_byte()? else { return Ok(None) };
+                if byte == b'#' {
+                    break;
+                }
+
+                if payload.len() >= MAX_PACKET_SIZE {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "received a packet larger than the advertised maximum packet size",
+                    ));
+                }
+
+                checksum = checksum.wrapping_add(byte);
+                payload.push(byte);
+            }
+
+            let (Some(hi), Some(lo)) = (self.read_byte()?, self.read_byte()?) else {
+                return Ok(None);
+            };
+
+            if !self.no_ack_mode {
+                if parse_hex_u64(&[hi, lo]) != Some(u64::from(checksum)) {
+                    log::warn!("GDB stub: received a packet with an invalid checksum");
+                    self.stream.write_all(b"-")?;
+                    self.stream.flush()?;
+                    continue;
+                }
+
+                self.stream.write_all(b"+")?;
+                self.stream.flush()?;
+            }
+
+            return Ok(Some(unescape(&payload)));
+        }
+    }
+
+    fn write_packet(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        let mut packet = Vec::with_capacity(payload.len() + 4);
+        packet.push(b'$');
+        let mut checksum = 0_u8;
+        for &byte in payload {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                packet.push(b'}');
+                checksum = checksum.wrapping_add(b'}');
+                packet.push(byte ^ 0x20);
+                checksum = checksum.wrapping_add(byte ^ 0x20);
+            } else {
+                packet.push(byte);
+                checksum = checksum.wrapping_add(byte);
+            }
+        }
+        packet.push(b'#');
+        packet.extend_from_slice(format!("{checksum:02x}").as_bytes());
+
+        loop {
+            self.stream.write_all(&packet)?;
+            self.stream.flush()?;
+            if self.no_ack_mode {
+                return Ok(());
+            }
+
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+fn unescape(payload: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(payload.len());
+    let mut iter = payload.iter();
+    while let Some(&byte) = iter.next() {
+        if byte == b'}' {
+            if let Some(&next) = iter.next() {
+                output.push(next ^ 0x20);
+            }
+        } else {
+            output.push(byte);
+        }
+    }
+
+    output
+}
+
+fn parse_hex_u64(input: &[u8]) -> Option<u64> {
+    if input.is_empty() || input.len() > 16 {
+        return None;
+    }
+
+    let mut value = 0_u64;
+    for &byte in input {
+        let digit = char::from(byte).to_digit(16)?;
+        value = (value << 4) | u64::from(digit);
+    }
+
+    Some(value)
+}
+
+fn parse_hex_u32(input: &[u8]) -> Option<u32> {
+    parse_hex_u64(input).and_then(|value| u32::try_from(value).ok())
+}
+
+fn decode_hex(input: &[u8]) -> Option<Vec<u8>> {
+    if input.len() % 2 != 0 {
+        return None;
+    }
+
+    input
+        .chunks_exact(2)
+        .map(|pair| parse_hex_u64(pair).map(|value| cast(value).truncate_to_u8()))
+        .collect()
+}
+
+fn encode_hex(output: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        let _ = write!(output, "{byte:02x}");
+    }
+}
+
+/// Parses an `address,length` pair.
+fn parse_memory_range(input: &[u8]) -> Option<(u32, u32)> {
+    let index = input.iter().position(|&byte| byte == b',')?;
+    Some((parse_hex_u32(&input[..index])?, parse_hex_u32(&input[index + 1..])?))
+}
+
+/// Finds every place where the code for the given source line starts.
+///
+/// The `path` only needs to match the end of the path stored in the program's debug info.
+pub fn find_source_line(module: &Module, path: &str, line: u32) -> Vec<ProgramCounter> {
+    let blob = module.blob();
+
+    let mut output = Vec::new();
+    let mut covered_until = ProgramCounter(0);
+    for instruction in module.instructions() {
+        if instruction.offset < covered_until {
+            continue;
+        }
+
+        let Ok(Some(mut line_program)) = blob.get_debug_line_program_at(instruction.offset) else {
+            continue;
+        };
+
+        let mut was_matching = false;
+        while let Ok(Some(region_info)) = line_program.run() {
+            let range = region_info.instruction_range();
+            covered_until = covered_until.max(range.end);
+
+            let is_matching = region_info.frames().last().is_some_and(|frame| {
+                let Ok(Some(location)) = frame.location() else { return false };
+                location.line() == Some(line) && location.path().ends_with(path)
+            });
+
+            if is_matching && !was_matching {
+                output.push(range.start);
+            }
+
+            was_matching = is_matching;
+        }
+
+        // Make sure we'll always make progress, even if the debug info is malformed.
+        covered_until = covered_until.max(instruction.next_offset);
+    }
+
+    output.sort_unstable();
+    output.dedup();
+    output
+}
+
+/// Describes the source location of the given program counter using the program's debug info, one line per frame.
+pub fn describe_location(module: &Module, pc: ProgramCounter) -> String {
+    let mut output = format!("At #{pc}:\n");
+    let Some(frames) = crate::frames::location_frames(module, pc) else {
+        output.push_str("  (no location available)\n");
+        return output;
+    };
+
+    for frame in frames {
+        let _ = writeln!(output, "  {}", frame.describe());
+    }
+
+    output
+}
+
+/// A GDB remote serial protocol server which drives a single [`RawInstance`].
+///
+/// The instance must be created from a module with step tracing enabled and must already
+/// be prepared to run, e.g. with [`RawInstance::prepare_call_untyped`].
+pub struct GdbStub<'a> {
+    instance: &'a mut RawInstance,
+    breakpoints: BTreeSet<ProgramCounter>,
+    interrupt_handler: Option<InterruptHandler<'a>>,
+    is_stopped_on_step: bool,
+    last_stop: StopReason,
+}
+
+impl<'a> GdbStub<'a> {
+    /// Creates a new stub for the given instance.
+    pub fn new(instance: &'a mut RawInstance) -> Result<Self, Error> {
+        if !instance.module().is_step_tracing() {
+            bail!("failed to create a GDB stub: the module was not compiled with step tracing enabled");
+        }
+
+        if instance.next_program_counter().is_none() {
+            bail!("failed to create a GDB stub: the instance has no next program counter set");
+        }
+
+        Ok(GdbStub {
+            instance,
+            breakpoints: BTreeSet::new(),
+            interrupt_handler: None,
+            is_stopped_on_step: false,
+            last_stop: StopReason::Step,
+        })
+    }
+
+    /// Sets a handler which will be called for every [`InterruptKind::Ecalli`] and [`InterruptKind::Segfault`]
+    /// triggered by the program.
+    ///
+    /// If the handler returns `true` the interruption is considered handled and the execution continues,
+    /// otherwise the execution stops and the debugger is notified.
+    pub fn set_interrupt_handler(&mut self, handler: impl FnMut(&mut RawInstance, &InterruptKind) -> bool + 'a) {
+        self.interrupt_handler = Some(Box::new(handler));
+    }
+
+    /// Returns the instance this stub is driving.
+    pub fn instance(&mut self) -> &mut RawInstance {
+        self.instance
+    }
+
+    /// Adds a breakpoint at the given program counter.
+    pub fn add_breakpoint(&mut self, pc: ProgramCounter) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Removes a breakpoint at the given program counter.
+    ///
+    /// Returns whether the breakpoint was set.
+    pub fn remove_breakpoint(&mut self, pc: ProgramCounter) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    /// Returns an iterator over all of the currently set breakpoints.
+    pub fn breakpoints(&self) -> impl Iterator<Item = ProgramCounter> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Adds breakpoints at every place where the code for the given source line starts.
+    ///
+    /// The `path` only needs to match the end of the path stored in the program's debug info.
+    ///
+    /// Returns the program counters at which the breakpoints were added.
+    pub fn add_source_breakpoint(&mut self, path: &str, line: u32) -> Vec<ProgramCounter> {
+        let list = find_source_line(self.instance.module(), path, line);
+        self.breakpoints.extend(list.iter().copied());
+        list
+    }
+
+    fn current_pc(&self) -> Option<ProgramCounter> {
+        self.instance.next_program_counter().or_else(|| self.instance.program_counter())
+    }
+
+    fn resume(&mut self, single_step: bool, poll_interrupt_request: &mut dyn FnMut() -> bool) -> StopReason {
+        if self.last_stop.is_final() {
+            return self.last_stop;
+        }
+
+        if self.instance.next_program_counter().is_none() {
+            // This can only happen after a trap, so the program can't continue.
+            let signal = match self.last_stop {
+                StopReason::Signal(signal) => signal,
+                _ => SIGILL,
+            };
+
+            return StopReason::Terminated(signal);
+        }
+
+        let start_pc = self.current_pc();
+        let mut is_first_step = !self.is_stopped_on_step;
+        self.is_stopped_on_step = false;
+        let mut steps_until_poll = INTERRUPT_POLL_INTERVAL;
+
+        loop {
+            let interruption = match self.instance.run() {
+                Ok(interruption) => interruption,
+                Err(error) => {
+                    log::error!("GDB stub: failed to run the instance: {error}");
+                    return StopReason::Terminated(SIGABRT);
+                }
+            };
+
+            match interruption {
+                InterruptKind::Step => {
+                    let pc = self.instance.program_counter();
+                    if core::mem::take(&mut is_first_step) && pc == start_pc {
+                        // Nothing was executed yet.
+                        continue;
+                    }
+
+                    if single_step {
+                        self.is_stopped_on_step = true;
+                        return StopReason::Step;
+                    }
+
+                    if pc.is_some_and(|pc| self.breakpoints.contains(&pc)) {
+                        self.is_stopped_on_step = true;
+                        return StopReason::Breakpoint;
+                    }
+
+                    steps_until_poll -= 1;
+                    if steps_until_poll == 0 {
+                        steps_until_poll = INTERRUPT_POLL_INTERVAL;
+                        if poll_interrupt_request() {
+                            self.is_stopped_on_step = true;
+                            return StopReason::Signal(SIGINT);
+                        }
+                    }
+                }
+                InterruptKind::Finished => return StopReason::Exited,
+                InterruptKind::Trap => return StopReason::Signal(SIGILL),
+                InterruptKind::Ecalli(..) | InterruptKind::Segfault(..) => {
+                    is_first_step = false;
+                    if let Some(ref mut handler) = self.interrupt_handler {
+                        if handler(self.instance, &interruption) {
+                            continue;
+                        }
+                    }
+
+                    if matches!(interruption, InterruptKind::Segfault(..)) {
+                        return StopReason::Signal(SIGSEGV);
+                    } else {
+                        return StopReason::Signal(SIGTRAP);
+                    }
+                }
+                InterruptKind::Watchpoint { address, is_write, .. } => return StopReason::Watchpoint { address, is_write },
+                InterruptKind::NotEnoughGas | InterruptKind::LimitReached(..) => return StopReason::Signal(SIGXCPU),
+                InterruptKind::Interrupted => return StopReason::Signal(SIGINT),
+            }
+        }
+    }
+
+    fn read_register(&self, regnum: u32) -> Option<u64> {
+        if regnum == PC_REGNUM {
+            return Some(self.current_pc().map_or(0, |pc| u64::from(pc.0)));
+        }
+
+        let reg = RISCV_REGS.get(cast(regnum).to_usize())?;
+        Some(reg.map_or(0, |reg| self.instance.reg(reg)))
+    }
+
+    fn write_register(&mut self, regnum: u32, value: u64) -> bool {
+        if regnum == PC_REGNUM {
+            let Ok(pc) = u32::try_from(value) else { return false };
+            self.instance.set_next_program_counter(ProgramCounter(pc));
+            self.is_stopped_on_step = false;
+            return true;
+        }
+
+        match RISCV_REGS.get(cast(regnum).to_usize()) {
+            Some(Some(reg)) => {
+                self.instance.set_reg(*reg, value);
+                true
+            }
+            // Writes to the registers which don't exist in the guest are ignored.
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    fn register_size(&self) -> usize {
+        if self.instance.is_64_bit() {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn encode_register(&self, output: &mut String, value: u64) {
+        encode_hex(output, &value.to_le_bytes()[..self.register_size()]);
+    }
+
+    fn decode_register(&self, input: &[u8]) -> Option<u64> {
+        let bytes = decode_hex(input)?;
+        if bytes.len() != self.register_size() {
+            return None;
+        }
+
+        let mut buffer = [0; 8];
+        buffer[..bytes.len()].copy_from_slice(&bytes);
+        Some(u64::from_le_bytes(buffer))
+    }
+
+    fn target_xml(&self) -> String {
+        let (architecture, bitsize) = if self.instance.is_64_bit() {
+            ("riscv:rv64", 64)
+        } else {
+            ("riscv:rv32", 32)
+        };
+
+        const NAMES: [&str; 16] = [
+            "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+        ];
+
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n");
+        let _ = writeln!(xml, "<architecture>{architecture}</architecture>");
+        xml.push_str("<feature name=\"org.gnu.gdb.riscv.cpu\">\n");
+        for (regnum, name) in NAMES.iter().enumerate() {
+            let kind = match regnum {
+                1 => "code_ptr",
+                2 | 8 => "data_ptr",
+                _ => "int",
+            };
+
+            let _ = writeln!(
+                xml,
+                "<reg name=\"{name}\" bitsize=\"{bitsize}\" type=\"{kind}\" regnum=\"{regnum}\"/>"
+            );
+        }
+        let _ = writeln!(
+            xml,
+            "<reg name=\"pc\" bitsize=\"{bitsize}\" type=\"code_ptr\" regnum=\"{PC_REGNUM}\"/>"
+        );
+        xml.push_str("</feature>\n</target>\n");
+        xml
+    }
+
+    fn handle_monitor_command(&mut self, command: &str) -> String {
+        let command = command.trim();
+        if command == "where" || command == "bt" {
+            return match self.current_pc() {
+                Some(pc) => describe_location(self.instance.module(), pc),
+                None => "The program is not running.\n".into(),
+            };
+        }
+
+        if let Some(location) = command.strip_prefix("break ") {
+            let location = location.trim();
+            let Some((path, line)) = location.rsplit_once(':') else {
+                return "Usage: break <path>:<line>\n".into();
+            };
+
+            let Ok(line) = line.parse::<u32>() else {
+                return format!("Invalid line number: '{line}'\n");
+            };
+
+            let list = self.add_source_breakpoint(path, line);
+            if list.is_empty() {
+                return format!("No code found for {location}\n");
+            }
+
+            let mut output = String::new();
+            for pc in list {
+                let _ = writeln!(output, "Breakpoint added at #{pc} (0x{:x})", pc.0);
+            }
+            return output;
+        }
+
+        if command == "help" {
+            return concat!(
+                "Available commands:\n",
+                "  where                  - shows the source location of the current instruction\n",
+                "  break <path>:<line>    - adds breakpoints on a given source line\n",
+            )
+            .into();
+        }
+
+        format!("Unknown command: '{command}'; try 'monitor help'\n")
+    }
+
+    /// Handles a single packet.
+    ///
+    /// Returns `None` if the session should end.
+    fn handle_packet(&mut self, packet: &[u8], no_ack_mode: &mut bool, poll_interrupt_request: &mut dyn FnMut() -> bool) -> Option<String> {
+        let mut output = String::new();
+        match packet {
+            b"?" => output = self.last_stop.reply(),
+            b"qAttached" => output.push('1'),
+            b"qfThreadInfo" => output.push_str("m01"),
+            b"qsThreadInfo" => output.push('l'),
+            b"qC" => output.push_str("QC01"),
+            b"QStartNoAckMode" => {
+                *no_ack_mode = true;
+                output.push_str("OK");
+            }
+            b"vCont?" => output.push_str("vCont;c;C;s;S"),
+            b"k" => return None,
+            _ if packet.starts_with(b"D") => output.push_str("OK"),
+            _ if packet.starts_with(b"qSupported") => {
+                let _ = write!(
+                    output,
+                    "PacketSize={MAX_PACKET_SIZE:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+;vContSupported+"
+                );
+            }
+            _ if packet.starts_with(b"qXfer:features:read:target.xml:") => {
+                let xml = self.target_xml();
+                let range = parse_memory_range(&packet[b"qXfer:features:read:target.xml:".len()..]);
+                let Some((offset, length)) = range else {
+                    return Some("E22".into());
+                };
+
+                let offset = cast(offset).to_usize().min(xml.len());
+                let end = offset.saturating_add(cast(length).to_usize()).min(xml.len());
+                output.push(if end == xml.len() { 'l' } else { 'm' });
+                output.push_str(&xml[offset..end]);
+            }
+            _ if packet.starts_with(b"qRcmd,") => {
+                let Some(command) = decode_hex(&packet[6..]).and_then(|command| String::from_utf8(command).ok()) else {
+                    return Some("E22".into());
+                };
+
+                let reply = self.handle_monitor_command(&command);
+                encode_hex(&mut output, reply.as_bytes());
+            }
+            _ if packet.starts_with(b"H") || packet.starts_with(b"T") => output.push_str("OK"),
+            b"g" => {
+                for regnum in (0..16).chain([PC_REGNUM]) {
+                    let value = self.read_register(regnum).unwrap_or(0);
+                    self.encode_register(&mut output, value);
+                }
+            }
+            _ if packet.starts_with(b"G") => {
+                let chunk_size = self.register_size() * 2;
+                let data = &packet[1..];
+                if data.len() != chunk_size * 17 {
+                    return Some("E22".into());
+                }
+
+                for (regnum, chunk) in (0..16).chain([PC_REGNUM]).zip(data.chunks_exact(chunk_size)) {
+                    let Some(value) = self.decode_register(chunk) else {
+                        return Some("E22".into());
+                    };
+
+                    self.write_register(regnum, value);
+                }
+
+                output.push_str("OK");
+            }
+            _ if packet.starts_with(b"p") => {
+                let Some(value) = parse_hex_u32(&packet[1..]).and_then(|regnum| self.read_register(regnum)) else {
+                    return Some("E22".into());
+                };
+
+                self.encode_register(&mut output, value);
+            }
+            _ if packet.starts_with(b"P") => {
+                let Some(index) = packet.iter().position(|&byte| byte == b'=') else {
+                    return Some("E22".into());
+                };
+
+                let regnum = parse_hex_u32(&packet[1..index]);
+                let value = self.decode_register(&packet[index + 1..]);
+                let (Some(regnum), Some(value)) = (regnum, value) else {
+                    return Some("E22".into());
+                };
+
+                if !self.write_register(regnum, value) {
+                    return Some("E22".into());
+                }
+
+                output.push_str("OK");
+            }
+            _ if packet.starts_with(b"m") => {
+                let Some((address, length)) = parse_memory_range(&packet[1..]) else {
+                    return Some("E22".into());
+                };
+
+                let length = length.min(cast(MAX_PACKET_SIZE / 2).assert_always_fits_in_u32());
+                let Ok(data) = self.instance.read_memory(address, length) else {
+                    return Some("E14".into());
+                };
+
+                encode_hex(&mut output, &data);
+            }
+            _ if packet.starts_with(b"M") || packet.starts_with(b"X") => {
+                let Some(index) = packet.iter().position(|&byte| byte == b':') else {
+                    return Some("E22".into());
+                };
+
+                let Some((address, length)) = parse_memory_range(&packet[1..index]) else {
+                    return Some("E22".into());
+                };
+
+                let data = if packet[0] == b'M' {
+                    decode_hex(&packet[index + 1..])
+                } else {
+                    Some(packet[index + 1..].to_vec())
+                };
+
+                let Some(data) = data.filter(|data| data.len() == cast(length).to_usize()) else {
+                    return Some("E22".into());
+                };
+
+                if !data.is_empty() && self.instance.write_memory(address, &data).is_err() {
+                    return Some("E14".into());
+                }
+
+                output.push_str("OK");
+            }
+            _ if packet.starts_with(b"Z0,") || packet.starts_with(b"Z1,") || packet.starts_with(b"z0,") || packet.starts_with(b"z1,") => {
+                let Some((address, _kind)) = parse_memory_range(&packet[3..]) else {
+                    return Some("E22".into());
+                };
+
+                if packet[0] == b'Z' {
+                    self.add_breakpoint(ProgramCounter(address));
+                } else {
+                    self.remove_breakpoint(ProgramCounter(address));
+                }
+
+                output.push_str("OK");
+            }
+            _ if packet.len() > 3 && matches!(packet[0], b'Z' | b'z') && matches!(packet[1], b'2' | b'3' | b'4') && packet[2] == b',' => {
+                let Some((address, length)) = parse_memory_range(&packet[3..]) else {
+                    return Some("E22".into());
+                };
+
+                let kind = match packet[1] {
+                    b'2' => WatchpointKind::Write,
+                    b'3' => WatchpointKind::Read,
+                    _ => WatchpointKind::ReadWrite,
+                };
+
+                if packet[0] == b'Z' {
+                    if self.instance.add_watchpoint(address, length, kind).is_err() {
+                        // Not supported by this backend; GDB will fall back to software watchpoints.
+                        return Some(output);
+                    }
+                } else {
+                    self.instance.remove_watchpoint(address, length, kind);
+                }
+
+                output.push_str("OK");
+            }
+            _ if packet.starts_with(b"c") || packet.starts_with(b"s") || packet.starts_with(b"C") || packet.starts_with(b"S") => {
+                let single_step = matches!(packet[0], b's' | b'S');
+                let address = if matches!(packet[0], b'c' | b's') {
+                    &packet[1..]
+                } else {
+                    // Skip the signal number; we never deliver signals to the guest.
+                    packet
+                        .iter()
+                        .position(|&byte| byte == b';')
+                        .map_or(&[][..], |index| &packet[index + 1..])
+                };
+
+                if !address.is_empty() {
+                    let Some(address) = parse_hex_u32(address) else {
+                        return Some("E22".into());
+                    };
+
+                    self.write_register(PC_REGNUM, u64::from(address));
+                }
+
+                output = self.stop_after_resume(single_step, poll_interrupt_request);
+            }
+            _ if packet.starts_with(b"vCont;") => {
+                // We only have a single thread, so the first action is the only relevant one.
+                let action = packet[6..].split(|&byte| byte == b';').next().unwrap_or(&[]);
+                let single_step = match action.first() {
+                    Some(b'c' | b'C') => false,
+                    Some(b's' | b'S') => true,
+                    _ => return Some("E22".into()),
+                };
+
+                output = self.stop_after_resume(single_step, poll_interrupt_request);
+            }
+            _ => {}
+        }
+
+        Some(output)
+    }
+
+    fn stop_after_resume(&mut self, single_step: bool, poll_interrupt_request: &mut dyn FnMut() -> bool) -> String {
+        self.last_stop = self.resume(single_step, poll_interrupt_request);
+        self.last_stop.reply()
+    }
+
+    /// Serves a single debugger session over the given stream.
+    ///
+    /// Returns once the debugger detaches, kills the program, or closes the connection.
+    ///
+    /// While the program is running the stream is periodically checked for an interrupt request,
+    /// which is what the debugger sends on e.g. Ctrl-C.
+    pub fn serve<S>(&mut self, stream: S) -> Result<(), Error>
+    where
+        S: DebuggerStream,
+    {
+        let mut connection = Connection {
+            stream,
+            buffer: Vec::new(),
+            position: 0,
+            no_ack_mode: false,
+        };
+
+        loop {
+            let packet = match connection.read_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok(()),
+                Err(error) => bail!("GDB stub: failed to read a packet: {error}"),
+            };
+
+            log::trace!("GDB stub: received: {}", String::from_utf8_lossy(&packet));
+            let mut no_ack_mode = connection.no_ack_mode;
+            let mut poll_interrupt_request = || {
+                connection.poll_interrupt_request().unwrap_or_else(|error| {
+                    log::warn!("GDB stub: failed to check for an interrupt request: {error}");
+                    false
+                })
+            };
+
+            let Some(reply) = self.handle_packet(&packet, &mut no_ack_mode, &mut poll_interrupt_request) else {
+                return Ok(());
+            };
+
+            log::trace!("GDB stub: sending: {}", reply);
+            if let Err(error) = connection.write_packet(reply.as_bytes()) {
+                bail!("GDB stub: failed to send a packet: {error}");
+            }
+
+            connection.no_ack_mode = no_ack_mode;
+            if packet.starts_with(b"D") {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Listens on the given TCP address and serves a single debugger session.
+    pub fn serve_tcp(&mut self, address: impl std::net::ToSocketAddrs) -> Result<(), Error> {
+        let listener =
+            std::net::TcpListener::bind(address).map_err(|error| Error::from(format!("failed to bind a TCP socket: {error}")))?;
+        let (stream, peer) = listener
+            .accept()
+            .map_err(|error| Error::from(format!("failed to accept a connection: {error}")))?;
+
+        log::info!("GDB stub: debugger connected from {peer}");
+        let _ = stream.set_nodelay(true);
+        self.serve(stream)
+    }
+
+    /// Listens on the given Unix socket path and serves a single debugger session.
+    #[cfg(unix)]
+    pub fn serve_unix(&mut self, path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+        let listener = std::os::unix::net::UnixListener::bind(path.as_ref())
+            .map_err(|error| Error::from(format!("failed to bind a Unix socket: {error}")))?;
+        let (stream, _) = listener
+            .accept()
+            .map_err(|error| Error::from(format!("failed to accept a connection: {error}")))?;
+
+        log::info!("GDB stub: debugger connected");
+        self.serve(stream)
+    }
+}
@@ -0,0 +1,101 @@
+//! Resolution of program counters into function frames through the debug info embedded in the program blob.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use polkavm_common::program::{FrameKind, ProgramCounter};
+
+use crate::Module;
+
+#[cfg(feature = "std")]
+const UNKNOWN_FUNCTION: &str = "[unknown]";
+
+/// A single function frame, possibly inlined.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Frame {
+    /// The kind of the frame.
+    pub kind: FrameKind,
+    /// The name of the function.
+    pub function: String,
+    /// The path to the source file, if known.
+    pub path: Option<String>,
+    /// The source line, if known.
+    pub line: Option<u32>,
+    /// The source column, if known.
+    pub column: Option<u32>,
+}
+
+impl Frame {
+    /// Returns a single line description of this frame, as used in the debug logs.
+    pub(crate) fn describe(&self) -> String {
+        let kind = match self.kind {
+            FrameKind::Enter => 'f',
+            FrameKind::Call => 'c',
+            FrameKind::Line => 'l',
+        };
+
+        let location = match (&self.path, self.line, self.column) {
+            (Some(path), Some(line), Some(column)) => format!(" [{path}:{line}:{column}]"),
+            (Some(path), Some(line), None) => format!(" [{path}:{line}]"),
+            (Some(path), None, _) => format!(" [{path}]"),
+            (None, ..) => String::new(),
+        };
+
+        format!("({kind}) '{}'{location}", self.function)
+    }
+}
+
+/// Resolves the function frames for a given program counter, starting with the outermost one.
+///
+/// Returns `None` if the program has no debug info for the given program counter.
+pub(crate) fn location_frames(module: &Module, pc: ProgramCounter) -> Option<Vec<Frame>> {
+    let Ok(Some(mut line_program)) = module.blob().get_debug_line_program_at(pc) else {
+        return None;
+    };
+
+    let mut frames = Vec::new();
+    for _ in 0..128 {
+        // Have an upper bound on the number of iterations, just in case.
+        let Ok(Some(region_info)) = line_program.run() else { break };
+        if !region_info.instruction_range().contains(&pc) {
+            continue;
+        }
+
+        for frame in region_info.frames() {
+            let Ok(name) = frame.full_name() else { continue };
+            let location = frame.location().ok().flatten();
+            frames.push(Frame {
+                kind: frame.kind(),
+                function: format!("{name}"),
+                path: location.as_ref().map(|location| location.path().into()),
+                line: location.as_ref().and_then(|location| location.line()),
+                column: location.as_ref().and_then(|location| location.column()),
+            });
+        }
+
+        break;
+    }
+
+    Some(frames)
+}
+
+/// Resolves the function frames for a given program counter, starting with the outermost one.
+///
+/// Always returns at least one frame; if there's no debug info available the function's name will be `[unknown]`.
+#[cfg(feature = "std")]
+pub(crate) fn resolve_frames(module: &Module, pc: ProgramCounter) -> Vec<Frame> {
+    let mut frames = location_frames(module, pc).unwrap_or_default();
+    if frames.is_empty() {
+        frames.push(Frame {
+            kind: FrameKind::Enter,
+            function: UNKNOWN_FUNCTION.into(),
+            path: None,
+            line: None,
+            column: None,
+        });
+    }
+
+    frames
+}
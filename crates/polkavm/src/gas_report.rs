@@ -0,0 +1,168 @@
+//! Gas usage reports.
+//!
+//! A [`GasReport`] splits a module into its basic blocks and calculates the gas cost of each of them
+//! with the module's cost model, which is then attributed to functions and source lines through
+//! the debug info embedded in the program blob.
+//!
+//! This gives a static picture of where the gas could go. For a dynamic picture the report can be fed every
+//! interruption of an instance created from a module with [`ModuleConfig::set_step_tracing`](crate::ModuleConfig::set_step_tracing)
+//! enabled, in which case it will also count how many times each basic block was executed.
+//!
+//! The whole cost of a basic block is attributed to the location of its first instruction.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use std::collections::HashMap;
+
+use polkavm_common::program::ProgramCounter;
+
+use crate::basic_blocks::{basic_blocks, BlockCounter};
+use crate::frames::{resolve_frames, Frame};
+use crate::{Gas, InterruptKind, Module, RawInstance};
+
+/// A single basic block.
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+    start: ProgramCounter,
+    end: ProgramCounter,
+    cost: Gas,
+    executions: u64,
+    frames: Vec<Frame>,
+}
+
+impl BasicBlock {
+    /// Returns the program counter of the first instruction of this block.
+    pub fn start(&self) -> ProgramCounter {
+        self.start
+    }
+
+    /// Returns the program counter right after the last instruction of this block.
+    pub fn end(&self) -> ProgramCounter {
+        self.end
+    }
+
+    /// Returns the gas cost of a single execution of this block.
+    pub fn cost(&self) -> Gas {
+        self.cost
+    }
+
+    /// Returns how many times this block was executed.
+    pub fn executions(&self) -> u64 {
+        self.executions
+    }
+
+    /// Returns the total amount of gas consumed by this block, which is its cost multiplied by its execution count.
+    pub fn dynamic_cost(&self) -> Gas {
+        self.cost.saturating_mul(Gas::try_from(self.executions).unwrap_or(Gas::MAX))
+    }
+
+    /// Returns the function frames of the first instruction of this block, starting with the outermost one.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+/// The gas usage aggregated over a group of basic blocks, e.g. a function or a source line.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct GasReportEntry {
+    /// The name of the group.
+    pub name: String,
+    /// The number of basic blocks in this group.
+    pub blocks: usize,
+    /// The sum of the costs of every basic block in this group.
+    pub static_cost: Gas,
+    /// How many times the basic blocks in this group were executed.
+    pub executions: u64,
+    /// The total amount of gas consumed by the basic blocks in this group.
+    pub dynamic_cost: Gas,
+}
+
+/// A report of the gas costs of a module.
+pub struct GasReport {
+    blocks: Vec<BasicBlock>,
+    block_counter: BlockCounter,
+}
+
+impl GasReport {
+    /// Creates a new report for the given module.
+    pub fn new(module: &Module) -> Self {
+        let blocks: Vec<_> = basic_blocks(module)
+            .into_iter()
+            .map(|range| BasicBlock {
+                start: range.start,
+                end: range.end,
+                cost: module.calculate_gas_cost_for(range.start).unwrap_or(0),
+                executions: 0,
+                frames: resolve_frames(module, range.start),
+            })
+            .collect();
+
+        let block_counter = BlockCounter::new(blocks.iter().map(|block| block.start));
+        GasReport { blocks, block_counter }
+    }
+
+    /// Records an interruption returned by [`RawInstance::run`], counting the executed basic blocks.
+    pub fn on_interrupt(&mut self, instance: &RawInstance, interrupt: &InterruptKind) {
+        if let Some(index) = self.block_counter.on_interrupt(instance, interrupt) {
+            self.blocks[index].executions += 1;
+        }
+    }
+
+    /// Returns every basic block of the module, in order.
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    /// Returns the total amount of gas consumed by every basic block.
+    pub fn total_dynamic_cost(&self) -> Gas {
+        self.blocks.iter().fold(0, |sum, block| sum.saturating_add(block.dynamic_cost()))
+    }
+
+    /// Returns the gas usage aggregated per function, with inlined functions counted separately.
+    ///
+    /// The entries are sorted by their dynamic cost, and then by their static cost.
+    pub fn by_function(&self) -> Vec<GasReportEntry> {
+        self.aggregate(|block| block.frames.last().map_or_else(String::new, |frame| frame.function.clone()))
+    }
+
+    /// Returns the gas usage aggregated per source line.
+    ///
+    /// The entries are sorted by their dynamic cost, and then by their static cost.
+    pub fn by_line(&self) -> Vec<GasReportEntry> {
+        self.aggregate(|block| match block.frames.last() {
+            Some(Frame {
+                path: Some(path),
+                line: Some(line),
+                ..
+            }) => format!("{path}:{line}"),
+            Some(Frame { path: Some(path), .. }) => path.clone(),
+            Some(frame) => frame.function.clone(),
+            None => String::new(),
+        })
+    }
+
+    fn aggregate(&self, key: impl Fn(&BasicBlock) -> String) -> Vec<GasReportEntry> {
+        let mut map: HashMap<String, GasReportEntry> = HashMap::new();
+        for block in &self.blocks {
+            let name = key(block);
+            let entry = map.entry(name.clone()).or_insert_with(|| GasReportEntry {
+                name,
+                blocks: 0,
+                static_cost: 0,
+                executions: 0,
+                dynamic_cost: 0,
+            });
+
+            entry.blocks += 1;
+            entry.static_cost = entry.static_cost.saturating_add(block.cost);
+            entry.executions += block.executions;
+            entry.dynamic_cost = entry.dynamic_cost.saturating_add(block.dynamic_cost());
+        }
+
+        let mut list: Vec<_> = map.into_values().collect();
+        list.sort_by(|lhs, rhs| (rhs.dynamic_cost, rhs.static_cost, &lhs.name).cmp(&(lhs.dynamic_cost, lhs.static_cost, &rhs.name)));
+        list
+    }
+}
@@ -4,11 +4,14 @@
 use crate::api::{MemoryAccessError, Module, RegValue};
 use crate::error::Error;
 use crate::gas::GasVisitor;
-use crate::utils::{FlatMap, GuestInit, InterruptKind, Segfault};
+use crate::utils::{
+    FlatMap, GuestInit, InterruptKind, InterruptState, LimitKind, MemoryDiagnostic, MemoryDiagnosticKind, Segfault, WatchpointKind,
+};
 use crate::{Gas, GasMeteringKind, ProgramCounter};
 use alloc::boxed::Box;
 use alloc::collections::btree_map::Entry;
 use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::mem::MaybeUninit;
 use core::num::NonZeroU32;
@@ -180,7 +183,7 @@ impl BasicMemory {
         memory_slice.get_mut(offset..offset_end)
     }
 
-    fn sbrk(&mut self, module: &Module, size: u32) -> Option<u32> {
+    fn sbrk(&mut self, module: &Module, max_heap_size: u32, size: u32) -> Option<u32> {
         let Some(new_heap_size) = self.heap_size.checked_add(size) else {
             log::trace!(
                 "sbrk: heap size overflow; ignoring request: heap_size={} + size={} > 0xffffffff",
@@ -190,11 +193,12 @@ impl BasicMemory {
             return None;
         };
         let memory_map = module.memory_map();
-        if new_heap_size > memory_map.max_heap_size() {
+        let max_heap_size = core::cmp::min(memory_map.max_heap_size(), max_heap_size);
+        if new_heap_size > max_heap_size {
             log::trace!(
                 "sbrk: new heap size is too large; ignoring request: {} > {}",
                 new_heap_size,
-                memory_map.max_heap_size()
+                max_heap_size
             );
             return None;
         }
@@ -247,6 +251,128 @@ impl core::ops::DerefMut for Page {
     }
 }
 
+struct Watchpoint {
+    start: u64,
+    end: u64,
+    kind: WatchpointKind,
+}
+
+/// The maximum number of diagnostics which will be kept by the memory sanitizer until they're taken.
+const MAX_MEMORY_DIAGNOSTICS: usize = 1024;
+
+#[derive(Default)]
+struct MemorySanitizer {
+    /// A bitmap of every byte which was written to, in chunks of 64 bytes.
+    initialized: BTreeMap<u32, u64>,
+    diagnostics: Vec<MemoryDiagnostic>,
+}
+
+impl MemorySanitizer {
+    fn reset(&mut self) {
+        self.initialized.clear();
+    }
+
+    fn mark_initialized(&mut self, address: u32, length: u32) {
+        let mut address = u64::from(address);
+        let end = address + u64::from(length);
+        while address < end {
+            let chunk = address >> 6;
+            let chunk_end = core::cmp::min((chunk + 1) << 6, end);
+            let mask = Self::mask(address, chunk_end);
+            *self.initialized.entry(cast(chunk).truncate_to_u32()).or_insert(0) |= mask;
+            address = chunk_end;
+        }
+    }
+
+    /// Returns the address of the first byte within `start..end` which was never written to.
+    fn find_uninitialized(&self, start: u64, end: u64) -> Option<u32> {
+        let mut address = start;
+        while address < end {
+            let chunk = address >> 6;
+            let chunk_end = core::cmp::min((chunk + 1) << 6, end);
+            let mask = Self::mask(address, chunk_end);
+            let initialized = self.initialized.get(&cast(chunk).truncate_to_u32()).copied().unwrap_or(0);
+            let uninitialized = !initialized & mask;
+            if uninitialized != 0 {
+                return Some(cast((chunk << 6) + u64::from(uninitialized.trailing_zeros())).truncate_to_u32());
+            }
+
+            address = chunk_end;
+        }
+
+        None
+    }
+
+    /// Returns a mask of the bits in the chunk which covers `start..end`.
+    fn mask(start: u64, end: u64) -> u64 {
+        let length = end - start;
+        if length == 64 {
+            u64::MAX
+        } else {
+            ((1 << length) - 1) << (start & 63)
+        }
+    }
+
+    fn check_access<const DEBUG: bool>(
+        &mut self,
+        module: &Module,
+        heap_size: u32,
+        program_counter: ProgramCounter,
+        address: u32,
+        length: u32,
+        is_write: bool,
+    ) {
+        let memory_map = module.memory_map();
+        let start = u64::from(address);
+        let end = start + u64::from(length);
+        let heap_base = u64::from(memory_map.heap_base());
+        let heap_top = heap_base + u64::from(heap_size);
+        let stack_low = u64::from(memory_map.stack_address_low());
+        let stack_high = u64::from(memory_map.stack_address_high());
+
+        let offending = if start < stack_low && heap_top < end {
+            Some((MemoryDiagnosticKind::UnallocatedAccess, core::cmp::max(start, heap_top)))
+        } else if !is_write {
+            // Everything outside of the heap and the stack is always initialized.
+            [heap_base..heap_top, stack_low..stack_high]
+                .into_iter()
+                .find_map(|range| self.find_uninitialized(core::cmp::max(start, range.start), core::cmp::min(end, range.end)))
+                .map(|address| (MemoryDiagnosticKind::UninitializedRead, u64::from(address)))
+        } else {
+            None
+        };
+
+        if is_write {
+            self.mark_initialized(address, length);
+        }
+
+        let Some((kind, offending_address)) = offending else {
+            return;
+        };
+
+        if DEBUG {
+            log::debug!("Memory sanitizer: {kind:?} of {length} bytes at 0x{address:x} (pc = {program_counter})");
+        }
+
+        if self.diagnostics.len() >= MAX_MEMORY_DIAGNOSTICS
+            || self
+                .diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.program_counter == program_counter && diagnostic.kind == kind)
+        {
+            return;
+        }
+
+        self.diagnostics.push(MemoryDiagnostic {
+            kind,
+            program_counter,
+            address: cast(offending_address).truncate_to_u32(),
+            length,
+            is_write,
+        });
+    }
+}
+
 pub(crate) struct DynamicMemory {
     pages: BTreeMap<u32, Page>,
 }
@@ -405,12 +531,21 @@ pub(crate) struct InterpretedInstance {
     next_program_counter_changed: bool,
     cycle_counter: u64,
     gas: i64,
+    basic_block_budget: i64,
+    #[cfg(feature = "std")]
+    deadline: Option<std::time::Instant>,
+    #[cfg(feature = "std")]
+    deadline_check_countdown: u32,
+    interrupt_state: Option<Arc<InterruptState>>,
+    max_heap_size: u32,
     compiled_offset_for_block: FlatMap<NonZeroU32>,
     compiled_handlers: Vec<Handler>,
     compiled_args: Vec<Args>,
     compiled_offset: u32,
     interrupt: InterruptKind,
     step_tracing: bool,
+    watchpoints: Vec<Watchpoint>,
+    memory_sanitizer: Option<Box<MemorySanitizer>>,
 }
 
 impl InterpretedInstance {
@@ -430,11 +565,24 @@ impl InterpretedInstance {
             next_program_counter_changed: true,
             cycle_counter: 0,
             gas: 0,
+            basic_block_budget: i64::MAX,
+            #[cfg(feature = "std")]
+            deadline: None,
+            #[cfg(feature = "std")]
+            deadline_check_countdown: 0,
+            interrupt_state: None,
+            max_heap_size: u32::MAX,
             compiled_offset: 0,
             interrupt: InterruptKind::Finished,
             step_tracing,
+            watchpoints: Vec::new(),
+            memory_sanitizer: None,
         };
 
+        if instance.module.is_memory_sanitizer_enabled() {
+            instance.memory_sanitizer = Some(Box::default());
+        }
+
         instance.initialize_module();
         instance
     }
@@ -467,6 +615,67 @@ impl InterpretedInstance {
         self.gas = gas;
     }
 
+    pub fn basic_block_budget(&self) -> i64 {
+        self.basic_block_budget
+    }
+
+    pub fn set_basic_block_budget(&mut self, budget: i64) {
+        self.basic_block_budget = budget;
+    }
+
+    #[cfg(feature = "std")]
+    pub fn set_deadline(&mut self, deadline: Option<std::time::Instant>) {
+        self.deadline = deadline;
+        self.deadline_check_countdown = 0;
+    }
+
+    pub fn set_interrupt_state(&mut self, state: Option<Arc<InterruptState>>) {
+        self.interrupt_state = state;
+    }
+
+    pub fn add_watchpoint(&mut self, address: u32, length: u32, kind: WatchpointKind) {
+        let start = u64::from(address);
+        self.watchpoints.push(Watchpoint {
+            start,
+            end: start + u64::from(length),
+            kind,
+        });
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u32, length: u32, kind: WatchpointKind) -> bool {
+        let start = u64::from(address);
+        let end = start + u64::from(length);
+        let Some(index) = self
+            .watchpoints
+            .iter()
+            .position(|watchpoint| watchpoint.start == start && watchpoint.end == end && watchpoint.kind == kind)
+        else {
+            return false;
+        };
+
+        self.watchpoints.remove(index);
+        true
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn take_memory_diagnostics(&mut self) -> Vec<MemoryDiagnostic> {
+        self.memory_sanitizer
+            .as_mut()
+            .map(|sanitizer| core::mem::take(&mut sanitizer.diagnostics))
+            .unwrap_or_default()
+    }
+
+    fn is_watched(&self, address: u32, length: u32, is_write: bool) -> bool {
+        let start = u64::from(address);
+        let end = start + u64::from(length);
+        self.watchpoints
+            .iter()
+            .any(|watchpoint| watchpoint.kind.matches(is_write) && start < watchpoint.end && watchpoint.start < end)
+    }
+
     pub fn program_counter(&self) -> Option<ProgramCounter> {
         if !self.program_counter_valid {
             None
@@ -515,6 +724,20 @@ impl InterpretedInstance {
         result.is_ok()
     }
 
+    pub fn count_mapped_pages(&self, address: u32, length: u32) -> u32 {
+        assert!(self.module.is_dynamic_paging());
+
+        let first_page_address = self.module.round_to_page_size_down(address);
+        let last_page_address = self.module.round_to_page_size_down(address + (length - 1));
+        let count = self.dynamic_memory.pages.range(first_page_address..=last_page_address).count();
+        cast(count).assert_always_fits_in_u32()
+    }
+
+    pub fn mapped_pages(&self) -> Vec<u32> {
+        assert!(self.module.is_dynamic_paging());
+        self.dynamic_memory.pages.keys().copied().collect()
+    }
+
     pub fn read_memory_into<'slice>(
         &self,
         address: u32,
@@ -564,6 +787,40 @@ impl InterpretedInstance {
         }
     }
 
+    pub fn memory_slice(&self, address: u32, length: u32) -> Option<&[u8]> {
+        if !self.module.is_dynamic_paging() {
+            self.basic_memory.get_memory_slice(&self.module, address, length)
+        } else {
+            let page_address = self.module.round_to_page_size_down(address);
+            if page_address != self.module.round_to_page_size_down(address + (length - 1)) {
+                return None;
+            }
+
+            let page = self.dynamic_memory.pages.get(&page_address)?;
+            let offset = cast(address - page_address).to_usize();
+            page.get(offset..offset + cast(length).to_usize())
+        }
+    }
+
+    pub fn memory_slice_mut(&mut self, address: u32, length: u32) -> Option<&mut [u8]> {
+        if !self.module.is_dynamic_paging() {
+            if let Some(ref mut sanitizer) = self.memory_sanitizer {
+                sanitizer.mark_initialized(address, length);
+            }
+
+            self.basic_memory.get_memory_slice_mut::<true>(&self.module, address, length)
+        } else {
+            let page_address = self.module.round_to_page_size_down(address);
+            if page_address != self.module.round_to_page_size_down(address + (length - 1)) {
+                return None;
+            }
+
+            let page = self.dynamic_memory.pages.get_mut(&page_address)?;
+            let offset = cast(address - page_address).to_usize();
+            page.get_mut(offset..offset + cast(length).to_usize())
+        }
+    }
+
     pub fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<(), MemoryAccessError> {
         if !self.module.is_dynamic_paging() {
             let Some(slice) =
@@ -577,6 +834,9 @@ impl InterpretedInstance {
             };
 
             slice.copy_from_slice(data);
+            if let Some(ref mut sanitizer) = self.memory_sanitizer {
+                sanitizer.mark_initialized(address, cast(data.len()).assert_always_fits_in_u32());
+            }
         } else {
             let dynamic_memory = &mut self.dynamic_memory;
             let page_size = self.module.memory_map().page_size();
@@ -606,6 +866,9 @@ impl InterpretedInstance {
             };
 
             slice.fill(0);
+            if let Some(ref mut sanitizer) = self.memory_sanitizer {
+                sanitizer.mark_initialized(address, length);
+            }
         } else {
             let dynamic_memory = &mut self.dynamic_memory;
             let page_size = self.module.memory_map().page_size();
@@ -678,9 +941,13 @@ impl InterpretedInstance {
         }
     }
 
+    pub fn set_max_heap_size(&mut self, size: u32) {
+        self.max_heap_size = size;
+    }
+
     pub fn sbrk(&mut self, size: u32) -> Option<u32> {
         if !self.module.is_dynamic_paging() {
-            self.basic_memory.sbrk(&self.module, size)
+            self.basic_memory.sbrk(&self.module, self.max_heap_size, size)
         } else {
             todo!()
         }
@@ -743,6 +1010,10 @@ impl InterpretedInstance {
     }
 
     pub fn reset_memory(&mut self) {
+        if let Some(ref mut sanitizer) = self.memory_sanitizer {
+            sanitizer.reset();
+        }
+
         if !self.module.is_dynamic_paging() {
             self.basic_memory.reset(&self.module);
         } else {
@@ -755,6 +1026,10 @@ impl InterpretedInstance {
             self.gas = 0;
         }
 
+        if let Some(ref mut sanitizer) = self.memory_sanitizer {
+            sanitizer.reset();
+        }
+
         if !self.module.is_dynamic_paging() {
             self.basic_memory.force_reset(&self.module);
         } else {
@@ -876,6 +1151,14 @@ impl InterpretedInstance {
                 emit!(self, step(instruction.offset));
             }
 
+            if self.module.has_execution_limits() && instruction.offset == program_counter {
+                if DEBUG {
+                    log::debug!("  [{}]: {}: check_limits", self.compiled_handlers.len(), instruction.offset);
+                }
+
+                emit!(self, check_limits(instruction.offset));
+            }
+
             if self.module.gas_metering().is_some() {
                 if charge_gas_index.is_none() {
                     if DEBUG {
@@ -1058,6 +1341,37 @@ impl<'a> Visitor<'a> {
         None
     }
 
+    #[cold]
+    fn watchpoint_impl<const DEBUG: bool>(&mut self, program_counter: ProgramCounter, address: u32, is_write: bool) -> Option<Target> {
+        if DEBUG {
+            log::debug!(
+                "Watchpoint triggered by a {} of 0x{address:x} (pc = {program_counter})",
+                if is_write { "write" } else { "read" }
+            );
+        }
+
+        // The access was already made, so resume right after the instruction which made it.
+        let next_offset = self
+            .inner
+            .module
+            .instructions_bounded_at(program_counter)
+            .next()
+            .unwrap()
+            .next_offset;
+        self.inner.program_counter = program_counter;
+        self.inner.program_counter_valid = true;
+        self.inner.next_program_counter = Some(next_offset);
+        self.inner.next_program_counter_changed = false;
+        self.inner.compiled_offset += 1;
+        self.inner.interrupt = InterruptKind::Watchpoint {
+            address,
+            is_write,
+            pc: program_counter,
+        };
+
+        None
+    }
+
     #[cold]
     fn segfault_or_trap_at_top_of_address_space<const DEBUG: bool>(&mut self, program_counter: ProgramCounter) -> Option<Target> {
         let page_address = self.inner.module.round_to_page_size_down(0xffffffff);
@@ -1095,7 +1409,13 @@ impl<'a> Visitor<'a> {
                 return trap_impl::<DEBUG>(self, program_counter);
             };
 
-            T::from_slice(slice)
+            let value = T::from_slice(slice);
+            if let Some(ref mut sanitizer) = self.inner.memory_sanitizer {
+                let heap_size = self.inner.basic_memory.heap_size();
+                sanitizer.check_access::<DEBUG>(&self.inner.module, heap_size, program_counter, address, length, false);
+            }
+
+            value
         } else {
             let Some(address_end) = address.checked_add(length) else {
                 return self.segfault_or_trap_at_top_of_address_space::<DEBUG>(program_counter);
@@ -1147,6 +1467,10 @@ impl<'a> Visitor<'a> {
         }
 
         self.set64::<false>(dst, value);
+        if !self.inner.watchpoints.is_empty() && self.inner.is_watched(address, length, false) {
+            return self.watchpoint_impl::<DEBUG>(program_counter, address, false);
+        }
+
         self.go_to_next_instruction()
     }
 
@@ -1203,6 +1527,10 @@ impl<'a> Visitor<'a> {
                 return trap_impl::<DEBUG>(self, program_counter);
             };
             slice.copy_from_slice(value.as_ref());
+            if let Some(ref mut sanitizer) = self.inner.memory_sanitizer {
+                let heap_size = self.inner.basic_memory.heap_size();
+                sanitizer.check_access::<DEBUG>(&self.inner.module, heap_size, program_counter, address, length, true);
+            }
         } else {
             let Some(address_end) = address.checked_add(length) else {
                 return self.segfault_or_trap_at_top_of_address_space::<DEBUG>(program_counter);
@@ -1270,6 +1598,10 @@ impl<'a> Visitor<'a> {
             }
         };
 
+        if !self.inner.watchpoints.is_empty() && self.inner.is_watched(address, length, true) {
+            return self.watchpoint_impl::<DEBUG>(program_counter, address, true);
+        }
+
         self.go_to_next_instruction()
     }
 
@@ -1861,26 +2193,41 @@ fn trap_impl<const DEBUG: bool>(visitor: &mut Visitor, program_counter: ProgramC
     None
 }
 
-fn not_enough_gas_impl<const DEBUG: bool>(visitor: &mut Visitor, program_counter: ProgramCounter, new_gas: i64) -> Option<Target> {
-    match visitor.inner.module.gas_metering().unwrap() {
-        GasMeteringKind::Async => {
-            visitor.inner.gas = new_gas;
-            visitor.inner.program_counter_valid = false;
-            visitor.inner.next_program_counter = None;
-            visitor.inner.next_program_counter_changed = true;
-        }
-        GasMeteringKind::Sync => {
-            visitor.inner.program_counter = program_counter;
-            visitor.inner.program_counter_valid = true;
-            visitor.inner.next_program_counter = Some(program_counter);
-            visitor.inner.next_program_counter_changed = false;
-        }
+fn not_enough_gas_impl<const DEBUG: bool>(visitor: &mut Visitor, program_counter: ProgramCounter) -> Option<Target> {
+    visitor.inner.program_counter = program_counter;
+    visitor.inner.program_counter_valid = true;
+    visitor.inner.next_program_counter = Some(program_counter);
+    visitor.inner.next_program_counter_changed = false;
+    visitor.inner.interrupt = InterruptKind::NotEnoughGas;
+    None
+}
+
+#[inline]
+fn is_gas_metering_sync(visitor: &Visitor) -> bool {
+    visitor.inner.module.gas_metering() == Some(GasMeteringKind::Sync)
+}
+
+fn limit_reached_impl<const DEBUG: bool>(
+    visitor: &mut Visitor,
+    program_counter: ProgramCounter,
+    interrupt: InterruptKind,
+) -> Option<Target> {
+    if DEBUG {
+        log::trace!("[{}]: execution limit: {interrupt:?}", visitor.inner.compiled_offset);
     }
 
-    visitor.inner.interrupt = InterruptKind::NotEnoughGas;
+    visitor.inner.program_counter = program_counter;
+    visitor.inner.program_counter_valid = true;
+    visitor.inner.next_program_counter = Some(program_counter);
+    visitor.inner.next_program_counter_changed = false;
+    visitor.inner.interrupt = interrupt;
     None
 }
 
+/// How many basic blocks are executed between checking whether the deadline has passed.
+#[cfg(feature = "std")]
+const DEADLINE_CHECK_INTERVAL: u32 = 4096;
+
 const TARGET_INVALID_BRANCH: Target = 0;
 const TARGET_OUT_OF_RANGE: Target = 1;
 
@@ -1907,14 +2254,41 @@ define_interpreter! {
             log::trace!("[{}]: charge_gas: {gas_cost} ({} -> {})", visitor.inner.compiled_offset, visitor.inner.gas, new_gas);
         }
 
-        if new_gas < 0 {
-            not_enough_gas_impl::<DEBUG>(visitor, program_counter, new_gas)
+        // With asynchronous gas metering we keep going and only report running out of gas at the next interruption.
+        if new_gas < 0 && is_gas_metering_sync(visitor) {
+            not_enough_gas_impl::<DEBUG>(visitor, program_counter)
         } else {
             visitor.inner.gas = new_gas;
             visitor.go_to_next_instruction()
         }
     }
 
+    fn check_limits<const DEBUG: bool>(visitor: &mut Visitor, program_counter: ProgramCounter) -> Option<Target> {
+        if visitor.inner.basic_block_budget <= 0 {
+            return limit_reached_impl::<DEBUG>(visitor, program_counter, InterruptKind::LimitReached(LimitKind::BasicBlocks));
+        }
+
+        if visitor.inner.interrupt_state.as_ref().is_some_and(|state| state.take_interrupt()) {
+            return limit_reached_impl::<DEBUG>(visitor, program_counter, InterruptKind::Interrupted);
+        }
+
+        #[cfg(feature = "std")]
+        if let Some(deadline) = visitor.inner.deadline {
+            if visitor.inner.deadline_check_countdown == 0 {
+                if std::time::Instant::now() >= deadline {
+                    return limit_reached_impl::<DEBUG>(visitor, program_counter, InterruptKind::LimitReached(LimitKind::Deadline));
+                }
+
+                visitor.inner.deadline_check_countdown = DEADLINE_CHECK_INTERVAL;
+            }
+
+            visitor.inner.deadline_check_countdown -= 1;
+        }
+
+        visitor.inner.basic_block_budget -= 1;
+        visitor.go_to_next_instruction()
+    }
+
     fn invalid_branch_target<const DEBUG: bool>(visitor: &mut Visitor) -> Option<Target> {
         if DEBUG {
             log::trace!("[{}]: trap (invalid branch)", visitor.inner.compiled_offset);
@@ -1931,8 +2305,8 @@ define_interpreter! {
 
         let program_counter = visitor.inner.program_counter;
         let new_gas = visitor.inner.gas - i64::from(gas);
-        if new_gas < 0 {
-            not_enough_gas_impl::<DEBUG>(visitor, program_counter, new_gas)
+        if new_gas < 0 && is_gas_metering_sync(visitor) {
+            not_enough_gas_impl::<DEBUG>(visitor, program_counter)
         } else {
             log::debug!("Trap at {}: out of range", program_counter);
 
@@ -1998,6 +2372,7 @@ define_interpreter! {
         }
 
         let gas_metering_enabled = visitor.inner.module.gas_metering().is_some();
+        let is_gas_metering_sync = is_gas_metering_sync(visitor);
 
         // TODO: This is very inefficient.
         let next_instruction = visitor.go_to_next_instruction();
@@ -2007,8 +2382,8 @@ define_interpreter! {
         let mut dst = visitor.get32(Reg::A0);
         let mut count = visitor.get64(Reg::A2);
         while count > 0 {
-            if gas_metering_enabled && visitor.inner.gas == 0 {
-                result = not_enough_gas_impl::<DEBUG>(visitor, program_counter, 0);
+            if is_gas_metering_sync && visitor.inner.gas == 0 {
+                result = not_enough_gas_impl::<DEBUG>(visitor, program_counter);
                 break;
             }
 
@@ -2018,6 +2393,20 @@ define_interpreter! {
                 result = visitor.store::<u8, DEBUG, false>(program_counter, value, None, dst);
             }
             if result != next_instruction {
+                if let InterruptKind::Watchpoint { .. } = visitor.inner.interrupt {
+                    // The byte was written, so account for it, and if there's anything left then resume at this instruction.
+                    if gas_metering_enabled {
+                        visitor.inner.gas -= 1;
+                    }
+
+                    dst += 1;
+                    count -= 1;
+                    if count > 0 {
+                        visitor.inner.compiled_offset -= 1;
+                        visitor.inner.next_program_counter = Some(program_counter);
+                    }
+                }
+
                 break;
             }
 
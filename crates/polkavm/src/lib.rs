@@ -55,12 +55,28 @@ extern crate alloc;
 mod error;
 
 mod api;
+#[cfg(feature = "std")]
+pub mod backtrace;
+#[cfg(feature = "std")]
+mod basic_blocks;
 mod config;
+#[cfg(feature = "std")]
+pub mod coverage;
+#[cfg(feature = "std")]
+pub mod debugger;
+mod frames;
 mod gas;
+#[cfg(feature = "std")]
+pub mod gas_report;
 mod interpreter;
 mod linker;
 #[cfg(feature = "std")]
+pub mod profiler;
+mod snapshot;
+#[cfg(feature = "std")]
 mod source_cache;
+#[cfg(feature = "std")]
+pub mod trace;
 mod utils;
 
 #[cfg(feature = "std")]
@@ -88,6 +104,7 @@ where
 mod module_cache;
 
 if_compiler_is_supported! {
+    mod code_cache;
     mod compiler;
     mod page_set;
     mod sandbox;
@@ -106,6 +123,8 @@ if_compiler_is_supported! {
 #[cfg(rustfmt)]
 mod bit_mask;
 #[cfg(rustfmt)]
+mod code_cache;
+#[cfg(rustfmt)]
 mod compiler;
 #[cfg(rustfmt)]
 mod generic_allocator;
@@ -138,12 +157,20 @@ pub mod program {
 
 pub type Gas = i64;
 
-pub use crate::api::{Engine, MemoryAccessError, Module, RawInstance, RegValue};
-pub use crate::config::{BackendKind, Config, CustomCodegen, GasMeteringKind, ModuleConfig, SandboxKind};
+pub use crate::api::{Engine, MemoryAccessError, MemorySliceMut, Module, RawInstance, RegValue};
+pub use crate::config::{BackendKind, Config, CustomCodegen, GasMeteringKind, InstanceLimits, ModuleConfig, SandboxKind};
 pub use crate::error::Error;
 pub use crate::gas::{Cost, CostModel, CostModelRef};
-pub use crate::linker::{CallError, Caller, Instance, InstancePre, Linker};
-pub use crate::utils::{InterruptKind, Segfault};
+pub use crate::linker::{
+    CallError, Caller, GuestPanic, HostFuture, Instance, InstancePre, Linker, NotEnoughGasError, TypedHostFuture, GUEST_PANIC_IMPORT,
+};
+pub use crate::snapshot::Snapshot;
+pub use crate::utils::{
+    InterruptHandle, InterruptKind, LimitKind, MemoryDiagnostic, MemoryDiagnosticKind, MemoryUsage, Segfault, WatchpointKind,
+};
+
+#[cfg(target_endian = "little")]
+pub use crate::utils::Pod;
 
 pub const RETURN_TO_HOST: u64 = polkavm_common::abi::VM_ADDR_RETURN_TO_HOST as u64;
 
@@ -1,13 +1,16 @@
 use crate::api::RegValue;
 use crate::error::bail;
 use crate::program::ProgramSymbol;
-use crate::{Error, InterruptKind, Module, ProgramCounter, RawInstance, Reg};
+use crate::{Error, Gas, InterruptKind, LimitKind, MemoryAccessError, Module, ProgramCounter, RawInstance, Reg, Segfault};
 use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
 use alloc::format;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::future::Future;
 use core::marker::PhantomData;
+use core::pin::Pin;
 
 #[cfg(not(feature = "std"))]
 use alloc::collections::btree_map::Entry;
@@ -28,6 +31,35 @@ pub struct CallFnArc<UserData, UserError>(Arc<dyn CallFn<UserData, UserError>>);
 
 type FallbackHandlerArc<UserData, UserError> = Arc<dyn Fn(Caller<UserData>, u32) -> Result<(), UserError> + Send + Sync + 'static>;
 
+/// The future returned by asynchronous host functions.
+pub type HostFuture<'a, UserError> = Pin<Box<dyn Future<Output = Result<(), UserError>> + Send + 'a>>;
+
+/// The future returned by statically typed asynchronous host functions.
+pub type TypedHostFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+type AsyncCallFnArc<UserData, UserError> = Arc<dyn for<'a> Fn(Caller<'a, UserData>) -> HostFuture<'a, UserError> + Send + Sync + 'static>;
+
+fn async_call_fn<UserData, UserError, F>(func: F) -> AsyncCallFnArc<UserData, UserError>
+where
+    F: for<'a> Fn(Caller<'a, UserData>) -> HostFuture<'a, UserError> + Send + Sync + 'static,
+{
+    Arc::new(func)
+}
+
+enum HostFn<UserData, UserError> {
+    Sync(CallFnArc<UserData, UserError>),
+    Async(AsyncCallFnArc<UserData, UserError>),
+}
+
+impl<UserData, UserError> Clone for HostFn<UserData, UserError> {
+    fn clone(&self) -> Self {
+        match self {
+            HostFn::Sync(host_fn) => HostFn::Sync(host_fn.clone()),
+            HostFn::Async(host_fn) => HostFn::Async(Arc::clone(host_fn)),
+        }
+    }
+}
+
 impl<UserData, UserError> Clone for CallFnArc<UserData, UserError> {
     fn clone(&self) -> Self {
         Self(Arc::clone(&self.0))
@@ -45,6 +77,11 @@ pub trait IntoCallFn<UserData, UserError, Params, Result>: Send + Sync + 'static
     fn _into_extern_fn(self) -> CallFnArc<UserData, UserError>;
 }
 
+pub trait IntoAsyncCallFn<UserData, UserError, Params, Result>: Send + Sync + 'static {
+    #[doc(hidden)]
+    fn _into_async_extern_fn(self) -> AsyncCallFnArc<UserData, UserError>;
+}
+
 /// A type which can be marshalled through the VM's FFI boundary.
 pub trait AbiTy: Sized + Send + 'static {
     #[doc(hidden)]
@@ -530,6 +567,49 @@ macro_rules! impl_into_extern_fn {
             }
         }
 
+        impl<UserData, UserError, F, $($args,)* R> IntoAsyncCallFn<UserData, UserError, (Caller<'_, UserData>, $($args,)*), R> for F
+        where
+            F: for<'a> Fn(Caller<'a, UserData>, $($args),*) -> TypedHostFuture<'a, R> + Send + Sync + 'static,
+            UserData: Send,
+            $($args: AbiTy,)*
+            R: ReturnTy<UserError> + Send,
+        {
+            fn _into_async_extern_fn(self) -> AsyncCallFnArc<UserData, UserError> {
+                let func = Arc::new(self);
+                async_call_fn(move |caller: Caller<'_, UserData>| {
+                    let func = Arc::clone(&func);
+                    let is_64_bit = caller.instance.module().blob().is_64_bit();
+
+                    // The arguments are read before the handler is called, exactly like for synchronous handlers.
+                    #[allow(unused_mut)]
+                    let mut caller = caller;
+                    #[allow(non_snake_case)]
+                    let take_args = |caller, $($args),*| (caller, ($($args,)*));
+                    #[allow(non_snake_case)]
+                    let (caller, ($($args,)*)) = impl_into_extern_fn!(@call is_64_bit, caller, take_args, $($args),*);
+
+                    Box::pin(async move {
+                        let Caller { user_data, instance } = caller;
+                        let result = (*func)(Caller { user_data, instance: &mut *instance }, $($args),*).await;
+                        let set_reg = {
+                            let mut reg_index = 0;
+                            move |value: RegValue| {
+                                let reg = Reg::ARG_REGS[reg_index];
+                                instance.set_reg(reg, value);
+                                reg_index += 1;
+                            }
+                        };
+
+                        if is_64_bit {
+                            result._handle_return64(set_reg)
+                        } else {
+                            result._handle_return32(set_reg)
+                        }
+                    })
+                })
+            }
+        }
+
         impl<$($args: Send + AbiTy,)*> FuncArgs for ($($args,)*) {
             const _REGS_REQUIRED_32: usize = 0 $(+ $args::_REGS_REQUIRED_32)*;
             const _REGS_REQUIRED_64: usize = 0 $(+ $args::_REGS_REQUIRED_64)*;
@@ -587,14 +667,78 @@ where
     }
 }
 
+struct GasMeteredFn<UserData, UserError> {
+    gas_cost: u64,
+    inner: CallFnArc<UserData, UserError>,
+}
+
+impl<UserData, UserError> CallFn<UserData, UserError> for GasMeteredFn<UserData, UserError> {
+    fn call(&self, user_data: &mut UserData, instance: &mut RawInstance) -> Result<(), UserError> {
+        let mut caller = Caller { user_data, instance };
+        if caller.charge_gas(self.gas_cost).is_err() {
+            return Ok(());
+        }
+
+        self.inner.0.call(caller.user_data, caller.instance)
+    }
+}
+
+/// The error returned by [`Caller::charge_gas`] when there's not enough gas left.
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct NotEnoughGasError;
+
+impl core::fmt::Display for NotEnoughGasError {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        fmt.write_str("not enough gas")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for NotEnoughGasError {}
+
 #[non_exhaustive]
 pub struct Caller<'a, UserData = ()> {
     pub user_data: &'a mut UserData,
     pub instance: &'a mut RawInstance,
 }
 
+impl<'a, UserData> Caller<'a, UserData> {
+    /// Charges the given amount of gas from the instance.
+    ///
+    /// If there's not enough gas left then the remaining gas will become negative and an error is returned.
+    /// Once the host function returns the execution will not be resumed and the call will fail with
+    /// [`CallError::NotEnoughGas`], regardless of what the host function returned.
+    ///
+    /// Does nothing if gas metering is disabled.
+    pub fn charge_gas(&mut self, amount: u64) -> Result<(), NotEnoughGasError> {
+        if self.instance.module().gas_metering().is_none() {
+            return Ok(());
+        }
+
+        let amount = Gas::try_from(amount).unwrap_or(Gas::MAX);
+        let gas = self.instance.gas();
+        self.instance.set_gas(gas.saturating_sub(amount));
+        if amount > gas {
+            log::trace!("Not enough gas to charge for a host call: {gas} < {amount}");
+            return Err(NotEnoughGasError);
+        }
+
+        Ok(())
+    }
+}
+
+/// The symbol of the import through which guest programs report panics.
+///
+/// This is the import called by `polkavm_derive::report_panic`. Unless a host function with this symbol is explicitly defined
+/// it's handled by the [`Linker`] itself, and calling it will abort the execution with [`CallError::GuestPanic`].
+pub const GUEST_PANIC_IMPORT: &str = "polkavm_guest_panic";
+
+/// The maximum length of a string which will be read from the guest's memory when it panics.
+const GUEST_PANIC_MAX_STRING_LENGTH: u32 = 4096;
+
 pub struct Linker<UserData = (), UserError = core::convert::Infallible> {
-    host_functions: LookupMap<Vec<u8>, CallFnArc<UserData, UserError>>,
+    host_functions: LookupMap<Vec<u8>, HostFn<UserData, UserError>>,
     #[allow(clippy::type_complexity)]
     fallback_handler: Option<FallbackHandlerArc<UserData, UserError>>,
     phantom: PhantomData<(UserData, UserError)>,
@@ -629,23 +773,37 @@ impl<UserData, UserError> Linker<UserData, UserError> {
     where
         UserData: 'static,
     {
-        let symbol = symbol.as_ref();
-        if self.host_functions.contains_key(symbol) {
-            bail!(
-                "cannot register host function: host function was already registered: {}",
-                ProgramSymbol::new(symbol)
-            );
-        }
-
-        self.host_functions.insert(
-            symbol.to_owned(),
-            CallFnArc(Arc::new(DynamicFn {
+        self.insert_host_function(
+            symbol.as_ref(),
+            HostFn::Sync(CallFnArc(Arc::new(DynamicFn {
                 callback: func,
                 _phantom: UnsafePhantomData(PhantomData),
-            })),
-        );
+            }))),
+        )
+    }
 
-        Ok(self)
+    /// Defines a new untyped asynchronous handler for external calls with a given symbol.
+    ///
+    /// Such a handler can only be called through [`Instance::call_typed_async`]; when the guest calls it
+    /// the execution is suspended until the returned future completes.
+    pub fn define_async(
+        &mut self,
+        symbol: impl AsRef<[u8]>,
+        func: impl for<'a> Fn(Caller<'a, UserData>) -> HostFuture<'a, UserError> + Send + Sync + 'static,
+    ) -> Result<&mut Self, Error> {
+        self.insert_host_function(symbol.as_ref(), HostFn::Async(Arc::new(func)))
+    }
+
+    /// Defines a new statically typed asynchronous handler for external calls with a given symbol.
+    ///
+    /// The arguments and the return value are marshalled exactly as with [`Linker::define_typed`], but like with
+    /// [`Linker::define_async`] the handler can only be called through [`Instance::call_typed_async`].
+    pub fn define_typed_async<Params, Args>(
+        &mut self,
+        symbol: impl AsRef<[u8]>,
+        func: impl IntoAsyncCallFn<UserData, UserError, Params, Args>,
+    ) -> Result<&mut Self, Error> {
+        self.insert_host_function(symbol.as_ref(), HostFn::Async(func._into_async_extern_fn()))
     }
 
     /// Defines a new statically typed handler for external calls with a given symbol.
@@ -654,7 +812,35 @@ impl<UserData, UserError> Linker<UserData, UserError> {
         symbol: impl AsRef<[u8]>,
         func: impl IntoCallFn<UserData, UserError, Params, Args>,
     ) -> Result<&mut Self, Error> {
-        let symbol = symbol.as_ref();
+        self.insert_host_function(symbol.as_ref(), HostFn::Sync(func._into_extern_fn()))
+    }
+
+    /// Defines a new statically typed handler for external calls with a given symbol, which costs a fixed amount of gas to call.
+    ///
+    /// The gas is charged before the handler is called; if there's not enough gas then the handler is not called
+    /// and the call fails with [`CallError::NotEnoughGas`].
+    ///
+    /// Additional gas can be charged from within the handler with [`Caller::charge_gas`].
+    pub fn define_typed_with_gas_cost<Params, Args>(
+        &mut self,
+        symbol: impl AsRef<[u8]>,
+        gas_cost: u64,
+        func: impl IntoCallFn<UserData, UserError, Params, Args>,
+    ) -> Result<&mut Self, Error>
+    where
+        UserData: 'static,
+        UserError: 'static,
+    {
+        self.insert_host_function(
+            symbol.as_ref(),
+            HostFn::Sync(CallFnArc(Arc::new(GasMeteredFn {
+                gas_cost,
+                inner: func._into_extern_fn(),
+            }))),
+        )
+    }
+
+    fn insert_host_function(&mut self, symbol: &[u8], host_fn: HostFn<UserData, UserError>) -> Result<&mut Self, Error> {
         if self.host_functions.contains_key(symbol) {
             bail!(
                 "cannot register host function: host function was already registered: {}",
@@ -662,7 +848,7 @@ impl<UserData, UserError> Linker<UserData, UserError> {
             );
         }
 
-        self.host_functions.insert(symbol.to_owned(), func._into_extern_fn());
+        self.host_functions.insert(symbol.to_owned(), host_fn);
         Ok(self)
     }
 
@@ -685,7 +871,8 @@ impl<UserData, UserError> Linker<UserData, UserError> {
             }
         }
 
-        let mut imports: Vec<Option<CallFnArc<UserData, UserError>>> = Vec::with_capacity(module.imports().len() as usize);
+        let mut imports: Vec<Option<HostFn<UserData, UserError>>> = Vec::with_capacity(module.imports().len() as usize);
+        let mut guest_panic_import = None;
         for symbol in module.imports() {
             let Some(symbol) = symbol else {
                 if module.is_strict() {
@@ -698,6 +885,9 @@ impl<UserData, UserError> Linker<UserData, UserError> {
 
             let host_fn = if let Some(host_fn) = self.host_functions.get(symbol.as_bytes()) {
                 Some(host_fn.clone())
+            } else if symbol.as_bytes() == GUEST_PANIC_IMPORT.as_bytes() {
+                guest_panic_import = Some(imports.len() as u32);
+                None
             } else if self.fallback_handler.is_some() {
                 None
             } else if module.is_strict() {
@@ -716,15 +906,17 @@ impl<UserData, UserError> Linker<UserData, UserError> {
             imports,
             exports,
             fallback_handler: self.fallback_handler.clone(),
+            guest_panic_import,
         })))
     }
 }
 
 struct InstancePreState<UserData, UserError> {
     module: Module,
-    imports: Vec<Option<CallFnArc<UserData, UserError>>>,
+    imports: Vec<Option<HostFn<UserData, UserError>>>,
     exports: LookupMap<Vec<u8>, ProgramCounter>,
     fallback_handler: Option<FallbackHandlerArc<UserData, UserError>>,
+    guest_panic_import: Option<u32>,
 }
 
 pub struct InstancePre<UserData = (), UserError = core::convert::Infallible>(Arc<InstancePreState<UserData, UserError>>);
@@ -759,8 +951,20 @@ pub enum CallError<UserError = core::convert::Infallible> {
     Trap,
 
     /// The execution ran out of gas.
+    ///
+    /// This is also returned when a host function didn't have enough gas to pay for its own execution;
+    /// see [`Linker::define_typed_with_gas_cost`] and [`Caller::charge_gas`].
     NotEnoughGas,
 
+    /// One of the limits set with [`RawInstance::set_limits`] was reached.
+    LimitReached(LimitKind),
+
+    /// The execution was interrupted through an [`InterruptHandle`](crate::InterruptHandle).
+    Interrupted,
+
+    /// The guest program panicked and reported it through the [`GUEST_PANIC_IMPORT`] host call.
+    GuestPanic(GuestPanic),
+
     /// The execution failed.
     Error(Error),
 
@@ -768,6 +972,34 @@ pub enum CallError<UserError = core::convert::Infallible> {
     User(UserError),
 }
 
+/// The details of a panic reported by a guest program.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct GuestPanic {
+    /// The panic message.
+    pub message: String,
+
+    /// The path of the source file in which the panic happened.
+    pub file: String,
+
+    /// The line at which the panic happened.
+    pub line: u32,
+
+    /// The column at which the panic happened.
+    pub column: u32,
+}
+
+impl core::fmt::Display for GuestPanic {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(fmt, "guest panicked at {}:{}:{}", self.file, self.line, self.column)?;
+        if !self.message.is_empty() {
+            write!(fmt, ": {}", self.message)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl<UserData, UserError> InstancePre<UserData, UserError> {
     pub fn instantiate(&self) -> Result<Instance<UserData, UserError>, Error> {
         Ok(Instance {
@@ -811,6 +1043,64 @@ impl<UserData, UserError> Instance<UserData, UserError> {
         entry_point: impl EntryPoint,
         args: FnArgs,
     ) -> Result<(), CallError<UserError>>
+    where
+        FnArgs: FuncArgs,
+    {
+        self.prepare_call(entry_point, args)?;
+        while let Some(hostcall) = self.run_until_host_call()? {
+            let result = match self.pre.0.imports.get(hostcall as usize).and_then(|host_fn| host_fn.as_ref()) {
+                Some(HostFn::Sync(host_fn)) => host_fn.0.call(user_data, &mut self.instance),
+                Some(HostFn::Async(_)) => {
+                    return Err(CallError::Error(Error::from_display(format!(
+                        "host function with ID = {hostcall} is asynchronous and can only be called through 'call_typed_async'"
+                    ))));
+                }
+                None => self.call_fallback_handler(user_data, hostcall)?,
+            };
+
+            self.handle_host_call_result(result)?;
+        }
+
+        Ok(())
+    }
+
+    /// Calls a given exported function with the given arguments, allowing asynchronous host functions to be used.
+    ///
+    /// Every time the guest calls a host function defined with [`Linker::define_async`] or [`Linker::define_typed_async`] the execution
+    /// is suspended until the future returned by the host function completes, after which it resumes.
+    /// Synchronous host functions can be called too.
+    pub async fn call_typed_async<FnArgs>(
+        &mut self,
+        user_data: &mut UserData,
+        entry_point: impl EntryPoint,
+        args: FnArgs,
+    ) -> Result<(), CallError<UserError>>
+    where
+        FnArgs: FuncArgs,
+    {
+        self.prepare_call(entry_point, args)?;
+        while let Some(hostcall) = self.run_until_host_call()? {
+            let result = match self.pre.0.imports.get(hostcall as usize).and_then(|host_fn| host_fn.as_ref()) {
+                Some(HostFn::Sync(host_fn)) => host_fn.0.call(user_data, &mut self.instance),
+                Some(HostFn::Async(host_fn)) => {
+                    let host_fn = Arc::clone(host_fn);
+                    let caller = Caller {
+                        user_data: &mut *user_data,
+                        instance: &mut self.instance,
+                    };
+
+                    host_fn(caller).await
+                }
+                None => self.call_fallback_handler(user_data, hostcall)?,
+            };
+
+            self.handle_host_call_result(result)?;
+        }
+
+        Ok(())
+    }
+
+    fn prepare_call<FnArgs>(&mut self, entry_point: impl EntryPoint, args: FnArgs) -> Result<(), CallError<UserError>>
     where
         FnArgs: FuncArgs,
     {
@@ -818,89 +1108,121 @@ impl<UserData, UserError> Instance<UserData, UserError> {
             .get(&self.pre.0.exports)
             .map_err(|error| CallError::Error(Error::from_display(error)))?;
         self.instance.prepare_call_typed(entry_point, args);
+        Ok(())
+    }
 
+    /// Runs the instance until it either finishes, in which case `None` is returned, or calls a host function
+    /// which has to be handled by the caller.
+    fn run_until_host_call(&mut self) -> Result<Option<u32>, CallError<UserError>> {
         loop {
             let interrupt = self.instance.run().map_err(CallError::Error)?;
             match interrupt {
-                InterruptKind::Finished => break,
+                InterruptKind::Finished => return Ok(None),
                 InterruptKind::Trap => return Err(CallError::Trap),
-                InterruptKind::Ecalli(hostcall) => {
-                    if let Some(host_fn) = self.pre.0.imports.get(hostcall as usize).and_then(|host_fn| host_fn.as_ref()) {
-                        host_fn.0.call(user_data, &mut self.instance).map_err(CallError::User)?;
-                    } else if let Some(ref fallback_handler) = self.pre.0.fallback_handler {
-                        let caller = Caller {
-                            user_data,
-                            instance: &mut self.instance,
-                        };
-
-                        fallback_handler(caller, hostcall).map_err(CallError::User)?;
-                    } else {
-                        log::debug!("Called a missing host function with ID = {}", hostcall);
-                        return Err(CallError::Trap);
-                    };
+                InterruptKind::Ecalli(hostcall) if Some(hostcall) == self.pre.0.guest_panic_import => {
+                    return Err(CallError::GuestPanic(self.read_guest_panic()));
                 }
+                InterruptKind::Ecalli(hostcall) => return Ok(Some(hostcall)),
                 InterruptKind::NotEnoughGas => return Err(CallError::NotEnoughGas),
-                InterruptKind::Segfault(segfault) => {
-                    let module = self.instance.module().clone();
-                    if segfault.page_address >= module.memory_map().stack_address_low()
-                        && segfault.page_address + segfault.page_size <= module.memory_map().stack_address_high()
-                    {
-                        self.instance
-                            .zero_memory(segfault.page_address, segfault.page_size)
-                            .map_err(|error| {
-                                CallError::Error(Error::from_display(format!(
-                                    "failed to zero memory when handling a segfault at 0x{:x}: {error}",
-                                    segfault.page_address
-                                )))
-                            })?;
-
-                        continue;
-                    }
+                InterruptKind::LimitReached(kind) => return Err(CallError::LimitReached(kind)),
+                InterruptKind::Interrupted => return Err(CallError::Interrupted),
+                InterruptKind::Segfault(segfault) => self.handle_segfault(segfault)?,
+                InterruptKind::Step | InterruptKind::Watchpoint { .. } => {}
+            }
+        }
+    }
 
-                    macro_rules! handle {
-                        ($range:ident, $data:ident) => {{
-                            if segfault.page_address >= module.memory_map().$range().start
-                                && segfault.page_address + segfault.page_size <= module.memory_map().$range().end
-                            {
-                                let data_offset = (segfault.page_address - module.memory_map().$range().start) as usize;
-                                let data = module.blob().$data();
-                                if let Some(chunk_length) = data.len().checked_sub(data_offset) {
-                                    let chunk_length = core::cmp::min(chunk_length, segfault.page_size as usize);
-                                    self.instance
-                                        .write_memory(segfault.page_address, &data[data_offset..data_offset + chunk_length])
-                                        .map_err(|error| {
-                                            CallError::Error(Error::from_display(format!(
-                                                "failed to write memory when handling a segfault at 0x{:x}: {error}",
-                                                segfault.page_address
-                                            )))
-                                        })?;
-                                } else {
-                                    self.instance
-                                        .zero_memory(segfault.page_address, segfault.page_size)
-                                        .map_err(|error| {
-                                            CallError::Error(Error::from_display(format!(
-                                                "failed to zero memory when handling a segfault at 0x{:x}: {error}",
-                                                segfault.page_address
-                                            )))
-                                        })?;
-                                };
-
-                                continue;
-                            }
-                        }};
-                    }
+    fn call_fallback_handler(&mut self, user_data: &mut UserData, hostcall: u32) -> Result<Result<(), UserError>, CallError<UserError>> {
+        if let Some(ref fallback_handler) = self.pre.0.fallback_handler {
+            let caller = Caller {
+                user_data,
+                instance: &mut self.instance,
+            };
 
-                    handle!(ro_data_range, ro_data);
-                    handle!(rw_data_range, rw_data);
+            Ok(fallback_handler(caller, hostcall))
+        } else {
+            log::debug!("Called a missing host function with ID = {}", hostcall);
+            Err(CallError::Trap)
+        }
+    }
 
-                    log::debug!("Unexpected segfault: 0x{:x}", segfault.page_address);
-                    return Err(CallError::Trap);
+    fn read_guest_panic(&self) -> GuestPanic {
+        let read_string = |address: Reg, length: Reg| {
+            let address = self.instance.reg(address) as u32;
+            let length = core::cmp::min(self.instance.reg(length), u64::from(GUEST_PANIC_MAX_STRING_LENGTH)) as u32;
+            match self.instance.read_memory(address, length) {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                Err(error) => {
+                    log::debug!("Failed to read the guest's panic message: {error}");
+                    String::new()
                 }
-                InterruptKind::Step => {}
             }
+        };
+
+        GuestPanic {
+            message: read_string(Reg::A0, Reg::A1),
+            file: read_string(Reg::A2, Reg::A3),
+            line: self.instance.reg(Reg::A4) as u32,
+            column: self.instance.reg(Reg::A5) as u32,
         }
+    }
 
-        Ok(())
+    fn handle_host_call_result(&self, result: Result<(), UserError>) -> Result<(), CallError<UserError>> {
+        if self.instance.module().gas_metering().is_some() && self.instance.gas() < 0 {
+            return Err(CallError::NotEnoughGas);
+        }
+
+        result.map_err(CallError::User)
+    }
+
+    fn handle_segfault(&mut self, segfault: Segfault) -> Result<(), CallError<UserError>> {
+        let module = self.instance.module().clone();
+        let map_error = |error: MemoryAccessError, action: &str| match error {
+            MemoryAccessError::MemoryLimitReached { .. } => CallError::LimitReached(LimitKind::Memory),
+            error => CallError::Error(Error::from_display(format!(
+                "failed to {action} memory when handling a segfault at 0x{:x}: {error}",
+                segfault.page_address
+            ))),
+        };
+
+        if segfault.page_address >= module.memory_map().stack_address_low()
+            && segfault.page_address + segfault.page_size <= module.memory_map().stack_address_high()
+        {
+            self.instance
+                .zero_memory(segfault.page_address, segfault.page_size)
+                .map_err(|error| map_error(error, "zero"))?;
+
+            return Ok(());
+        }
+
+        macro_rules! handle {
+            ($range:ident, $data:ident) => {{
+                if segfault.page_address >= module.memory_map().$range().start
+                    && segfault.page_address + segfault.page_size <= module.memory_map().$range().end
+                {
+                    let data_offset = (segfault.page_address - module.memory_map().$range().start) as usize;
+                    let data = module.blob().$data();
+                    if let Some(chunk_length) = data.len().checked_sub(data_offset) {
+                        let chunk_length = core::cmp::min(chunk_length, segfault.page_size as usize);
+                        self.instance
+                            .write_memory(segfault.page_address, &data[data_offset..data_offset + chunk_length])
+                            .map_err(|error| map_error(error, "write"))?;
+                    } else {
+                        self.instance
+                            .zero_memory(segfault.page_address, segfault.page_size)
+                            .map_err(|error| map_error(error, "zero"))?;
+                    };
+
+                    return Ok(());
+                }
+            }};
+        }
+
+        handle!(ro_data_range, ro_data);
+        handle!(rw_data_range, rw_data);
+
+        log::debug!("Unexpected segfault: 0x{:x}", segfault.page_address);
+        Err(CallError::Trap)
     }
 
     /// A conveniance function to call [`Instance::call_typed`] and [`RawInstance::get_result_typed`] in a single function call.
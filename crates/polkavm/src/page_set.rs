@@ -269,11 +269,25 @@ impl PageSet {
         }
     }
 
+    /// Returns how many pages within the given range are in the set.
+    pub fn count(&self, (min, max): (u32, u32)) -> u32 {
+        let mut count = 0;
+        let mut iter = self.intervals.range(Interval { min: 0, max: 0 }..=Interval { min: max, max: 0 });
+        while let Some(i) = iter.next_back() {
+            if i.max < min {
+                break;
+            }
+
+            count += i.max.min(max) - i.min.max(min) + 1;
+        }
+
+        count
+    }
+
     pub fn clear(&mut self) {
         self.intervals.clear();
     }
 
-    #[allow(dead_code)]
     pub fn iter(&'_ self) -> impl ExactSizeIterator<Item = (u32, u32)> + '_ {
         self.intervals.iter().map(|interval| (interval.min, interval.max))
     }
@@ -585,4 +599,20 @@ mod tests {
             assert_eq!(set.to_vec(), vec![]);
         }
     }
+
+    #[test]
+    fn test_page_set_count() {
+        let mut set = PageSet::new();
+        assert_eq!(set.count((0, u32::MAX)), 0);
+
+        set.insert((10, 19));
+        set.insert((30, 39));
+        assert_eq!(set.count((0, u32::MAX)), 20);
+        assert_eq!(set.count((10, 19)), 10);
+        assert_eq!(set.count((15, 34)), 10);
+        assert_eq!(set.count((20, 29)), 0);
+        assert_eq!(set.count((19, 30)), 2);
+        assert_eq!(set.count((39, 39)), 1);
+        assert_eq!(set.count((40, 50)), 0);
+    }
 }
@@ -0,0 +1,486 @@
+//! An instrumenting profiler for guest programs.
+//!
+//! The [`Profiler`] is fed every interruption of a [`RawInstance`] whose module was compiled with
+//! [`ModuleConfig::set_step_tracing`](crate::ModuleConfig::set_step_tracing) enabled, and attributes
+//! every executed instruction and every unit of consumed gas to the call stack which was active at the time.
+//!
+//! Function names (including inlined functions) are resolved through the debug info embedded in the program blob,
+//! and the results can be exported either as folded stacks (as consumed by e.g. `flamegraph.pl` or `inferno`)
+//! or in the `pprof` format.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use polkavm_common::program::{Instruction, ProgramCounter, Reg};
+
+use crate::error::bail;
+use crate::frames::resolve_frames;
+use crate::{Error, Gas, InterruptKind, Module, RawInstance};
+
+pub use crate::frames::Frame;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum ControlFlow {
+    Call,
+    Return,
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+struct Counts {
+    instructions: u64,
+    gas: u64,
+}
+
+impl Counts {
+    fn add(&mut self, rhs: Counts) {
+        self.instructions += rhs.instructions;
+        self.gas += rhs.gas;
+    }
+}
+
+struct Node {
+    parent: usize,
+    call_site: ProgramCounter,
+}
+
+/// Collects execution statistics for a single module.
+pub struct Profiler {
+    module: Module,
+    control_flow: HashMap<ProgramCounter, ControlFlow>,
+    nodes: Vec<Node>,
+    children: HashMap<(usize, ProgramCounter), usize>,
+    current_node: usize,
+    samples: HashMap<(usize, ProgramCounter), Counts>,
+    pending: Option<(ProgramCounter, Gas)>,
+}
+
+impl Profiler {
+    /// Creates a new profiler for the given module.
+    pub fn new(module: &Module) -> Result<Self, Error> {
+        if !module.is_step_tracing() {
+            bail!("failed to create a profiler: the module was not compiled with step tracing enabled");
+        }
+
+        let ra = Reg::RA.into();
+        let mut control_flow = HashMap::new();
+        for instruction in module.instructions() {
+            let kind = match instruction.kind {
+                Instruction::load_imm_and_jump(reg, ..) | Instruction::load_imm_and_jump_indirect(reg, ..) if reg == ra => {
+                    ControlFlow::Call
+                }
+                Instruction::jump_indirect(base, 0) if base == ra => ControlFlow::Return,
+                _ => continue,
+            };
+
+            control_flow.insert(instruction.offset, kind);
+        }
+
+        Ok(Profiler {
+            module: module.clone(),
+            control_flow,
+            nodes: alloc::vec![Node {
+                parent: 0,
+                call_site: ProgramCounter(0),
+            }],
+            children: HashMap::new(),
+            current_node: 0,
+            samples: HashMap::new(),
+            pending: None,
+        })
+    }
+
+    /// Records an interruption returned by [`RawInstance::run`].
+    ///
+    /// This should be called for every interruption returned by the instance, before the host handles it.
+    pub fn on_interrupt(&mut self, instance: &RawInstance, interrupt: &InterruptKind) {
+        match interrupt {
+            InterruptKind::Step => {
+                self.flush(instance);
+                if let Some(pc) = instance.program_counter() {
+                    self.pending = Some((pc, instance.gas()));
+                }
+            }
+            InterruptKind::Finished | InterruptKind::Trap => {
+                self.flush(instance);
+                self.current_node = 0;
+            }
+            InterruptKind::Ecalli(..)
+            | InterruptKind::Segfault(..)
+            | InterruptKind::NotEnoughGas
+            | InterruptKind::LimitReached(..)
+            | InterruptKind::Interrupted
+            | InterruptKind::Watchpoint { .. } => {}
+        }
+    }
+
+    /// Attributes the previously stepped over instruction to the current call stack.
+    fn flush(&mut self, instance: &RawInstance) {
+        let Some((pc, gas)) = self.pending.take() else { return };
+        let consumed = u64::try_from(gas.saturating_sub(instance.gas())).unwrap_or(0);
+        self.samples.entry((self.current_node, pc)).or_default().add(Counts {
+            instructions: 1,
+            gas: consumed,
+        });
+
+        match self.control_flow.get(&pc) {
+            Some(ControlFlow::Call) => {
+                let next_index = self.nodes.len();
+                let index = *self.children.entry((self.current_node, pc)).or_insert(next_index);
+                if index == next_index {
+                    self.nodes.push(Node {
+                        parent: self.current_node,
+                        call_site: pc,
+                    });
+                }
+
+                self.current_node = index;
+            }
+            Some(ControlFlow::Return) => {
+                self.current_node = self.nodes[self.current_node].parent;
+            }
+            None => {}
+        }
+    }
+
+    /// Returns the profile gathered so far.
+    pub fn profile(&self) -> Profile {
+        let mut symbolizer = Symbolizer {
+            module: &self.module,
+            cache: HashMap::new(),
+        };
+
+        let mut stacks: HashMap<Vec<ProgramCounter>, Counts> = HashMap::new();
+        for (&(node, pc), &counts) in &self.samples {
+            let mut stack = alloc::vec![pc];
+            let mut current = node;
+            while current != 0 {
+                stack.push(self.nodes[current].call_site);
+                current = self.nodes[current].parent;
+            }
+
+            stack.reverse();
+            stacks.entry(stack).or_default().add(counts);
+        }
+
+        let mut samples: Vec<Sample> = stacks
+            .into_iter()
+            .map(|(program_counters, counts)| {
+                let mut frames = Vec::new();
+                let mut frame_counts = Vec::with_capacity(program_counters.len());
+                for &pc in &program_counters {
+                    let pc_frames = symbolizer.frames(pc);
+                    frame_counts.push(pc_frames.len());
+                    frames.extend_from_slice(pc_frames);
+                }
+
+                Sample {
+                    program_counters,
+                    frames,
+                    instructions: counts.instructions,
+                    gas: counts.gas,
+                    frame_counts,
+                }
+            })
+            .collect();
+
+        samples.sort_by(|lhs, rhs| lhs.program_counters.cmp(&rhs.program_counters));
+        Profile { samples }
+    }
+}
+
+struct Symbolizer<'a> {
+    module: &'a Module,
+    cache: HashMap<ProgramCounter, Vec<Frame>>,
+}
+
+impl<'a> Symbolizer<'a> {
+    fn frames(&mut self, pc: ProgramCounter) -> &[Frame] {
+        let module = self.module;
+        self.cache.entry(pc).or_insert_with(|| resolve_frames(module, pc))
+    }
+}
+
+/// The statistics for a single unique call stack.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    program_counters: Vec<ProgramCounter>,
+    frames: Vec<Frame>,
+    frame_counts: Vec<usize>,
+    instructions: u64,
+    gas: u64,
+}
+
+impl Sample {
+    /// Returns the program counters of the call sites and of the executed instruction, starting with the outermost caller.
+    pub fn program_counters(&self) -> &[ProgramCounter] {
+        &self.program_counters
+    }
+
+    /// Returns the function frames, starting with the outermost caller and including the inlined functions.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Returns the number of executed instructions.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Returns the amount of consumed gas.
+    pub fn gas(&self) -> u64 {
+        self.gas
+    }
+
+    /// Returns the frames for each of the program counters.
+    fn frames_per_program_counter(&self) -> impl Iterator<Item = &[Frame]> {
+        let mut position = 0;
+        self.frame_counts.iter().map(move |&count| {
+            let frames = &self.frames[position..position + count];
+            position += count;
+            frames
+        })
+    }
+}
+
+/// The statistics for a single function.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct FunctionStats {
+    /// The name of the function.
+    pub name: String,
+    /// The number of instructions executed directly in this function.
+    pub self_instructions: u64,
+    /// The amount of gas consumed directly in this function.
+    pub self_gas: u64,
+    /// The number of instructions executed in this function and everything it called.
+    pub total_instructions: u64,
+    /// The amount of gas consumed in this function and everything it called.
+    pub total_gas: u64,
+}
+
+/// Which value to use as the weight of the stacks when exporting a profile.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProfileWeight {
+    /// Weigh the stacks by the number of executed instructions.
+    Instructions,
+    /// Weigh the stacks by the amount of consumed gas.
+    Gas,
+}
+
+/// A profile gathered by a [`Profiler`].
+pub struct Profile {
+    samples: Vec<Sample>,
+}
+
+impl Profile {
+    /// Returns the statistics for every unique call stack.
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// Returns the statistics aggregated per function, sorted by the amount of gas consumed directly in each function.
+    pub fn functions(&self) -> Vec<FunctionStats> {
+        let mut map: HashMap<&str, FunctionStats> = HashMap::new();
+        let mut seen = HashSet::new();
+        for sample in &self.samples {
+            seen.clear();
+            for (nth, frame) in sample.frames.iter().enumerate() {
+                let stats = map.entry(&frame.function).or_insert_with(|| FunctionStats {
+                    name: frame.function.clone(),
+                    self_instructions: 0,
+                    self_gas: 0,
+                    total_instructions: 0,
+                    total_gas: 0,
+                });
+
+                if nth + 1 == sample.frames.len() {
+                    stats.self_instructions += sample.instructions;
+                    stats.self_gas += sample.gas;
+                }
+
+                // Recursive functions must only be counted once per stack.
+                if seen.insert(&*frame.function) {
+                    stats.total_instructions += sample.instructions;
+                    stats.total_gas += sample.gas;
+                }
+            }
+        }
+
+        let mut list: Vec<_> = map.into_values().collect();
+        list.sort_by(|lhs, rhs| (rhs.self_gas, rhs.self_instructions, &lhs.name).cmp(&(lhs.self_gas, lhs.self_instructions, &rhs.name)));
+        list
+    }
+
+    /// Writes the profile as folded stacks, one stack per line.
+    ///
+    /// This is the format consumed by e.g. `flamegraph.pl` or `inferno-flamegraph`.
+    pub fn write_folded(&self, mut output: impl Write, weight: ProfileWeight) -> std::io::Result<()> {
+        for sample in &self.samples {
+            let value = match weight {
+                ProfileWeight::Instructions => sample.instructions,
+                ProfileWeight::Gas => sample.gas,
+            };
+
+            if value == 0 {
+                continue;
+            }
+
+            for (nth, frame) in sample.frames.iter().enumerate() {
+                if nth != 0 {
+                    output.write_all(b";")?;
+                }
+
+                // Semicolons are used as separators.
+                output.write_all(frame.function.replace(';', ":").as_bytes())?;
+            }
+
+            writeln!(output, " {value}")?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the profile as an uncompressed `pprof` protobuf message.
+    ///
+    /// Both the instruction counts and the consumed gas are included as separate sample types.
+    pub fn write_pprof(&self, mut output: impl Write) -> std::io::Result<()> {
+        output.write_all(&self.encode_pprof())
+    }
+
+    fn encode_pprof(&self) -> Vec<u8> {
+        let mut strings = StringTable::default();
+        strings.get("");
+
+        let mut message = Vec::new();
+        for (kind, unit) in [("instructions", "count"), ("gas", "count")] {
+            let mut value_type = Vec::new();
+            protobuf::write_varint_field(&mut value_type, 1, strings.get(kind));
+            protobuf::write_varint_field(&mut value_type, 2, strings.get(unit));
+            protobuf::write_bytes_field(&mut message, 1, &value_type);
+        }
+
+        let mut functions: HashMap<(&str, Option<&str>), u64> = HashMap::new();
+        let mut encoded_functions = Vec::new();
+        let mut locations: HashMap<ProgramCounter, u64> = HashMap::new();
+        let mut encoded_locations = Vec::new();
+        for sample in &self.samples {
+            let mut location_ids = Vec::with_capacity(sample.program_counters.len());
+            for (&pc, frames) in sample.program_counters.iter().zip(sample.frames_per_program_counter()) {
+                let next_id = cast_len(locations.len()) + 1;
+                let location_id = *locations.entry(pc).or_insert(next_id);
+                if location_id == next_id {
+                    let mut location = Vec::new();
+                    protobuf::write_varint_field(&mut location, 1, location_id);
+                    protobuf::write_varint_field(&mut location, 3, u64::from(pc.0));
+
+                    // The innermost inlined function goes first.
+                    for frame in frames.iter().rev() {
+                        let key = (&*frame.function, frame.path.as_deref());
+                        let next_id = cast_len(functions.len()) + 1;
+                        let function_id = *functions.entry(key).or_insert(next_id);
+                        if function_id == next_id {
+                            let mut function = Vec::new();
+                            protobuf::write_varint_field(&mut function, 1, function_id);
+                            protobuf::write_varint_field(&mut function, 2, strings.get(&frame.function));
+                            protobuf::write_varint_field(&mut function, 3, strings.get(&frame.function));
+                            if let Some(ref path) = frame.path {
+                                protobuf::write_varint_field(&mut function, 4, strings.get(path));
+                            }
+                            protobuf::write_bytes_field(&mut encoded_functions, 5, &function);
+                        }
+
+                        let mut line = Vec::new();
+                        protobuf::write_varint_field(&mut line, 1, function_id);
+                        if let Some(number) = frame.line {
+                            protobuf::write_varint_field(&mut line, 2, u64::from(number));
+                        }
+                        protobuf::write_bytes_field(&mut location, 4, &line);
+                    }
+
+                    protobuf::write_bytes_field(&mut encoded_locations, 4, &location);
+                }
+
+                location_ids.push(location_id);
+            }
+
+            // The leaf goes first.
+            location_ids.reverse();
+
+            let mut encoded_sample = Vec::new();
+            protobuf::write_packed_field(&mut encoded_sample, 1, location_ids.iter().copied());
+            protobuf::write_packed_field(&mut encoded_sample, 2, [sample.instructions, sample.gas]);
+            protobuf::write_bytes_field(&mut message, 2, &encoded_sample);
+        }
+
+        message.extend_from_slice(&encoded_locations);
+        message.extend_from_slice(&encoded_functions);
+        for string in &strings.list {
+            protobuf::write_bytes_field(&mut message, 6, string.as_bytes());
+        }
+
+        message
+    }
+}
+
+fn cast_len(length: usize) -> u64 {
+    u64::try_from(length).unwrap_or(u64::MAX)
+}
+
+#[derive(Default)]
+struct StringTable {
+    list: Vec<String>,
+    map: HashMap<String, u64>,
+}
+
+impl StringTable {
+    fn get(&mut self, string: &str) -> u64 {
+        if let Some(&index) = self.map.get(string) {
+            return index;
+        }
+
+        let index = cast_len(self.list.len());
+        self.list.push(string.into());
+        self.map.insert(string.into(), index);
+        index
+    }
+}
+
+mod protobuf {
+    use alloc::vec::Vec;
+    use polkavm_common::cast::cast;
+
+    fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+        loop {
+            let byte = cast(value & 0x7f).truncate_to_u8();
+            value >>= 7;
+            if value == 0 {
+                output.push(byte);
+                break;
+            }
+
+            output.push(byte | 0x80);
+        }
+    }
+
+    pub fn write_varint_field(output: &mut Vec<u8>, field: u32, value: u64) {
+        write_varint(output, u64::from(field) << 3);
+        write_varint(output, value);
+    }
+
+    pub fn write_bytes_field(output: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+        write_varint(output, (u64::from(field) << 3) | 2);
+        write_varint(output, super::cast_len(bytes.len()));
+        output.extend_from_slice(bytes);
+    }
+
+    pub fn write_packed_field(output: &mut Vec<u8>, field: u32, values: impl IntoIterator<Item = u64>) {
+        let mut buffer = Vec::new();
+        for value in values {
+            write_varint(&mut buffer, value);
+        }
+
+        write_bytes_field(output, field, &buffer);
+    }
+}
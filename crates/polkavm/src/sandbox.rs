@@ -9,7 +9,7 @@ use crate::compiler::CompiledModule;
 use crate::config::{Config, SandboxKind};
 use crate::error::Error;
 use crate::mutex::Mutex;
-use crate::utils::GuestInit;
+use crate::utils::{GuestInit, InterruptState};
 use crate::{Gas, InterruptKind, MemoryAccessError, ProgramCounter, Reg, RegValue};
 
 macro_rules! get_field_offset {
@@ -77,6 +77,7 @@ pub trait SandboxProgram: Clone {
 
 pub struct OffsetTable {
     pub arg: usize,
+    pub basic_block_budget: usize,
     pub gas: usize,
     pub heap_info: usize,
     pub next_native_program_counter: usize,
@@ -119,6 +120,11 @@ pub(crate) trait Sandbox: Sized {
     fn set_reg(&mut self, reg: Reg, value: RegValue);
     fn gas(&self) -> Gas;
     fn set_gas(&mut self, gas: Gas);
+    fn basic_block_budget(&self) -> i64;
+    fn set_basic_block_budget(&mut self, budget: i64);
+    fn set_deadline(&mut self, deadline: Option<std::time::Instant>);
+    fn set_interrupt_state(&mut self, state: Option<Arc<InterruptState>>);
+    fn set_max_heap_size(&mut self, size: u32);
     fn program_counter(&self) -> Option<ProgramCounter>;
     fn next_program_counter(&self) -> Option<ProgramCounter>;
     fn next_native_program_counter(&self) -> Option<usize>;
@@ -126,8 +132,12 @@ pub(crate) trait Sandbox: Sized {
     fn accessible_aux_size(&self) -> u32;
     fn set_accessible_aux_size(&mut self, size: u32) -> Result<(), Self::Error>;
     fn is_memory_accessible(&self, address: u32, size: u32, is_writable: bool) -> bool;
+    fn count_mapped_pages(&self, address: u32, length: u32) -> u32;
+    fn mapped_pages(&self) -> Vec<u32>;
     fn reset_memory(&mut self) -> Result<(), Self::Error>;
     fn read_memory_into<'slice>(&self, address: u32, slice: &'slice mut [MaybeUninit<u8>]) -> Result<&'slice mut [u8], MemoryAccessError>;
+    fn memory_slice(&self, address: u32, length: u32) -> Option<&[u8]>;
+    fn memory_slice_mut(&mut self, address: u32, length: u32) -> Option<&mut [u8]>;
     fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<(), MemoryAccessError>;
     fn zero_memory(&mut self, address: u32, length: u32) -> Result<(), MemoryAccessError>;
     fn protect_memory(&mut self, address: u32, length: u32) -> Result<(), MemoryAccessError>;
@@ -161,26 +171,30 @@ impl<S> SandboxInstance<S>
 where
     S: Sandbox,
 {
-    pub fn spawn_and_load_module(engine_state: Arc<EngineState>, module: &Module) -> Result<Self, Error> {
+    fn reuse_or_spawn_sandbox(engine_state: &EngineState) -> Result<S, Error> {
         use crate::sandbox::SandboxConfig;
 
+        if let Some(sandbox) = engine_state
+            .sandbox_cache
+            .as_ref()
+            .and_then(|cache| S::downcast_worker_cache(cache).reuse_sandbox())
+        {
+            return Ok(sandbox);
+        }
+
         let mut sandbox_config = S::Config::default();
         sandbox_config.enable_logger(is_sandbox_logging_enabled());
         sandbox_config.enable_sandboxing(engine_state.sandboxing_enabled);
 
         let global = S::downcast_global_state(engine_state.sandbox_global.as_ref().unwrap());
-        let mut sandbox = if let Some(sandbox) = engine_state
-            .sandbox_cache
-            .as_ref()
-            .and_then(|cache| S::downcast_worker_cache(cache).reuse_sandbox())
-        {
-            sandbox
-        } else {
-            S::spawn(global, &sandbox_config)
-                .map_err(Error::from_display)
-                .map_err(|error| error.context("instantiation failed: failed to create a sandbox"))?
-        };
+        S::spawn(global, &sandbox_config)
+            .map_err(Error::from_display)
+            .map_err(|error| error.context("instantiation failed: failed to create a sandbox"))
+    }
 
+    pub fn spawn_and_load_module(engine_state: Arc<EngineState>, module: &Module) -> Result<Self, Error> {
+        let mut sandbox = Self::reuse_or_spawn_sandbox(&engine_state)?;
+        let global = S::downcast_global_state(engine_state.sandbox_global.as_ref().unwrap());
         sandbox
             .load_module(global, module)
             .map_err(Error::from_display)
@@ -201,6 +215,23 @@ where
     }
 }
 
+#[cfg(target_os = "linux")]
+impl SandboxInstance<linux::Sandbox> {
+    /// Creates a new instance in the exact same state as this one, sharing the guest's memory copy-on-write.
+    pub fn fork(&self) -> Result<Self, Error> {
+        let mut sandbox = Self::reuse_or_spawn_sandbox(&self.engine_state)?;
+        sandbox
+            .load_forked_module(self.sandbox())
+            .map_err(Error::from_display)
+            .map_err(|error| error.context("failed to fork the instance"))?;
+
+        Ok(SandboxInstance {
+            sandbox: Some(sandbox),
+            engine_state: Arc::clone(&self.engine_state),
+        })
+    }
+}
+
 impl<S> Drop for SandboxInstance<S>
 where
     S: Sandbox,
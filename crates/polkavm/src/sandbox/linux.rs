@@ -4,6 +4,7 @@
 extern crate polkavm_linux_raw as linux_raw;
 
 use polkavm_common::{
+    abi::MemoryMap,
     cast::cast,
     program::Reg,
     utils::{align_to_next_page_usize, slice_assume_init_mut},
@@ -29,9 +30,11 @@ use crate::api::{CompiledModuleKind, MemoryAccessError, Module};
 use crate::compiler::{Bitness, CompiledModule, B32, B64};
 use crate::config::Config;
 use crate::config::GasMeteringKind;
+use crate::mutex::Mutex;
 use crate::page_set::PageSet;
 use crate::shm_allocator::{ShmAllocation, ShmAllocator};
-use crate::{Gas, InterruptKind, ProgramCounter, RegValue, Segfault};
+use crate::utils::InterruptState;
+use crate::{Gas, InterruptKind, LimitKind, ProgramCounter, RegValue, Segfault};
 
 pub struct GlobalState {
     shared_memory: ShmAllocator,
@@ -911,6 +914,64 @@ struct ProgramMap {
     initialize_with: InitializeWith,
 }
 
+impl ProgramMap {
+    fn to_vm_map(&self) -> VmMap {
+        let (fd, fd_offset) = match self.initialize_with {
+            InitializeWith::None => (VmFd::None, 0),
+            InitializeWith::Shm(ref alloc) => (VmFd::Shm, alloc.offset() as u64),
+            InitializeWith::Mem(offset) => (VmFd::Mem, u64::from(offset)),
+        };
+
+        VmMap {
+            address: self.address,
+            length: self.length,
+            protection: linux_raw::PROT_READ | if self.is_writable { linux_raw::PROT_WRITE } else { 0 },
+            flags: if !matches!(self.initialize_with, InitializeWith::None) {
+                linux_raw::MAP_FIXED | linux_raw::MAP_PRIVATE
+            } else {
+                linux_raw::MAP_FIXED | linux_raw::MAP_PRIVATE | linux_raw::MAP_ANONYMOUS
+            },
+            fd,
+            fd_offset,
+        }
+    }
+}
+
+/// A copy of the guest's writable memory (the read-write data, the heap and the stack) in the shared memory.
+///
+/// Sandboxes map it privately, so they share its pages with each other until they write to them.
+struct ForkedMemory {
+    shm: ShmAllocation,
+    heap_top: u64,
+    heap_threshold: u64,
+    /// The length of the read-write data and the heap; the stack is stored right after them.
+    heap_length: u64,
+}
+
+impl ForkedMemory {
+    fn vm_maps(&self, memory_map: &MemoryMap) -> impl Iterator<Item = VmMap> + Clone {
+        let offset = self.shm.offset() as u64;
+        [
+            (u64::from(memory_map.rw_data_address()), self.heap_length, offset),
+            (
+                u64::from(memory_map.stack_address_low()),
+                u64::from(memory_map.stack_size()),
+                offset + self.heap_length,
+            ),
+        ]
+        .into_iter()
+        .filter(|&(_, length, _)| length > 0)
+        .map(|(address, length, fd_offset)| VmMap {
+            address,
+            length,
+            protection: linux_raw::PROT_READ | linux_raw::PROT_WRITE,
+            flags: linux_raw::MAP_FIXED | linux_raw::MAP_PRIVATE,
+            fd: VmFd::Shm,
+            fd_offset,
+        })
+    }
+}
+
 struct SandboxProgramInner {
     memory_map: Vec<ProgramMap>,
     shm_code: ShmAllocation,
@@ -1083,6 +1144,17 @@ pub struct Sandbox {
     aux_data_address: u32,
     aux_data_length: u32,
     is_borked: bool,
+
+    shared_memory: ShmAllocator,
+    /// The copy of another sandbox's memory which is mapped into this sandbox, if it was forked.
+    forked_memory: Option<Arc<ForkedMemory>>,
+    /// A copy of this sandbox's current memory to fork from; cleared whenever the memory might change.
+    fork_base: Mutex<Option<Arc<ForkedMemory>>>,
+
+    deadline: Option<Instant>,
+    interrupt_state: Option<Arc<InterruptState>>,
+    /// The basic block budget from before it was overwritten to force the guest to stop.
+    budget_before_force_stop: Option<i64>,
 }
 
 impl Drop for Sandbox {
@@ -1609,138 +1681,19 @@ impl super::Sandbox for Sandbox {
             aux_data_address: 0,
             aux_data_length: 0,
             is_borked: false,
-        })
-    }
-
-    fn load_module(&mut self, global: &Self::GlobalState, module: &Module) -> Result<(), Self::Error> {
-        if self.module.is_some() {
-            return Err(Error::from("module already loaded"));
-        }
-
-        if module.is_dynamic_paging() && get_native_page_size() != module.memory_map().page_size() as usize {
-            return Err(Error::from(
-                "dynamic paging is currently unsupported if the module's page size doesn't match the native page size",
-            ));
-        }
-
-        log::debug!(
-            "Loading module into sandbox #{}... (dynamic paging = {})",
-            self.child.pid,
-            module.is_dynamic_paging()
-        );
-
-        let compiled_module = Self::downcast_module(module);
-        let program = &compiled_module.sandbox_program.0;
-
-        let memory_map = if !module.is_dynamic_paging() {
-            let Some(memory_map) = global.shared_memory.alloc(core::mem::size_of::<VmMap>() * program.memory_map.len()) else {
-                return Err(Error::from_str("out of shared memory"));
-            };
-
-            let vm_maps = unsafe { memory_map.as_typed_slice_mut::<VmMap>() };
-            for (chunk, vm_map) in program.memory_map.iter().zip(vm_maps.iter_mut()) {
-                let (fd, fd_offset) = match chunk.initialize_with {
-                    InitializeWith::None => (VmFd::None, 0),
-                    InitializeWith::Shm(ref alloc) => (VmFd::Shm, alloc.offset() as u64),
-                    InitializeWith::Mem(offset) => (VmFd::Mem, u64::from(offset)),
-                };
-
-                *vm_map = VmMap {
-                    address: chunk.address,
-                    length: chunk.length,
-                    protection: linux_raw::PROT_READ | if chunk.is_writable { linux_raw::PROT_WRITE } else { 0 },
-                    flags: if !matches!(chunk.initialize_with, InitializeWith::None) {
-                        linux_raw::MAP_FIXED | linux_raw::MAP_PRIVATE
-                    } else {
-                        linux_raw::MAP_FIXED | linux_raw::MAP_PRIVATE | linux_raw::MAP_ANONYMOUS
-                    },
-                    fd,
-                    fd_offset,
-                };
-            }
 
-            self.vmctx()
-                .shm_memory_map_count
-                .store(program.memory_map.len() as u64, Ordering::Relaxed);
-            memory_map
-        } else {
-            let Some(memory_map) = global.shared_memory.alloc(core::mem::size_of::<VmMap>()) else {
-                return Err(Error::from_str("out of shared memory"));
-            };
-
-            let vm_maps = unsafe { memory_map.as_typed_slice_mut::<VmMap>() };
-            vm_maps[0] = VmMap {
-                address: 0x10000,
-                length: u64::from(u32::MAX) + 1 - 0x10000,
-                protection: linux_raw::PROT_READ | linux_raw::PROT_WRITE,
-                flags: linux_raw::MAP_FIXED | linux_raw::MAP_SHARED,
-                fd: VmFd::Mem,
-                fd_offset: 0x10000,
-            };
-
-            self.vmctx().shm_memory_map_count.store(1, Ordering::Relaxed);
-            memory_map
-        };
-
-        self.vmctx()
-            .shm_memory_map_offset
-            .store(memory_map.offset() as u64, Ordering::Relaxed);
+            shared_memory: global.shared_memory.clone(),
+            forked_memory: None,
+            fork_base: Mutex::new(None),
 
-        unsafe {
-            *self.vmctx().heap_info.heap_top.get() = u64::from(module.memory_map().heap_base());
-            *self.vmctx().heap_info.heap_threshold.get() = u64::from(module.memory_map().rw_data_range().end);
-            *self.vmctx().heap_base.get() = module.memory_map().heap_base();
-            *self.vmctx().heap_initial_threshold.get() = module.memory_map().rw_data_range().end;
-            *self.vmctx().heap_max_size.get() = module.memory_map().max_heap_size();
-            *self.vmctx().page_size.get() = module.memory_map().page_size();
-        }
-
-        self.vmctx()
-            .shm_code_offset
-            .store(program.shm_code.offset() as u64, Ordering::Relaxed);
-        self.vmctx().shm_code_length.store(program.shm_code.len() as u64, Ordering::Relaxed);
-        self.vmctx()
-            .shm_jump_table_offset
-            .store(program.shm_jump_table.offset() as u64, Ordering::Relaxed);
-        self.vmctx()
-            .shm_jump_table_length
-            .store(program.shm_jump_table.len() as u64, Ordering::Relaxed);
-        self.vmctx().sysreturn_address.store(program.sysreturn_address, Ordering::Relaxed);
-
-        self.vmctx().program_counter.store(0, Ordering::Relaxed);
-        self.vmctx().next_program_counter.store(0, Ordering::Relaxed);
-        self.vmctx().next_native_program_counter.store(0, Ordering::Relaxed);
-        self.vmctx().jump_into.store(ZYGOTE_TABLES.1.ext_load_program, Ordering::Relaxed);
-        self.vmctx().gas.store(0, Ordering::Relaxed);
-        for reg in &self.vmctx().regs {
-            reg.store(0, Ordering::Relaxed);
-        }
-
-        self.aux_data_address = module.memory_map().aux_data_address();
-        self.aux_data_length = module.memory_map().aux_data_size();
-        self.dynamic_paging_enabled = module.is_dynamic_paging();
-        self.is_program_counter_valid = false;
-        self.gas_metering = module.gas_metering();
-        self.module = Some(module.clone());
-        self.wake_oneshot_and_expect_idle()?;
-        core::mem::drop(memory_map);
-
-        if module.is_dynamic_paging() {
-            linux_raw::sys_uffdio_register(
-                self.userfaultfd.borrow(),
-                &mut linux_raw::uffdio_register {
-                    range: linux_raw::uffdio_range {
-                        start: 0x10000,
-                        len: u64::from(u32::MAX) + 1 - 0x10000,
-                    },
-                    mode: linux_raw::UFFDIO_REGISTER_MODE_MISSING | linux_raw::UFFDIO_REGISTER_MODE_WP,
-                    ..linux_raw::uffdio_register::default()
-                },
-            )
-            .map_err(|error| Error::from(format!("failed to register the guest memory with userfaultfd: {error}")))?;
-        }
+            deadline: None,
+            interrupt_state: None,
+            budget_before_force_stop: None,
+        })
+    }
 
-        Ok(())
+    fn load_module(&mut self, _global: &Self::GlobalState, module: &Module) -> Result<(), Self::Error> {
+        self.load_module_impl(module, None)
     }
 
     fn recycle(&mut self, _global: &Self::GlobalState) -> Result<(), Self::Error> {
@@ -1755,9 +1708,12 @@ impl super::Sandbox for Sandbox {
 
         self.module = None;
         self.page_set.clear();
+        self.invalidate_fork_base();
 
         self.vmctx().jump_into.store(ZYGOTE_TABLES.1.ext_recycle, Ordering::Relaxed);
-        self.wake_oneshot_and_expect_idle()
+        self.wake_oneshot_and_expect_idle()?;
+        self.forked_memory = None;
+        Ok(())
     }
 
     fn run(&mut self) -> Result<InterruptKind, Self::Error> {
@@ -1765,6 +1721,7 @@ impl super::Sandbox for Sandbox {
             return Err(Error::from_str("no module loaded into the sandbox"));
         };
 
+        self.invalidate_fork_base();
         if self.next_program_counter_changed {
             let Some(pc) = self.next_program_counter.take() else {
                 panic!("failed to run: next program counter is not set");
@@ -1800,19 +1757,54 @@ impl super::Sandbox for Sandbox {
             );
         };
 
+        let interrupt_state = self.interrupt_state.clone();
+        if interrupt_state.as_ref().is_some_and(|state| state.is_interrupted())
+            || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.force_stop();
+        }
+
         let compiled_module = Self::downcast_module(self.module.as_ref().unwrap());
         debug_assert_eq!(self.vmctx().futex.load(Ordering::Relaxed) & 1, VMCTX_FUTEX_IDLE);
         self.vmctx()
             .jump_into
             .store(compiled_module.sandbox_program.0.sysenter_address, Ordering::Relaxed);
-        self.wake_worker()?;
-        self.is_program_counter_valid = true;
 
-        let result = self.wait()?;
-        if self.module.as_ref().unwrap().gas_metering() == Some(GasMeteringKind::Async) && self.gas() < 0 {
-            self.is_program_counter_valid = false;
-            self.vmctx().next_native_program_counter.store(0, Ordering::Relaxed);
-            return Ok(InterruptKind::NotEnoughGas);
+        if let Some(ref state) = interrupt_state {
+            state.set_waker(Some((wake_vmctx_futex, self.vmctx() as *const VmCtx as usize)));
+        }
+
+        let bitness = compiled_module.bitness;
+        let result = self.wake_worker().and_then(|()| {
+            self.is_program_counter_valid = true;
+            self.wait_until(self.deadline, interrupt_state.as_deref())
+        });
+
+        if let Some(ref state) = interrupt_state {
+            state.set_waker(None);
+        }
+
+        let result = result?;
+        let is_execution_limit = match result {
+            Interrupt::Step if self.module.as_ref().unwrap().has_execution_limits() => match bitness {
+                Bitness::B32 => crate::compiler::ArchVisitor::<Self, B32>::on_step(self.vmctx()),
+                Bitness::B64 => crate::compiler::ArchVisitor::<Self, B64>::on_step(self.vmctx()),
+            }
+            .map_err(Error::from_str)?,
+            _ => false,
+        };
+
+        let is_forced_stop = self.restore_budget_after_force_stop();
+        if is_execution_limit {
+            if !is_forced_stop {
+                return Ok(InterruptKind::LimitReached(LimitKind::BasicBlocks));
+            }
+
+            if interrupt_state.is_some_and(|state| state.take_interrupt()) {
+                return Ok(InterruptKind::Interrupted);
+            }
+
+            return Ok(InterruptKind::LimitReached(LimitKind::Deadline));
         }
 
         Ok(match result {
@@ -1861,6 +1853,30 @@ impl super::Sandbox for Sandbox {
         self.vmctx().gas.store(gas, Ordering::Relaxed)
     }
 
+    fn basic_block_budget(&self) -> i64 {
+        self.vmctx().basic_block_budget.load(Ordering::Relaxed)
+    }
+
+    fn set_basic_block_budget(&mut self, budget: i64) {
+        self.vmctx().basic_block_budget.store(budget, Ordering::Relaxed)
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    fn set_interrupt_state(&mut self, state: Option<Arc<InterruptState>>) {
+        self.interrupt_state = state;
+    }
+
+    fn set_max_heap_size(&mut self, size: u32) {
+        let module = self.module.as_ref().unwrap();
+        let size = core::cmp::min(size, module.memory_map().max_heap_size());
+        unsafe {
+            *self.vmctx().heap_max_size.get() = size;
+        }
+    }
+
     fn program_counter(&self) -> Option<ProgramCounter> {
         if !self.is_program_counter_valid {
             return None;
@@ -1924,17 +1940,39 @@ impl super::Sandbox for Sandbox {
         assert!(self.dynamic_paging_enabled);
 
         let module = self.module.as_ref().unwrap();
-        let page_start = module.address_to_page(module.round_to_page_size_down(address));
-        let page_end = module.address_to_page(module.round_to_page_size_down(address + size));
+        let (page_start, page_end) = page_range(module, address, size);
         self.page_set.contains((page_start, page_end))
     }
 
+    fn count_mapped_pages(&self, address: u32, length: u32) -> u32 {
+        assert!(self.dynamic_paging_enabled);
+
+        let module = self.module.as_ref().unwrap();
+        self.page_set.count(page_range(module, address, length))
+    }
+
+    fn mapped_pages(&self) -> Vec<u32> {
+        assert!(self.dynamic_paging_enabled);
+
+        let page_size = self.module.as_ref().unwrap().memory_map().page_size();
+        self.page_set
+            .iter()
+            .flat_map(|(page_start, page_end)| (page_start..=page_end).map(move |page| page * page_size))
+            .collect()
+    }
+
     fn reset_memory(&mut self) -> Result<(), Error> {
         if self.module.is_none() {
             return Err(Error::from_str("no module loaded into the sandbox"));
         };
 
+        self.invalidate_fork_base();
         if !self.dynamic_paging_enabled {
+            if self.forked_memory.is_some() {
+                // Resetting the existing mappings would bring back the memory from the moment of the fork.
+                return self.map_memory(None);
+            }
+
             self.vmctx().jump_into.store(ZYGOTE_TABLES.1.ext_reset_memory, Ordering::Relaxed);
             self.wake_oneshot_and_expect_idle()
         } else {
@@ -1959,8 +1997,7 @@ impl super::Sandbox for Sandbox {
             }
         } else {
             let module = self.module.as_ref().unwrap();
-            let page_start = module.address_to_page(module.round_to_page_size_down(address));
-            let page_end = module.address_to_page(module.round_to_page_size_down(address + slice.len() as u32));
+            let (page_start, page_end) = page_range(module, address, slice.len() as u32);
             if !self.page_set.contains((page_start, page_end)) {
                 return Err(MemoryAccessError::Error("incomplete read".into()));
             } else {
@@ -1974,6 +2011,16 @@ impl super::Sandbox for Sandbox {
         }
     }
 
+    fn memory_slice(&self, address: u32, length: u32) -> Option<&[u8]> {
+        let range = self.shared_memory_range(address, length)?;
+        self.memory_mmap.as_slice().get(range)
+    }
+
+    fn memory_slice_mut(&mut self, address: u32, length: u32) -> Option<&mut [u8]> {
+        let range = self.shared_memory_range(address, length)?;
+        self.memory_mmap.as_slice_mut().get_mut(range)
+    }
+
     fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<(), MemoryAccessError> {
         log::trace!(
             "Writing memory: 0x{:x}-0x{:x} ({} bytes)",
@@ -1986,6 +2033,7 @@ impl super::Sandbox for Sandbox {
             return Ok(());
         }
 
+        self.invalidate_fork_base();
         let module = self.module.as_ref().unwrap();
         if !self.dynamic_paging_enabled {
             let memory_map = module.memory_map();
@@ -2022,8 +2070,7 @@ impl super::Sandbox for Sandbox {
                 Err(error) => Err(MemoryAccessError::Error(error.into())),
             }
         } else {
-            let page_start = module.address_to_page(module.round_to_page_size_down(address));
-            let page_end = module.address_to_page(module.round_to_page_size_down(address + data.len() as u32));
+            let (page_start, page_end) = page_range(module, address, data.len() as u32);
             self.page_set.insert((page_start, page_end));
             self.memory_mmap.as_slice_mut()[address as usize..address as usize + data.len()].copy_from_slice(data);
             Ok(())
@@ -2038,6 +2085,7 @@ impl super::Sandbox for Sandbox {
             length
         );
 
+        self.invalidate_fork_base();
         let module = self.module.as_ref().unwrap();
         if !self.dynamic_paging_enabled {
             let memory_map = module.memory_map();
@@ -2073,8 +2121,7 @@ impl super::Sandbox for Sandbox {
                 return Err(MemoryAccessError::Error(error.into()));
             }
         } else {
-            let page_start = module.address_to_page(module.round_to_page_size_down(address));
-            let page_end = module.address_to_page(module.round_to_page_size_down(address + length));
+            let (page_start, page_end) = page_range(module, address, length);
             if module.is_multiple_of_page_size(address)
                 && module.is_multiple_of_page_size(length)
                 && self.page_set.is_whole_region_empty((page_start, page_end))
@@ -2141,8 +2188,7 @@ impl super::Sandbox for Sandbox {
                 self.page_set.clear();
             } else {
                 let module = self.module.as_ref().unwrap();
-                let page_start = module.address_to_page(module.round_to_page_size_down(address));
-                let page_end = module.address_to_page(module.round_to_page_size_down(address + length));
+                let (page_start, page_end) = page_range(module, address, length);
                 self.page_set.remove((page_start, page_end));
             }
 
@@ -2161,6 +2207,7 @@ impl super::Sandbox for Sandbox {
             return Ok(Some(unsafe { *self.vmctx().heap_info.heap_top.get() as u32 }));
         }
 
+        self.invalidate_fork_base();
         self.vmctx().jump_into.store(ZYGOTE_TABLES.1.ext_sbrk, Ordering::Relaxed);
         self.vmctx().arg.store(size, Ordering::Relaxed);
         self.wake_worker()?;
@@ -2186,6 +2233,7 @@ impl super::Sandbox for Sandbox {
     fn offset_table() -> OffsetTable {
         OffsetTable {
             arg: get_field_offset!(VmCtx::new(), |base| base.arg.as_ptr()),
+            basic_block_budget: get_field_offset!(VmCtx::new(), |base| base.basic_block_budget.as_ptr()),
             gas: get_field_offset!(VmCtx::new(), |base| base.gas.as_ptr()),
             heap_info: get_field_offset!(VmCtx::new(), |base| &base.heap_info),
             next_native_program_counter: get_field_offset!(VmCtx::new(), |base| base.next_native_program_counter.as_ptr()),
@@ -2200,6 +2248,25 @@ impl super::Sandbox for Sandbox {
     }
 }
 
+/// The value to which the basic block budget is set to force the guest to stop.
+///
+/// This is far away enough from any legitimate budget that it's easy to tell whether the guest has overwritten it.
+const BASIC_BLOCK_BUDGET_FORCED_STOP: i64 = i64::MIN / 2;
+
+/// Returns the inclusive range of pages which cover a given non-empty memory region.
+fn page_range(module: &Module, address: u32, length: u32) -> (u32, u32) {
+    let page_start = module.address_to_page(module.round_to_page_size_down(address));
+    let page_end = module.address_to_page(module.round_to_page_size_down(address + (length - 1)));
+    (page_start, page_end)
+}
+
+/// Wakes up the host thread which is waiting for the guest, so that it can notice that it was interrupted.
+fn wake_vmctx_futex(vmctx_address: usize) {
+    // SAFETY: The waker is only registered while the sandbox is running, so the VM context is still mapped.
+    let vmctx = unsafe { &*(vmctx_address as *const VmCtx) };
+    let _ = linux_raw::sys_futex_wake_one(&vmctx.futex);
+}
+
 #[must_use]
 enum Interrupt {
     Idle,
@@ -2224,6 +2291,262 @@ impl Interrupt {
 }
 
 impl Sandbox {
+    fn load_module_impl(&mut self, module: &Module, forked_memory: Option<Arc<ForkedMemory>>) -> Result<(), Error> {
+        if self.module.is_some() {
+            return Err(Error::from("module already loaded"));
+        }
+
+        if module.is_dynamic_paging() && get_native_page_size() != module.memory_map().page_size() as usize {
+            return Err(Error::from(
+                "dynamic paging is currently unsupported if the module's page size doesn't match the native page size",
+            ));
+        }
+
+        log::debug!(
+            "Loading module into sandbox #{}... (dynamic paging = {}, forked = {})",
+            self.child.pid,
+            module.is_dynamic_paging(),
+            forked_memory.is_some()
+        );
+
+        let compiled_module = <Self as super::Sandbox>::downcast_module(module);
+        let program = &compiled_module.sandbox_program.0;
+
+        unsafe {
+            *self.vmctx().heap_base.get() = module.memory_map().heap_base();
+            *self.vmctx().heap_initial_threshold.get() = module.memory_map().rw_data_range().end;
+            *self.vmctx().heap_max_size.get() = module.memory_map().max_heap_size();
+            *self.vmctx().page_size.get() = module.memory_map().page_size();
+        }
+
+        self.vmctx()
+            .shm_code_offset
+            .store(program.shm_code.offset() as u64, Ordering::Relaxed);
+        self.vmctx().shm_code_length.store(program.shm_code.len() as u64, Ordering::Relaxed);
+        self.vmctx()
+            .shm_jump_table_offset
+            .store(program.shm_jump_table.offset() as u64, Ordering::Relaxed);
+        self.vmctx()
+            .shm_jump_table_length
+            .store(program.shm_jump_table.len() as u64, Ordering::Relaxed);
+        self.vmctx().sysreturn_address.store(program.sysreturn_address, Ordering::Relaxed);
+
+        self.vmctx().program_counter.store(0, Ordering::Relaxed);
+        self.vmctx().next_program_counter.store(0, Ordering::Relaxed);
+        self.vmctx().next_native_program_counter.store(0, Ordering::Relaxed);
+        self.vmctx().gas.store(0, Ordering::Relaxed);
+        self.vmctx().basic_block_budget.store(i64::MAX, Ordering::Relaxed);
+        self.deadline = None;
+        self.interrupt_state = None;
+        for reg in &self.vmctx().regs {
+            reg.store(0, Ordering::Relaxed);
+        }
+
+        self.aux_data_address = module.memory_map().aux_data_address();
+        self.aux_data_length = module.memory_map().aux_data_size();
+        self.dynamic_paging_enabled = module.is_dynamic_paging();
+        self.is_program_counter_valid = false;
+        self.gas_metering = module.gas_metering();
+        self.module = Some(module.clone());
+        self.invalidate_fork_base();
+
+        if !module.is_dynamic_paging() {
+            self.map_memory(forked_memory)?;
+        } else {
+            unsafe {
+                *self.vmctx().heap_info.heap_top.get() = u64::from(module.memory_map().heap_base());
+                *self.vmctx().heap_info.heap_threshold.get() = u64::from(module.memory_map().rw_data_range().end);
+            }
+
+            self.map_memory_and_load_program(core::iter::once_with(|| VmMap {
+                address: 0x10000,
+                length: u64::from(u32::MAX) + 1 - 0x10000,
+                protection: linux_raw::PROT_READ | linux_raw::PROT_WRITE,
+                flags: linux_raw::MAP_FIXED | linux_raw::MAP_SHARED,
+                fd: VmFd::Mem,
+                fd_offset: 0x10000,
+            }))?;
+
+            linux_raw::sys_uffdio_register(
+                self.userfaultfd.borrow(),
+                &mut linux_raw::uffdio_register {
+                    range: linux_raw::uffdio_range {
+                        start: 0x10000,
+                        len: u64::from(u32::MAX) + 1 - 0x10000,
+                    },
+                    mode: linux_raw::UFFDIO_REGISTER_MODE_MISSING | linux_raw::UFFDIO_REGISTER_MODE_WP,
+                    ..linux_raw::uffdio_register::default()
+                },
+            )
+            .map_err(|error| Error::from(format!("failed to register the guest memory with userfaultfd: {error}")))?;
+        }
+
+        Ok(())
+    }
+
+    /// Maps the guest's memory from scratch, or from a copy of another sandbox's memory if one is given.
+    fn map_memory(&mut self, forked_memory: Option<Arc<ForkedMemory>>) -> Result<(), Error> {
+        let module = self.module.clone().unwrap();
+        let memory_map = module.memory_map();
+        let program = &<Self as super::Sandbox>::downcast_module(&module).sandbox_program.0;
+        let is_forked = forked_memory.is_some();
+        let chunks = program
+            .memory_map
+            .iter()
+            .filter(move |chunk| !is_forked || !chunk.is_writable)
+            .map(ProgramMap::to_vm_map);
+
+        let (heap_top, heap_threshold) = if let Some(ref forked_memory) = forked_memory {
+            self.map_memory_and_load_program(chunks.chain(forked_memory.vm_maps(memory_map)))?;
+            (forked_memory.heap_top, forked_memory.heap_threshold)
+        } else {
+            self.map_memory_and_load_program(chunks)?;
+            (u64::from(memory_map.heap_base()), u64::from(memory_map.rw_data_range().end))
+        };
+
+        unsafe {
+            *self.vmctx().heap_info.heap_top.get() = heap_top;
+            *self.vmctx().heap_info.heap_threshold.get() = heap_threshold;
+        }
+
+        self.forked_memory = forked_memory;
+        if self.aux_data_length != memory_map.aux_data_size() {
+            // The whole auxiliary data region was just mapped as accessible.
+            super::Sandbox::set_accessible_aux_size(self, self.aux_data_length)?;
+        }
+
+        Ok(())
+    }
+
+    /// Uploads the given memory map and has the sandbox map the guest's memory and code from scratch.
+    fn map_memory_and_load_program(&mut self, maps: impl Iterator<Item = VmMap> + Clone) -> Result<(), Error> {
+        let count = maps.clone().count();
+        let Some(memory_map) = self.shared_memory.alloc(core::mem::size_of::<VmMap>() * count) else {
+            return Err(Error::from_str("out of shared memory"));
+        };
+
+        let vm_maps = unsafe { memory_map.as_typed_slice_mut::<VmMap>() };
+        for (map, vm_map) in maps.zip(vm_maps.iter_mut()) {
+            *vm_map = map;
+        }
+
+        self.vmctx().shm_memory_map_count.store(count as u64, Ordering::Relaxed);
+        self.vmctx()
+            .shm_memory_map_offset
+            .store(memory_map.offset() as u64, Ordering::Relaxed);
+        self.vmctx().jump_into.store(ZYGOTE_TABLES.1.ext_load_program, Ordering::Relaxed);
+        self.wake_oneshot_and_expect_idle()?;
+        core::mem::drop(memory_map);
+
+        Ok(())
+    }
+
+    /// Returns a copy of the guest's current writable memory, reusing the last one if the memory didn't change since it was made.
+    fn fork_base(&self) -> Result<Arc<ForkedMemory>, Error> {
+        let mut fork_base = self.fork_base.lock();
+        if let Some(ref fork_base) = *fork_base {
+            return Ok(Arc::clone(fork_base));
+        }
+
+        let memory_map = self.module.as_ref().unwrap().memory_map();
+        let heap_top = unsafe { *self.vmctx().heap_info.heap_top.get() };
+        let heap_threshold = unsafe { *self.vmctx().heap_info.heap_threshold.get() };
+        let heap_length = heap_threshold - u64::from(memory_map.rw_data_address());
+        let stack_length = u64::from(memory_map.stack_size());
+        let length = (heap_length + stack_length) as usize;
+        let Some(shm) = self.shared_memory.alloc(length) else {
+            return Err(Error::from_str("failed to fork: out of shared memory"));
+        };
+
+        let memory: &mut [MaybeUninit<u8>] = unsafe { core::slice::from_raw_parts_mut(shm.as_mut_ptr().cast(), length) };
+        let (heap, stack) = memory.split_at_mut(heap_length as usize);
+        let remote = [
+            (cast(memory_map.rw_data_address()).to_usize(), heap.len()),
+            (cast(memory_map.stack_address_low()).to_usize(), stack.len()),
+        ];
+
+        match linux_raw::vm_read_memory(self.child.pid, [heap, stack], remote) {
+            Ok(actual_length) if actual_length == length => {}
+            Ok(_) => return Err(Error::from_str("failed to fork: incomplete read")),
+            Err(error) => return Err(error),
+        }
+
+        let forked_memory = Arc::new(ForkedMemory {
+            shm,
+            heap_top,
+            heap_threshold,
+            heap_length,
+        });
+
+        *fork_base = Some(Arc::clone(&forked_memory));
+        Ok(forked_memory)
+    }
+
+    /// Loads the module of `parent` and copies over its whole state, sharing the guest's memory with it copy-on-write.
+    ///
+    /// The auxiliary data region is not shared and is copied eagerly.
+    pub(crate) fn load_forked_module(&mut self, parent: &Sandbox) -> Result<(), Error> {
+        let Some(ref module) = parent.module else {
+            return Err(Error::from_str("no module loaded into the sandbox"));
+        };
+
+        if parent.dynamic_paging_enabled {
+            return Err(Error::from_str("forking is not supported with dynamic paging"));
+        }
+
+        let forked_memory = parent.fork_base()?;
+        self.load_module_impl(module, Some(Arc::clone(&forked_memory)))?;
+
+        let (vmctx, parent_vmctx) = (self.vmctx(), parent.vmctx());
+        for (reg, parent_reg) in vmctx.regs.iter().zip(parent_vmctx.regs.iter()) {
+            reg.store(parent_reg.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+
+        vmctx.gas.store(parent_vmctx.gas.load(Ordering::Relaxed), Ordering::Relaxed);
+        vmctx
+            .basic_block_budget
+            .store(parent_vmctx.basic_block_budget.load(Ordering::Relaxed), Ordering::Relaxed);
+        vmctx
+            .program_counter
+            .store(parent_vmctx.program_counter.load(Ordering::Relaxed), Ordering::Relaxed);
+        vmctx
+            .next_program_counter
+            .store(parent_vmctx.next_program_counter.load(Ordering::Relaxed), Ordering::Relaxed);
+        vmctx
+            .next_native_program_counter
+            .store(parent_vmctx.next_native_program_counter.load(Ordering::Relaxed), Ordering::Relaxed);
+        vmctx.tmp_reg.store(parent_vmctx.tmp_reg.load(Ordering::Relaxed), Ordering::Relaxed);
+        vmctx.rip.store(parent_vmctx.rip.load(Ordering::Relaxed), Ordering::Relaxed);
+        vmctx.arg.store(parent_vmctx.arg.load(Ordering::Relaxed), Ordering::Relaxed);
+        vmctx.arg2.store(parent_vmctx.arg2.load(Ordering::Relaxed), Ordering::Relaxed);
+        vmctx.arg3.store(parent_vmctx.arg3.load(Ordering::Relaxed), Ordering::Relaxed);
+        unsafe {
+            *vmctx.heap_max_size.get() = *parent_vmctx.heap_max_size.get();
+        }
+
+        let aux_data_address = cast(self.aux_data_address).to_usize();
+        let aux_data_range = aux_data_address..aux_data_address + cast(module.memory_map().aux_data_size()).to_usize();
+        self.memory_mmap.as_slice_mut()[aux_data_range.clone()].copy_from_slice(&parent.memory_mmap.as_slice()[aux_data_range]);
+        if parent.aux_data_length != self.aux_data_length {
+            super::Sandbox::set_accessible_aux_size(self, parent.aux_data_length)?;
+        }
+
+        self.state = parent.state;
+        self.is_program_counter_valid = parent.is_program_counter_valid;
+        self.next_program_counter = parent.next_program_counter;
+        self.next_program_counter_changed = parent.next_program_counter_changed;
+        self.budget_before_force_stop = parent.budget_before_force_stop;
+
+        // Our memory is now exactly the same as the parent's copy, so it can be reused if we get forked too.
+        *self.fork_base.lock() = Some(forked_memory);
+        Ok(())
+    }
+
+    /// Forgets the copy of the guest's memory to fork from, since the memory might be about to change.
+    fn invalidate_fork_base(&mut self) {
+        *self.fork_base.lock() = None;
+    }
+
     #[inline]
     fn vmctx(&self) -> &VmCtx {
         unsafe { &*self.vmctx_mmap.as_ptr().cast::<VmCtx>() }
@@ -2250,13 +2573,13 @@ impl Sandbox {
         let is_out_of_gas = match compiled_module.bitness {
             Bitness::B32 => crate::compiler::ArchVisitor::<Self, B32>::on_signal_trap(
                 compiled_module,
-                self.gas_metering.is_some(),
+                self.gas_metering,
                 machine_code_offset,
                 self.vmctx(),
             ),
             Bitness::B64 => crate::compiler::ArchVisitor::<Self, B64>::on_signal_trap(
                 compiled_module,
-                self.gas_metering.is_some(),
+                self.gas_metering,
                 machine_code_offset,
                 self.vmctx(),
             ),
@@ -2271,9 +2594,64 @@ impl Sandbox {
         }
     }
 
+    /// Returns the range of `memory_mmap` corresponding to the given guest memory, if it's all accessible through it.
+    ///
+    /// With dynamic paging all of the guest memory lives there, otherwise only the auxiliary data region does.
+    fn shared_memory_range(&self, address: u32, length: u32) -> Option<core::ops::Range<usize>> {
+        let address_end = u64::from(address) + u64::from(length);
+        if self.dynamic_paging_enabled {
+            let module = self.module.as_ref().unwrap();
+            if !self.page_set.contains(page_range(module, address, length)) {
+                return None;
+            }
+        } else if address < self.aux_data_address || address_end > u64::from(self.aux_data_address) + u64::from(self.aux_data_length) {
+            return None;
+        }
+
+        Some(address as usize..address_end as usize)
+    }
+
+    /// Forces the guest to stop at the start of the next basic block by overwriting its basic block budget.
+    fn force_stop(&mut self) {
+        let budget = self
+            .vmctx()
+            .basic_block_budget
+            .swap(BASIC_BLOCK_BUDGET_FORCED_STOP, Ordering::Relaxed);
+        self.budget_before_force_stop = Some(match self.budget_before_force_stop {
+            // The guest might have executed some basic blocks since the last time we've overwritten the budget.
+            Some(previous_budget) if budget <= BASIC_BLOCK_BUDGET_FORCED_STOP / 2 => {
+                previous_budget - (BASIC_BLOCK_BUDGET_FORCED_STOP - budget)
+            }
+            _ => budget,
+        });
+    }
+
+    /// Restores the basic block budget if it was overwritten to force the guest to stop.
+    ///
+    /// Returns whether it was overwritten.
+    fn restore_budget_after_force_stop(&mut self) -> bool {
+        let Some(previous_budget) = self.budget_before_force_stop.take() else {
+            return false;
+        };
+
+        let budget = self.vmctx().basic_block_budget.load(Ordering::Relaxed);
+        let budget = if budget <= BASIC_BLOCK_BUDGET_FORCED_STOP / 2 {
+            previous_budget - (BASIC_BLOCK_BUDGET_FORCED_STOP - budget)
+        } else {
+            budget
+        };
+
+        self.vmctx().basic_block_budget.store(budget, Ordering::Relaxed);
+        true
+    }
+
+    fn wait(&mut self) -> Result<Interrupt, Error> {
+        self.wait_until(None, None)
+    }
+
     #[inline(never)]
     #[cold]
-    fn wait(&mut self) -> Result<Interrupt, Error> {
+    fn wait_until(&mut self, deadline: Option<Instant>, interrupt_state: Option<&InterruptState>) -> Result<Interrupt, Error> {
         use crate::sandbox::Sandbox;
 
         'outer: loop {
@@ -2392,8 +2770,25 @@ impl Sandbox {
                 }
             }
 
+            let mut timeout = Duration::from_millis(100);
+            let mut should_stop = interrupt_state.is_some_and(|state| state.is_interrupted());
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    should_stop = true;
+                } else {
+                    timeout = core::cmp::min(timeout, deadline - now);
+                }
+            }
+
+            if should_stop {
+                // Keep on doing this until the guest stops, since the guest doesn't update the budget atomically.
+                self.force_stop();
+                timeout = Duration::from_millis(1);
+            }
+
             self.count_futex_wait += 1;
-            match linux_raw::sys_futex_wait(&self.vmctx().futex, VMCTX_FUTEX_BUSY, Some(Duration::from_millis(100))) {
+            match linux_raw::sys_futex_wait(&self.vmctx().futex, VMCTX_FUTEX_BUSY, Some(timeout)) {
                 Ok(()) => continue,
                 Err(error) if error.errno() == linux_raw::EAGAIN || error.errno() == linux_raw::EINTR => continue,
                 Err(error) if error.errno() == linux_raw::ETIMEDOUT => {
@@ -0,0 +1,248 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use polkavm_common::abi::MemoryMap;
+use polkavm_common::cast::cast;
+use polkavm_common::hasher::Hash;
+use polkavm_common::program::Reg;
+use polkavm_common::writer::Writer;
+
+use crate::error::{bail, bail_static, Error};
+use crate::utils::Reader;
+use crate::{Gas, ProgramCounter, RegValue};
+
+/// The magic bytes with which every serialized snapshot starts.
+const SNAPSHOT_MAGIC: [u8; 4] = [b'P', b'V', b'S', b'\0'];
+
+/// The current version of the serialized snapshot format.
+const SNAPSHOT_VERSION_V1: u8 = 1;
+
+const FLAG_DYNAMIC_PAGING: u8 = 1 << 0;
+const FLAG_HAS_NEXT_PROGRAM_COUNTER: u8 = 1 << 1;
+const FLAG_IS_64_BIT: u8 = 1 << 2;
+
+const PAGE_KIND_ZERO: u8 = 0;
+const PAGE_KIND_DATA: u8 = 1;
+
+/// The layout of the memory of the instance from which a [`Snapshot`] was taken.
+///
+/// Together with the page size these fully determine the memory map.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct SnapshotMemoryMap {
+    pub(crate) ro_data_size: u32,
+    pub(crate) rw_data_size: u32,
+    pub(crate) stack_size: u32,
+    pub(crate) aux_data_size: u32,
+}
+
+impl SnapshotMemoryMap {
+    pub(crate) fn new(map: &MemoryMap) -> Self {
+        SnapshotMemoryMap {
+            ro_data_size: map.ro_data_size(),
+            rw_data_size: map.rw_data_size(),
+            stack_size: map.stack_size(),
+            aux_data_size: map.aux_data_size(),
+        }
+    }
+}
+
+/// A single page of guest memory captured in a [`Snapshot`].
+#[derive(Clone)]
+pub(crate) struct SnapshotPage {
+    pub(crate) address: u32,
+    /// The contents of the page, or `None` if the page is filled with zeros.
+    pub(crate) data: Option<Box<[u8]>>,
+}
+
+/// A snapshot of an instance's state.
+///
+/// Created with [`RawInstance::snapshot`](crate::RawInstance::snapshot) and can be
+/// applied back with [`RawInstance::restore`](crate::RawInstance::restore).
+///
+/// A snapshot can be serialized with [`Snapshot::to_vec`] and loaded back with [`Snapshot::parse`],
+/// e.g. to suspend an instance and resume it later in another process.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub(crate) program_hash: Hash,
+    pub(crate) memory_map: SnapshotMemoryMap,
+    pub(crate) regs: [RegValue; Reg::ALL.len()],
+    pub(crate) gas: Gas,
+    pub(crate) next_program_counter: Option<ProgramCounter>,
+    pub(crate) heap_size: u32,
+    pub(crate) accessible_aux_size: u32,
+    pub(crate) page_size: u32,
+    pub(crate) is_dynamic_paging: bool,
+    pub(crate) is_64_bit: bool,
+    pub(crate) pages: Vec<SnapshotPage>,
+}
+
+impl Snapshot {
+    /// Gets the value of a given register at the time the snapshot was taken.
+    pub fn reg(&self, reg: Reg) -> RegValue {
+        self.regs[reg as usize]
+    }
+
+    /// Gets the amount of gas remaining at the time the snapshot was taken.
+    pub fn gas(&self) -> Gas {
+        self.gas
+    }
+
+    /// Gets the program counter at which the execution will resume after the snapshot is restored.
+    pub fn next_program_counter(&self) -> Option<ProgramCounter> {
+        self.next_program_counter
+    }
+
+    /// Gets the size of the heap at the time the snapshot was taken.
+    ///
+    /// Always zero when dynamic paging is enabled.
+    pub fn heap_size(&self) -> u32 {
+        self.heap_size
+    }
+
+    /// Gets the number of non-zero memory pages stored in the snapshot.
+    pub fn dirty_page_count(&self) -> usize {
+        self.pages.iter().filter(|page| page.data.is_some()).count()
+    }
+
+    /// Serializes the snapshot.
+    ///
+    /// The format is versioned and stable; it can be loaded back with [`Snapshot::parse`].
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut writer = Writer::new(&mut output);
+
+        let mut flags = 0;
+        if self.is_dynamic_paging {
+            flags |= FLAG_DYNAMIC_PAGING;
+        }
+        if self.next_program_counter.is_some() {
+            flags |= FLAG_HAS_NEXT_PROGRAM_COUNTER;
+        }
+        if self.is_64_bit {
+            flags |= FLAG_IS_64_BIT;
+        }
+
+        writer.push_raw_bytes(&SNAPSHOT_MAGIC);
+        writer.push_byte(SNAPSHOT_VERSION_V1);
+        writer.push_byte(flags);
+        writer.push_u32(self.page_size);
+        writer.push_raw_bytes(&self.program_hash.0);
+        writer.push_u32(self.memory_map.ro_data_size);
+        writer.push_u32(self.memory_map.rw_data_size);
+        writer.push_u32(self.memory_map.stack_size);
+        writer.push_u32(self.memory_map.aux_data_size);
+        for value in self.regs {
+            writer.push_raw_bytes(&value.to_le_bytes());
+        }
+        writer.push_raw_bytes(&self.gas.to_le_bytes());
+        writer.push_u32(self.next_program_counter.map_or(0, |pc| pc.0));
+        writer.push_u32(self.heap_size);
+        writer.push_u32(self.accessible_aux_size);
+        writer.push_u32(cast(self.pages.len()).assert_always_fits_in_u32());
+        for page in &self.pages {
+            writer.push_u32(page.address);
+            if let Some(ref data) = page.data {
+                writer.push_byte(PAGE_KIND_DATA);
+                writer.push_raw_bytes(data);
+            } else {
+                writer.push_byte(PAGE_KIND_ZERO);
+            }
+        }
+
+        output
+    }
+
+    /// Parses a snapshot previously serialized with [`Snapshot::to_vec`].
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes, "failed to parse snapshot: unexpected end of data");
+        if reader.read_slice(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            bail_static!("failed to parse snapshot: missing magic bytes");
+        }
+
+        let version = reader.read_u8()?;
+        if version != SNAPSHOT_VERSION_V1 {
+            bail!("failed to parse snapshot: unsupported version: {version}");
+        }
+
+        let flags = reader.read_u8()?;
+        if flags & !(FLAG_DYNAMIC_PAGING | FLAG_HAS_NEXT_PROGRAM_COUNTER | FLAG_IS_64_BIT) != 0 {
+            bail!("failed to parse snapshot: unsupported flags: 0x{flags:x}");
+        }
+
+        let page_size = reader.read_u32()?;
+        if page_size == 0 || !page_size.is_power_of_two() {
+            bail!("failed to parse snapshot: invalid page size: {page_size}");
+        }
+
+        let program_hash = Hash(reader.read_slice(32)?.try_into().unwrap());
+        let memory_map = SnapshotMemoryMap {
+            ro_data_size: reader.read_u32()?,
+            rw_data_size: reader.read_u32()?,
+            stack_size: reader.read_u32()?,
+            aux_data_size: reader.read_u32()?,
+        };
+
+        let mut regs = [0; Reg::ALL.len()];
+        for value in &mut regs {
+            *value = reader.read_u64()?;
+        }
+
+        let gas = cast(reader.read_u64()?).to_signed();
+        let next_program_counter = reader.read_u32()?;
+        let next_program_counter = if flags & FLAG_HAS_NEXT_PROGRAM_COUNTER != 0 {
+            Some(ProgramCounter(next_program_counter))
+        } else {
+            None
+        };
+
+        let heap_size = reader.read_u32()?;
+        let accessible_aux_size = reader.read_u32()?;
+        let page_count = reader.read_u32()?;
+        let mut pages = Vec::new();
+        for _ in 0..page_count {
+            let address = reader.read_u32()?;
+            if address < 0x10000 || address & (page_size - 1) != 0 {
+                bail!("failed to parse snapshot: invalid page address: 0x{address:x}");
+            }
+
+            let data = match reader.read_u8()? {
+                PAGE_KIND_ZERO => None,
+                PAGE_KIND_DATA => Some(reader.read_slice(cast(page_size).to_usize())?.into()),
+                kind => bail!("failed to parse snapshot: invalid page kind: {kind}"),
+            };
+
+            pages.push(SnapshotPage { address, data });
+        }
+
+        if reader.remaining() != 0 {
+            bail_static!("failed to parse snapshot: trailing data");
+        }
+
+        Ok(Snapshot {
+            program_hash,
+            memory_map,
+            regs,
+            gas,
+            next_program_counter,
+            heap_size,
+            accessible_aux_size,
+            page_size,
+            is_dynamic_paging: flags & FLAG_DYNAMIC_PAGING != 0,
+            is_64_bit: flags & FLAG_IS_64_BIT != 0,
+            pages,
+        })
+    }
+}
+
+impl core::fmt::Debug for Snapshot {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        fmt.debug_struct("Snapshot")
+            .field("regs", &self.regs)
+            .field("gas", &self.gas)
+            .field("next_program_counter", &self.next_program_counter)
+            .field("heap_size", &self.heap_size)
+            .field("accessible_aux_size", &self.accessible_aux_size)
+            .field("page_count", &self.pages.len())
+            .finish_non_exhaustive()
+    }
+}
@@ -1,8 +1,10 @@
 use crate::mutex::Mutex;
 use crate::{
-    BackendKind, CallError, Caller, Config, Engine, GasMeteringKind, InterruptKind, Linker, MemoryAccessError, Module, ModuleConfig,
-    ProgramBlob, ProgramCounter, Reg, Segfault,
+    BackendKind, CallError, Caller, Config, Engine, Gas, GasMeteringKind, HostFuture, InstanceLimits, InterruptKind, LimitKind, Linker,
+    MemoryAccessError, MemoryDiagnosticKind, MemoryUsage, Module, ModuleConfig, NotEnoughGasError, ProgramBlob, ProgramCounter, Reg,
+    Segfault, Snapshot, TypedHostFuture, WatchpointKind,
 };
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::format;
 use alloc::string::{String, ToString};
@@ -12,7 +14,7 @@ use alloc::vec::Vec;
 use polkavm_common::abi::MemoryMapBuilder;
 use polkavm_common::program::{asm, DefaultInstructionSet};
 use polkavm_common::program::{BlobLen, Reg::*};
-use polkavm_common::utils::align_to_next_page_u32;
+use polkavm_common::utils::{align_to_next_page_u32, ArcBytes};
 use polkavm_common::writer::ProgramBlobBuilder;
 
 use paste::paste;
@@ -268,6 +270,55 @@ fn fallback_hostcall_handler_works(config: Config) {
     assert_eq!(result, 111);
 }
 
+fn guest_panic_is_reported(config: Config) {
+    let _ = env_logger::try_init();
+
+    let memory_map = MemoryMapBuilder::new(0x4000).ro_data_size(0x4000).build().unwrap();
+    let address = memory_map.ro_data_address();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_ro_data_size(0x4000);
+    builder.set_ro_data(b"assertion failedsrc/main.rs".to_vec());
+    builder.add_export_by_basic_block(0, b"main");
+    builder.add_import(crate::GUEST_PANIC_IMPORT.as_bytes());
+    builder.set_code(
+        &[
+            asm::load_imm(A0, address),
+            asm::load_imm(A1, 16),
+            asm::load_imm(A2, address + 16),
+            asm::load_imm(A3, 11),
+            asm::load_imm(A4, 42),
+            asm::load_imm(A5, 5),
+            asm::ecalli(0),
+            asm::trap(),
+        ],
+        &[],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let module = Module::from_blob(&engine, &Default::default(), blob).unwrap();
+    let linker: Linker = Linker::new();
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
+    let mut instance = instance_pre.instantiate().unwrap();
+    match instance.call_typed(&mut (), "main", ()) {
+        Err(CallError::GuestPanic(panic)) => {
+            assert_eq!(panic.message, "assertion failed");
+            assert_eq!(panic.file, "src/main.rs");
+            assert_eq!(panic.line, 42);
+            assert_eq!(panic.column, 5);
+            assert_eq!(panic.to_string(), "guest panicked at src/main.rs:42:5: assertion failed");
+        }
+        result => panic!("unexpected result: {result:?}"),
+    }
+
+    // The import can still be overridden.
+    let mut linker: Linker = Linker::new();
+    linker.define_typed(crate::GUEST_PANIC_IMPORT, |_: Caller<()>| {}).unwrap();
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
+    let mut instance = instance_pre.instantiate().unwrap();
+    assert!(matches!(instance.call_typed(&mut (), "main", ()), Err(CallError::Trap)));
+}
+
 macro_rules! match_interrupt {
     ($interrupt:expr, $pattern:pat) => {
         let i = $interrupt;
@@ -2426,648 +2477,2507 @@ fn access_memory_from_host(config: Config) {
     assert!(instance.zero_memory(0xffffffff, 0).is_ok());
 }
 
-fn sbrk_knob_works(config: Config) {
+fn snapshot_and_restore(config: Config) {
     let _ = env_logger::try_init();
     let engine = Engine::new(&config).unwrap();
     let page_size = get_native_page_size() as u32;
+    let rw_address = MemoryMapBuilder::new(page_size).rw_data_size(1).build().unwrap().rw_data_address();
+
     let mut builder = ProgramBlobBuilder::new();
+    builder.set_rw_data_size(1);
+    builder.set_stack_size(1);
     builder.add_export_by_basic_block(0, b"main");
-    builder.set_code(&[asm::sbrk(Reg::A0, Reg::A0), asm::ret()], &[]);
+    builder.set_code(
+        &[
+            asm::load_i32(Reg::A1, rw_address),
+            asm::add_32(Reg::A1, Reg::A1, Reg::A0),
+            asm::store_u32(Reg::A1, rw_address),
+            asm::ecalli(0),
+            asm::ret(),
+        ],
+        &[],
+    );
 
     let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    assert_eq!(module.memory_map().rw_data_address(), rw_address);
+    let offsets: Vec<_> = module
+        .blob()
+        .instructions(DefaultInstructionSet::default())
+        .map(|inst| inst.offset)
+        .collect();
 
-    for sbrk_allowed in [true, false] {
-        let mut module_config: ModuleConfig = ModuleConfig::new();
-        module_config.set_page_size(page_size);
-        module_config.set_allow_sbrk(sbrk_allowed);
-        module_config.set_gas_metering(Some(GasMeteringKind::Sync));
-        let module = Module::from_blob(&engine, &module_config, blob.clone()).unwrap();
+    let heap_address = rw_address + page_size;
+    let stack_address = module.memory_map().stack_address_low();
 
-        let mut instance = module.instantiate().unwrap();
-        instance.set_reg(Reg::A0, 0);
-        instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+    let mut instance = module.instantiate().unwrap();
+    instance.write_u32(rw_address, 10).unwrap();
+    instance.prepare_call_untyped(offsets[0], &[5]);
+    match_interrupt!(instance.run().unwrap(), InterruptKind::Ecalli(0));
+    assert_eq!(instance.read_u32(rw_address).unwrap(), 15);
+    instance.sbrk(page_size).unwrap().unwrap();
+    instance.write_u32(heap_address, 0x12345678).unwrap();
+    instance.write_u32(stack_address, 0xaabbccdd).unwrap();
+
+    let snapshot = instance.snapshot().unwrap();
+    assert_eq!(snapshot.reg(Reg::A1), 15);
+    assert_eq!(snapshot.heap_size(), page_size);
+    assert_eq!(snapshot.next_program_counter(), Some(offsets[4]));
+
+    instance.write_u32(rw_address, 1000).unwrap();
+    instance.sbrk(page_size * 4).unwrap().unwrap();
+    instance.write_u32(heap_address, 0).unwrap();
+    instance.write_u32(stack_address, 0).unwrap();
+    instance.set_reg(Reg::A1, 0);
+    instance.set_next_program_counter(offsets[0]);
 
-        instance.set_gas(5);
-        instance.set_next_program_counter(ProgramCounter(0));
+    instance.restore(&snapshot).unwrap();
+    assert_eq!(instance.read_u32(rw_address).unwrap(), 15);
+    assert_eq!(instance.read_u32(heap_address).unwrap(), 0x12345678);
+    assert_eq!(instance.read_u32(stack_address).unwrap(), 0xaabbccdd);
+    assert_eq!(instance.heap_size(), page_size);
+    assert_eq!(instance.reg(Reg::A1), 15);
+    assert_eq!(instance.next_program_counter(), Some(offsets[4]));
+    match_interrupt!(instance.run().unwrap(), InterruptKind::Finished);
 
-        #[allow(clippy::branches_sharing_code)]
-        if sbrk_allowed {
-            match_interrupt!(instance.run().unwrap(), InterruptKind::Finished);
-            assert_eq!(instance.gas(), 3);
-        } else {
-            match_interrupt!(instance.run().unwrap(), InterruptKind::Trap);
-            assert_eq!(instance.program_counter(), Some(ProgramCounter(0)));
-            assert_eq!(instance.gas(), 4);
-        }
-    }
+    // The snapshot can also be restored into a completely fresh instance.
+    let mut instance = module.instantiate().unwrap();
+    instance.restore(&snapshot).unwrap();
+    assert_eq!(instance.read_u32(rw_address).unwrap(), 15);
+    assert_eq!(instance.read_u32(heap_address).unwrap(), 0x12345678);
+    assert_eq!(instance.heap_size(), page_size);
+    match_interrupt!(instance.run().unwrap(), InterruptKind::Finished);
 }
 
-struct TestInstance {
-    module: crate::Module,
-    instance: crate::Instance,
-}
+fn snapshot_serialization(config: Config) {
+    let _ = env_logger::try_init();
+    let page_size = get_native_page_size() as u32;
+    let rw_address = MemoryMapBuilder::new(page_size).rw_data_size(1).build().unwrap().rw_data_address();
 
-impl TestInstance {
-    fn new(config: &Config, elf: &'static [u8], optimize: bool) -> Self {
-        let _ = env_logger::try_init();
-        let blob = get_blob_impl(optimize, false, elf);
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_rw_data_size(1);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            asm::store_imm_u32(rw_address, 0x12345678),
+            asm::ecalli(0),
+            asm::load_i32(Reg::A0, rw_address),
+            asm::ret(),
+        ],
+        &[],
+    );
 
-        let engine = Engine::new(config).unwrap();
-        let module = Module::from_blob(&engine, &Default::default(), blob).unwrap();
-        let mut linker = Linker::new();
-        linker
-            .define_typed("multiply_by_2", |_caller: Caller<()>, value: u32| -> u32 { value * 2 })
-            .unwrap();
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    module_config.set_gas_metering(Some(GasMeteringKind::Sync));
 
-        linker
-            .define_typed("identity", |_caller: Caller<()>, value: u32| -> u32 { value })
-            .unwrap();
+    let bytes = {
+        let engine = Engine::new(&config).unwrap();
+        let module = Module::from_blob(&engine, &module_config, blob.clone()).unwrap();
+        let mut instance = module.instantiate().unwrap();
+        instance.set_gas(1000);
+        instance.prepare_call_untyped(ProgramCounter(0), &[]);
+        match_interrupt!(instance.run().unwrap(), InterruptKind::Ecalli(0));
+        instance.write_u32(rw_address + 4, 0xaabbccdd).unwrap();
+        instance.snapshot().unwrap().to_vec()
+    };
 
-        linker
-            .define_untyped("multiply_all_input_registers", |caller: Caller<()>| {
-                let mut value = 1;
+    // Every truncated snapshot must be rejected.
+    for length in 0..bytes.len() {
+        assert!(Snapshot::parse(&bytes[..length]).is_err());
+    }
 
-                use Reg as R;
-                for reg in [R::A0, R::A1, R::A2, R::A3, R::A4, R::A5, R::T0, R::T1, R::T2] {
-                    value *= caller.instance.reg(reg);
-                }
+    let mut bytes_with_bad_magic = bytes.clone();
+    bytes_with_bad_magic[0] = b'X';
+    assert!(Snapshot::parse(&bytes_with_bad_magic).is_err());
 
-                caller.instance.set_reg(Reg::A0, value);
-                Ok(())
-            })
-            .unwrap();
+    let snapshot = Snapshot::parse(&bytes).unwrap();
+    assert_eq!(snapshot.to_vec(), bytes);
 
-        linker
-            .define_typed("call_sbrk_indirectly_impl", |caller: Caller<()>, size: u32| -> u32 {
-                caller.instance.sbrk(size).unwrap().unwrap_or(0)
-            })
-            .unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let module = Module::from_blob(&engine, &module_config, blob.clone()).unwrap();
+    let mut instance = module.instantiate().unwrap();
+    instance.restore(&snapshot).unwrap();
+    assert_eq!(instance.gas(), snapshot.gas());
+    assert_eq!(instance.read_u32(rw_address + 4).unwrap(), 0xaabbccdd);
+    match_interrupt!(instance.run().unwrap(), InterruptKind::Finished);
+    assert_eq!(instance.reg(Reg::A0), 0x12345678);
 
-        linker
-            .define_untyped("return_tuple_u32", |caller: Caller<()>| {
-                caller.instance.set_reg(Reg::A0, 0x12345678);
-                caller.instance.set_reg(Reg::A1, 0x9abcdefe);
-                Ok(())
-            })
-            .unwrap();
+    // The snapshot can't be restored into an instance with a different memory map...
+    let mut module_config_with_aux_data = module_config.clone();
+    module_config_with_aux_data.set_aux_data_size(page_size);
+    let module = Module::from_blob(&engine, &module_config_with_aux_data, blob).unwrap();
+    let mut instance = module.instantiate().unwrap();
+    assert!(instance.restore(&snapshot).is_err());
 
-        linker
-            .define_untyped("return_tuple_u64", |caller: Caller<()>| {
-                caller.instance.set_reg(Reg::A0, 0x123456789abcdefe);
-                caller.instance.set_reg(Reg::A1, 0x1122334455667788);
-                Ok(())
-            })
-            .unwrap();
+    // ...nor into an instance of a different program.
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_rw_data_size(1);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::ecalli(0), asm::ret()], &[]);
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let mut instance = module.instantiate().unwrap();
+    assert!(instance.restore(&snapshot).is_err());
+}
 
-        linker
-            .define_untyped("return_tuple_usize", move |caller: Caller<()>| {
-                if caller.instance.is_64_bit() {
-                    caller.instance.set_reg(Reg::A0, 0x123456789abcdefe);
-                    caller.instance.set_reg(Reg::A1, 0x1122334455667788);
-                } else {
-                    caller.instance.set_reg(Reg::A0, 0x12345678);
-                    caller.instance.set_reg(Reg::A1, 0x9abcdefe);
-                }
+fn fork_instance(config: Config) {
+    let _ = env_logger::try_init();
+    let engine = Engine::new(&config).unwrap();
+    let page_size = get_native_page_size() as u32;
+    let rw_address = MemoryMapBuilder::new(page_size).rw_data_size(1).build().unwrap().rw_data_address();
 
-                Ok(())
-            })
-            .unwrap();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_rw_data_size(1);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            asm::store_imm_u32(rw_address, 1),
+            asm::ecalli(0),
+            asm::load_i32(Reg::A1, rw_address),
+            asm::add_32(Reg::A0, Reg::A0, Reg::A1),
+            asm::ret(),
+        ],
+        &[],
+    );
 
-        let instance_pre = linker.instantiate_pre(&module).unwrap();
-        let instance = instance_pre.instantiate().unwrap();
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
 
-        TestInstance { module, instance }
+    let mut instance = module.instantiate().unwrap();
+    instance.prepare_call_untyped(ProgramCounter(0), &[100]);
+    match_interrupt!(instance.run().unwrap(), InterruptKind::Ecalli(0));
+    let heap_address = instance.sbrk(page_size).unwrap().unwrap();
+    instance.write_u32(heap_address, 0x1234).unwrap();
+
+    let mut forks: Vec<_> = (0..3).map(|_| instance.fork().unwrap()).collect();
+    forks.push(forks[0].fork().unwrap());
+    instance.write_u32(heap_address, 0x5678).unwrap();
+    forks.push(instance.fork().unwrap());
+
+    for (index, fork) in forks.iter_mut().enumerate() {
+        let expected = if index < 4 { 0x1234 } else { 0x5678 };
+        assert_eq!(fork.read_u32(heap_address).unwrap(), expected);
+        assert_eq!(fork.heap_size(), instance.heap_size());
+        fork.write_u32(rw_address, index as u32 * 10).unwrap();
     }
 
-    pub fn call<FnArgs, FnResult>(&mut self, name: &str, args: FnArgs) -> Result<FnResult, crate::CallError>
-    where
-        FnArgs: crate::linker::FuncArgs,
-        FnResult: crate::linker::FuncResult,
-    {
-        self.instance.call_typed_and_get_result::<FnResult, FnArgs>(&mut (), name, args)
+    match_interrupt!(instance.run().unwrap(), InterruptKind::Finished);
+    assert_eq!(instance.reg(Reg::A0), 101);
+
+    for (index, fork) in forks.iter_mut().enumerate() {
+        match_interrupt!(fork.run().unwrap(), InterruptKind::Finished);
+        assert_eq!(fork.reg(Reg::A0), 100 + index as u64 * 10);
     }
-}
 
-fn test_blob_basic_test(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    assert_eq!(i.call::<(), u32>("push_one_to_global_vec", ()).unwrap(), 1);
-    assert_eq!(i.call::<(), u32>("push_one_to_global_vec", ()).unwrap(), 2);
-    assert_eq!(i.call::<(), u32>("push_one_to_global_vec", ()).unwrap(), 3);
-}
+    assert_eq!(instance.read_u32(rw_address).unwrap(), 1);
+    assert_eq!(instance.read_u32(heap_address).unwrap(), 0x5678);
 
-fn test_blob_atomic_fetch_add(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (1,)).unwrap(), 0);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (1,)).unwrap(), 1);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (1,)).unwrap(), 2);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (0,)).unwrap(), 3);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (0,)).unwrap(), 3);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (2,)).unwrap(), 3);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (0,)).unwrap(), 5);
+    forks[1].reset_memory().unwrap();
+    assert_eq!(forks[1].read_u32(rw_address).unwrap(), 0);
+    assert_eq!(forks[1].heap_size(), 0);
+    assert_eq!(forks[2].read_u32(rw_address).unwrap(), 20);
+    assert_eq!(forks[2].read_u32(heap_address).unwrap(), 0x1234);
 }
 
-fn test_blob_atomic_fetch_swap(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_swap", (10,)).unwrap(), 0);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_swap", (100,)).unwrap(), 10);
-    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_swap", (1000,)).unwrap(), 100);
-}
+#[cfg(not(feature = "std"))]
+fn fork_instance_with_limits(_config: Config) {}
 
-fn test_blob_atomic_fetch_minmax(config: Config, optimize: bool, is_64_bit: bool) {
-    use core::cmp::{max, min};
+#[cfg(feature = "std")]
+fn fork_instance_with_limits(config: Config) {
+    let _ = env_logger::try_init();
+    let engine = Engine::new(&config).unwrap();
+    let page_size = get_native_page_size() as u32;
 
-    fn maxu(a: i32, b: i32) -> i32 {
-        max(a as u32, b as u32) as i32
-    }
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::add_imm_32(A0, A0, 1), asm::jump(0)], &[]);
 
-    fn minu(a: i32, b: i32) -> i32 {
-        min(a as u32, b as u32) as i32
-    }
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    module_config.set_execution_limits(true);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
 
-    #[allow(clippy::type_complexity)]
-    let list: [(&str, fn(i32, i32) -> i32); 4] = [
-        ("atomic_fetch_max_signed", max),
-        ("atomic_fetch_min_signed", min),
-        ("atomic_fetch_max_unsigned", maxu),
-        ("atomic_fetch_min_unsigned", minu),
-    ];
+    let mut instance = module.instantiate().unwrap();
+    let parent_handle = instance.interrupt_handle().unwrap();
+    let memory_limit = instance.memory_usage().total() + u64::from(page_size);
+    let deadline = std::time::Instant::now() + core::time::Duration::from_secs(3600);
+    let mut limits = InstanceLimits::new();
+    limits
+        .set_basic_block_limit(Some(10))
+        .set_memory_limit(Some(memory_limit))
+        .set_deadline(Some(deadline));
+    instance.set_limits(limits).unwrap();
+
+    instance.prepare_call_untyped(ProgramCounter(0), &[]);
+    match_interrupt!(instance.run().unwrap(), InterruptKind::LimitReached(LimitKind::BasicBlocks));
+    assert_eq!(instance.reg(A0), 10);
+
+    let mut fork = instance.fork().unwrap();
+    assert_eq!(fork.limits().basic_block_limit(), Some(10));
+    assert_eq!(fork.limits().memory_limit(), Some(memory_limit));
+    assert_eq!(fork.limits().deadline(), Some(deadline));
+
+    // The fork has exactly as many basic blocks left as its parent; none.
+    assert_eq!(fork.basic_blocks_remaining(), Some(0));
+    match_interrupt!(fork.run().unwrap(), InterruptKind::LimitReached(LimitKind::BasicBlocks));
+    assert_eq!(fork.reg(A0), 10);
+
+    // The memory limit is enforced for the fork too.
+    assert!(fork.sbrk(page_size).unwrap().is_some());
+    assert!(fork.sbrk(page_size).unwrap().is_none());
+    assert_eq!(fork.memory_usage().total(), memory_limit);
+
+    // The fork can't be interrupted through the parent's handle, only through its own.
+    parent_handle.interrupt();
+    let mut limits = fork.limits().clone();
+    limits.set_basic_block_limit(Some(3));
+    fork.set_limits(limits).unwrap();
+    match_interrupt!(fork.run().unwrap(), InterruptKind::LimitReached(LimitKind::BasicBlocks));
+    assert_eq!(fork.reg(A0), 13);
+
+    fork.interrupt_handle().unwrap().interrupt();
+    match_interrupt!(fork.run().unwrap(), InterruptKind::Interrupted);
+    match_interrupt!(instance.run().unwrap(), InterruptKind::Interrupted);
+
+    // A deadline which has passed is enforced for the fork too.
+    let mut limits = InstanceLimits::new();
+    limits.set_deadline(Some(std::time::Instant::now()));
+    instance.set_limits(limits).unwrap();
+    let mut fork = instance.fork().unwrap();
+    assert_eq!(fork.limits().basic_block_limit(), None);
+    assert_eq!(fork.basic_blocks_remaining(), None);
+    match_interrupt!(fork.run().unwrap(), InterruptKind::LimitReached(LimitKind::Deadline));
+    assert_eq!(fork.reg(A0), 10);
+}
 
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    for (name, cb) in list {
-        for a in [-10, 0, 10] {
-            for b in [-10, 0, 10] {
-                let new_value = cb(a, b);
+fn snapshot_and_restore_with_dynamic_paging(mut engine_config: Config) {
+    engine_config.set_allow_dynamic_paging(true);
+
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&engine_config).unwrap();
+    let page_size = get_native_page_size() as u32;
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::load_i32(Reg::A0, 0x10000), asm::ret()], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    module_config.set_dynamic_paging(true);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+
+    let mut instance = module.instantiate().unwrap();
+    instance.write_u32(0x10000, 0x1234).unwrap();
+    instance.zero_memory(0x10000 + page_size * 2, page_size).unwrap();
+    let top_page_address = 0_u32.wrapping_sub(page_size);
+    instance.write_u32(top_page_address, 0x5678).unwrap();
+    instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+    instance.set_next_program_counter(ProgramCounter(0));
+
+    let snapshot = instance.snapshot().unwrap();
+    assert_eq!(snapshot.dirty_page_count(), 2);
+
+    instance.free_pages(0x10000, page_size * 4).unwrap();
+    instance.write_u32(0x10000 + page_size, 1).unwrap();
+    instance.set_reg(Reg::RA, 0);
+
+    instance.restore(&snapshot).unwrap();
+    assert!(!instance.is_memory_accessible(0x10000 + page_size, 4, false));
+    assert!(instance.is_memory_accessible(0x10000 + page_size * 2, 4, false));
+    assert_eq!(instance.read_u32(0x10000 + page_size * 2).unwrap(), 0);
+    assert_eq!(instance.read_u32(top_page_address).unwrap(), 0x5678);
+    assert_eq!(instance.reg(Reg::RA), crate::RETURN_TO_HOST);
+    match_interrupt!(instance.run().unwrap(), InterruptKind::Finished);
+    assert_eq!(instance.reg(Reg::A0), 0x1234);
+}
+
+fn memory_slices(config: Config) {
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&config).unwrap();
+    let page_size = get_native_page_size() as u32;
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_ro_data(vec![1, 2, 3, 4]);
+    builder.set_ro_data_size(page_size);
+    builder.set_rw_data_size(page_size);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::ret()], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    module_config.set_aux_data_size(page_size);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let memory_map = module.memory_map().clone();
+    let mut instance = module.instantiate().unwrap();
+    instance.set_accessible_aux_size(page_size).unwrap();
+
+    let is_interpreter = config.backend() == Some(BackendKind::Interpreter);
+    let can_borrow_aux = !config.crosscheck();
+    let can_borrow_rw_data = is_interpreter && !config.crosscheck();
+
+    let rw_address = memory_map.rw_data_address();
+    let aux_address = memory_map.aux_data_address();
+    for (address, can_borrow) in [(rw_address, can_borrow_rw_data), (aux_address, can_borrow_aux)] {
+        instance.write_memory(address, &[1, 2, 3, 4]).unwrap();
+        let slice = instance.memory_slice(address, 4).unwrap();
+        assert_eq!(&*slice, &[1, 2, 3, 4]);
+        assert_eq!(matches!(slice, alloc::borrow::Cow::Borrowed(..)), can_borrow);
+
+        let mut slice = instance.memory_slice_mut(address + 1, 2).unwrap();
+        assert_eq!(slice.is_borrowed(), can_borrow);
+        slice.copy_from_slice(&[5, 6]);
+        core::mem::drop(slice);
+        assert_eq!(instance.read_memory(address, 4).unwrap(), vec![1, 5, 6, 4]);
+    }
+
+    assert_eq!(&*instance.memory_slice(memory_map.ro_data_address(), 4).unwrap(), &[1, 2, 3, 4]);
+    assert!(instance.memory_slice(memory_map.rw_data_address() - 4, 8).is_err());
+    assert!(instance.memory_slice_mut(memory_map.ro_data_address(), 4).is_err());
+    assert!(instance.memory_slice_mut(aux_address + page_size - 1, 2).is_err());
+    assert!(instance.memory_slice(0, 0).unwrap().is_empty());
+
+    #[cfg(target_endian = "little")]
+    {
+        #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+        #[repr(C)]
+        struct Pair {
+            a: u32,
+            b: u16,
+            c: [u8; 2],
+        }
+
+        // SAFETY: The struct is `repr(C)`, has no padding and every bit pattern is valid.
+        unsafe impl crate::Pod for Pair {}
+
+        let pairs = [
+            Pair { a: 1, b: 2, c: [3, 4] },
+            Pair {
+                a: 0x12345678,
+                b: 0xabcd,
+                c: [0xff, 0],
+            },
+        ];
+
+        instance.write_pod_slice(rw_address, &pairs).unwrap();
+        assert_eq!(instance.read_u32(rw_address + 8).unwrap(), 0x12345678);
+        assert_eq!(instance.read_pod::<Pair>(rw_address + 8).unwrap(), pairs[1]);
+        assert_eq!(instance.read_pod_slice::<Pair>(rw_address, 2).unwrap(), pairs);
+        assert_eq!(instance.read_pod::<[u16; 2]>(rw_address + 12).unwrap(), [0xabcd, 0x00ff]);
+        assert!(instance.read_pod_slice::<Pair>(rw_address, page_size).is_err());
+        assert!(instance.read_pod_slice::<u64>(rw_address, u32::MAX).is_err());
+
+        instance.write_pod(rw_address, &0x11223344_u32).unwrap();
+        assert_eq!(instance.read_memory(rw_address, 4).unwrap(), vec![0x44, 0x33, 0x22, 0x11]);
+    }
+}
+
+fn dynamic_paging_memory_slices(mut engine_config: Config) {
+    engine_config.set_allow_dynamic_paging(true);
+
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&engine_config).unwrap();
+    let page_size = get_native_page_size() as u32;
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::ret()], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    module_config.set_dynamic_paging(true);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let mut instance = module.instantiate().unwrap();
+
+    let is_interpreter = engine_config.backend() == Some(BackendKind::Interpreter);
+    let can_borrow = !engine_config.crosscheck();
+
+    assert!(instance.memory_slice(0x10000, 4).is_err());
+    assert!(instance.memory_slice_mut(0x10000, 4).is_err());
+
+    instance.write_memory(0x10000 + page_size - 2, &[1, 2, 3, 4]).unwrap();
+    let slice = instance.memory_slice(0x10000 + page_size - 2, 4).unwrap();
+    assert_eq!(&*slice, &[1, 2, 3, 4]);
+    // The interpreter keeps every page in a separate allocation.
+    assert_eq!(matches!(slice, alloc::borrow::Cow::Borrowed(..)), can_borrow && !is_interpreter);
+
+    let mut slice = instance.memory_slice_mut(0x10000 + page_size - 1, 1).unwrap();
+    assert_eq!(slice.is_borrowed(), can_borrow);
+    slice[0] = 0xff;
+    core::mem::drop(slice);
+
+    let mut slice = instance.memory_slice_mut(0x10000 + page_size - 2, 4).unwrap();
+    assert_eq!(slice.is_borrowed(), can_borrow && !is_interpreter);
+    assert_eq!(&*slice, &[1, 0xff, 3, 4]);
+    slice[3] = 0xee;
+    core::mem::drop(slice);
+
+    assert_eq!(instance.read_memory(0x10000 + page_size - 2, 4).unwrap(), vec![1, 0xff, 3, 0xee]);
+    assert!(instance.memory_slice(0x10000 + page_size * 2 - 2, 4).is_err());
+}
+
+#[cfg(feature = "std")]
+struct ScriptedConnection {
+    input: std::io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl std::io::Read for ScriptedConnection {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buffer)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Write for ScriptedConnection {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buffer)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl crate::debugger::DebuggerStream for ScriptedConnection {
+    fn set_nonblocking(&mut self, _value: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+fn gdb_packet(payload: &str) -> Vec<u8> {
+    let checksum = payload.bytes().fold(0_u8, |checksum, byte| checksum.wrapping_add(byte));
+    format!("${payload}#{checksum:02x}").into_bytes()
+}
+
+#[cfg(feature = "std")]
+fn gdb_replies(output: &[u8]) -> Vec<String> {
+    let mut replies = Vec::new();
+    let mut iter = output.iter().copied();
+    while let Some(byte) = iter.next() {
+        if byte != b'$' {
+            continue;
+        }
+
+        let payload: Vec<u8> = iter.by_ref().take_while(|&byte| byte != b'#').collect();
+        iter.by_ref().take(2).for_each(drop);
+        replies.push(String::from_utf8(payload).unwrap());
+    }
+
+    replies
+}
+
+#[cfg(feature = "std")]
+fn gdb_stub(config: Config) {
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&config).unwrap();
+    let page_size = get_native_page_size() as u32;
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_rw_data_size(page_size);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            asm::load_imm(A0, 1),
+            asm::load_imm(A1, 2),
+            asm::ecalli(0),
+            asm::add_32(A0, A0, A1),
+            asm::ret(),
+        ],
+        &[],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let offsets: Vec<_> = blob
+        .instructions(DefaultInstructionSet::default())
+        .map(|inst| inst.offset)
+        .collect();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    module_config.set_step_tracing(true);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let rw_address = module.memory_map().rw_data_address();
+
+    let mut instance = module.instantiate().unwrap();
+    assert!(crate::debugger::GdbStub::new(&mut instance).is_err());
+    instance.prepare_call_untyped(ProgramCounter(0), &[]);
+
+    let pc_hex = |pc: ProgramCounter| -> String {
+        use core::fmt::Write;
+        pc.0.to_le_bytes().iter().fold(String::new(), |mut output, byte| {
+            let _ = write!(output, "{byte:02x}");
+            output
+        })
+    };
+    let commands: Vec<(String, Option<String>)> = vec![
+        ("qSupported:swbreak+".into(), None),
+        ("QStartNoAckMode".into(), Some("OK".into())),
+        ("?".into(), Some("T05thread:01;".into())),
+        ("p20".into(), Some(pc_hex(offsets[0]))),
+        (format!("Z0,{:x},4", offsets[3].0), Some("OK".into())),
+        ("c".into(), Some("T05swbreak:;thread:01;".into())),
+        ("pa".into(), Some("01000000".into())),
+        ("pb".into(), Some("28000000".into())),
+        ("p20".into(), Some(pc_hex(offsets[3]))),
+        ("s".into(), Some("T05thread:01;".into())),
+        ("pa".into(), Some("29000000".into())),
+        ("p20".into(), Some(pc_hex(offsets[4]))),
+        ("Pb=05000000".into(), Some("OK".into())),
+        (format!("M{rw_address:x},4:78563412"), Some("OK".into())),
+        (format!("m{rw_address:x},4"), Some("78563412".into())),
+        ("m0,4".into(), Some("E14".into())),
+        (format!("z0,{:x},4", offsets[3].0), Some("OK".into())),
+        ("qRcmd,7768657265".into(), None),
+        ("qXfer:features:read:target.xml:0,1000".into(), None),
+        ("c".into(), Some("W00".into())),
+        ("k".into(), None),
+    ];
+
+    let mut input = Vec::new();
+    for (index, (command, _)) in commands.iter().enumerate() {
+        input.extend(gdb_packet(command));
+        if index < 2 {
+            // Acknowledge the replies sent before the no-ack mode was enabled.
+            input.push(b'+');
+        }
+    }
+
+    let mut connection = ScriptedConnection {
+        input: std::io::Cursor::new(input),
+        output: Vec::new(),
+    };
+
+    let mut stub = crate::debugger::GdbStub::new(&mut instance).unwrap();
+    stub.set_interrupt_handler(|instance, interruption| {
+        assert_eq!(*interruption, InterruptKind::Ecalli(0));
+        instance.set_reg(Reg::A1, 40);
+        true
+    });
+    stub.serve(&mut connection).unwrap();
+    core::mem::drop(stub);
+
+    let replies = gdb_replies(&connection.output);
+    assert_eq!(replies.len(), commands.len() - 1);
+    assert!(replies[0].starts_with("PacketSize="));
+    for ((command, expected), reply) in commands.iter().zip(replies.iter()) {
+        if let Some(expected) = expected {
+            assert_eq!(reply, expected, "unexpected reply to '{command}'");
+        }
+    }
+
+    let monitor_output = replies[17]
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(core::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect::<Vec<u8>>();
+    assert!(String::from_utf8(monitor_output).unwrap().contains("(no location available)"));
+    assert!(replies[18].starts_with("l<?xml"));
+    assert!(replies[18].contains("riscv:rv32"));
+
+    assert_eq!(instance.reg(Reg::A0), 41);
+    assert_eq!(instance.reg(Reg::A1), 5);
+    assert_eq!(instance.read_u32(rw_address).unwrap(), 0x12345678);
+}
+
+#[cfg(not(feature = "std"))]
+fn gdb_stub(_config: Config) {}
+
+#[cfg(feature = "std")]
+fn gdb_stub_interrupt(config: Config) {
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&config).unwrap();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::add_imm_32(A0, A0, 1), asm::jump(0)], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_step_tracing(true);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+
+    let mut instance = module.instantiate().unwrap();
+    instance.prepare_call_untyped(ProgramCounter(0), &[]);
+
+    let mut input = Vec::new();
+    input.extend(gdb_packet("QStartNoAckMode"));
+    input.push(b'+');
+    input.extend(gdb_packet("c"));
+    input.push(0x03);
+    input.extend(gdb_packet("k"));
+
+    let mut connection = ScriptedConnection {
+        input: std::io::Cursor::new(input),
+        output: Vec::new(),
+    };
+
+    let mut stub = crate::debugger::GdbStub::new(&mut instance).unwrap();
+    stub.serve(&mut connection).unwrap();
+    core::mem::drop(stub);
+
+    let replies = gdb_replies(&connection.output);
+    assert_eq!(replies, ["OK", "T02thread:01;"]);
+    assert!(instance.reg(Reg::A0) > 0);
+    assert!(instance.next_program_counter().is_some());
+}
+
+#[cfg(not(feature = "std"))]
+fn gdb_stub_interrupt(_config: Config) {}
+
+#[cfg(feature = "std")]
+fn profiler(config: Config) {
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&config).unwrap();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            // main:
+            asm::move_reg(S0, RA),
+            asm::load_imm_and_jump(RA, 2, 3),
+            asm::load_imm_and_jump(RA, 4, 3),
+            asm::move_reg(RA, S0),
+            asm::ret(),
+            // callee:
+            asm::add_imm_32(A0, A0, 1),
+            asm::ret(),
+        ],
+        &[1, 2],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let offsets: Vec<_> = blob
+        .instructions(DefaultInstructionSet::default())
+        .map(|inst| inst.offset)
+        .collect();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_step_tracing(true);
+    module_config.set_gas_metering(Some(GasMeteringKind::Sync));
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+
+    let mut profiler = crate::profiler::Profiler::new(&module).unwrap();
+    let mut instance = module.instantiate().unwrap();
+    instance.prepare_call_untyped(ProgramCounter(0), &[]);
+    instance.set_gas(1000);
+    loop {
+        let interrupt = instance.run().unwrap();
+        profiler.on_interrupt(&instance, &interrupt);
+        match interrupt {
+            InterruptKind::Step => continue,
+            InterruptKind::Finished => break,
+            interrupt => panic!("unexpected interrupt: {interrupt:?}"),
+        }
+    }
+
+    assert_eq!(instance.reg(Reg::A0), 2);
+
+    let profile = profiler.profile();
+    let total_instructions: u64 = profile.samples().iter().map(|sample| sample.instructions()).sum();
+    let total_gas: u64 = profile.samples().iter().map(|sample| sample.gas()).sum();
+    assert_eq!(total_instructions, 9);
+    assert_eq!(total_gas, (1000 - instance.gas()) as u64);
+
+    let call_sites: BTreeMap<ProgramCounter, u64> =
+        profile
+            .samples()
+            .iter()
+            .filter(|sample| sample.program_counters().len() == 2)
+            .fold(BTreeMap::new(), |mut map, sample| {
+                *map.entry(sample.program_counters()[0]).or_default() += sample.instructions();
+                map
+            });
+    assert_eq!(call_sites, [(offsets[1], 2), (offsets[2], 2)].into_iter().collect());
+    assert!(profile
+        .samples()
+        .iter()
+        .all(|sample| sample.frames().len() == sample.program_counters().len()));
+
+    let functions = profile.functions();
+    assert_eq!(functions.len(), 1);
+    assert_eq!(functions[0].name, "[unknown]");
+    assert_eq!(functions[0].total_instructions, 9);
+
+    let mut folded = Vec::new();
+    profile
+        .write_folded(&mut folded, crate::profiler::ProfileWeight::Instructions)
+        .unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    let mut folded_total = 0;
+    for line in folded.lines() {
+        let (stack, count) = line.rsplit_once(' ').unwrap();
+        assert!(stack == "[unknown]" || stack == "[unknown];[unknown]");
+        folded_total += count.parse::<u64>().unwrap();
+    }
+    assert_eq!(folded_total, 9);
+
+    let mut pprof = Vec::new();
+    profile.write_pprof(&mut pprof).unwrap();
+    assert!(pprof.starts_with(&[0x0a]));
+}
+
+#[cfg(not(feature = "std"))]
+fn profiler(_config: Config) {}
+
+#[cfg(feature = "std")]
+fn gas_report(config: Config) {
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&config).unwrap();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            asm::move_reg(S0, RA),
+            asm::load_imm_and_jump(RA, 2, 3),
+            asm::load_imm_and_jump(RA, 4, 3),
+            asm::move_reg(RA, S0),
+            asm::ret(),
+            asm::add_imm_32(A0, A0, 1),
+            asm::ret(),
+        ],
+        &[1, 2],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let offsets: Vec<_> = blob
+        .instructions(DefaultInstructionSet::default())
+        .map(|inst| inst.offset)
+        .collect();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_step_tracing(true);
+    module_config.set_gas_metering(Some(GasMeteringKind::Sync));
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+
+    let mut report = crate::gas_report::GasReport::new(&module);
+    let starts: Vec<_> = report.blocks().iter().map(|block| block.start()).collect();
+    assert_eq!(starts, [offsets[0], offsets[2], offsets[3], offsets[5]]);
+    for block in report.blocks() {
+        assert_eq!(Some(block.cost()), module.calculate_gas_cost_for(block.start()));
+        assert_eq!(block.executions(), 0);
+    }
+
+    let static_cost: Gas = report.blocks().iter().map(|block| block.cost()).sum();
+    let functions = report.by_function();
+    assert_eq!(functions.len(), 1);
+    assert_eq!(functions[0].blocks, 4);
+    assert_eq!(functions[0].static_cost, static_cost);
+    assert_eq!(functions[0].dynamic_cost, 0);
+
+    let mut instance = module.instantiate().unwrap();
+    instance.prepare_call_untyped(ProgramCounter(0), &[]);
+    instance.set_gas(1000);
+    loop {
+        let interrupt = instance.run().unwrap();
+        report.on_interrupt(&instance, &interrupt);
+        match interrupt {
+            InterruptKind::Step => continue,
+            InterruptKind::Finished => break,
+            interrupt => panic!("unexpected interrupt: {interrupt:?}"),
+        }
+    }
+
+    let executions: Vec<_> = report.blocks().iter().map(|block| block.executions()).collect();
+    assert_eq!(executions, [1, 1, 1, 2]);
+    assert_eq!(report.total_dynamic_cost(), 1000 - instance.gas());
+
+    let lines = report.by_line();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].executions, 5);
+    assert_eq!(lines[0].dynamic_cost, 1000 - instance.gas());
+}
+
+#[cfg(not(feature = "std"))]
+fn gas_report(_config: Config) {}
+
+#[cfg(feature = "std")]
+fn coverage(config: Config) {
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&config).unwrap();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            asm::branch_eq_imm(A0, 0, 2),
+            asm::add_imm_32(A0, A0, 1),
+            asm::ret(),
+            asm::add_imm_32(A0, A0, 2),
+            asm::ret(),
+        ],
+        &[],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_step_tracing(true);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+
+    let run = |coverage: &mut crate::coverage::Coverage, argument: u64| {
+        let mut instance = module.instantiate().unwrap();
+        instance.prepare_call_untyped(ProgramCounter(0), &[argument]);
+        loop {
+            let interrupt = instance.run().unwrap();
+            coverage.on_interrupt(&instance, &interrupt);
+            match interrupt {
+                InterruptKind::Step => continue,
+                InterruptKind::Finished => break,
+                interrupt => panic!("unexpected interrupt: {interrupt:?}"),
+            }
+        }
+    };
+
+    let mut coverage = crate::coverage::Coverage::new(&module).unwrap();
+    run(&mut coverage, 1);
+    run(&mut coverage, 5);
+    let hits: Vec<_> = coverage.blocks().iter().map(|block| block.hits()).collect();
+    assert_eq!(hits, [2, 2, 0]);
+
+    let mut other = crate::coverage::Coverage::new(&module).unwrap();
+    run(&mut other, 0);
+    coverage.merge(&other).unwrap();
+    let hits: Vec<_> = coverage.blocks().iter().map(|block| block.hits()).collect();
+    assert_eq!(hits, [3, 2, 1]);
+
+    // The program has no debug info, so there's nothing to report.
+    assert!(coverage.files().is_empty());
+    let mut lcov = Vec::new();
+    coverage.write_lcov(&mut lcov, "test").unwrap();
+    assert!(lcov.is_empty());
+    let mut cobertura = Vec::new();
+    coverage.write_cobertura(&mut cobertura).unwrap();
+    let cobertura = String::from_utf8(cobertura).unwrap();
+    assert!(cobertura.contains(r#"lines-covered="0" lines-valid="0""#));
+
+    let mut module_config = ModuleConfig::new();
+    module_config.set_step_tracing(false);
+    let blob = module.blob().clone();
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    assert!(crate::coverage::Coverage::new(&module).is_err());
+}
+
+#[cfg(not(feature = "std"))]
+fn coverage(_config: Config) {}
+
+#[cfg(feature = "std")]
+fn trace_record_and_replay(config: Config) {
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_step_tracing(true);
+    module_config.set_gas_metering(Some(GasMeteringKind::Sync));
+    let module = Module::from_blob(&engine, &module_config, basic_test_blob()).unwrap();
+    let address = module.memory_map().rw_data_address();
+
+    let mut instance = module.instantiate().unwrap();
+    let mut recorder = crate::trace::TraceRecorder::new(&module, Vec::new()).unwrap();
+    instance.write_memory(address + 4, &[1, 2, 3, 4]).unwrap();
+    recorder.on_memory_write(address + 4, &[1, 2, 3, 4]).unwrap();
+    instance.prepare_call_untyped(module.exports().next().unwrap().program_counter(), &[1, 10]);
+    instance.set_gas(1000);
+    loop {
+        let interrupt = instance.run().unwrap();
+        recorder.on_interrupt(&instance, &interrupt).unwrap();
+        match interrupt {
+            InterruptKind::Step => continue,
+            InterruptKind::Ecalli(0) => {
+                assert_eq!(instance.read_memory(address, 8).unwrap(), [0x78, 0x56, 0x34, 0x12, 1, 2, 3, 4]);
+                instance.set_reg(Reg::A0, 100);
+                instance.write_memory(address + 8, &[5, 6]).unwrap();
+                recorder.on_memory_write(address + 8, &[5, 6]).unwrap();
+            }
+            InterruptKind::Finished => break,
+            interrupt => panic!("unexpected interrupt: {interrupt:?}"),
+        }
+    }
+
+    assert_eq!(instance.reg(Reg::A0), 111);
+    let trace = recorder.finish().unwrap();
+    let module_config = crate::trace::module_config_for(&trace).unwrap();
+    let module = Module::from_blob(&engine, &module_config, basic_test_blob()).unwrap();
+    assert_eq!(crate::trace::replay(&module, &trace).unwrap(), None);
+
+    // A truncated trace is replayed for as long as it lasts.
+    assert_eq!(crate::trace::replay(&module, &trace[..trace.len() - 8]).unwrap(), None);
+
+    let mut tampered_trace = trace.clone();
+    *tampered_trace.last_mut().unwrap() += 1;
+    let divergence = crate::trace::replay(&module, &tampered_trace).unwrap().unwrap();
+    let return_pc = module.blob().instructions(DefaultInstructionSet::default()).nth(4).unwrap().offset;
+    assert_eq!(divergence.instructions, 4);
+    assert_eq!(divergence.program_counter, Some(return_pc));
+    assert_eq!(divergence.expected, "trap");
+    assert_eq!(divergence.actual, "finished");
+
+    let mut module_config = ModuleConfig::new();
+    module_config.set_step_tracing(true);
+    let module = Module::from_blob(&engine, &module_config, basic_test_blob()).unwrap();
+    assert!(crate::trace::replay(&module, &trace).is_err());
+}
+
+#[cfg(not(feature = "std"))]
+fn trace_record_and_replay(_config: Config) {}
+
+fn watchpoints(config: Config) {
+    let _ = env_logger::try_init();
+
+    let memory_map = MemoryMapBuilder::new(0x4000).rw_data_size(0x4000).build().unwrap();
+    let address = memory_map.rw_data_address();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_rw_data_size(0x4000);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            asm::store_imm_u32(address, 0x12345678),
+            asm::load_i32(S0, address + 4),
+            asm::memset(),
+            asm::ret(),
+        ],
+        &[],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let offsets: Vec<_> = blob
+        .instructions(DefaultInstructionSet::default())
+        .map(|inst| inst.offset)
+        .collect();
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_gas_metering(Some(GasMeteringKind::Sync));
+    module_config.set_watchpoints(true);
+    let module = Module::from_blob(&engine, &module_config, blob.clone()).unwrap();
+
+    let mut instance = module.instantiate().unwrap();
+    assert!(instance.add_watchpoint(address, 0, WatchpointKind::Write).is_err());
+    instance.add_watchpoint(address + 2, 1, WatchpointKind::Write).unwrap();
+    instance.add_watchpoint(address + 4, 4, WatchpointKind::Read).unwrap();
+    instance.add_watchpoint(address + 10, 1, WatchpointKind::ReadWrite).unwrap();
+    instance.write_memory(address + 4, &[1, 2, 3, 4]).unwrap();
+    instance.set_reg(Reg::A0, u64::from(address + 8));
+    instance.set_reg(Reg::A1, 0xaa);
+    instance.set_reg(Reg::A2, 4);
+    instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+    instance.set_next_program_counter(offsets[0]);
+    instance.set_gas(100);
+
+    assert_eq!(
+        instance.run().unwrap(),
+        InterruptKind::Watchpoint {
+            address,
+            is_write: true,
+            pc: offsets[0]
+        }
+    );
+    assert_eq!(instance.program_counter(), Some(offsets[0]));
+    assert_eq!(instance.next_program_counter(), Some(offsets[1]));
+    assert_eq!(instance.read_memory(address, 4).unwrap(), [0x78, 0x56, 0x34, 0x12]);
+
+    assert_eq!(
+        instance.run().unwrap(),
+        InterruptKind::Watchpoint {
+            address: address + 4,
+            is_write: false,
+            pc: offsets[1]
+        }
+    );
+    assert_eq!(instance.reg(Reg::S0), 0x04030201);
+
+    assert_eq!(
+        instance.run().unwrap(),
+        InterruptKind::Watchpoint {
+            address: address + 10,
+            is_write: true,
+            pc: offsets[2]
+        }
+    );
+    assert_eq!(instance.program_counter(), Some(offsets[2]));
+    assert_eq!(instance.reg(Reg::A2), 1);
+    assert_eq!(instance.read_memory(address + 8, 4).unwrap(), [0xaa, 0xaa, 0xaa, 0]);
+
+    assert_eq!(instance.run().unwrap(), InterruptKind::Finished);
+    assert_eq!(instance.read_memory(address + 8, 4).unwrap(), [0xaa, 0xaa, 0xaa, 0xaa]);
+    assert_eq!(instance.reg(Reg::A2), 0);
+    assert_eq!(instance.gas(), 100 - 4 - 4);
+
+    assert!(instance.remove_watchpoint(address + 2, 1, WatchpointKind::Write));
+    assert!(!instance.remove_watchpoint(address + 2, 1, WatchpointKind::Write));
+    instance.clear_watchpoints();
+    instance.set_next_program_counter(offsets[0]);
+    instance.set_reg(Reg::A2, 0);
+    assert_eq!(instance.run().unwrap(), InterruptKind::Finished);
+
+    if config.backend() == Some(BackendKind::Compiler) {
+        let module = Module::from_blob(&engine, &ModuleConfig::new(), blob).unwrap();
+        let mut instance = module.instantiate().unwrap();
+        assert!(instance.add_watchpoint(address, 4, WatchpointKind::Write).is_err());
+    }
+}
+
+#[cfg(feature = "std")]
+fn backtrace(config: Config) {
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&config).unwrap();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_stack_size(4096);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            // main:
+            asm::add_imm_32(SP, SP, 0xfffffff0),
+            asm::store_indirect_u32(RA, SP, 12),
+            asm::load_imm_and_jump(RA, 2, 2),
+            asm::load_indirect_i32(RA, SP, 12),
+            asm::add_imm_32(SP, SP, 16),
+            asm::ret(),
+            // outer:
+            asm::add_imm_32(SP, SP, 0xffffffe0),
+            asm::store_indirect_u32(RA, SP, 8),
+            asm::load_imm_and_jump(RA, 4, 4),
+            asm::load_indirect_i32(RA, SP, 8),
+            asm::add_imm_32(SP, SP, 32),
+            asm::ret(),
+            // inner:
+            asm::add_imm_32(A0, A0, 1),
+            asm::trap(),
+        ],
+        &[1, 3],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let offsets: Vec<_> = blob
+        .instructions(DefaultInstructionSet::default())
+        .map(|inst| inst.offset)
+        .collect();
+    let module = Module::from_blob(&engine, &ModuleConfig::new(), blob).unwrap();
+    let mut instance = module.instantiate().unwrap();
+    instance.prepare_call_untyped(ProgramCounter(0), &[]);
+    assert_eq!(instance.run().unwrap(), InterruptKind::Trap);
+
+    let backtrace = crate::backtrace::Backtrace::capture(&instance);
+    let program_counters: Vec<_> = backtrace.frames().iter().map(|frame| frame.program_counter()).collect();
+    assert_eq!(program_counters, [offsets[13], offsets[8], offsets[2]]);
+    assert_eq!(backtrace.frames()[0].functions()[0].function, "[unknown]");
+    assert_eq!(
+        backtrace.to_string(),
+        format!(
+            "#0   #{} in [unknown]\n#1   #{} in [unknown]\n#2   #{} in [unknown]\n",
+            offsets[13], offsets[8], offsets[2]
+        )
+    );
+}
+
+#[cfg(not(feature = "std"))]
+fn backtrace(_config: Config) {}
+
+fn memory_sanitizer(config: Config) {
+    let _ = env_logger::try_init();
+
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_rw_data_size(0x100);
+    builder.set_stack_size(4096);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            asm::load_indirect_i32(S0, A0, 0),
+            asm::store_indirect_u32(A0, A0, 4),
+            asm::load_indirect_i32(S1, A0, 4),
+            asm::load_indirect_i32(T0, A3, 0xfc),
+            asm::load_indirect_i32(T0, A1, 0),
+            asm::sbrk(T1, A2),
+            asm::store_indirect_u8(A0, A1, 0),
+            asm::load_indirect_i32(T2, A1, 0),
+            asm::load_indirect_i32(T2, A0, 8),
+            asm::store_indirect_u32(A0, A1, 16),
+            asm::ret(),
+        ],
+        &[],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let offsets: Vec<_> = blob
+        .instructions(DefaultInstructionSet::default())
+        .map(|inst| inst.offset)
+        .collect();
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_memory_sanitizer(true);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let memory_map = module.memory_map().clone();
+    let stack_address = memory_map.stack_address_high() - 32;
+    let heap_base = memory_map.heap_base();
+    assert_eq!(heap_base, memory_map.rw_data_address() + 0x100);
+
+    let mut instance = module.instantiate().unwrap();
+    instance.write_memory(stack_address + 8, &[1, 2, 3, 4]).unwrap();
+    instance.set_reg(Reg::A0, u64::from(stack_address));
+    instance.set_reg(Reg::A1, u64::from(heap_base));
+    instance.set_reg(Reg::A2, 16);
+    instance.set_reg(Reg::A3, u64::from(memory_map.rw_data_address()));
+    instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+    instance.set_next_program_counter(offsets[0]);
+    assert_eq!(instance.run().unwrap(), InterruptKind::Finished);
+    assert_eq!(instance.reg(Reg::S1), u64::from(stack_address));
+
+    let diagnostics = instance.take_memory_diagnostics();
+    let diagnostics: Vec<_> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            (
+                diagnostic.kind,
+                diagnostic.program_counter,
+                diagnostic.address,
+                diagnostic.length,
+                diagnostic.is_write,
+            )
+        })
+        .collect();
+
+    assert_eq!(
+        diagnostics,
+        [
+            (MemoryDiagnosticKind::UninitializedRead, offsets[0], stack_address, 4, false),
+            (MemoryDiagnosticKind::UnallocatedAccess, offsets[4], heap_base, 4, false),
+            (MemoryDiagnosticKind::UninitializedRead, offsets[7], heap_base + 1, 4, false),
+            (MemoryDiagnosticKind::UnallocatedAccess, offsets[9], heap_base + 16, 4, true),
+        ]
+    );
+    assert!(instance.take_memory_diagnostics().is_empty());
+
+    // Resetting the memory also forgets which bytes were initialized.
+    instance.reset_memory().unwrap();
+    instance.set_reg(Reg::A0, u64::from(stack_address + 8));
+    instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+    instance.set_next_program_counter(offsets[0]);
+    assert_eq!(instance.run().unwrap(), InterruptKind::Finished);
+    let diagnostics = instance.take_memory_diagnostics();
+    assert_eq!(diagnostics[0].kind, MemoryDiagnosticKind::UninitializedRead);
+    assert_eq!(diagnostics[0].address, stack_address + 8);
+}
+
+fn dynamic_paging_memory_usage_and_limit(mut engine_config: Config) {
+    engine_config.set_allow_dynamic_paging(true);
+
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&engine_config).unwrap();
+    let page_size = get_native_page_size() as u32;
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_ro_data_size(page_size * 4);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::load_i32(Reg::A0, 0x10000 + page_size * 3), asm::ret()], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    module_config.set_dynamic_paging(true);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let linker: Linker = Linker::new();
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
+    let mut instance = instance_pre.instantiate().unwrap();
+    assert_eq!(instance.memory_usage(), MemoryUsage::default());
+
+    instance.zero_memory(0x10000, page_size * 2).unwrap();
+    assert_eq!(instance.memory_usage().ro_data, page_size * 2);
+    assert_eq!(instance.memory_usage().total(), u64::from(page_size) * 2);
+
+    let mut limits = InstanceLimits::new();
+    limits.set_memory_limit(Some(u64::from(page_size) * 2));
+    instance.set_limits(limits).unwrap();
+
+    // Pages which are already mapped in can still be accessed.
+    instance.write_memory(0x10000, &[1, 2, 3, 4]).unwrap();
+    instance.zero_memory(0x10000, page_size * 2).unwrap();
+
+    assert!(matches!(
+        instance.zero_memory(0x10000 + page_size, page_size * 2),
+        Err(MemoryAccessError::MemoryLimitReached { .. })
+    ));
+    assert!(matches!(
+        instance.write_memory(0x10000 + page_size * 2, &[1]),
+        Err(MemoryAccessError::MemoryLimitReached { .. })
+    ));
+    assert_eq!(instance.memory_usage().total(), u64::from(page_size) * 2);
+
+    let result = instance.call_typed(&mut (), "main", ());
+    assert!(
+        matches!(result, Err(CallError::LimitReached(LimitKind::Memory))),
+        "unexpected result: {result:?}"
+    );
+
+    instance.free_pages(0x10000, page_size).unwrap();
+    assert_eq!(instance.memory_usage().ro_data, page_size);
+    instance.call_typed(&mut (), "main", ()).unwrap();
+    assert_eq!(instance.memory_usage().ro_data, page_size * 2);
+
+    // Page faults which are handled manually are subject to the limit too.
+    let mut instance = module.instantiate().unwrap();
+    let mut limits = InstanceLimits::new();
+    limits.set_memory_limit(Some(u64::from(page_size) * 2));
+    instance.set_limits(limits).unwrap();
+    instance.zero_memory(0x10000, page_size * 2).unwrap();
+    instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+    instance.set_next_program_counter(ProgramCounter(0));
+    let segfault = expect_segfault(instance.run().unwrap());
+    assert_eq!(segfault.page_address, 0x10000 + page_size * 3);
+    assert!(matches!(
+        instance.zero_memory(segfault.page_address, segfault.page_size),
+        Err(MemoryAccessError::MemoryLimitReached { .. })
+    ));
+    assert_eq!(instance.memory_usage().total(), u64::from(page_size) * 2);
+}
+
+fn dynamic_paging_page_aligned_access_covers_only_its_pages(mut engine_config: Config) {
+    engine_config.set_allow_dynamic_paging(true);
+
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&engine_config).unwrap();
+    let page_size = get_native_page_size() as u32;
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_ro_data_size(page_size * 2);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::ret()], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    module_config.set_dynamic_paging(true);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let mut instance = module.instantiate().unwrap();
+
+    instance.write_memory(0x10000, &vec![1; page_size as usize]).unwrap();
+    assert_eq!(instance.memory_usage().ro_data, page_size);
+    assert!(instance.read_u32(0x10000 + page_size).is_err());
+
+    instance.zero_memory(0x10000, page_size).unwrap();
+    assert_eq!(instance.memory_usage().ro_data, page_size);
+    assert!(instance.read_u32(0x10000 + page_size).is_err());
+
+    let mut limits = InstanceLimits::new();
+    limits.set_memory_limit(Some(u64::from(page_size)));
+    instance.set_limits(limits).unwrap();
+    instance.write_memory(0x10000, &vec![2; page_size as usize]).unwrap();
+    assert_eq!(instance.read_u32(page_size + 0x10000 - 4).unwrap(), 0x02020202);
+}
+
+fn memory_usage_and_limit(config: Config) {
+    let _ = env_logger::try_init();
+
+    let engine = Engine::new(&config).unwrap();
+    let page_size = get_native_page_size() as u32;
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_ro_data_size(1);
+    builder.set_rw_data_size(1);
+    builder.set_stack_size(page_size * 2);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::sbrk(Reg::A0, Reg::A0), asm::ret()], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_page_size(page_size);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let mut instance = module.instantiate().unwrap();
+    assert_eq!(
+        instance.memory_usage(),
+        MemoryUsage {
+            ro_data: page_size,
+            rw_data: page_size,
+            heap: 0,
+            stack: page_size * 2,
+            aux_data: 0,
+        }
+    );
+
+    assert!(instance.sbrk(page_size).unwrap().is_some());
+    assert_eq!(instance.memory_usage().heap, page_size);
+    assert_eq!(instance.memory_usage().total(), u64::from(page_size) * 5);
+
+    let mut limits = InstanceLimits::new();
+    limits.set_memory_limit(Some(u64::from(page_size) * 6));
+    instance.set_limits(limits).unwrap();
+
+    let mut call_sbrk = |size: u32| {
+        instance.set_reg(Reg::A0, u64::from(size));
+        instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+        instance.set_next_program_counter(ProgramCounter(0));
+        match_interrupt!(instance.run().unwrap(), InterruptKind::Finished);
+        instance.reg(Reg::A0)
+    };
+
+    // The heap can grow by exactly one more page.
+    assert_ne!(call_sbrk(page_size), 0);
+    assert_ne!(call_sbrk(1), 0);
+    assert_eq!(call_sbrk(page_size), 0);
+    assert_eq!(instance.memory_usage().heap, page_size * 2);
+    assert_eq!(instance.memory_usage().total(), u64::from(page_size) * 6);
+    assert!(instance.sbrk(page_size).unwrap().is_none());
+
+    instance.set_limits(InstanceLimits::new()).unwrap();
+    assert!(instance.sbrk(page_size).unwrap().is_some());
+    assert_eq!(instance.memory_usage().heap, page_size * 3);
+}
+
+fn sbrk_knob_works(config: Config) {
+    let _ = env_logger::try_init();
+    let engine = Engine::new(&config).unwrap();
+    let page_size = get_native_page_size() as u32;
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::sbrk(Reg::A0, Reg::A0), asm::ret()], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+
+    for sbrk_allowed in [true, false] {
+        let mut module_config: ModuleConfig = ModuleConfig::new();
+        module_config.set_page_size(page_size);
+        module_config.set_allow_sbrk(sbrk_allowed);
+        module_config.set_gas_metering(Some(GasMeteringKind::Sync));
+        let module = Module::from_blob(&engine, &module_config, blob.clone()).unwrap();
+
+        let mut instance = module.instantiate().unwrap();
+        instance.set_reg(Reg::A0, 0);
+        instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+
+        instance.set_gas(5);
+        instance.set_next_program_counter(ProgramCounter(0));
+
+        #[allow(clippy::branches_sharing_code)]
+        if sbrk_allowed {
+            match_interrupt!(instance.run().unwrap(), InterruptKind::Finished);
+            assert_eq!(instance.gas(), 3);
+        } else {
+            match_interrupt!(instance.run().unwrap(), InterruptKind::Trap);
+            assert_eq!(instance.program_counter(), Some(ProgramCounter(0)));
+            assert_eq!(instance.gas(), 4);
+        }
+    }
+}
+
+struct TestInstance {
+    module: crate::Module,
+    instance: crate::Instance,
+}
+
+impl TestInstance {
+    fn new(config: &Config, elf: &'static [u8], optimize: bool) -> Self {
+        let _ = env_logger::try_init();
+        let blob = get_blob_impl(optimize, false, elf);
+
+        let engine = Engine::new(config).unwrap();
+        let module = Module::from_blob(&engine, &Default::default(), blob).unwrap();
+        let mut linker = Linker::new();
+        linker
+            .define_typed("multiply_by_2", |_caller: Caller<()>, value: u32| -> u32 { value * 2 })
+            .unwrap();
+
+        linker
+            .define_typed("identity", |_caller: Caller<()>, value: u32| -> u32 { value })
+            .unwrap();
+
+        linker
+            .define_untyped("multiply_all_input_registers", |caller: Caller<()>| {
+                let mut value = 1;
+
+                use Reg as R;
+                for reg in [R::A0, R::A1, R::A2, R::A3, R::A4, R::A5, R::T0, R::T1, R::T2] {
+                    value *= caller.instance.reg(reg);
+                }
+
+                caller.instance.set_reg(Reg::A0, value);
+                Ok(())
+            })
+            .unwrap();
+
+        linker
+            .define_typed("call_sbrk_indirectly_impl", |caller: Caller<()>, size: u32| -> u32 {
+                caller.instance.sbrk(size).unwrap().unwrap_or(0)
+            })
+            .unwrap();
+
+        linker
+            .define_untyped("return_tuple_u32", |caller: Caller<()>| {
+                caller.instance.set_reg(Reg::A0, 0x12345678);
+                caller.instance.set_reg(Reg::A1, 0x9abcdefe);
+                Ok(())
+            })
+            .unwrap();
+
+        linker
+            .define_untyped("return_tuple_u64", |caller: Caller<()>| {
+                caller.instance.set_reg(Reg::A0, 0x123456789abcdefe);
+                caller.instance.set_reg(Reg::A1, 0x1122334455667788);
+                Ok(())
+            })
+            .unwrap();
+
+        linker
+            .define_untyped("return_tuple_usize", move |caller: Caller<()>| {
+                if caller.instance.is_64_bit() {
+                    caller.instance.set_reg(Reg::A0, 0x123456789abcdefe);
+                    caller.instance.set_reg(Reg::A1, 0x1122334455667788);
+                } else {
+                    caller.instance.set_reg(Reg::A0, 0x12345678);
+                    caller.instance.set_reg(Reg::A1, 0x9abcdefe);
+                }
+
+                Ok(())
+            })
+            .unwrap();
+
+        let instance_pre = linker.instantiate_pre(&module).unwrap();
+        let instance = instance_pre.instantiate().unwrap();
+
+        TestInstance { module, instance }
+    }
+
+    pub fn call<FnArgs, FnResult>(&mut self, name: &str, args: FnArgs) -> Result<FnResult, crate::CallError>
+    where
+        FnArgs: crate::linker::FuncArgs,
+        FnResult: crate::linker::FuncResult,
+    {
+        self.instance.call_typed_and_get_result::<FnResult, FnArgs>(&mut (), name, args)
+    }
+}
+
+fn test_blob_basic_test(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    assert_eq!(i.call::<(), u32>("push_one_to_global_vec", ()).unwrap(), 1);
+    assert_eq!(i.call::<(), u32>("push_one_to_global_vec", ()).unwrap(), 2);
+    assert_eq!(i.call::<(), u32>("push_one_to_global_vec", ()).unwrap(), 3);
+}
+
+fn test_blob_atomic_fetch_add(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (1,)).unwrap(), 0);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (1,)).unwrap(), 1);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (1,)).unwrap(), 2);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (0,)).unwrap(), 3);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (0,)).unwrap(), 3);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (2,)).unwrap(), 3);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_add", (0,)).unwrap(), 5);
+}
+
+fn test_blob_atomic_fetch_swap(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_swap", (10,)).unwrap(), 0);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_swap", (100,)).unwrap(), 10);
+    assert_eq!(i.call::<(u32,), u32>("atomic_fetch_swap", (1000,)).unwrap(), 100);
+}
+
+fn test_blob_atomic_fetch_minmax(config: Config, optimize: bool, is_64_bit: bool) {
+    use core::cmp::{max, min};
+
+    fn maxu(a: i32, b: i32) -> i32 {
+        max(a as u32, b as u32) as i32
+    }
+
+    fn minu(a: i32, b: i32) -> i32 {
+        min(a as u32, b as u32) as i32
+    }
+
+    #[allow(clippy::type_complexity)]
+    let list: [(&str, fn(i32, i32) -> i32); 4] = [
+        ("atomic_fetch_max_signed", max),
+        ("atomic_fetch_min_signed", min),
+        ("atomic_fetch_max_unsigned", maxu),
+        ("atomic_fetch_min_unsigned", minu),
+    ];
+
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    for (name, cb) in list {
+        for a in [-10, 0, 10] {
+            for b in [-10, 0, 10] {
+                let new_value = cb(a, b);
                 i.call::<(i32,), ()>("set_global", (a,)).unwrap();
                 assert_eq!(i.call::<(i32,), i32>(name, (b,)).unwrap(), a);
                 assert_eq!(i.call::<(), i32>("get_global", ()).unwrap(), new_value);
             }
         }
     }
-}
+}
+
+fn test_blob_hostcall(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    assert_eq!(i.call::<(u32,), u32>("test_multiply_by_6", (10,)).unwrap(), 60);
+}
+
+fn test_blob_define_abi(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    assert!(i.call::<(), ()>("test_define_abi", ()).is_ok());
+}
+
+fn test_blob_input_registers(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    assert!(i.call::<(), ()>("test_input_registers", ()).is_ok());
+}
+
+fn test_blob_call_sbrk_from_guest(config: Config, optimize: bool, is_64_bit: bool) {
+    test_blob_call_sbrk_impl(config, optimize, is_64_bit, |i, size| {
+        i.call::<(u32,), u32>("call_sbrk", (size,)).unwrap()
+    })
+}
+
+fn test_blob_call_sbrk_from_host_instance(config: Config, optimize: bool, is_64_bit: bool) {
+    test_blob_call_sbrk_impl(config, optimize, is_64_bit, |i, size| i.instance.sbrk(size).unwrap().unwrap_or(0))
+}
+
+fn test_blob_call_sbrk_from_host_function(config: Config, optimize: bool, is_64_bit: bool) {
+    test_blob_call_sbrk_impl(config, optimize, is_64_bit, |i, size| {
+        i.call::<(u32,), u32>("call_sbrk_indirectly", (size,)).unwrap()
+    })
+}
+
+fn test_blob_program_memory_can_be_reused_and_cleared(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    let address = i.call::<(), u32>("get_global_address", ()).unwrap();
+
+    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x00, 0x00, 0x00, 0x00]);
+
+    i.call::<(), ()>("increment_global", ()).unwrap();
+    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x01, 0x00, 0x00, 0x00]);
+
+    i.call::<(), ()>("increment_global", ()).unwrap();
+    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x02, 0x00, 0x00, 0x00]);
+
+    i.instance.reset_memory().unwrap();
+    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x00, 0x00, 0x00, 0x00]);
+
+    i.call::<(), ()>("increment_global", ()).unwrap();
+    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x01, 0x00, 0x00, 0x00]);
+}
+
+fn test_blob_out_of_bounds_memory_access_generates_a_trap(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    let address = i.call::<(), u32>("get_global_address", ()).unwrap();
+    assert_eq!(i.call::<(u32,), u32>("read_u32", (address,)).unwrap(), 0);
+    i.call::<(), ()>("increment_global", ()).unwrap();
+    assert_eq!(i.call::<(u32,), u32>("read_u32", (address,)).unwrap(), 1);
+    assert!(matches!(i.call::<(u32,), u32>("read_u32", (4,)), Err(CallError::Trap)));
+
+    assert_eq!(i.call::<(u32,), u32>("read_u32", (address,)).unwrap(), 1);
+    i.call::<(), ()>("increment_global", ()).unwrap();
+    assert_eq!(i.call::<(u32,), u32>("read_u32", (address,)).unwrap(), 2);
+}
+
+fn test_blob_call_sbrk_impl(config: Config, optimize: bool, is_64_bit: bool, mut call_sbrk: impl FnMut(&mut TestInstance, u32) -> u32) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    let memory_map = i.module.memory_map().clone();
+    let heap_base = memory_map.heap_base();
+    let page_size = memory_map.page_size();
+
+    assert_eq!(i.instance.read_memory(memory_map.rw_data_range().end - 1, 1).unwrap(), vec![0]);
+    assert!(i.instance.read_memory(memory_map.rw_data_range().end, 1).is_err());
+    assert!(i
+        .instance
+        .read_memory(heap_base, memory_map.rw_data_range().end - heap_base)
+        .unwrap()
+        .iter()
+        .all(|&byte| byte == 0));
+    assert_eq!(i.instance.heap_size(), 0);
+
+    assert_eq!(call_sbrk(&mut i, 0), heap_base);
+    assert_eq!(i.instance.heap_size(), 0);
+    assert_eq!(call_sbrk(&mut i, 0), heap_base);
+    assert_eq!(call_sbrk(&mut i, 1), heap_base + 1);
+    assert_eq!(i.instance.heap_size(), 1);
+    assert_eq!(call_sbrk(&mut i, 0), heap_base + 1);
+    assert_eq!(call_sbrk(&mut i, 0xffffffff), 0);
+    assert_eq!(call_sbrk(&mut i, 0), heap_base + 1);
+
+    i.instance.write_memory(heap_base, &[0x33]).unwrap();
+    assert_eq!(i.instance.read_memory(heap_base, 1).unwrap(), vec![0x33]);
+
+    let new_origin = align_to_next_page_u32(memory_map.page_size(), heap_base + i.instance.heap_size()).unwrap();
+    {
+        let until_next_page = new_origin - (heap_base + i.instance.heap_size());
+        assert_eq!(call_sbrk(&mut i, until_next_page), new_origin);
+    }
+
+    assert_eq!(i.instance.read_memory(new_origin - 1, 1).unwrap(), vec![0]);
+    assert!(i.instance.read_memory(new_origin, 1).is_err());
+    assert!(i.instance.write_memory(new_origin, &[0x34]).is_err());
+
+    assert_eq!(call_sbrk(&mut i, 1), new_origin + 1);
+    assert_eq!(i.instance.read_memory(new_origin, page_size).unwrap().len(), page_size as usize);
+    assert!(i.instance.read_memory(new_origin, page_size + 1).is_err());
+    assert!(i.instance.write_memory(new_origin, &[0x35]).is_ok());
+
+    assert_eq!(call_sbrk(&mut i, page_size - 1), new_origin + page_size);
+    assert!(i.instance.read_memory(new_origin, page_size + 1).is_err());
+
+    i.instance.reset_memory().unwrap();
+    assert_eq!(call_sbrk(&mut i, 0), heap_base);
+    assert_eq!(i.instance.heap_size(), 0);
+    assert!(i.instance.read_memory(memory_map.rw_data_range().end, 1).is_err());
+
+    assert_eq!(call_sbrk(&mut i, 1), heap_base + 1);
+    assert_eq!(i.instance.read_memory(heap_base, 1).unwrap(), vec![0]);
+}
+
+fn test_blob_add_u32(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    assert_eq!(i.call::<(u32, u32), u32>("add_u32", (1, 2,)).unwrap(), 3);
+    assert_eq!(i.instance.reg(Reg::A0), 3);
+
+    assert_eq!(i.call::<(u32, u32), u32>("add_u32", (0xfffffffa, 2,)).unwrap(), 0xfffffffc);
+    assert_eq!(i.instance.reg(Reg::A0), 0xfffffffc);
+
+    assert_eq!(i.call::<(u32, u32), u32>("add_u32", (0xffffffff, 2,)).unwrap(), 1);
+    assert_eq!(i.instance.reg(Reg::A0), 1);
+
+    if is_64_bit {
+        assert_eq!(i.call::<(u32, u32), u32>("add_u32_asm", (0xfffffffa, 2,)).unwrap(), 0xfffffffc);
+        assert_eq!(i.instance.reg(Reg::A0), 0xfffffffffffffffc);
+    }
+}
+
+fn test_blob_add_u64(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    assert_eq!(i.call::<(u64, u64), u64>("add_u64", (1, 2,)).unwrap(), 3);
+    assert_eq!(i.instance.reg(Reg::A0), 3);
+    assert_eq!(
+        i.call::<(u64, u64), u64>("add_u64", (0xaaaaaaaa, 0xcccccccc,)).unwrap(),
+        0xaaaaaaaa + 0xcccccccc
+    );
+}
+
+fn test_blob_xor_imm_u32(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    for value in [0, 0xaaaaaaaa, 0x55555555, 0x12345678, 0xffffffff] {
+        assert_eq!(i.call::<(u32,), u32>("xor_imm_u32", (value,)).unwrap(), value ^ 0xfb8f5c1e);
+    }
+}
+
+fn test_blob_branch_less_than_zero(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    i.call::<(), ()>("test_branch_less_than_zero", ()).unwrap();
+}
+
+fn test_blob_fetch_add_atomic_u64(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (1,)).unwrap(), 0);
+    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (0,)).unwrap(), 1);
+    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (0,)).unwrap(), 1);
+    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (0xffffffff,)).unwrap(), 1);
+    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (0,)).unwrap(), 0x100000000);
+}
+
+fn test_blob_cmov_if_zero_with_zero_reg(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    i.call::<(), ()>("cmov_if_zero_with_zero_reg", ()).unwrap();
+}
+
+fn test_blob_cmov_if_not_zero_with_zero_reg(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    i.call::<(), ()>("cmov_if_not_zero_with_zero_reg", ()).unwrap();
+}
+
+fn test_blob_min_stack_size(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let i = TestInstance::new(&config, elf, optimize);
+    assert_eq!(i.instance.module().memory_map().stack_size(), 65536);
+}
+
+fn test_blob_negate_and_add(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    if !is_64_bit {
+        assert_eq!(i.call::<(u32, u32), u32>("negate_and_add", (123, 1,)).unwrap(), 15);
+    } else {
+        assert_eq!(i.call::<(u64, u64), u64>("negate_and_add", (123, 1,)).unwrap(), 15);
+    }
+}
+
+fn test_blob_return_tuple_from_import(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    i.call::<(), ()>("test_return_tuple", ()).unwrap();
+}
+
+fn test_blob_return_tuple_from_export(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    if is_64_bit {
+        let a0 = 0x123456789abcdefe_u64;
+        let a1 = 0x1122334455667788_u64;
+        i.call::<(), ()>("export_return_tuple_u64", ()).unwrap();
+        assert_eq!(i.instance.reg(Reg::A0), a0);
+        assert_eq!(i.instance.reg(Reg::A1), a1);
+
+        i.instance.set_reg(Reg::A0, 0);
+        i.instance.set_reg(Reg::A1, 0);
+        i.call::<(), ()>("export_return_tuple_usize", ()).unwrap();
+        assert_eq!(i.instance.reg(Reg::A0), a0);
+        assert_eq!(i.instance.reg(Reg::A1), a1);
+    } else {
+        let a0 = 0x12345678_u64;
+        let a1 = 0x9abcdefe_u64;
+        i.call::<(), ()>("export_return_tuple_u32", ()).unwrap();
+        assert_eq!(i.instance.reg(Reg::A0), a0);
+        assert_eq!(i.instance.reg(Reg::A1), a1);
+
+        i.instance.set_reg(Reg::A0, 0);
+        i.instance.set_reg(Reg::A1, 0);
+        i.call::<(), ()>("export_return_tuple_usize", ()).unwrap();
+        assert_eq!(i.instance.reg(Reg::A0), a0);
+        assert_eq!(i.instance.reg(Reg::A1), a1);
+    }
+}
+
+fn test_blob_get_heap_base(config: Config, optimize: bool, is_64_bit: bool) {
+    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
+    let mut i = TestInstance::new(&config, elf, optimize);
+    let heap_base = i.call::<(), u32>("get_heap_base", ()).unwrap();
+    assert_eq!(heap_base, i.instance.module().memory_map().heap_base());
+}
+
+fn test_asm_reloc_add_sub(config: Config, optimize: bool, _is_64_bit: bool) {
+    const BLOB_64: &[u8] = include_bytes!("../../../guest-programs/asm-tests/output/reloc_add_sub_64.elf");
+
+    let elf = BLOB_64;
+    let mut i = TestInstance::new(&config, elf, optimize);
+
+    let address = i.call::<(u32,), u32>("get_string", (0,)).unwrap();
+    assert_eq!(i.instance.read_u32(address).unwrap(), 0x01010101);
+
+    let address = i.call::<(u32,), u32>("get_string", (1,)).unwrap();
+    assert_eq!(i.instance.read_u32(address).unwrap(), 0x02020202);
+
+    let address = i.call::<(u32,), u32>("get_string", (2,)).unwrap();
+    assert_eq!(i.instance.read_u32(address).unwrap(), 0x03030303);
+}
+
+fn test_asm_reloc_hi_lo(config: Config, optimize: bool, _is_64_bit: bool) {
+    const BLOB_64: &[u8] = include_bytes!("../../../guest-programs/asm-tests/output/reloc_hi_lo_64.elf");
+
+    let elf = BLOB_64;
+    let mut i = TestInstance::new(&config, elf, optimize);
+
+    let address = i.call::<(u32,), u32>("get_string", (0,)).unwrap();
+    assert_eq!(i.instance.read_u32(address).unwrap(), 0xA1010101);
+
+    let address = i.call::<(u32,), u32>("get_string", (1,)).unwrap();
+    assert_eq!(i.instance.read_u32(address).unwrap(), 0xB2020202);
+
+    let address = i.call::<(u32,), u32>("get_string", (2,)).unwrap();
+    assert_eq!(i.instance.read_u32(address).unwrap(), 0xC3030303);
+}
+
+fn basic_gas_metering(config: Config, gas_metering_kind: GasMeteringKind) {
+    let _ = env_logger::try_init();
+
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::fallthrough(), asm::add_imm_32(A0, A0, 666), asm::ret()], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::default();
+    module_config.set_gas_metering(Some(gas_metering_kind));
+
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let linker: Linker = Linker::new();
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
+    let mut instance = instance_pre.instantiate().unwrap();
+
+    {
+        instance.set_gas(3);
+        instance.call_typed(&mut (), "main", ()).unwrap();
+        assert_eq!(instance.get_result_typed::<i32>(), 666);
+        assert_eq!(instance.gas(), 0);
+        assert_eq!(instance.program_counter(), None);
+        assert_eq!(instance.next_program_counter(), None);
+    }
+
+    {
+        instance.set_gas(2);
+        let result = instance.call_typed(&mut (), "main", ());
+        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
+        match gas_metering_kind {
+            GasMeteringKind::Sync => {
+                assert_eq!(instance.gas(), 1);
+                assert_eq!(instance.get_result_typed::<i32>(), 0);
+                assert_eq!(instance.program_counter(), Some(ProgramCounter(1)));
+                assert_eq!(instance.next_program_counter(), Some(ProgramCounter(1)));
+
+                let result = instance.run().unwrap();
+                assert!(matches!(result, InterruptKind::NotEnoughGas), "unexpected result: {result:?}");
+                assert_eq!(instance.gas(), 1);
+                assert_eq!(instance.program_counter(), Some(ProgramCounter(1)));
+                assert_eq!(instance.next_program_counter(), Some(ProgramCounter(1)));
+
+                instance.set_gas(2);
+                let result = instance.run().unwrap();
+                assert!(matches!(result, InterruptKind::Finished), "unexpected result: {result:?}");
+                assert_eq!(instance.get_result_typed::<i32>(), 666);
+                assert_eq!(instance.gas(), 0);
+                assert_eq!(instance.program_counter(), None);
+                assert_eq!(instance.next_program_counter(), None);
+            }
+            GasMeteringKind::Async => {
+                assert!(instance.gas() < 0);
+                assert_eq!(instance.program_counter(), None);
+                assert_eq!(instance.next_program_counter(), None);
+            }
+        }
+    }
+
+    {
+        instance.set_gas(6);
+        instance.call_typed(&mut (), "main", ()).unwrap();
+        assert_eq!(instance.get_result_typed::<i32>(), 666);
+        assert_eq!(instance.gas(), 3);
+        assert_eq!(instance.program_counter(), None);
+        assert_eq!(instance.next_program_counter(), None);
+
+        instance.call_typed(&mut (), "main", ()).unwrap();
+        assert_eq!(instance.get_result_typed::<i32>(), 666);
+        assert_eq!(instance.gas(), 0);
+        assert_eq!(instance.program_counter(), None);
+        assert_eq!(instance.next_program_counter(), None);
+
+        let result = instance.call_typed(&mut (), "main", ());
+        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
+        match gas_metering_kind {
+            GasMeteringKind::Sync => {
+                assert_eq!(instance.gas(), 0);
+            }
+            GasMeteringKind::Async => {
+                assert!(instance.gas() < 0);
+            }
+        }
+    }
 
-fn test_blob_hostcall(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    assert_eq!(i.call::<(u32,), u32>("test_multiply_by_6", (10,)).unwrap(), 60);
+    {
+        core::mem::drop(instance);
+        let mut instance = instance_pre.instantiate().unwrap();
+        assert_eq!(instance.gas(), 0);
+
+        let result = instance.call_typed(&mut (), "main", ());
+        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
+        match gas_metering_kind {
+            GasMeteringKind::Sync => {
+                assert_eq!(instance.gas(), 0);
+            }
+            GasMeteringKind::Async => {
+                assert!(instance.gas() < 0);
+            }
+        }
+    }
+
+    // Stress test.
+    let mut instance = instance_pre.instantiate().unwrap();
+    for _ in 0..100 {
+        instance.set_gas(2);
+        let result = instance.call_typed(&mut (), "main", ());
+        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
+        match gas_metering_kind {
+            GasMeteringKind::Sync => {
+                assert_eq!(instance.get_result_typed::<i32>(), 0);
+                assert_eq!(instance.gas(), 1);
+            }
+            GasMeteringKind::Async => {
+                assert!(instance.gas() < 0);
+            }
+        }
+
+        instance.set_gas(5);
+        instance.call_typed(&mut (), "main", ()).unwrap();
+        assert_eq!(instance.gas(), 2);
+        assert_eq!(instance.get_result_typed::<i32>(), 666);
+    }
 }
 
-fn test_blob_define_abi(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    assert!(i.call::<(), ()>("test_define_abi", ()).is_ok());
+fn basic_gas_metering_sync(config: Config) {
+    basic_gas_metering(config, GasMeteringKind::Sync);
 }
 
-fn test_blob_input_registers(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    assert!(i.call::<(), ()>("test_input_registers", ()).is_ok());
+fn basic_gas_metering_async(config: Config) {
+    basic_gas_metering(config, GasMeteringKind::Async);
 }
 
-fn test_blob_call_sbrk_from_guest(config: Config, optimize: bool, is_64_bit: bool) {
-    test_blob_call_sbrk_impl(config, optimize, is_64_bit, |i, size| {
-        i.call::<(u32,), u32>("call_sbrk", (size,)).unwrap()
-    })
+fn resume_after_running_out_of_gas(config: Config, gas_metering_kind: GasMeteringKind) {
+    let _ = env_logger::try_init();
+
+    let memory_map = MemoryMapBuilder::new(0x4000).rw_data_size(0x4000).build().unwrap();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.set_rw_data_size(0x4000);
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(
+        &[
+            asm::load_imm(A0, 0),
+            asm::load_imm(A1, 20),
+            asm::fallthrough(),
+            asm::add_32(A0, A0, A1),
+            asm::store_u32(A0, memory_map.rw_data_address()),
+            asm::ecalli(0),
+            asm::add_imm_32(A1, A1, 0xffffffff),
+            asm::branch_not_eq_imm(A1, 0, 1),
+            asm::ret(),
+        ],
+        &[],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::default();
+    module_config.set_page_size(0x4000);
+    module_config.set_gas_metering(Some(gas_metering_kind));
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let entry_point = module.exports().find(|export| export == "main").unwrap().program_counter();
+
+    // Runs the program to completion, adding `chunk` gas every time it runs out.
+    let run = |chunk: Gas| {
+        let mut instance = module.instantiate().unwrap();
+        instance.prepare_call_untyped(entry_point, &[]);
+        instance.set_gas(chunk);
+
+        let mut gas_paid = chunk;
+        let mut refills = 0;
+        loop {
+            match instance.run().unwrap() {
+                InterruptKind::Finished => break,
+                InterruptKind::Ecalli(0) => {
+                    let value = instance.reg(A2) + instance.reg(A0);
+                    instance.set_reg(A2, value);
+                }
+                InterruptKind::NotEnoughGas => {
+                    instance.set_gas(instance.gas() + chunk);
+                    gas_paid += chunk;
+                    refills += 1;
+                }
+                interruption => panic!("unexpected interruption: {interruption:?}"),
+            }
+        }
+
+        let regs = Reg::ALL.map(|reg| instance.reg(reg));
+        let memory = instance.read_memory(memory_map.rw_data_address(), 4).unwrap();
+        (regs, memory, gas_paid - instance.gas(), refills)
+    };
+
+    let (expected_regs, expected_memory, expected_gas_used, refills) = run(10000);
+    assert_eq!(refills, 0);
+    assert_eq!(expected_regs[A0 as usize], 210);
+    assert_eq!(expected_regs[A2 as usize], (1..=20).map(|n| (n..=20).sum::<u64>()).sum::<u64>());
+    assert_eq!(expected_memory, 210_u32.to_le_bytes());
+
+    for chunk in [1, 2, 3, 5, 8, 13] {
+        let (regs, memory, gas_used, refills) = run(chunk);
+        assert!(refills > 0);
+        assert_eq!(regs, expected_regs, "register mismatch when refilling {chunk} gas at a time");
+        assert_eq!(memory, expected_memory, "memory mismatch when refilling {chunk} gas at a time");
+        assert_eq!(
+            gas_used, expected_gas_used,
+            "gas usage mismatch when refilling {chunk} gas at a time"
+        );
+    }
 }
 
-fn test_blob_call_sbrk_from_host_instance(config: Config, optimize: bool, is_64_bit: bool) {
-    test_blob_call_sbrk_impl(config, optimize, is_64_bit, |i, size| i.instance.sbrk(size).unwrap().unwrap_or(0))
+fn resume_after_running_out_of_gas_sync(config: Config) {
+    resume_after_running_out_of_gas(config, GasMeteringKind::Sync);
 }
 
-fn test_blob_call_sbrk_from_host_function(config: Config, optimize: bool, is_64_bit: bool) {
-    test_blob_call_sbrk_impl(config, optimize, is_64_bit, |i, size| {
-        i.call::<(u32,), u32>("call_sbrk_indirectly", (size,)).unwrap()
-    })
+fn resume_after_running_out_of_gas_async(config: Config) {
+    resume_after_running_out_of_gas(config, GasMeteringKind::Async);
 }
 
-fn test_blob_program_memory_can_be_reused_and_cleared(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    let address = i.call::<(), u32>("get_global_address", ()).unwrap();
+fn resume_after_running_out_of_gas_when_finishing_async(config: Config) {
+    let _ = env_logger::try_init();
 
-    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x00, 0x00, 0x00, 0x00]);
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::fallthrough(), asm::add_imm_32(A0, A0, 666), asm::ret()], &[]);
 
-    i.call::<(), ()>("increment_global", ()).unwrap();
-    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x01, 0x00, 0x00, 0x00]);
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::default();
+    module_config.set_gas_metering(Some(GasMeteringKind::Async));
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let linker: Linker = Linker::new();
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
+    let mut instance = instance_pre.instantiate().unwrap();
 
-    i.call::<(), ()>("increment_global", ()).unwrap();
-    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x02, 0x00, 0x00, 0x00]);
+    instance.set_gas(2);
+    let result = instance.call_typed(&mut (), "main", ());
+    assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
+    assert_eq!(instance.gas(), -1);
 
-    i.instance.reset_memory().unwrap();
-    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x00, 0x00, 0x00, 0x00]);
+    // The program has already finished, but that's only reported once the gas is no longer negative.
+    let result = instance.run().unwrap();
+    assert!(matches!(result, InterruptKind::NotEnoughGas), "unexpected result: {result:?}");
+    assert_eq!(instance.gas(), -1);
 
-    i.call::<(), ()>("increment_global", ()).unwrap();
-    assert_eq!(i.instance.read_memory(address, 4).unwrap(), [0x01, 0x00, 0x00, 0x00]);
+    instance.set_gas(1);
+    let result = instance.run().unwrap();
+    assert!(matches!(result, InterruptKind::Finished), "unexpected result: {result:?}");
+    assert_eq!(instance.get_result_typed::<i32>(), 666);
+    assert_eq!(instance.gas(), 1);
+    assert_eq!(instance.program_counter(), None);
+    assert_eq!(instance.next_program_counter(), None);
 }
 
-fn test_blob_out_of_bounds_memory_access_generates_a_trap(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    let address = i.call::<(), u32>("get_global_address", ()).unwrap();
-    assert_eq!(i.call::<(u32,), u32>("read_u32", (address,)).unwrap(), 0);
-    i.call::<(), ()>("increment_global", ()).unwrap();
-    assert_eq!(i.call::<(u32,), u32>("read_u32", (address,)).unwrap(), 1);
-    assert!(matches!(i.call::<(u32,), u32>("read_u32", (4,)), Err(CallError::Trap)));
-
-    assert_eq!(i.call::<(u32,), u32>("read_u32", (address,)).unwrap(), 1);
-    i.call::<(), ()>("increment_global", ()).unwrap();
-    assert_eq!(i.call::<(u32,), u32>("read_u32", (address,)).unwrap(), 2);
-}
+fn consume_gas_in_host_function(config: Config, gas_metering_kind: GasMeteringKind) {
+    let _ = env_logger::try_init();
 
-fn test_blob_call_sbrk_impl(config: Config, optimize: bool, is_64_bit: bool, mut call_sbrk: impl FnMut(&mut TestInstance, u32) -> u32) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    let memory_map = i.module.memory_map().clone();
-    let heap_base = memory_map.heap_base();
-    let page_size = memory_map.page_size();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.add_import(b"hostfn");
+    builder.set_code(&[asm::ecalli(0), asm::ret()], &[]);
 
-    assert_eq!(i.instance.read_memory(memory_map.rw_data_range().end - 1, 1).unwrap(), vec![0]);
-    assert!(i.instance.read_memory(memory_map.rw_data_range().end, 1).is_err());
-    assert!(i
-        .instance
-        .read_memory(heap_base, memory_map.rw_data_range().end - heap_base)
-        .unwrap()
-        .iter()
-        .all(|&byte| byte == 0));
-    assert_eq!(i.instance.heap_size(), 0);
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::default();
+    module_config.set_gas_metering(Some(gas_metering_kind));
 
-    assert_eq!(call_sbrk(&mut i, 0), heap_base);
-    assert_eq!(i.instance.heap_size(), 0);
-    assert_eq!(call_sbrk(&mut i, 0), heap_base);
-    assert_eq!(call_sbrk(&mut i, 1), heap_base + 1);
-    assert_eq!(i.instance.heap_size(), 1);
-    assert_eq!(call_sbrk(&mut i, 0), heap_base + 1);
-    assert_eq!(call_sbrk(&mut i, 0xffffffff), 0);
-    assert_eq!(call_sbrk(&mut i, 0), heap_base + 1);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let mut linker: Linker<i64, core::convert::Infallible> = Linker::new();
+    linker
+        .define_typed("hostfn", |caller: Caller<i64>| -> u32 {
+            assert_eq!(caller.instance.gas(), 1);
+            caller.instance.set_gas(1 - *caller.user_data);
+            666
+        })
+        .unwrap();
 
-    i.instance.write_memory(heap_base, &[0x33]).unwrap();
-    assert_eq!(i.instance.read_memory(heap_base, 1).unwrap(), vec![0x33]);
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
+    let mut instance = instance_pre.instantiate().unwrap();
 
-    let new_origin = align_to_next_page_u32(memory_map.page_size(), heap_base + i.instance.heap_size()).unwrap();
     {
-        let until_next_page = new_origin - (heap_base + i.instance.heap_size());
-        assert_eq!(call_sbrk(&mut i, until_next_page), new_origin);
+        instance.set_gas(3);
+        instance.call_typed(&mut 0, "main", ()).unwrap();
+        assert_eq!(instance.get_result_typed::<i32>(), 666);
+        assert_eq!(instance.gas(), 1);
     }
 
-    assert_eq!(i.instance.read_memory(new_origin - 1, 1).unwrap(), vec![0]);
-    assert!(i.instance.read_memory(new_origin, 1).is_err());
-    assert!(i.instance.write_memory(new_origin, &[0x34]).is_err());
-
-    assert_eq!(call_sbrk(&mut i, 1), new_origin + 1);
-    assert_eq!(i.instance.read_memory(new_origin, page_size).unwrap().len(), page_size as usize);
-    assert!(i.instance.read_memory(new_origin, page_size + 1).is_err());
-    assert!(i.instance.write_memory(new_origin, &[0x35]).is_ok());
+    {
+        instance.set_gas(3);
+        instance.call_typed(&mut 1, "main", ()).unwrap();
+        assert_eq!(instance.get_result_typed::<i32>(), 666);
+        assert_eq!(instance.gas(), 0);
+    }
 
-    assert_eq!(call_sbrk(&mut i, page_size - 1), new_origin + page_size);
-    assert!(i.instance.read_memory(new_origin, page_size + 1).is_err());
+    {
+        instance.set_gas(3);
+        let result = instance.call_typed(&mut 2, "main", ());
+        assert_eq!(instance.gas(), -1);
+        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
+    }
+}
 
-    i.instance.reset_memory().unwrap();
-    assert_eq!(call_sbrk(&mut i, 0), heap_base);
-    assert_eq!(i.instance.heap_size(), 0);
-    assert!(i.instance.read_memory(memory_map.rw_data_range().end, 1).is_err());
+fn charge_gas_in_host_function(config: Config) {
+    let _ = env_logger::try_init();
 
-    assert_eq!(call_sbrk(&mut i, 1), heap_base + 1);
-    assert_eq!(i.instance.read_memory(heap_base, 1).unwrap(), vec![0]);
-}
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.add_import(b"fixed");
+    builder.add_import(b"dynamic");
+    builder.set_code(&[asm::ecalli(0), asm::ecalli(1), asm::ret()], &[]);
 
-fn test_blob_add_u32(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    assert_eq!(i.call::<(u32, u32), u32>("add_u32", (1, 2,)).unwrap(), 3);
-    assert_eq!(i.instance.reg(Reg::A0), 3);
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::default();
+    module_config.set_gas_metering(Some(GasMeteringKind::Sync));
 
-    assert_eq!(i.call::<(u32, u32), u32>("add_u32", (0xfffffffa, 2,)).unwrap(), 0xfffffffc);
-    assert_eq!(i.instance.reg(Reg::A0), 0xfffffffc);
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let mut linker: Linker<(u32, u64), NotEnoughGasError> = Linker::new();
+    linker
+        .define_typed_with_gas_cost("fixed", 10, |caller: Caller<(u32, u64)>| {
+            caller.user_data.0 += 1;
+        })
+        .unwrap();
+    linker
+        .define_typed("dynamic", |mut caller: Caller<(u32, u64)>| -> Result<u32, NotEnoughGasError> {
+            let amount = caller.user_data.1;
+            caller.charge_gas(amount)?;
+            caller.user_data.0 += 1;
+            Ok(666)
+        })
+        .unwrap();
 
-    assert_eq!(i.call::<(u32, u32), u32>("add_u32", (0xffffffff, 2,)).unwrap(), 1);
-    assert_eq!(i.instance.reg(Reg::A0), 1);
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
+    let mut instance = instance_pre.instantiate().unwrap();
+    let base_cost = 3;
 
-    if is_64_bit {
-        assert_eq!(i.call::<(u32, u32), u32>("add_u32_asm", (0xfffffffa, 2,)).unwrap(), 0xfffffffc);
-        assert_eq!(i.instance.reg(Reg::A0), 0xfffffffffffffffc);
+    {
+        let mut state = (0, 5);
+        instance.set_gas(base_cost + 15);
+        instance.call_typed(&mut state, "main", ()).unwrap();
+        assert_eq!(instance.get_result_typed::<u32>(), 666);
+        assert_eq!(instance.gas(), 0);
+        assert_eq!(state.0, 2);
     }
-}
 
-fn test_blob_add_u64(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    assert_eq!(i.call::<(u64, u64), u64>("add_u64", (1, 2,)).unwrap(), 3);
-    assert_eq!(i.instance.reg(Reg::A0), 3);
-    assert_eq!(
-        i.call::<(u64, u64), u64>("add_u64", (0xaaaaaaaa, 0xcccccccc,)).unwrap(),
-        0xaaaaaaaa + 0xcccccccc
-    );
-}
+    {
+        // Not enough gas for the fixed cost; the host function is not called at all.
+        let mut state = (0, 5);
+        instance.set_gas(base_cost + 9);
+        let result = instance.call_typed(&mut state, "main", ());
+        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
+        assert_eq!(instance.gas(), -1);
+        assert_eq!(state.0, 0);
+    }
 
-fn test_blob_xor_imm_u32(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    for value in [0, 0xaaaaaaaa, 0x55555555, 0x12345678, 0xffffffff] {
-        assert_eq!(i.call::<(u32,), u32>("xor_imm_u32", (value,)).unwrap(), value ^ 0xfb8f5c1e);
+    {
+        // Not enough gas for the dynamic cost.
+        let mut state = (0, 6);
+        instance.set_gas(base_cost + 15);
+        let result = instance.call_typed(&mut state, "main", ());
+        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
+        assert_eq!(instance.gas(), -1);
+        assert_eq!(state.0, 1);
     }
-}
 
-fn test_blob_branch_less_than_zero(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    i.call::<(), ()>("test_branch_less_than_zero", ()).unwrap();
+    {
+        // An amount which doesn't even fit into the gas counter can never be paid for.
+        let mut state = (0, u64::MAX);
+        instance.set_gas(base_cost + 15);
+        let result = instance.call_typed(&mut state, "main", ());
+        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
+        assert!(instance.gas() < 0);
+        assert_eq!(state.0, 1);
+    }
 }
 
-fn test_blob_fetch_add_atomic_u64(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (1,)).unwrap(), 0);
-    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (0,)).unwrap(), 1);
-    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (0,)).unwrap(), 1);
-    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (0xffffffff,)).unwrap(), 1);
-    assert_eq!(i.call::<(u64,), u64>("fetch_add_atomic_u64", (0,)).unwrap(), 0x100000000);
-}
+fn block_on<F: core::future::Future>(future: F) -> F::Output {
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
-fn test_blob_cmov_if_zero_with_zero_reg(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    i.call::<(), ()>("cmov_if_zero_with_zero_reg", ()).unwrap();
-}
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
 
-fn test_blob_cmov_if_not_zero_with_zero_reg(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    i.call::<(), ()>("cmov_if_not_zero_with_zero_reg", ()).unwrap();
-}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
 
-fn test_blob_min_stack_size(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let i = TestInstance::new(&config, elf, optimize);
-    assert_eq!(i.instance.module().memory_map().stack_size(), 65536);
+    // SAFETY: The vtable's functions are all no-ops.
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut context = Context::from_waker(&waker);
+    let mut future = core::pin::pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
 }
 
-fn test_blob_negate_and_add(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    if !is_64_bit {
-        assert_eq!(i.call::<(u32, u32), u32>("negate_and_add", (123, 1,)).unwrap(), 15);
-    } else {
-        assert_eq!(i.call::<(u64, u64), u64>("negate_and_add", (123, 1,)).unwrap(), 15);
+struct YieldOnce(bool);
+
+impl core::future::Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: core::pin::Pin<&mut Self>, context: &mut core::task::Context) -> core::task::Poll<()> {
+        if self.0 {
+            core::task::Poll::Ready(())
+        } else {
+            self.0 = true;
+            context.waker().wake_by_ref();
+            core::task::Poll::Pending
+        }
     }
 }
 
-fn test_blob_return_tuple_from_import(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    i.call::<(), ()>("test_return_tuple", ()).unwrap();
-}
+fn async_host_functions(config: Config) {
+    let _ = env_logger::try_init();
 
-fn test_blob_return_tuple_from_export(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    if is_64_bit {
-        let a0 = 0x123456789abcdefe_u64;
-        let a1 = 0x1122334455667788_u64;
-        i.call::<(), ()>("export_return_tuple_u64", ()).unwrap();
-        assert_eq!(i.instance.reg(Reg::A0), a0);
-        assert_eq!(i.instance.reg(Reg::A1), a1);
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.add_import(b"fetch");
+    builder.add_import(b"increment");
+    builder.set_code(&[asm::ecalli(0), asm::ecalli(1), asm::ret()], &[]);
 
-        i.instance.set_reg(Reg::A0, 0);
-        i.instance.set_reg(Reg::A1, 0);
-        i.call::<(), ()>("export_return_tuple_usize", ()).unwrap();
-        assert_eq!(i.instance.reg(Reg::A0), a0);
-        assert_eq!(i.instance.reg(Reg::A1), a1);
-    } else {
-        let a0 = 0x12345678_u64;
-        let a1 = 0x9abcdefe_u64;
-        i.call::<(), ()>("export_return_tuple_u32", ()).unwrap();
-        assert_eq!(i.instance.reg(Reg::A0), a0);
-        assert_eq!(i.instance.reg(Reg::A1), a1);
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let module = Module::from_blob(&engine, &ModuleConfig::default(), blob).unwrap();
 
-        i.instance.set_reg(Reg::A0, 0);
-        i.instance.set_reg(Reg::A1, 0);
-        i.call::<(), ()>("export_return_tuple_usize", ()).unwrap();
-        assert_eq!(i.instance.reg(Reg::A0), a0);
-        assert_eq!(i.instance.reg(Reg::A1), a1);
+    fn fetch(caller: Caller<'_, u32>) -> HostFuture<'_, core::convert::Infallible> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            *caller.user_data += 1;
+            caller.instance.set_reg(Reg::A0, 100);
+            Ok(())
+        })
     }
-}
 
-fn test_blob_get_heap_base(config: Config, optimize: bool, is_64_bit: bool) {
-    let elf = get_test_program(TestProgram::TestBlob, is_64_bit);
-    let mut i = TestInstance::new(&config, elf, optimize);
-    let heap_base = i.call::<(), u32>("get_heap_base", ()).unwrap();
-    assert_eq!(heap_base, i.instance.module().memory_map().heap_base());
+    let mut linker: Linker<u32> = Linker::new();
+    linker.define_async("fetch", fetch).unwrap();
+    linker
+        .define_typed("increment", |caller: Caller<u32>, value: u32| -> u32 {
+            *caller.user_data += 1;
+            value + 1
+        })
+        .unwrap();
+
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
+    let mut instance = instance_pre.instantiate().unwrap();
+
+    let mut state = 0;
+    block_on(instance.call_typed_async(&mut state, "main", ())).unwrap();
+    assert_eq!(instance.get_result_typed::<u32>(), 101);
+    assert_eq!(state, 2);
+
+    // Asynchronous host functions cannot be called synchronously.
+    let result = instance.call_typed(&mut state, "main", ());
+    assert!(matches!(result, Err(CallError::Error(..))), "unexpected result: {result:?}");
+    assert_eq!(state, 2);
 }
 
-fn test_asm_reloc_add_sub(config: Config, optimize: bool, _is_64_bit: bool) {
-    const BLOB_64: &[u8] = include_bytes!("../../../guest-programs/asm-tests/output/reloc_add_sub_64.elf");
+fn typed_async_host_functions(config: Config) {
+    let _ = env_logger::try_init();
 
-    let elf = BLOB_64;
-    let mut i = TestInstance::new(&config, elf, optimize);
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.add_import(b"add");
+    builder.add_import(b"double");
+    builder.set_code(&[asm::ecalli(0), asm::ecalli(1), asm::ret()], &[]);
 
-    let address = i.call::<(u32,), u32>("get_string", (0,)).unwrap();
-    assert_eq!(i.instance.read_u32(address).unwrap(), 0x01010101);
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let module = Module::from_blob(&engine, &ModuleConfig::default(), blob).unwrap();
 
-    let address = i.call::<(u32,), u32>("get_string", (1,)).unwrap();
-    assert_eq!(i.instance.read_u32(address).unwrap(), 0x02020202);
+    fn add(caller: Caller<'_, u32>, a: u32, b: u32) -> TypedHostFuture<'_, u32> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            *caller.user_data += 1;
+            a + b
+        })
+    }
 
-    let address = i.call::<(u32,), u32>("get_string", (2,)).unwrap();
-    assert_eq!(i.instance.read_u32(address).unwrap(), 0x03030303);
-}
+    fn double(caller: Caller<'_, u32>, value: u32) -> TypedHostFuture<'_, Result<u32, String>> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            *caller.user_data += 1;
+            value.checked_mul(2).ok_or_else(|| String::from("overflow"))
+        })
+    }
 
-fn test_asm_reloc_hi_lo(config: Config, optimize: bool, _is_64_bit: bool) {
-    const BLOB_64: &[u8] = include_bytes!("../../../guest-programs/asm-tests/output/reloc_hi_lo_64.elf");
+    let mut linker: Linker<u32, String> = Linker::new();
+    linker.define_typed_async("add", add).unwrap();
+    linker.define_typed_async("double", double).unwrap();
 
-    let elf = BLOB_64;
-    let mut i = TestInstance::new(&config, elf, optimize);
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
+    let mut instance = instance_pre.instantiate().unwrap();
 
-    let address = i.call::<(u32,), u32>("get_string", (0,)).unwrap();
-    assert_eq!(i.instance.read_u32(address).unwrap(), 0xA1010101);
+    let mut state = 0;
+    block_on(instance.call_typed_async(&mut state, "main", (10_u32, 32_u32))).unwrap();
+    assert_eq!(instance.get_result_typed::<u32>(), 84);
+    assert_eq!(state, 2);
 
-    let address = i.call::<(u32,), u32>("get_string", (1,)).unwrap();
-    assert_eq!(i.instance.read_u32(address).unwrap(), 0xB2020202);
+    let result = block_on(instance.call_typed_async(&mut state, "main", (u32::MAX, 0_u32)));
+    assert!(
+        matches!(result, Err(CallError::User(ref error)) if error == "overflow"),
+        "unexpected result: {result:?}"
+    );
+    assert_eq!(state, 4);
 
-    let address = i.call::<(u32,), u32>("get_string", (2,)).unwrap();
-    assert_eq!(i.instance.read_u32(address).unwrap(), 0xC3030303);
+    // Asynchronous host functions cannot be called synchronously.
+    let result = instance.call_typed(&mut state, "main", (1_u32, 2_u32));
+    assert!(matches!(result, Err(CallError::Error(..))), "unexpected result: {result:?}");
+    assert_eq!(state, 4);
 }
 
-fn basic_gas_metering(config: Config, gas_metering_kind: GasMeteringKind) {
+fn execution_limits_basic_blocks(config: Config) {
     let _ = env_logger::try_init();
 
     let mut builder = ProgramBlobBuilder::new();
     builder.add_export_by_basic_block(0, b"main");
-    builder.set_code(&[asm::fallthrough(), asm::add_imm_32(A0, A0, 666), asm::ret()], &[]);
+    builder.set_code(&[asm::add_imm_32(A0, A0, 1), asm::jump(0)], &[]);
 
     let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
     let engine = Engine::new(&config).unwrap();
+
+    {
+        let module = Module::from_blob(&engine, &ModuleConfig::default(), blob.clone()).unwrap();
+        let mut instance = module.instantiate().unwrap();
+        let mut limits = InstanceLimits::new();
+        limits.set_basic_block_limit(Some(1));
+        assert!(instance.set_limits(limits).is_err());
+        assert!(instance.set_limits(InstanceLimits::new()).is_ok());
+    }
+
     let mut module_config = ModuleConfig::default();
-    module_config.set_gas_metering(Some(gas_metering_kind));
+    module_config.set_execution_limits(true);
 
     let module = Module::from_blob(&engine, &module_config, blob).unwrap();
     let linker: Linker = Linker::new();
     let instance_pre = linker.instantiate_pre(&module).unwrap();
     let mut instance = instance_pre.instantiate().unwrap();
+    assert_eq!(instance.basic_blocks_remaining(), None);
 
-    {
-        instance.set_gas(3);
-        instance.call_typed(&mut (), "main", ()).unwrap();
-        assert_eq!(instance.get_result_typed::<i32>(), 666);
-        assert_eq!(instance.gas(), 0);
-        assert_eq!(instance.program_counter(), None);
-        assert_eq!(instance.next_program_counter(), None);
-    }
-
-    {
-        instance.set_gas(2);
-        let result = instance.call_typed(&mut (), "main", ());
-        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
-        match gas_metering_kind {
-            GasMeteringKind::Sync => {
-                assert_eq!(instance.gas(), 1);
-                assert_eq!(instance.get_result_typed::<i32>(), 0);
-                assert_eq!(instance.program_counter(), Some(ProgramCounter(1)));
-                assert_eq!(instance.next_program_counter(), Some(ProgramCounter(1)));
+    let mut limits = InstanceLimits::new();
+    limits.set_basic_block_limit(Some(10));
+    instance.set_limits(limits.clone()).unwrap();
+    assert_eq!(instance.basic_blocks_remaining(), Some(10));
 
-                let result = instance.run().unwrap();
-                assert!(matches!(result, InterruptKind::NotEnoughGas), "unexpected result: {result:?}");
-                assert_eq!(instance.gas(), 1);
-                assert_eq!(instance.program_counter(), Some(ProgramCounter(1)));
-                assert_eq!(instance.next_program_counter(), Some(ProgramCounter(1)));
+    let result = instance.call_typed(&mut (), "main", ());
+    assert!(
+        matches!(result, Err(CallError::LimitReached(LimitKind::BasicBlocks))),
+        "unexpected result: {result:?}"
+    );
+    assert_eq!(instance.reg(A0), 10);
+    assert_eq!(instance.basic_blocks_remaining(), Some(0));
+    assert_eq!(instance.program_counter(), Some(ProgramCounter(0)));
+    assert_eq!(instance.next_program_counter(), Some(ProgramCounter(0)));
 
-                instance.set_gas(2);
-                let result = instance.run().unwrap();
-                assert!(matches!(result, InterruptKind::Finished), "unexpected result: {result:?}");
-                assert_eq!(instance.get_result_typed::<i32>(), 666);
-                assert_eq!(instance.gas(), 0);
-                assert_eq!(instance.program_counter(), None);
-                assert_eq!(instance.next_program_counter(), None);
-            }
-            GasMeteringKind::Async => {
-                assert!(instance.gas() < 0);
-                assert_eq!(instance.program_counter(), None);
-                assert_eq!(instance.next_program_counter(), None);
-            }
-        }
-    }
+    // Resuming without raising the limit interrupts again immediately.
+    let result = instance.run().unwrap();
+    assert_eq!(result, InterruptKind::LimitReached(LimitKind::BasicBlocks));
+    assert_eq!(instance.reg(A0), 10);
 
-    {
-        instance.set_gas(6);
-        instance.call_typed(&mut (), "main", ()).unwrap();
-        assert_eq!(instance.get_result_typed::<i32>(), 666);
-        assert_eq!(instance.gas(), 3);
-        assert_eq!(instance.program_counter(), None);
-        assert_eq!(instance.next_program_counter(), None);
+    limits.set_basic_block_limit(Some(5));
+    instance.set_limits(limits).unwrap();
+    let result = instance.run().unwrap();
+    assert_eq!(result, InterruptKind::LimitReached(LimitKind::BasicBlocks));
+    assert_eq!(instance.reg(A0), 15);
+}
 
-        instance.call_typed(&mut (), "main", ()).unwrap();
-        assert_eq!(instance.get_result_typed::<i32>(), 666);
-        assert_eq!(instance.gas(), 0);
-        assert_eq!(instance.program_counter(), None);
-        assert_eq!(instance.next_program_counter(), None);
+#[cfg(not(feature = "std"))]
+fn execution_limits_deadline(_config: Config) {}
 
-        let result = instance.call_typed(&mut (), "main", ());
-        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
-        match gas_metering_kind {
-            GasMeteringKind::Sync => {
-                assert_eq!(instance.gas(), 0);
-            }
-            GasMeteringKind::Async => {
-                assert!(instance.gas() < 0);
-            }
-        }
-    }
+#[cfg(feature = "std")]
+fn execution_limits_deadline(config: Config) {
+    let _ = env_logger::try_init();
 
-    {
-        core::mem::drop(instance);
-        let mut instance = instance_pre.instantiate().unwrap();
-        assert_eq!(instance.gas(), 0);
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"main");
+    builder.set_code(&[asm::add_imm_32(A0, A0, 1), asm::jump(0)], &[]);
 
-        let result = instance.call_typed(&mut (), "main", ());
-        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
-        match gas_metering_kind {
-            GasMeteringKind::Sync => {
-                assert_eq!(instance.gas(), 0);
-            }
-            GasMeteringKind::Async => {
-                assert!(instance.gas() < 0);
-            }
-        }
-    }
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::default();
+    module_config.set_execution_limits(true);
 
-    // Stress test.
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    let linker: Linker = Linker::new();
+    let instance_pre = linker.instantiate_pre(&module).unwrap();
     let mut instance = instance_pre.instantiate().unwrap();
-    for _ in 0..100 {
-        instance.set_gas(2);
-        let result = instance.call_typed(&mut (), "main", ());
-        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
-        match gas_metering_kind {
-            GasMeteringKind::Sync => {
-                assert_eq!(instance.get_result_typed::<i32>(), 0);
-                assert_eq!(instance.gas(), 1);
-            }
-            GasMeteringKind::Async => {
-                assert!(instance.gas() < 0);
-            }
-        }
 
-        instance.set_gas(5);
-        instance.call_typed(&mut (), "main", ()).unwrap();
-        assert_eq!(instance.gas(), 2);
-        assert_eq!(instance.get_result_typed::<i32>(), 666);
-    }
-}
+    let basic_block_limit = 1_000_000_000_000;
+    let timestamp = std::time::Instant::now();
+    let mut limits = InstanceLimits::new();
+    limits
+        .set_basic_block_limit(Some(basic_block_limit))
+        .set_deadline(Some(timestamp + core::time::Duration::from_millis(50)));
+    instance.set_limits(limits).unwrap();
+
+    let result = instance.call_typed(&mut (), "main", ());
+    assert!(
+        matches!(result, Err(CallError::LimitReached(LimitKind::Deadline))),
+        "unexpected result: {result:?}"
+    );
+    assert!(timestamp.elapsed() >= core::time::Duration::from_millis(50));
+    assert!(timestamp.elapsed() < core::time::Duration::from_secs(10));
+    assert_eq!(instance.program_counter(), Some(ProgramCounter(0)));
 
-fn basic_gas_metering_sync(config: Config) {
-    basic_gas_metering(config, GasMeteringKind::Sync);
-}
+    // The executed basic blocks are still accounted for.
+    let executed_basic_blocks = instance.reg(A0);
+    let remaining = instance.basic_blocks_remaining().unwrap();
+    assert!(executed_basic_blocks > 0);
+    assert!(remaining.abs_diff(basic_block_limit - executed_basic_blocks) <= 1);
 
-fn basic_gas_metering_async(config: Config) {
-    basic_gas_metering(config, GasMeteringKind::Async);
+    // The deadline has already passed, so this will interrupt immediately.
+    let result = instance.run().unwrap();
+    assert_eq!(result, InterruptKind::LimitReached(LimitKind::Deadline));
+    assert_eq!(instance.reg(A0), executed_basic_blocks);
+
+    let mut limits = InstanceLimits::new();
+    limits.set_basic_block_limit(Some(3));
+    instance.set_limits(limits).unwrap();
+    let result = instance.run().unwrap();
+    assert_eq!(result, InterruptKind::LimitReached(LimitKind::BasicBlocks));
+    assert_eq!(instance.reg(A0), executed_basic_blocks + 3);
 }
 
-fn consume_gas_in_host_function(config: Config, gas_metering_kind: GasMeteringKind) {
+#[cfg(not(feature = "std"))]
+fn interrupt_handle(_config: Config) {}
+
+#[cfg(feature = "std")]
+fn interrupt_handle(config: Config) {
     let _ = env_logger::try_init();
 
     let mut builder = ProgramBlobBuilder::new();
     builder.add_export_by_basic_block(0, b"main");
-    builder.add_import(b"hostfn");
-    builder.set_code(&[asm::ecalli(0), asm::ret()], &[]);
+    builder.set_code(&[asm::add_imm_32(A0, A0, 1), asm::jump(0)], &[]);
 
     let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
     let engine = Engine::new(&config).unwrap();
+
+    let module = Module::from_blob(&engine, &ModuleConfig::default(), blob.clone()).unwrap();
+    assert!(module.instantiate().unwrap().interrupt_handle().is_err());
+
     let mut module_config = ModuleConfig::default();
-    module_config.set_gas_metering(Some(gas_metering_kind));
+    module_config.set_execution_limits(true);
 
     let module = Module::from_blob(&engine, &module_config, blob).unwrap();
-    let mut linker: Linker<i64, core::convert::Infallible> = Linker::new();
-    linker
-        .define_typed("hostfn", |caller: Caller<i64>| -> u32 {
-            assert_eq!(caller.instance.gas(), 1);
-            caller.instance.set_gas(1 - *caller.user_data);
-            666
-        })
-        .unwrap();
-
+    let linker: Linker = Linker::new();
     let instance_pre = linker.instantiate_pre(&module).unwrap();
     let mut instance = instance_pre.instantiate().unwrap();
+    let handle = instance.interrupt_handle().unwrap();
+
+    let timestamp = std::time::Instant::now();
+    let thread = std::thread::spawn({
+        let handle = handle.clone();
+        move || {
+            std::thread::sleep(core::time::Duration::from_millis(50));
+            handle.interrupt();
+        }
+    });
 
-    {
-        instance.set_gas(3);
-        instance.call_typed(&mut 0, "main", ()).unwrap();
-        assert_eq!(instance.get_result_typed::<i32>(), 666);
-        assert_eq!(instance.gas(), 1);
-    }
+    let result = instance.call_typed(&mut (), "main", ());
+    thread.join().unwrap();
+    assert!(matches!(result, Err(CallError::Interrupted)), "unexpected result: {result:?}");
+    assert!(timestamp.elapsed() >= core::time::Duration::from_millis(50));
+    assert!(timestamp.elapsed() < core::time::Duration::from_secs(10));
+    assert_eq!(instance.program_counter(), Some(ProgramCounter(0)));
 
-    {
-        instance.set_gas(3);
-        instance.call_typed(&mut 1, "main", ()).unwrap();
-        assert_eq!(instance.get_result_typed::<i32>(), 666);
-        assert_eq!(instance.gas(), 0);
-    }
+    let executed_basic_blocks = instance.reg(A0);
+    assert!(executed_basic_blocks > 0);
 
-    {
-        instance.set_gas(3);
-        let result = instance.call_typed(&mut 2, "main", ());
-        assert_eq!(instance.gas(), -1);
-        assert!(matches!(result, Err(CallError::NotEnoughGas)), "unexpected result: {result:?}");
-    }
+    // An interrupt which happens while the instance is not running is delivered on the next run.
+    handle.interrupt();
+    let result = instance.run().unwrap();
+    assert_eq!(result, InterruptKind::Interrupted);
+    assert_eq!(instance.reg(A0), executed_basic_blocks);
+
+    // The execution can be resumed after being interrupted.
+    let mut limits = InstanceLimits::new();
+    limits.set_basic_block_limit(Some(3));
+    instance.set_limits(limits).unwrap();
+    let result = instance.run().unwrap();
+    assert_eq!(result, InterruptKind::LimitReached(LimitKind::BasicBlocks));
+    assert_eq!(instance.reg(A0), executed_basic_blocks + 3);
 }
 
 fn consume_gas_in_host_function_sync(config: Config) {
@@ -3546,6 +5456,517 @@ fn module_cache(mut config: Config) {
     assert!(Module::from_cache(&engine_with_lru_cache, &Default::default(), &blob).is_some());
 }
 
+#[cfg(not(feature = "std"))]
+fn code_cache(_config: Config) {}
+
+#[cfg(feature = "std")]
+fn code_cache(mut config: Config) {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let _ = env_logger::try_init();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"add");
+    builder.add_export_by_basic_block(1, b"indirect");
+    builder.set_code(
+        &[
+            asm::add_32(A0, A0, A1),
+            asm::ret(),
+            asm::load_imm(T0, 2),
+            asm::jump_indirect(T0, 0),
+            asm::mul_32(A0, A0, A1),
+            asm::ret(),
+        ],
+        &[2],
+    );
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let path = std::env::temp_dir().join(format!(
+        "polkavm-code-cache-test-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&path);
+
+    // Make sure the in-memory module cache doesn't get in the way.
+    config.set_cache_enabled(false);
+    // SAFETY: The directory was just created by us.
+    unsafe {
+        config.set_code_cache_dir(Some(path.clone()));
+    }
+
+    let run = |module: &Module| {
+        let mut instance = module.instantiate().unwrap();
+        for (name, expected) in [("add", 13), ("indirect", 42)] {
+            let program_counter = module.exports().find(|export| export == name).unwrap().program_counter();
+            instance.set_reg(Reg::A0, 6);
+            instance.set_reg(Reg::A1, 7);
+            instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+            instance.set_next_program_counter(program_counter);
+            assert_eq!(instance.run().unwrap(), InterruptKind::Finished);
+            assert_eq!(instance.reg(Reg::A0), expected);
+        }
+    };
+
+    let list_entries = || -> Vec<_> {
+        let Ok(entries) = std::fs::read_dir(&path) else {
+            return Vec::new();
+        };
+
+        entries.map(|entry| entry.unwrap().path()).collect()
+    };
+
+    let engine = Engine::new(&config).unwrap();
+    let module_1 = Module::from_blob(&engine, &Default::default(), blob.clone()).unwrap();
+    run(&module_1);
+
+    let entries = list_entries();
+    if engine.backend() != BackendKind::Compiler || config.sandbox() != Some(crate::SandboxKind::Linux) {
+        assert!(entries.is_empty());
+        let _ = std::fs::remove_dir_all(&path);
+        return;
+    }
+
+    assert_eq!(entries.len(), 1);
+    let entry_path = &entries[0];
+    let original_entry = std::fs::read(entry_path).unwrap();
+
+    // A cache hit must not rewrite the entry.
+    let old_timestamp = std::time::SystemTime::UNIX_EPOCH + core::time::Duration::from_secs(1000);
+    std::fs::File::options()
+        .write(true)
+        .open(entry_path)
+        .unwrap()
+        .set_modified(old_timestamp)
+        .unwrap();
+
+    let engine = Engine::new(&config).unwrap();
+    let module_2 = Module::from_blob(&engine, &Default::default(), blob.clone()).unwrap();
+    assert_eq!(module_1.machine_code(), module_2.machine_code());
+    run(&module_2);
+    assert_eq!(std::fs::metadata(entry_path).unwrap().modified().unwrap(), old_timestamp);
+
+    // A damaged entry must be ignored and replaced.
+    let mut damaged_entry = original_entry.clone();
+    *damaged_entry.last_mut().unwrap() ^= 0xff;
+    std::fs::write(entry_path, &damaged_entry).unwrap();
+
+    let engine = Engine::new(&config).unwrap();
+    let module_3 = Module::from_blob(&engine, &Default::default(), blob).unwrap();
+    run(&module_3);
+    assert_eq!(std::fs::read(entry_path).unwrap(), original_entry);
+    assert_eq!(list_entries().len(), 1);
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+fn precompiled_module(config: Config) {
+    let _ = env_logger::try_init();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"add");
+    builder.add_export_by_basic_block(1, b"indirect");
+    builder.set_code(
+        &[
+            asm::add_32(A0, A0, A1),
+            asm::ret(),
+            asm::load_imm(T0, 2),
+            asm::jump_indirect(T0, 0),
+            asm::mul_32(A0, A0, A1),
+            asm::ret(),
+        ],
+        &[2],
+    );
+
+    let bytes: ArcBytes = builder.into_vec().into();
+    let engine = Engine::new(&config).unwrap();
+    let module_config = ModuleConfig::new();
+    let artifact = match Module::precompile(&engine, &module_config, bytes.clone()) {
+        Ok(artifact) => artifact,
+        Err(error) => {
+            assert!(engine.backend() != BackendKind::Compiler || config.sandbox() != Some(crate::SandboxKind::Linux));
+            assert!(error.to_string().contains("failed to precompile module"));
+            // SAFETY: An empty artifact is always rejected.
+            assert!(unsafe { Module::from_precompiled(&engine, &module_config, &[]) }.is_err());
+            return;
+        }
+    };
+
+    // The artifact must be loadable by a different engine.
+    let engine = Engine::new(&config).unwrap();
+    // SAFETY: The artifact was just produced by `Module::precompile`.
+    let module = unsafe { Module::from_precompiled(&engine, &module_config, &artifact) }.unwrap();
+    let reference_module = Module::new(&engine, &module_config, bytes).unwrap();
+    assert_eq!(module.machine_code(), reference_module.machine_code());
+
+    let mut instance = module.instantiate().unwrap();
+    for (name, expected) in [("add", 13), ("indirect", 42)] {
+        let program_counter = module.exports().find(|export| export == name).unwrap().program_counter();
+        instance.set_reg(Reg::A0, 6);
+        instance.set_reg(Reg::A1, 7);
+        instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+        instance.set_next_program_counter(program_counter);
+        assert_eq!(instance.run().unwrap(), InterruptKind::Finished);
+        assert_eq!(instance.reg(Reg::A0), expected);
+    }
+
+    let mut other_module_config = ModuleConfig::new();
+    other_module_config.set_gas_metering(Some(GasMeteringKind::Sync));
+    // SAFETY: The artifact was just produced by `Module::precompile`.
+    let error = unsafe { Module::from_precompiled(&engine, &other_module_config, &artifact) }
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("different module config"));
+
+    let mut damaged_artifact = artifact.clone();
+    let middle = damaged_artifact.len() / 2;
+    damaged_artifact[middle] ^= 0xff;
+    // SAFETY: The damaged artifact is rejected before any of its code could be executed.
+    let error = unsafe { Module::from_precompiled(&engine, &module_config, &damaged_artifact) }
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("corrupted"));
+
+    // SAFETY: The truncated artifact is rejected before any of its code could be executed.
+    let error = unsafe { Module::from_precompiled(&engine, &module_config, &artifact[..artifact.len() - 1]) }
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("corrupted"));
+
+    // SAFETY: This is not an artifact at all, so it's always rejected.
+    let error = unsafe { Module::from_precompiled(&engine, &module_config, b"not a precompiled module") }
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("not a precompiled PolkaVM module"));
+}
+
+#[cfg(not(feature = "std"))]
+fn tiered_compilation(_config: Config) {}
+
+#[cfg(feature = "std")]
+fn tiered_compilation(config: Config) {
+    let _ = env_logger::try_init();
+    let mut builder = ProgramBlobBuilder::new();
+    builder.add_export_by_basic_block(0, b"add");
+    builder.set_code(&[asm::add_32(A0, A0, A1), asm::ret()], &[]);
+
+    let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+    let engine = Engine::new(&config).unwrap();
+    let mut module_config = ModuleConfig::new();
+    module_config.set_tiered_compilation(Some(2));
+    let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+    assert!(module.machine_code().is_none());
+
+    let run = |instance: &mut crate::RawInstance| {
+        let program_counter = module.exports().find(|export| export == "add").unwrap().program_counter();
+        instance.set_reg(Reg::A0, 6);
+        instance.set_reg(Reg::A1, 7);
+        instance.set_reg(Reg::RA, crate::RETURN_TO_HOST);
+        instance.set_next_program_counter(program_counter);
+        assert_eq!(instance.run().unwrap(), InterruptKind::Finished);
+        assert_eq!(instance.reg(Reg::A0), 13);
+    };
+
+    // The first instances must always be interpreted; the second one kicks off the compilation.
+    for _ in 0..2 {
+        let mut instance = module.instantiate().unwrap();
+        assert!(instance.module().machine_code().is_none());
+        run(&mut instance);
+    }
+
+    if engine.backend() != BackendKind::Compiler {
+        return;
+    }
+
+    let deadline = std::time::Instant::now() + core::time::Duration::from_secs(30);
+    loop {
+        let mut instance = module.instantiate().unwrap();
+        run(&mut instance);
+        if instance.module().machine_code().is_some() {
+            break;
+        }
+
+        assert!(
+            std::time::Instant::now() < deadline,
+            "the module was not compiled in the background"
+        );
+        std::thread::sleep(core::time::Duration::from_millis(1));
+    }
+}
+
+fn special_cased_immediates(config: Config) {
+    let _ = env_logger::try_init();
+    let engine = Engine::new(&config).unwrap();
+    for is_64_bit in [false, true] {
+        let mut builder = if is_64_bit {
+            ProgramBlobBuilder::new_64bit()
+        } else {
+            ProgramBlobBuilder::new()
+        };
+
+        builder.add_export_by_basic_block(0, b"main");
+        let mut code = vec![
+            asm::load_imm(A0, 0),
+            asm::load_imm(A1, 0x7fffffff),
+            asm::load_imm(A2, 0x80000000),
+            asm::and_imm(A3, S0, 0x7f00ff00),
+            asm::mul_imm_32(A4, S0, 0x10),
+            asm::shift_logical_right_imm_32(A5, S0, 4),
+            asm::add_imm_32(T0, T0, 0xffffffff),
+            asm::add_imm_32(T1, T1, 0),
+            asm::set_less_than_signed_imm(T2, S0, 0),
+        ];
+
+        if is_64_bit {
+            code.extend([asm::mul_imm_64(S1, S1, 0x40000000), asm::mul_imm_64(SP, SP, 0x80000000)]);
+        }
+
+        code.extend([
+            asm::branch_less_signed_imm(S0, 0, 2),
+            asm::load_imm(RA, 1),
+            asm::trap(),
+            asm::load_imm(RA, 2),
+            asm::trap(),
+        ]);
+
+        builder.set_code(&code, &[]);
+
+        let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+        let module = Module::from_blob(&engine, &Default::default(), blob).unwrap();
+        let mut instance = module.instantiate().unwrap();
+        let sign_extend = |value: u32| {
+            if is_64_bit {
+                i64::from(value as i32) as u64
+            } else {
+                u64::from(value)
+            }
+        };
+        for reg in [A0, A1, A2, A3, A4, A5, T0, T2] {
+            instance.set_reg(reg, sign_extend(0xcccccccc));
+        }
+        instance.set_reg(S0, sign_extend(0xdeadbeef));
+        instance.set_reg(T0, 0);
+        instance.set_reg(T1, sign_extend(0x87654321));
+        instance.set_reg(S1, 3);
+        instance.set_reg(SP, 1);
+        instance.set_next_program_counter(ProgramCounter(0));
+        assert!(matches!(instance.run().unwrap(), InterruptKind::Trap));
+
+        assert_eq!(instance.reg(A0), 0);
+        assert_eq!(instance.reg(A1), 0x7fffffff);
+        assert_eq!(instance.reg(A2), sign_extend(0x80000000));
+        assert_eq!(instance.reg(A3), 0x5e00be00);
+        assert_eq!(instance.reg(A4), sign_extend(0xeadbeef0));
+        assert_eq!(instance.reg(A5), 0x0deadbee);
+        assert_eq!(instance.reg(T0), sign_extend(0xffffffff));
+        assert_eq!(instance.reg(T1), sign_extend(0x87654321));
+        assert_eq!(instance.reg(T2), 1);
+        assert_eq!(instance.reg(RA), 2);
+        if is_64_bit {
+            assert_eq!(instance.reg(S1), 0xc0000000);
+            assert_eq!(instance.reg(SP), 0xffffffff80000000);
+        }
+    }
+}
+
+fn block_level_optimizations(config: Config) {
+    let _ = env_logger::try_init();
+    let engine = Engine::new(&config).unwrap();
+    let memory_map = MemoryMapBuilder::new(0x4000).rw_data_size(0x4000).build().unwrap();
+    let address = memory_map.rw_data_address();
+    for is_64_bit in [false, true] {
+        let mut builder = if is_64_bit {
+            ProgramBlobBuilder::new_64bit()
+        } else {
+            ProgramBlobBuilder::new()
+        };
+
+        builder.set_rw_data_size(0x4000);
+        builder.add_export_by_basic_block(0, b"main");
+        builder.set_code(
+            &[
+                asm::load_imm(A0, address),
+                if is_64_bit {
+                    asm::add_imm_64(A1, A0, 8)
+                } else {
+                    asm::add_imm_32(A1, A0, 8)
+                },
+                asm::store_imm_indirect_u32(A1, 4, 0x12345678),
+                asm::load_imm(A2, 0x100),
+                asm::store_indirect_u32(A2, A0, 0),
+                asm::load_indirect_u16(A3, A0, 14),
+                asm::zero_extend_16(A4, A3),
+                asm::add_32(A5, A3, A4),
+                asm::load_imm(T0, 5),
+                asm::load_imm(T1, 7),
+                asm::sub_32(T2, T0, T1),
+                asm::shift_logical_left_imm_32(S0, T2, 4),
+                asm::xor(S1, T0, T1),
+                asm::and_imm(T1, A3, 0xffff),
+                asm::set_less_than_unsigned_imm(RA, A5, 0x3000),
+                asm::branch_not_eq_imm(RA, 0, 2),
+                // 1:
+                asm::trap(),
+                // 2:
+                asm::load_imm(T0, 5),
+                asm::branch_eq_imm(T0, 5, 4),
+                // 3:
+                asm::trap(),
+                // 4:
+                asm::load_imm(SP, 0x1234),
+                asm::trap(),
+            ],
+            &[],
+        );
+
+        let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+        let instructions: Vec<_> = blob.instructions(DefaultInstructionSet::default()).collect();
+        let module = Module::from_blob(&engine, &Default::default(), blob).unwrap();
+        let sign_extend = |value: u32| {
+            if is_64_bit {
+                i64::from(value as i32) as u64
+            } else {
+                u64::from(value)
+            }
+        };
+
+        let mut instance = module.instantiate().unwrap();
+        instance.set_next_program_counter(ProgramCounter(0));
+        assert!(matches!(instance.run().unwrap(), InterruptKind::Trap));
+        assert_eq!(instance.read_u32(address).unwrap(), 0x100);
+        assert_eq!(instance.read_u32(address + 12).unwrap(), 0x12345678);
+        assert_eq!(instance.reg(A1), u64::from(address + 8));
+        assert_eq!(instance.reg(A3), 0x1234);
+        assert_eq!(instance.reg(A4), 0x1234);
+        assert_eq!(instance.reg(A5), 0x2468);
+        assert_eq!(instance.reg(T1), 0x1234);
+        assert_eq!(instance.reg(T2), sign_extend(-2_i32 as u32));
+        assert_eq!(instance.reg(S0), sign_extend(-32_i32 as u32));
+        assert_eq!(instance.reg(S1), 2);
+        assert_eq!(instance.reg(RA), 1);
+        assert_eq!(instance.reg(SP), 0x1234);
+
+        // Start in the middle of the block; nothing that was known about the registers there is true anymore.
+        let mut instance = module.instantiate().unwrap();
+        instance.set_reg(A0, u64::from(address + 0x40));
+        instance.set_reg(A2, 0x77);
+        instance.set_reg(T0, 0);
+        instance.set_reg(T1, 0);
+        instance.set_next_program_counter(instructions[4].offset);
+        assert!(matches!(instance.run().unwrap(), InterruptKind::Trap));
+        assert_eq!(instance.read_u32(address).unwrap(), 0);
+        assert_eq!(instance.read_u32(address + 0x40).unwrap(), 0x77);
+        assert_eq!(instance.reg(A3), 0);
+        assert_eq!(instance.reg(A4), 0);
+        assert_eq!(instance.reg(A5), 0);
+        assert_eq!(instance.reg(T1), 0);
+        assert_eq!(instance.reg(T2), sign_extend(-2_i32 as u32));
+        assert_eq!(instance.reg(S1), 2);
+        assert_eq!(instance.reg(RA), 1);
+        assert_eq!(instance.reg(SP), 0x1234);
+
+        // Start right before the branch, which otherwise reuses the flags set by the comparison.
+        let mut instance = module.instantiate().unwrap();
+        instance.set_reg(RA, 0);
+        instance.set_reg(SP, 0);
+        instance.set_next_program_counter(instructions[15].offset);
+        assert!(matches!(instance.run().unwrap(), InterruptKind::Trap));
+        assert_eq!(instance.program_counter(), Some(instructions[16].offset));
+        assert_eq!(instance.reg(SP), 0);
+    }
+}
+
+fn block_level_optimizations_program_counters(config: Config) {
+    let _ = env_logger::try_init();
+    let engine = Engine::new(&config).unwrap();
+    let memory_map = MemoryMapBuilder::new(0x4000).rw_data_size(0x4000).build().unwrap();
+    let address = memory_map.rw_data_address();
+    for is_64_bit in [false, true] {
+        let mut builder = if is_64_bit {
+            ProgramBlobBuilder::new_64bit()
+        } else {
+            ProgramBlobBuilder::new()
+        };
+
+        let add_imm = |d, s, imm| {
+            if is_64_bit {
+                asm::add_imm_64(d, s, imm)
+            } else {
+                asm::add_imm_32(d, s, imm)
+            }
+        };
+
+        builder.set_rw_data_size(0x4000);
+        builder.add_export_by_basic_block(0, b"main");
+        builder.set_code(
+            &[
+                asm::load_imm(A0, address),
+                add_imm(A1, A0, 8),
+                asm::load_imm(A2, 5),
+                asm::add_32(A2, A2, A2),
+                asm::ecalli(0),
+                asm::add_imm_32(A3, A2, 1),
+                asm::store_indirect_u32(A3, A1, 0),
+                asm::set_less_than_unsigned_imm(RA, A3, 200),
+                asm::branch_not_eq_imm(RA, 0, 2),
+                // 1:
+                asm::trap(),
+                // 2:
+                asm::load_imm(T0, 0x10),
+                add_imm(T1, T0, 4),
+                asm::store_indirect_u32(A3, T1, 0),
+                asm::ret(),
+            ],
+            &[],
+        );
+
+        let blob = ProgramBlob::parse(builder.into_vec().into()).unwrap();
+        let instructions: Vec<_> = blob.instructions(DefaultInstructionSet::default()).collect();
+        let mut module_config = ModuleConfig::new();
+        module_config.set_gas_metering(Some(GasMeteringKind::Sync));
+        let module = Module::from_blob(&engine, &module_config, blob).unwrap();
+
+        // The host call in the middle of the first block sees the folded values, and what it changes isn't folded away afterwards.
+        let mut instance = module.instantiate().unwrap();
+        instance.set_gas(9);
+        instance.set_next_program_counter(ProgramCounter(0));
+        assert_eq!(instance.run().unwrap(), InterruptKind::Ecalli(0));
+        assert_eq!(instance.program_counter(), Some(instructions[4].offset));
+        assert_eq!(instance.next_program_counter(), Some(instructions[5].offset));
+        assert_eq!(instance.reg(A1), u64::from(address + 8));
+        assert_eq!(instance.reg(A2), 10);
+        instance.set_reg(A2, 100);
+
+        // The fused branch is taken, and the execution stops before the next block since there's not enough gas for it.
+        assert_eq!(instance.run().unwrap(), InterruptKind::NotEnoughGas);
+        assert_eq!(instance.gas(), 0);
+        assert_eq!(instance.program_counter(), Some(instructions[10].offset));
+        assert_eq!(instance.next_program_counter(), Some(instructions[10].offset));
+        assert_eq!(instance.reg(A3), 101);
+        assert_eq!(instance.reg(RA), 1);
+        assert_eq!(instance.read_u32(address + 8).unwrap(), 101);
+
+        // The store through a folded address traps on the right instruction, with the registers it depends on set.
+        instance.set_gas(100);
+        assert_eq!(instance.run().unwrap(), InterruptKind::Trap);
+        assert_eq!(instance.program_counter(), Some(instructions[12].offset));
+        assert_eq!(instance.next_program_counter(), None);
+        assert_eq!(instance.reg(T0), 0x10);
+        assert_eq!(instance.reg(T1), 0x14);
+        assert_eq!(instance.gas(), 96);
+
+        // Without the host call the fused branch isn't taken, and the trap is reported right after it.
+        let mut instance = module.instantiate().unwrap();
+        instance.set_gas(100);
+        instance.set_next_program_counter(instructions[5].offset);
+        instance.set_reg(A1, u64::from(address));
+        instance.set_reg(A2, 1000);
+        assert_eq!(instance.run().unwrap(), InterruptKind::Trap);
+        assert_eq!(instance.program_counter(), Some(instructions[9].offset));
+        assert_eq!(instance.reg(RA), 0);
+        assert_eq!(instance.read_u32(address).unwrap(), 1001);
+    }
+}
+
 fn run_riscv_test(engine_config: Config, elf: &[u8], testnum_reg: Reg, optimize: bool) {
     let _ = env_logger::try_init();
     let mut linker_config = polkavm_linker::Config::default();
@@ -3595,6 +6016,7 @@ include!("tests_riscv.rs");
 run_tests! {
     basic_test
     fallback_hostcall_handler_works
+    guest_panic_is_reported
     step_tracing_basic
     step_tracing_invalid_store
     step_tracing_invalid_load
@@ -3643,12 +6065,40 @@ run_tests! {
     aux_data_works
     aux_data_accessible_area
     access_memory_from_host
+    snapshot_and_restore
+    snapshot_and_restore_with_dynamic_paging
+    snapshot_serialization
+    fork_instance
+    fork_instance_with_limits
     sbrk_knob_works
+    memory_usage_and_limit
+    dynamic_paging_memory_usage_and_limit
+    dynamic_paging_page_aligned_access_covers_only_its_pages
+    memory_slices
+    dynamic_paging_memory_slices
+    gdb_stub
+    gdb_stub_interrupt
+    profiler
+    gas_report
+    coverage
+    trace_record_and_replay
+    watchpoints
+    backtrace
+    memory_sanitizer
 
     basic_gas_metering_sync
     basic_gas_metering_async
+    resume_after_running_out_of_gas_sync
+    resume_after_running_out_of_gas_async
+    resume_after_running_out_of_gas_when_finishing_async
     consume_gas_in_host_function_sync
     consume_gas_in_host_function_async
+    charge_gas_in_host_function
+    async_host_functions
+    typed_async_host_functions
+    execution_limits_basic_blocks
+    execution_limits_deadline
+    interrupt_handle
     gas_metering_with_more_than_one_basic_block
     gas_metering_with_implicit_trap
 
@@ -3660,6 +6110,12 @@ run_tests! {
 
     spawn_stress_test
     module_cache
+    code_cache
+    precompiled_module
+    tiered_compilation
+    special_cased_immediates
+    block_level_optimizations
+    block_level_optimizations_program_counters
 }
 
 run_test_blob_tests! {
@@ -3719,4 +6175,5 @@ assert_send_sync! {
     crate::Module,
     crate::ModuleConfig,
     crate::ProgramBlob,
+    crate::Snapshot,
 }
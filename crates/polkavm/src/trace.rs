@@ -0,0 +1,628 @@
+//! Execution trace recording and deterministic replay.
+//!
+//! A [`TraceRecorder`] is fed every interruption of an instance created from a module with
+//! [`ModuleConfig::set_step_tracing`](crate::ModuleConfig::set_step_tracing) enabled, and writes out a compact
+//! binary log of every executed instruction along with its effects on the registers, the memory and the gas.
+//! Whatever the host does to the instance while handling a host call (or any other interruption which can be resumed)
+//! is recorded too, so the trace can later be [`replay`]ed without the host, and the first point at which
+//! the execution diverges from the recorded one is reported.
+//!
+//! Memory writes made by the host are not visible to the recorder and must be reported to it
+//! through [`TraceRecorder::on_memory_write`].
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use std::collections::HashMap;
+use std::io::Write;
+
+use polkavm_common::cast::cast;
+use polkavm_common::program::{Instruction, ProgramCounter, Reg};
+
+use crate::error::bail;
+use crate::utils::Reader;
+use crate::{Error, Gas, GasMeteringKind, InterruptKind, Module, ModuleConfig, RawInstance, RegValue};
+
+const TRACE_MAGIC: [u8; 8] = *b"PVMTRACE";
+const TRACE_VERSION: u8 = 1;
+
+const TAG_START: u8 = 1;
+const TAG_STEP: u8 = 2;
+const TAG_REG_WRITE: u8 = 3;
+const TAG_MEMORY_WRITE: u8 = 4;
+const TAG_GAS: u8 = 5;
+const TAG_HOST_CALL: u8 = 6;
+const TAG_OUT_OF_GAS: u8 = 7;
+const TAG_SEGFAULT: u8 = 8;
+const TAG_RESUME: u8 = 9;
+const TAG_FINISHED: u8 = 10;
+const TAG_TRAP: u8 = 11;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Event {
+    /// The execution started at the given program counter with the given state.
+    Start {
+        program_counter: ProgramCounter,
+        regs: [RegValue; Reg::ALL.len()],
+        gas: Gas,
+    },
+    /// The instruction at the given program counter is about to be executed.
+    Step(ProgramCounter),
+    RegWrite(Reg, RegValue),
+    MemoryWrite(u32, Vec<u8>),
+    Gas(Gas),
+    /// The execution was handed over to the host, which might modify the state before resuming it.
+    HostCall(u32),
+    OutOfGas,
+    Segfault(u32),
+    /// The execution was resumed by the host.
+    Resume,
+    Finished,
+    Trap,
+}
+
+impl core::fmt::Display for Event {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Event::Start { program_counter, gas, .. } => write!(fmt, "start at {program_counter} with {gas} gas"),
+            Event::Step(program_counter) => write!(fmt, "step at {program_counter}"),
+            Event::RegWrite(reg, value) => write!(fmt, "{reg} = 0x{value:x}"),
+            Event::MemoryWrite(address, data) => {
+                write!(fmt, "write of {} byte(s) at 0x{address:x}:", data.len())?;
+                for byte in data {
+                    write!(fmt, " {byte:02x}")?;
+                }
+
+                Ok(())
+            }
+            Event::Gas(gas) => write!(fmt, "gas = {gas}"),
+            Event::HostCall(index) => write!(fmt, "host call {index}"),
+            Event::OutOfGas => fmt.write_str("out of gas"),
+            Event::Segfault(page_address) => write!(fmt, "segfault at page 0x{page_address:x}"),
+            Event::Resume => fmt.write_str("resume"),
+            Event::Finished => fmt.write_str("finished"),
+            Event::Trap => fmt.write_str("trap"),
+        }
+    }
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push(cast(value).truncate_to_u8() | 0x80);
+        value >>= 7;
+    }
+
+    output.push(cast(value).truncate_to_u8());
+}
+
+fn write_signed_varint(output: &mut Vec<u8>, value: i64) {
+    write_varint(output, cast((value << 1) ^ (value >> 63)).to_unsigned());
+}
+
+impl Event {
+    fn serialize(&self, output: &mut Vec<u8>) {
+        match self {
+            Event::Start {
+                program_counter,
+                regs,
+                gas,
+            } => {
+                output.push(TAG_START);
+                write_varint(output, u64::from(program_counter.0));
+                for &value in regs {
+                    write_varint(output, value);
+                }
+                write_signed_varint(output, *gas);
+            }
+            Event::Step(program_counter) => {
+                output.push(TAG_STEP);
+                write_varint(output, u64::from(program_counter.0));
+            }
+            Event::RegWrite(reg, value) => {
+                output.push(TAG_REG_WRITE);
+                output.push(cast(reg.to_u32()).truncate_to_u8());
+                write_varint(output, *value);
+            }
+            Event::MemoryWrite(address, data) => {
+                output.push(TAG_MEMORY_WRITE);
+                write_varint(output, u64::from(*address));
+                write_varint(output, cast(data.len()).to_u64());
+                output.extend_from_slice(data);
+            }
+            Event::Gas(gas) => {
+                output.push(TAG_GAS);
+                write_signed_varint(output, *gas);
+            }
+            Event::HostCall(index) => {
+                output.push(TAG_HOST_CALL);
+                write_varint(output, u64::from(*index));
+            }
+            Event::OutOfGas => output.push(TAG_OUT_OF_GAS),
+            Event::Segfault(page_address) => {
+                output.push(TAG_SEGFAULT);
+                write_varint(output, u64::from(*page_address));
+            }
+            Event::Resume => output.push(TAG_RESUME),
+            Event::Finished => output.push(TAG_FINISHED),
+            Event::Trap => output.push(TAG_TRAP),
+        }
+    }
+}
+
+fn read_varint(reader: &mut Reader) -> Result<u64, Error> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = reader.read_u8()?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    bail!("failed to parse the trace: varint is too long");
+}
+
+fn read_varint_u32(reader: &mut Reader) -> Result<u32, Error> {
+    let value = read_varint(reader)?;
+    let Ok(value) = u32::try_from(value) else {
+        bail!("failed to parse the trace: value out of range: {value}");
+    };
+
+    Ok(value)
+}
+
+fn read_signed_varint(reader: &mut Reader) -> Result<i64, Error> {
+    let value = read_varint(reader)?;
+    Ok(cast(value >> 1).to_signed() ^ -cast(value & 1).to_signed())
+}
+
+fn read_event(reader: &mut Reader) -> Result<Event, Error> {
+    let event = match reader.read_u8()? {
+        TAG_START => {
+            let program_counter = ProgramCounter(read_varint_u32(reader)?);
+            let mut regs = [0; Reg::ALL.len()];
+            for value in &mut regs {
+                *value = read_varint(reader)?;
+            }
+
+            Event::Start {
+                program_counter,
+                regs,
+                gas: read_signed_varint(reader)?,
+            }
+        }
+        TAG_STEP => Event::Step(ProgramCounter(read_varint_u32(reader)?)),
+        TAG_REG_WRITE => {
+            let index = reader.read_u8()?;
+            let Some(reg) = Reg::from_raw(u32::from(index)) else {
+                bail!("failed to parse the trace: invalid register: {index}");
+            };
+
+            Event::RegWrite(reg, read_varint(reader)?)
+        }
+        TAG_MEMORY_WRITE => {
+            let address = read_varint_u32(reader)?;
+            let length = cast(read_varint_u32(reader)?).to_usize();
+            Event::MemoryWrite(address, reader.read_slice(length)?.into())
+        }
+        TAG_GAS => Event::Gas(read_signed_varint(reader)?),
+        TAG_HOST_CALL => Event::HostCall(read_varint_u32(reader)?),
+        TAG_OUT_OF_GAS => Event::OutOfGas,
+        TAG_SEGFAULT => Event::Segfault(read_varint_u32(reader)?),
+        TAG_RESUME => Event::Resume,
+        TAG_FINISHED => Event::Finished,
+        TAG_TRAP => Event::Trap,
+        tag => bail!("failed to parse the trace: invalid event tag: {tag}"),
+    };
+
+    Ok(event)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    /// The execution hasn't started yet, or has already finished.
+    Idle,
+    Running,
+    /// The execution was interrupted and the host might modify the state before resuming it.
+    Yielded,
+}
+
+/// Turns the interruptions of an instance into a stream of events.
+struct Observer {
+    memory_writes: HashMap<ProgramCounter, Instruction>,
+    state: State,
+    regs: [RegValue; Reg::ALL.len()],
+    gas: Gas,
+    pending_memory_write: Option<(u32, u32)>,
+}
+
+impl Observer {
+    fn new(module: &Module) -> Self {
+        let memory_writes = module
+            .instructions()
+            .filter(|instruction| {
+                matches!(
+                    instruction.kind,
+                    Instruction::store_u8(..)
+                        | Instruction::store_u16(..)
+                        | Instruction::store_u32(..)
+                        | Instruction::store_u64(..)
+                        | Instruction::store_imm_u8(..)
+                        | Instruction::store_imm_u16(..)
+                        | Instruction::store_imm_u32(..)
+                        | Instruction::store_imm_u64(..)
+                        | Instruction::store_indirect_u8(..)
+                        | Instruction::store_indirect_u16(..)
+                        | Instruction::store_indirect_u32(..)
+                        | Instruction::store_indirect_u64(..)
+                        | Instruction::store_imm_indirect_u8(..)
+                        | Instruction::store_imm_indirect_u16(..)
+                        | Instruction::store_imm_indirect_u32(..)
+                        | Instruction::store_imm_indirect_u64(..)
+                        | Instruction::memset
+                )
+            })
+            .map(|instruction| (instruction.offset, instruction.kind))
+            .collect();
+
+        Observer {
+            memory_writes,
+            state: State::Idle,
+            regs: [0; Reg::ALL.len()],
+            gas: 0,
+            pending_memory_write: None,
+        }
+    }
+
+    /// Returns the memory range which the instruction at the given program counter is about to write to.
+    fn memory_write_range(&self, instance: &RawInstance, program_counter: ProgramCounter) -> Option<(u32, u32)> {
+        let reg = |reg: polkavm_common::program::RawReg| cast(instance.reg(reg.get())).truncate_to_u32();
+        let range = match *self.memory_writes.get(&program_counter)? {
+            Instruction::store_u8(_, address) | Instruction::store_imm_u8(address, _) => (address, 1),
+            Instruction::store_u16(_, address) | Instruction::store_imm_u16(address, _) => (address, 2),
+            Instruction::store_u32(_, address) | Instruction::store_imm_u32(address, _) => (address, 4),
+            Instruction::store_u64(_, address) | Instruction::store_imm_u64(address, _) => (address, 8),
+            Instruction::store_indirect_u8(_, base, offset) | Instruction::store_imm_indirect_u8(base, offset, _) => {
+                (reg(base).wrapping_add(offset), 1)
+            }
+            Instruction::store_indirect_u16(_, base, offset) | Instruction::store_imm_indirect_u16(base, offset, _) => {
+                (reg(base).wrapping_add(offset), 2)
+            }
+            Instruction::store_indirect_u32(_, base, offset) | Instruction::store_imm_indirect_u32(base, offset, _) => {
+                (reg(base).wrapping_add(offset), 4)
+            }
+            Instruction::store_indirect_u64(_, base, offset) | Instruction::store_imm_indirect_u64(base, offset, _) => {
+                (reg(base).wrapping_add(offset), 8)
+            }
+            Instruction::memset => {
+                let length = u32::try_from(instance.reg(Reg::A2)).ok()?;
+                (cast(instance.reg(Reg::A0)).truncate_to_u32(), length)
+            }
+            _ => return None,
+        };
+
+        Some(range)
+    }
+
+    /// Emits the effects of whatever happened since the previous interruption.
+    fn flush(&mut self, instance: &RawInstance, output: &mut Vec<Event>) {
+        if let Some((address, length)) = self.pending_memory_write.take() {
+            if let Ok(data) = instance.read_memory(address, length) {
+                output.push(Event::MemoryWrite(address, data));
+            }
+        }
+
+        for (reg, old_value) in Reg::ALL.into_iter().zip(self.regs.iter_mut()) {
+            let value = instance.reg(reg);
+            if value != *old_value {
+                *old_value = value;
+                output.push(Event::RegWrite(reg, value));
+            }
+        }
+
+        let gas = instance.gas();
+        if gas != self.gas {
+            self.gas = gas;
+            output.push(Event::Gas(gas));
+        }
+    }
+
+    fn on_interrupt(&mut self, instance: &RawInstance, interrupt: &InterruptKind, output: &mut Vec<Event>) {
+        if matches!(
+            interrupt,
+            InterruptKind::LimitReached(..) | InterruptKind::Interrupted | InterruptKind::Watchpoint { .. }
+        ) {
+            // These are either not deterministic or depend on how the instance is being debugged,
+            // so just pick up whatever happened at the next interruption.
+            return;
+        }
+
+        if self.state == State::Idle {
+            let Some(program_counter) = instance.program_counter().filter(|_| matches!(interrupt, InterruptKind::Step)) else {
+                return;
+            };
+
+            for (reg, value) in Reg::ALL.into_iter().zip(self.regs.iter_mut()) {
+                *value = instance.reg(reg);
+            }
+
+            self.gas = instance.gas();
+            self.state = State::Running;
+            self.pending_memory_write = self.memory_write_range(instance, program_counter);
+            output.push(Event::Start {
+                program_counter,
+                regs: self.regs,
+                gas: self.gas,
+            });
+
+            return;
+        }
+
+        if self.state == State::Yielded {
+            self.flush(instance, output);
+            self.state = State::Running;
+            output.push(Event::Resume);
+        }
+
+        self.flush(instance, output);
+        let event = match *interrupt {
+            InterruptKind::Step => {
+                let Some(program_counter) = instance.program_counter() else {
+                    return;
+                };
+
+                self.pending_memory_write = self.memory_write_range(instance, program_counter);
+                Event::Step(program_counter)
+            }
+            InterruptKind::Ecalli(index) => Event::HostCall(index),
+            InterruptKind::NotEnoughGas => Event::OutOfGas,
+            InterruptKind::Segfault(ref segfault) => Event::Segfault(segfault.page_address),
+            InterruptKind::Finished => Event::Finished,
+            InterruptKind::Trap => Event::Trap,
+            InterruptKind::LimitReached(..) | InterruptKind::Interrupted | InterruptKind::Watchpoint { .. } => unreachable!(),
+        };
+
+        self.state = match event {
+            Event::HostCall(..) | Event::OutOfGas | Event::Segfault(..) => State::Yielded,
+            Event::Finished | Event::Trap => State::Idle,
+            _ => State::Running,
+        };
+
+        output.push(event);
+    }
+}
+
+fn write_header(output: &mut Vec<u8>, module: &Module) {
+    output.extend_from_slice(&TRACE_MAGIC);
+    output.push(TRACE_VERSION);
+    output.push(match module.gas_metering() {
+        None => 0,
+        Some(GasMeteringKind::Sync) => 1,
+        Some(GasMeteringKind::Async) => 2,
+    });
+    output.extend_from_slice(&module.unique_hash().0);
+}
+
+/// Returns a module configuration with which the given trace can be [`replay`]ed.
+///
+/// This enables step tracing and sets the same gas metering as was used when the trace was recorded.
+pub fn module_config_for(trace: &[u8]) -> Result<ModuleConfig, Error> {
+    let Some(header) = trace.get(..TRACE_MAGIC.len() + 2).filter(|header| header.starts_with(&TRACE_MAGIC)) else {
+        bail!("failed to parse the trace: not a trace file");
+    };
+
+    if header[TRACE_MAGIC.len()] != TRACE_VERSION {
+        bail!("failed to parse the trace: unsupported version");
+    }
+
+    let gas_metering = match header[TRACE_MAGIC.len() + 1] {
+        0 => None,
+        1 => Some(GasMeteringKind::Sync),
+        2 => Some(GasMeteringKind::Async),
+        kind => bail!("failed to parse the trace: invalid gas metering kind: {kind}"),
+    };
+
+    let mut config = ModuleConfig::new();
+    config.set_step_tracing(true);
+    config.set_gas_metering(gas_metering);
+    Ok(config)
+}
+
+/// Records the execution of an instance into a trace.
+pub struct TraceRecorder<W: Write> {
+    output: W,
+    observer: Observer,
+    events: Vec<Event>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> TraceRecorder<W> {
+    /// Creates a new recorder for the given module, writing the trace into `output`.
+    ///
+    /// The recorder should be created before the instance is run for the first time,
+    /// and any memory writes made by the host before that should be reported with [`TraceRecorder::on_memory_write`].
+    pub fn new(module: &Module, mut output: W) -> Result<Self, Error> {
+        if !module.is_step_tracing() {
+            bail!("failed to create a trace recorder: the module was not compiled with step tracing enabled");
+        }
+
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, module);
+        if let Err(error) = output.write_all(&buffer) {
+            bail!("failed to write the trace: {error}");
+        }
+
+        buffer.clear();
+        Ok(TraceRecorder {
+            output,
+            observer: Observer::new(module),
+            events: Vec::new(),
+            buffer,
+        })
+    }
+
+    /// Records an interruption returned by [`RawInstance::run`].
+    ///
+    /// This should be called for every interruption, before the host handles it.
+    pub fn on_interrupt(&mut self, instance: &RawInstance, interrupt: &InterruptKind) -> std::io::Result<()> {
+        self.observer.on_interrupt(instance, interrupt, &mut self.events);
+        self.write_events()
+    }
+
+    /// Records a write to the guest's memory made by the host, e.g. with [`RawInstance::write_memory`].
+    pub fn on_memory_write(&mut self, address: u32, data: &[u8]) -> std::io::Result<()> {
+        self.events.push(Event::MemoryWrite(address, data.into()));
+        self.write_events()
+    }
+
+    /// Flushes the trace and returns the underlying writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.output.flush()?;
+        Ok(self.output)
+    }
+
+    fn write_events(&mut self) -> std::io::Result<()> {
+        for event in self.events.drain(..) {
+            event.serialize(&mut self.buffer);
+        }
+
+        self.output.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+/// The first point at which a replayed execution diverged from the recorded one.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct Divergence {
+    /// The number of instructions which were replayed before the divergence.
+    pub instructions: u64,
+    /// The program counter of the last instruction which was about to be executed, if any.
+    pub program_counter: Option<ProgramCounter>,
+    /// What was recorded in the trace.
+    pub expected: String,
+    /// What actually happened.
+    pub actual: String,
+}
+
+impl core::fmt::Display for Divergence {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(fmt, "execution diverged after {} instruction(s)", self.instructions)?;
+        if let Some(program_counter) = self.program_counter {
+            write!(fmt, " at {program_counter}")?;
+        }
+
+        write!(fmt, ": expected {}, got {}", self.expected, self.actual)
+    }
+}
+
+/// Replays a trace recorded with a [`TraceRecorder`] on a new instance of the given module.
+///
+/// Host calls are not made; instead their recorded results are fed back into the instance.
+/// Returns the first divergence from the trace, if any.
+///
+/// The module must be created from the same program blob as the one which was recorded,
+/// with a configuration returned by [`module_config_for`].
+pub fn replay(module: &Module, trace: &[u8]) -> Result<Option<Divergence>, Error> {
+    if !module.is_step_tracing() {
+        bail!("failed to replay the trace: the module was not compiled with step tracing enabled");
+    }
+
+    let mut header = Vec::new();
+    write_header(&mut header, module);
+    let Some(trace) = trace.strip_prefix(&*header) else {
+        module_config_for(trace)?;
+        bail!("failed to replay the trace: the trace was recorded for a different program or with different gas metering");
+    };
+
+    let mut reader = Reader::new(trace, "failed to parse the trace: unexpected end of input");
+    let mut expected_events = Vec::new();
+    while reader.remaining() != 0 {
+        expected_events.push(read_event(&mut reader)?);
+    }
+
+    let mut instance = module.instantiate()?;
+    let mut observer = Observer::new(module);
+    let mut events = Vec::new();
+    let mut position = 0;
+    let mut instructions = 0;
+    let mut program_counter = None;
+    loop {
+        // Apply whatever the host did before the execution started or while it was yielded.
+        if observer.state != State::Running {
+            while let Some(Event::MemoryWrite(address, data)) = expected_events.get(position) {
+                if let Err(error) = instance.write_memory(*address, data) {
+                    bail!("failed to replay the trace: memory write by the host failed: {error}");
+                }
+
+                position += 1;
+            }
+
+            for event in &expected_events[position..] {
+                match *event {
+                    Event::Start {
+                        program_counter,
+                        regs,
+                        gas,
+                    } if observer.state == State::Idle => {
+                        for (reg, value) in Reg::ALL.into_iter().zip(regs) {
+                            instance.set_reg(reg, value);
+                        }
+
+                        instance.set_gas(gas);
+                        instance.set_next_program_counter(program_counter);
+                        break;
+                    }
+                    Event::RegWrite(reg, value) if observer.state == State::Yielded => instance.set_reg(reg, value),
+                    Event::Gas(gas) if observer.state == State::Yielded => instance.set_gas(gas),
+                    _ => break,
+                }
+            }
+        }
+
+        if position == expected_events.len() {
+            return Ok(None);
+        }
+
+        if observer.state == State::Idle && !matches!(expected_events[position], Event::Start { .. }) {
+            bail!(
+                "failed to replay the trace: expected the execution to start, found: {}",
+                expected_events[position]
+            );
+        }
+
+        let interrupt = instance.run()?;
+        observer.on_interrupt(&instance, &interrupt, &mut events);
+        for event in events.drain(..) {
+            let Some(expected_event) = expected_events.get(position) else {
+                // The trace might have been truncated, so there's nothing more to compare against.
+                return Ok(None);
+            };
+
+            if event != *expected_event {
+                return Ok(Some(Divergence {
+                    instructions,
+                    program_counter,
+                    expected: format!("{expected_event}"),
+                    actual: format!("{event}"),
+                }));
+            }
+
+            position += 1;
+            match event {
+                Event::Start { program_counter: pc, .. } | Event::Step(pc) => {
+                    if program_counter.is_some() {
+                        instructions += 1;
+                    }
+                    program_counter = Some(pc);
+                }
+                Event::Finished | Event::Trap => {
+                    instructions += 1;
+                    program_counter = None;
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,5 +1,10 @@
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use polkavm_common::program::RawReg;
+use core::sync::atomic::{AtomicBool, Ordering};
+use polkavm_common::program::{ProgramCounter, RawReg};
+
+use crate::error::Error;
+use crate::mutex::Mutex;
 
 #[derive(Copy, Clone)]
 pub enum RegImm {
@@ -59,37 +64,105 @@ where
         Self { inner }
     }
 
-    #[inline]
-    #[allow(dead_code)]
-    pub fn new_reusing_memory(mut memory: Self, capacity: u32) -> Self {
-        memory.inner.clear();
-        memory.inner.resize_with(capacity as usize, || None);
-        memory
-    }
-
     #[inline]
     pub fn get(&self, key: u32) -> Option<T> {
         self.inner.get(key as usize).and_then(|value| *value)
     }
 
-    #[inline]
-    #[allow(dead_code)]
-    pub fn len(&self) -> u32 {
-        self.inner.len() as u32
-    }
-
     #[inline]
     pub fn insert(&mut self, key: u32, value: T) {
         self.inner[key as usize] = Some(value);
     }
 
-    #[inline]
-    #[allow(dead_code)]
-    pub fn clear(&mut self) {
-        self.inner.clear();
+    if_compiler_is_supported! {
+        #[inline]
+        pub fn new_reusing_memory(mut memory: Self, capacity: u32) -> Self {
+            memory.inner.clear();
+            memory.inner.resize_with(capacity as usize, || None);
+            memory
+        }
+
+        #[inline]
+        pub fn len(&self) -> u32 {
+            self.inner.len() as u32
+        }
+
+        #[inline]
+        pub fn clear(&mut self) {
+            self.inner.clear();
+        }
     }
 }
 
+/// A reader of serialized little endian data.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    /// The error which is returned when trying to read past the end of the data.
+    error: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8], error: &'static str) -> Self {
+        Reader { bytes, error }
+    }
+
+    /// Returns the number of bytes which weren't read yet.
+    pub fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn read_slice(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if length > self.bytes.len() {
+            return Err(Error::from_static_str(self.error));
+        }
+
+        let (slice, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.read_slice(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.read_slice(8)?.try_into().unwrap()))
+    }
+}
+
+/// A marker trait for plain old data types which can be directly copied to and from the VM's memory.
+///
+/// Values are copied as-is in the host's native byte order, so this is only available on little endian hosts
+/// where that matches the guest's byte order.
+///
+/// # Safety
+///
+/// The type must be `#[repr(C)]` or `#[repr(transparent)]`, must not contain any padding bytes nor pointers,
+/// and every possible bit pattern must be a valid value of the type.
+#[cfg(target_endian = "little")]
+pub unsafe trait Pod: Copy + 'static {}
+
+#[cfg(target_endian = "little")]
+macro_rules! impl_pod {
+    ($($type:ty)+) => {
+        $(
+            // SAFETY: Primitive integers have no padding and every bit pattern is valid.
+            unsafe impl Pod for $type {}
+        )+
+    };
+}
+
+#[cfg(target_endian = "little")]
+impl_pod!(u8 u16 u32 u64 u128 i8 i16 i32 i64 i128);
+
+#[cfg(target_endian = "little")]
+// SAFETY: Arrays have no padding between elements, and `T` has no padding either.
+unsafe impl<T, const N: usize> Pod for [T; N] where T: Pod {}
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 #[non_exhaustive]
 pub struct Segfault {
@@ -100,6 +173,123 @@ pub struct Segfault {
     pub page_size: u32,
 }
 
+/// The kind of an execution limit set through [`RawInstance::set_limits`](crate::RawInstance::set_limits).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum LimitKind {
+    /// The maximum number of executed basic blocks was reached.
+    BasicBlocks,
+
+    /// The deadline has passed.
+    Deadline,
+
+    /// The memory limit was reached.
+    Memory,
+}
+
+/// The kind of memory accesses which trigger a watchpoint set through [`RawInstance::add_watchpoint`](crate::RawInstance::add_watchpoint).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WatchpointKind {
+    /// Triggers on reads.
+    Read,
+
+    /// Triggers on writes.
+    Write,
+
+    /// Triggers on both reads and writes.
+    ReadWrite,
+}
+
+impl WatchpointKind {
+    pub(crate) fn matches(self, is_write: bool) -> bool {
+        match self {
+            WatchpointKind::Read => !is_write,
+            WatchpointKind::Write => is_write,
+            WatchpointKind::ReadWrite => true,
+        }
+    }
+}
+
+/// The kind of a bug detected by the memory sanitizer enabled with [`ModuleConfig::set_memory_sanitizer`](crate::ModuleConfig::set_memory_sanitizer).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum MemoryDiagnosticKind {
+    /// A byte of the stack or of the heap was read before anything was written to it.
+    UninitializedRead,
+
+    /// Memory between the top of the heap and the bottom of the stack was accessed.
+    ///
+    /// Such memory is only accessible because the heap is rounded up to the page size.
+    UnallocatedAccess,
+}
+
+/// A bug in the guest program detected by the memory sanitizer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub struct MemoryDiagnostic {
+    /// The kind of the bug.
+    pub kind: MemoryDiagnosticKind,
+
+    /// The program counter of the instruction which made the access.
+    pub program_counter: ProgramCounter,
+
+    /// The address of the first offending byte.
+    pub address: u32,
+
+    /// The length of the whole access.
+    pub length: u32,
+
+    /// Whether the access was a write.
+    pub is_write: bool,
+}
+
+impl core::fmt::Display for MemoryDiagnostic {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let access = if self.is_write { "write" } else { "read" };
+        let kind = match self.kind {
+            MemoryDiagnosticKind::UninitializedRead => "uninitialized read",
+            MemoryDiagnosticKind::UnallocatedAccess => "unallocated access",
+        };
+
+        write!(
+            fmt,
+            "{kind} of 0x{address:x} by a {length}-byte {access} (pc = {pc})",
+            length = self.length,
+            address = self.address,
+            pc = self.program_counter
+        )
+    }
+}
+
+/// The amount of memory used by an instance, broken down by region.
+///
+/// All of the sizes are in bytes and are multiples of the page size.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub struct MemoryUsage {
+    /// The size of the read-only data.
+    pub ro_data: u32,
+
+    /// The size of the read-write data, excluding the heap.
+    pub rw_data: u32,
+
+    /// The size of the heap.
+    pub heap: u32,
+
+    /// The size of the stack.
+    pub stack: u32,
+
+    /// The size of the auxiliary data.
+    pub aux_data: u32,
+}
+
+impl MemoryUsage {
+    /// The total amount of memory used.
+    pub fn total(&self) -> u64 {
+        u64::from(self.ro_data) + u64::from(self.rw_data) + u64::from(self.heap) + u64::from(self.stack) + u64::from(self.aux_data)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum InterruptKind {
     /// The execution finished normally.
@@ -129,11 +319,110 @@ pub enum InterruptKind {
 
     /// The execution ran out of gas.
     ///
+    /// The execution can be resumed by adding more gas with [`RawInstance::set_gas`](crate::RawInstance::set_gas)
+    /// and calling [`RawInstance::run`](crate::RawInstance::run) again.
+    ///
     /// Requires gas metering to be enabled with [`ModuleConfig::set_gas_metering`](crate::ModuleConfig::set_gas_metering), otherwise is never emitted.
     NotEnoughGas,
 
+    /// The program accessed memory watched by a watchpoint.
+    ///
+    /// This is triggered right after the access is made; [`RawInstance::program_counter`](crate::RawInstance::program_counter)
+    /// will return the instruction which made the access, and the execution can be resumed by calling [`RawInstance::run`](crate::RawInstance::run) again.
+    ///
+    /// Requires a watchpoint to be set with [`RawInstance::add_watchpoint`](crate::RawInstance::add_watchpoint), otherwise is never emitted.
+    Watchpoint {
+        /// The start address of the access which triggered the watchpoint.
+        address: u32,
+
+        /// Whether the access was a write.
+        is_write: bool,
+
+        /// The program counter of the instruction which made the access.
+        pc: ProgramCounter,
+    },
+
     /// Executed a single instruction.
     ///
     /// Requires execution step-tracing to be enabled with [`ModuleConfig::set_step_tracing`](crate::ModuleConfig::set_step_tracing), otherwise is never emitted.
     Step,
+
+    /// One of the limits set with [`RawInstance::set_limits`](crate::RawInstance::set_limits) was reached.
+    ///
+    /// The execution can be resumed after the limits are raised.
+    ///
+    /// Requires execution limits to be enabled with [`ModuleConfig::set_execution_limits`](crate::ModuleConfig::set_execution_limits), otherwise is never emitted.
+    LimitReached(LimitKind),
+
+    /// The execution was interrupted through an [`InterruptHandle`].
+    ///
+    /// The execution can be resumed by calling [`RawInstance::run`](crate::RawInstance::run) again.
+    ///
+    /// Requires execution limits to be enabled with [`ModuleConfig::set_execution_limits`](crate::ModuleConfig::set_execution_limits), otherwise is never emitted.
+    Interrupted,
+}
+
+/// A handle which can be used to interrupt an instance from another thread.
+///
+/// Obtained through [`RawInstance::interrupt_handle`](crate::RawInstance::interrupt_handle).
+#[derive(Clone)]
+pub struct InterruptHandle(Arc<InterruptState>);
+
+impl InterruptHandle {
+    /// Interrupts the execution of the instance.
+    ///
+    /// If the instance is currently running then [`RawInstance::run`](crate::RawInstance::run) will promptly return
+    /// [`InterruptKind::Interrupted`], otherwise the next call to it will.
+    pub fn interrupt(&self) {
+        self.0.interrupt();
+    }
+}
+
+/// A callback which wakes up the thread which is waiting for the instance to stop.
+pub(crate) type InterruptWaker = (fn(usize), usize);
+
+pub(crate) struct InterruptState {
+    is_interrupted: AtomicBool,
+    waker: Mutex<Option<InterruptWaker>>,
+}
+
+impl InterruptState {
+    pub fn new() -> Arc<Self> {
+        Arc::new(InterruptState {
+            is_interrupted: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        })
+    }
+
+    pub fn handle(self: &Arc<Self>) -> InterruptHandle {
+        InterruptHandle(Arc::clone(self))
+    }
+
+    fn interrupt(&self) {
+        // The lock must be held while waking so that the waker can't be unregistered in the meantime.
+        let waker = self.waker.lock();
+        self.is_interrupted.store(true, Ordering::SeqCst);
+        if let Some((wake, data)) = *waker {
+            wake(data);
+        }
+    }
+
+    #[inline]
+    pub fn is_interrupted(&self) -> bool {
+        self.is_interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Clears the interrupt flag, returning whether it was set.
+    #[inline]
+    pub fn take_interrupt(&self) -> bool {
+        // Avoid an atomic read-modify-write in the common case.
+        self.is_interrupted() && self.is_interrupted.swap(false, Ordering::SeqCst)
+    }
+
+    if_compiler_is_supported! {
+        #[cfg(target_os = "linux")]
+        pub fn set_waker(&self, waker: Option<InterruptWaker>) {
+            *self.waker.lock() = waker;
+        }
+    }
 }
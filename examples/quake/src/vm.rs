@@ -199,7 +199,7 @@ impl Vm {
                     buffer.push(byte)
                 }
                 Err(MemoryAccessError::Error(error)) => return Err(error.into()),
-                Err(MemoryAccessError::OutOfRangeAccess { .. }) => return Ok(None),
+                Err(MemoryAccessError::OutOfRangeAccess { .. } | MemoryAccessError::MemoryLimitReached { .. }) => return Ok(None),
             }
         }
 
@@ -297,7 +297,7 @@ impl Vm {
         match self.instance.write_memory(address, blob) {
             Ok(()) => {}
             Err(MemoryAccessError::Error(error)) => return Err(error.into()),
-            Err(MemoryAccessError::OutOfRangeAccess { .. }) => {
+            Err(MemoryAccessError::OutOfRangeAccess { .. } | MemoryAccessError::MemoryLimitReached { .. }) => {
                 log::trace!("  -> EFAULT");
                 return Ok(errno(EFAULT));
             }
@@ -331,7 +331,7 @@ impl Vm {
         let data = match self.instance.read_memory(address, length as u32) {
             Ok(data) => data,
             Err(MemoryAccessError::Error(error)) => return Err(error.into()),
-            Err(MemoryAccessError::OutOfRangeAccess { .. }) => return Ok(errno(EFAULT)),
+            Err(MemoryAccessError::OutOfRangeAccess { .. } | MemoryAccessError::MemoryLimitReached { .. }) => return Ok(errno(EFAULT)),
         };
 
         use std::io::Write;
@@ -618,7 +618,13 @@ impl Vm {
                 InterruptKind::NotEnoughGas => {
                     return Err("ran out of gas".into());
                 }
-                InterruptKind::Segfault(_) | InterruptKind::Step => unreachable!(),
+                InterruptKind::Segfault(_)
+                | InterruptKind::Step
+                | InterruptKind::LimitReached(_)
+                | InterruptKind::Interrupted
+                | InterruptKind::Watchpoint { .. } => {
+                    unreachable!()
+                }
             }
         }
     }
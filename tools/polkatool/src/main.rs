@@ -83,6 +83,69 @@ enum Args {
         inputs: Vec<PathBuf>,
     },
 
+    /// Runs a given export of a .polkavm blob and prints the final state of the VM.
+    Run {
+        /// Records a trace of the execution into the given file.
+        #[clap(long)]
+        record_trace: Option<PathBuf>,
+
+        #[clap(flatten)]
+        args: run::RunArgs,
+    },
+
+    /// Replays a trace recorded with `run --record-trace` and reports the first divergence from it.
+    Replay {
+        /// The trace file.
+        #[clap(long)]
+        trace: PathBuf,
+
+        #[clap(long, value_enum, default_value_t = run::Backend::Interpreter)]
+        backend: run::Backend,
+
+        /// The input file.
+        input: PathBuf,
+    },
+
+    /// Runs a given export of a .polkavm blob under an interactive debugger.
+    Debug {
+        /// Instead of starting an interactive session wait for a GDB connection on the given address, e.g. `127.0.0.1:1234`.
+        #[clap(long)]
+        gdb: Option<String>,
+
+        #[clap(flatten)]
+        args: run::RunArgs,
+    },
+
+    /// Profiles a given export of a .polkavm blob.
+    Profile {
+        /// The output file.
+        #[clap(short = 'o', long)]
+        output: PathBuf,
+
+        #[clap(short = 'f', long, value_enum, default_value_t = run::ProfileFormat::Folded)]
+        format: run::ProfileFormat,
+
+        /// What to weigh the folded stacks by.
+        #[clap(long, value_enum, default_value_t = run::ProfileWeight::Gas)]
+        weight: run::ProfileWeight,
+
+        #[clap(flatten)]
+        args: run::RunArgs,
+    },
+
+    /// Prints a report of the gas costs of a .polkavm blob.
+    GasReport {
+        /// Runs the given export and multiplies the static costs by how many times each basic block was executed.
+        #[clap(long)]
+        dynamic: bool,
+
+        #[clap(long, value_enum, default_value_t = run::GasReportGrouping::Function)]
+        group_by: run::GasReportGrouping,
+
+        #[clap(flatten)]
+        args: run::RunArgs,
+    },
+
     /// Writes a path to a JSON target file for rustc to stdout.
     GetTargetJsonPath {
         #[clap(short = 'b', long, value_enum, default_value_t = Bitness::B64)]
@@ -96,6 +159,8 @@ macro_rules! bail {
     }
 }
 
+mod run;
+
 fn main() {
     env_logger::init();
 
@@ -118,6 +183,16 @@ fn main() {
         } => main_disassemble(input, format, display_gas, show_raw_bytes, output),
         Args::Assemble { input, output } => main_assemble(input, output),
         Args::Stats { inputs } => main_stats(inputs),
+        Args::Run { record_trace, args } => run::main_run(args, record_trace),
+        Args::Replay { trace, backend, input } => run::main_replay(input, trace, backend),
+        Args::Debug { gdb, args } => run::main_debug(args, gdb),
+        Args::Profile {
+            output,
+            format,
+            weight,
+            args,
+        } => run::main_profile(args, output, format, weight),
+        Args::GasReport { dynamic, group_by, args } => run::main_gas_report(args, dynamic, group_by),
         Args::GetTargetJsonPath { bitness } => {
             let result = match bitness {
                 Bitness::B32 => polkavm_linker::target_json_32_path(),
@@ -0,0 +1,848 @@
+use polkavm::{
+    BackendKind, Config, Engine, GasMeteringKind, InterruptKind, LimitKind, Module, ModuleConfig, ProgramCounter, RawInstance, Reg,
+    WatchpointKind,
+};
+use polkavm_common::program::{Instruction, InstructionFormat, ProgramBlob, ISA32_V1, ISA64_V1};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+pub enum Backend {
+    Interpreter,
+    Compiler,
+}
+
+/// What to do when the program calls an import.
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+pub enum ImportStub {
+    /// Return 0 from the import.
+    Zero,
+    /// Stop the execution.
+    Trap,
+    /// Print the import's name and arguments, and return 0 from it.
+    Log,
+}
+
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+pub enum ProfileFormat {
+    /// Folded stacks, as consumed by e.g. `flamegraph.pl` or `inferno-flamegraph`.
+    Folded,
+    /// An uncompressed `pprof` protobuf.
+    Pprof,
+}
+
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+pub enum ProfileWeight {
+    Instructions,
+    Gas,
+}
+
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+pub enum GasReportGrouping {
+    Function,
+    Line,
+    Block,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RunArgs {
+    /// The name of the export to call.
+    #[clap(short = 'e', long, default_value = "main")]
+    export: String,
+
+    /// An argument to pass to the export; can be specified multiple times.
+    #[clap(long = "arg", value_parser = parse_u64)]
+    args: Vec<u64>,
+
+    /// Enables gas metering and sets the amount of gas available to the program.
+    #[clap(long)]
+    gas: Option<i64>,
+
+    #[clap(long, value_enum, default_value_t = Backend::Interpreter)]
+    backend: Backend,
+
+    /// What to do when the program calls an import.
+    #[clap(long, value_enum, default_value_t = ImportStub::Trap)]
+    on_import: ImportStub,
+
+    /// Checks the program's memory accesses for reads of uninitialized memory and accesses outside of its heap and stack.
+    #[clap(long)]
+    sanitize_memory: bool,
+
+    /// The input file.
+    input: PathBuf,
+}
+
+fn parse_u64(input: &str) -> Result<u64, String> {
+    let result = if let Some(input) = input.strip_prefix("0x") {
+        u64::from_str_radix(input, 16)
+    } else if let Some(input) = input.strip_prefix('-') {
+        input.parse::<i64>().map(|value| value.wrapping_neg() as u64)
+    } else {
+        input.parse::<u64>()
+    };
+
+    result.map_err(|error| format!("invalid number '{input}': {error}"))
+}
+
+struct Program {
+    blob: ProgramBlob,
+    instance: RawInstance,
+    on_import: ImportStub,
+    initial_gas: Option<i64>,
+}
+
+impl Program {
+    fn new(args: &RunArgs, step_tracing: bool, watchpoints: bool) -> Result<Self, String> {
+        let blob = crate::load_blob(&args.input)?;
+
+        let mut config = Config::from_env().map_err(|error| error.to_string())?;
+        config.set_backend(Some(match args.backend {
+            Backend::Interpreter => BackendKind::Interpreter,
+            Backend::Compiler => BackendKind::Compiler,
+        }));
+
+        let engine = match Engine::new(&config) {
+            Ok(engine) => engine,
+            Err(error) => {
+                bail!("failed to initialize the VM: {error}");
+            }
+        };
+
+        let mut module_config = ModuleConfig::new();
+        module_config.set_step_tracing(step_tracing);
+        module_config.set_watchpoints(watchpoints);
+        module_config.set_memory_sanitizer(args.sanitize_memory);
+        if args.gas.is_some() {
+            module_config.set_gas_metering(Some(GasMeteringKind::Sync));
+        }
+
+        let module = match Module::from_blob(&engine, &module_config, blob.clone()) {
+            Ok(module) => module,
+            Err(error) => {
+                bail!("failed to load {:?}: {error}", args.input);
+            }
+        };
+
+        let Some(export) = module.exports().find(|export| export.symbol() == args.export.as_str()) else {
+            bail!("export not found: '{}'", args.export);
+        };
+
+        if args.args.len() > Reg::ARG_REGS.len() {
+            bail!("too many arguments: at most {} are supported", Reg::ARG_REGS.len());
+        }
+
+        let pc = export.program_counter();
+        let mut instance = match module.instantiate() {
+            Ok(instance) => instance,
+            Err(error) => {
+                bail!("failed to instantiate the program: {error}");
+            }
+        };
+
+        instance.prepare_call_untyped(pc, &args.args);
+        if let Some(gas) = args.gas {
+            instance.set_gas(gas);
+        }
+
+        Ok(Program {
+            blob,
+            instance,
+            on_import: args.on_import,
+            initial_gas: args.gas,
+        })
+    }
+
+    /// Handles a call to an import; returns whether the execution should continue.
+    fn handle_import(&mut self, index: u32) -> bool {
+        handle_import(&self.blob, &mut self.instance, self.on_import, index)
+    }
+
+    fn print_state(&self, interrupt: &InterruptKind) {
+        println!("Interrupt: {}", describe_interrupt(interrupt));
+        if let InterruptKind::Ecalli(index) = interrupt {
+            println!("Import: {}", import_name(&self.blob, *index));
+        }
+
+        if let Some(pc) = self.instance.program_counter() {
+            println!("Program counter: {pc}");
+        }
+
+        if let Some(initial_gas) = self.initial_gas {
+            let gas = self.instance.gas();
+            println!("Gas used: {} (remaining: {gas})", initial_gas - gas);
+        }
+
+        println!("Registers:");
+        print_registers(&self.instance);
+
+        if matches!(interrupt, InterruptKind::Trap | InterruptKind::Segfault(..)) {
+            println!("Backtrace:");
+            print!("{}", polkavm::backtrace::Backtrace::capture(&self.instance));
+        }
+    }
+}
+
+fn describe_interrupt(interrupt: &InterruptKind) -> String {
+    match interrupt {
+        InterruptKind::Finished => "finished".into(),
+        InterruptKind::Trap => "trap".into(),
+        InterruptKind::Ecalli(index) => format!("ecalli {index}"),
+        InterruptKind::Segfault(segfault) => format!("segfault at page 0x{:x}", segfault.page_address),
+        InterruptKind::NotEnoughGas => "not enough gas".into(),
+        InterruptKind::Step => "step".into(),
+        InterruptKind::LimitReached(LimitKind::BasicBlocks) => "basic block limit reached".into(),
+        InterruptKind::LimitReached(LimitKind::Deadline) => "deadline reached".into(),
+        InterruptKind::LimitReached(LimitKind::Memory) => "memory limit reached".into(),
+        InterruptKind::LimitReached(_) => "limit reached".into(),
+        InterruptKind::Interrupted => "interrupted".into(),
+        InterruptKind::Watchpoint { address, is_write, pc } => {
+            let access = if *is_write { "write to" } else { "read from" };
+            format!("watchpoint hit by a {access} 0x{address:x} at {pc}")
+        }
+    }
+}
+
+fn import_name(blob: &ProgramBlob, index: u32) -> String {
+    match blob.imports().get(index) {
+        Some(symbol) => format!("{symbol} (#{index})"),
+        None => format!("#{index}"),
+    }
+}
+
+fn handle_import(blob: &ProgramBlob, instance: &mut RawInstance, on_import: ImportStub, index: u32) -> bool {
+    match on_import {
+        ImportStub::Trap => false,
+        ImportStub::Zero => {
+            instance.set_reg(Reg::A0, 0);
+            true
+        }
+        ImportStub::Log => {
+            let args: Vec<_> = Reg::ARG_REGS
+                .into_iter()
+                .map(|reg| format!("{reg}=0x{:x}", instance.reg(reg)))
+                .collect();
+            println!("Called import {} with: {}", import_name(blob, index), args.join(", "));
+            instance.set_reg(Reg::A0, 0);
+            true
+        }
+    }
+}
+
+fn print_registers(instance: &RawInstance) {
+    for reg in Reg::ALL {
+        let value = instance.reg(reg);
+        if instance.is_64_bit() {
+            println!("  {:>2} = 0x{value:016x} ({value})", reg.name());
+        } else {
+            println!("  {:>2} = 0x{value:08x} ({value})", reg.name());
+        }
+    }
+}
+
+pub fn main_run(args: RunArgs, record_trace: Option<PathBuf>) -> Result<(), String> {
+    let mut program = Program::new(&args, record_trace.is_some(), false)?;
+    let mut recorder = match record_trace {
+        Some(ref path) => {
+            let fp = match std::fs::File::create(path) {
+                Ok(fp) => fp,
+                Err(error) => {
+                    bail!("failed to create output file {path:?}: {error}");
+                }
+            };
+
+            let recorder = polkavm::trace::TraceRecorder::new(program.instance.module(), std::io::BufWriter::new(fp))
+                .map_err(|error| error.to_string())?;
+            Some(recorder)
+        }
+        None => None,
+    };
+
+    let interrupt = loop {
+        let interrupt = match program.instance.run() {
+            Ok(interrupt) => interrupt,
+            Err(error) => {
+                bail!("failed to run the program: {error}");
+            }
+        };
+
+        if let Some(ref mut recorder) = recorder {
+            if let Err(error) = recorder.on_interrupt(&program.instance, &interrupt) {
+                bail!("failed to write the trace: {error}");
+            }
+        }
+
+        match interrupt {
+            InterruptKind::Step => continue,
+            InterruptKind::Ecalli(index) if program.handle_import(index) => continue,
+            interrupt => break interrupt,
+        }
+    };
+
+    program.print_state(&interrupt);
+    if let Some(recorder) = recorder {
+        if let Err(error) = recorder.finish() {
+            bail!("failed to write the trace: {error}");
+        }
+    }
+
+    let diagnostics = program.instance.take_memory_diagnostics();
+    if !diagnostics.is_empty() {
+        println!("Memory sanitizer diagnostics:");
+        for diagnostic in &diagnostics {
+            println!("  {diagnostic}");
+        }
+
+        bail!("the memory sanitizer found {} bug(s)", diagnostics.len());
+    }
+
+    Ok(())
+}
+
+pub fn main_replay(input: PathBuf, trace_path: PathBuf, backend: Backend) -> Result<(), String> {
+    let blob = crate::load_blob(&input)?;
+    let trace = match std::fs::read(&trace_path) {
+        Ok(trace) => trace,
+        Err(error) => {
+            bail!("failed to read {trace_path:?}: {error}");
+        }
+    };
+
+    let mut config = Config::from_env().map_err(|error| error.to_string())?;
+    config.set_backend(Some(match backend {
+        Backend::Interpreter => BackendKind::Interpreter,
+        Backend::Compiler => BackendKind::Compiler,
+    }));
+
+    let engine = match Engine::new(&config) {
+        Ok(engine) => engine,
+        Err(error) => {
+            bail!("failed to initialize the VM: {error}");
+        }
+    };
+
+    let module_config = polkavm::trace::module_config_for(&trace).map_err(|error| error.to_string())?;
+    let module = match Module::from_blob(&engine, &module_config, blob) {
+        Ok(module) => module,
+        Err(error) => {
+            bail!("failed to load {input:?}: {error}");
+        }
+    };
+
+    match polkavm::trace::replay(&module, &trace) {
+        Ok(None) => {
+            println!("The execution matches the trace.");
+            Ok(())
+        }
+        Ok(Some(divergence)) => {
+            if let Some(pc) = divergence.program_counter {
+                print!("{}", polkavm::debugger::describe_location(&module, pc));
+            }
+
+            bail!("{divergence}");
+        }
+        Err(error) => bail!("{error}"),
+    }
+}
+
+pub fn main_debug(args: RunArgs, gdb: Option<String>) -> Result<(), String> {
+    // Watchpoints are only supported by the interpreter, so this always runs the program interpreted.
+    let mut program = Program::new(&args, true, true)?;
+    if let Some(address) = gdb {
+        let Program {
+            ref blob,
+            ref mut instance,
+            on_import,
+            ..
+        } = program;
+
+        let mut stub = polkavm::debugger::GdbStub::new(instance).map_err(|error| error.to_string())?;
+        stub.set_interrupt_handler(|instance, interrupt| match interrupt {
+            InterruptKind::Ecalli(index) => handle_import(blob, instance, on_import, *index),
+            _ => false,
+        });
+
+        println!("Waiting for a debugger to connect on {address}...");
+        return stub.serve_tcp(address.as_str()).map_err(|error| error.to_string());
+    }
+
+    let instructions: BTreeMap<ProgramCounter, Instruction> = if program.blob.is_64_bit() {
+        program.blob.instructions(ISA64_V1).map(|inst| (inst.offset, inst.kind)).collect()
+    } else {
+        program.blob.instructions(ISA32_V1).map(|inst| (inst.offset, inst.kind)).collect()
+    };
+
+    let mut debugger = Debugger {
+        program,
+        instructions,
+        breakpoints: BTreeSet::new(),
+        is_stopped_on_step: false,
+        is_running: true,
+    };
+
+    debugger.repl()
+}
+
+const HELP: &str = "\
+Available commands:
+  s, step [count]         - executes a single instruction (or 'count' instructions)
+  c, continue             - continues the execution until a breakpoint is hit or the program stops
+  b, break <pc>           - adds a breakpoint at a given program counter
+  b, break <path>:<line>  - adds breakpoints on a given source line
+  d, delete <pc>          - removes a breakpoint at a given program counter
+  breakpoints             - lists all breakpoints
+  watch <addr> [length]   - stops when the given memory range is written to
+  rwatch <addr> [length]  - stops when the given memory range is read from
+  awatch <addr> [length]  - stops when the given memory range is read from or written to
+  unwatch <addr> [length] - removes the watchpoints on a given memory range
+  r, regs                 - prints the registers
+  x <address> [length]    - dumps the memory at a given address
+  w, where                - shows the source location of the current instruction
+  bt, backtrace           - shows the call stack
+  q, quit                 - exits the debugger
+An empty line repeats the last command.";
+
+struct Debugger {
+    program: Program,
+    instructions: BTreeMap<ProgramCounter, Instruction>,
+    breakpoints: BTreeSet<ProgramCounter>,
+    is_stopped_on_step: bool,
+    is_running: bool,
+}
+
+enum Stop {
+    Step,
+    Breakpoint,
+    Interrupt(InterruptKind),
+}
+
+impl Debugger {
+    fn current_pc(&self) -> Option<ProgramCounter> {
+        let instance = &self.program.instance;
+        instance.next_program_counter().or_else(|| instance.program_counter())
+    }
+
+    fn resume(&mut self, single_step: bool) -> Result<Stop, String> {
+        let start_pc = self.current_pc();
+        let mut is_first_step = !self.is_stopped_on_step;
+        self.is_stopped_on_step = false;
+
+        loop {
+            let interrupt = match self.program.instance.run() {
+                Ok(interrupt) => interrupt,
+                Err(error) => {
+                    self.is_running = false;
+                    bail!("failed to run the program: {error}");
+                }
+            };
+
+            match interrupt {
+                InterruptKind::Step => {
+                    let pc = self.program.instance.program_counter();
+                    if core::mem::take(&mut is_first_step) && pc == start_pc {
+                        // Nothing was executed yet.
+                        continue;
+                    }
+
+                    if single_step {
+                        self.is_stopped_on_step = true;
+                        return Ok(Stop::Step);
+                    }
+
+                    if pc.is_some_and(|pc| self.breakpoints.contains(&pc)) {
+                        self.is_stopped_on_step = true;
+                        return Ok(Stop::Breakpoint);
+                    }
+                }
+                InterruptKind::Ecalli(index) => {
+                    is_first_step = false;
+                    if self.program.handle_import(index) {
+                        continue;
+                    }
+
+                    return Ok(Stop::Interrupt(interrupt));
+                }
+                InterruptKind::Finished | InterruptKind::Trap => {
+                    self.is_running = false;
+                    return Ok(Stop::Interrupt(interrupt));
+                }
+                _ => return Ok(Stop::Interrupt(interrupt)),
+            }
+        }
+    }
+
+    fn print_location(&self) {
+        let Some(pc) = self.current_pc() else { return };
+        match self.instructions.get(&pc) {
+            Some(instruction) => {
+                let mut format = InstructionFormat::default();
+                format.is_64_bit = self.program.blob.is_64_bit();
+
+                println!("#{pc}: {}", instruction.display(&format));
+            }
+            None => println!("#{pc}: (invalid instruction)"),
+        }
+
+        // Only show the innermost frame here; the full list is available through 'where'.
+        let location = polkavm::debugger::describe_location(self.program.instance.module(), pc);
+        if let Some(innermost) = location.lines().skip(1).last() {
+            if !innermost.contains("(no location available)") {
+                println!("{}", innermost.trim());
+            }
+        }
+    }
+
+    fn run_command(&mut self, command: &str, args: &[&str]) -> Result<bool, String> {
+        match (command, args) {
+            ("s" | "step", _) | ("c" | "continue", []) => {
+                if !self.is_running {
+                    bail!("the program is not running");
+                }
+
+                let single_step = matches!(command, "s" | "step");
+                let count = match args.first() {
+                    Some(count) => parse_u64(count)?,
+                    None => 1,
+                };
+
+                for _ in 0..count {
+                    match self.resume(single_step)? {
+                        Stop::Step => {}
+                        Stop::Breakpoint => {
+                            println!("Breakpoint hit");
+                            break;
+                        }
+                        Stop::Interrupt(interrupt) => {
+                            if self.is_running {
+                                println!("Interrupted: {}", describe_interrupt(&interrupt));
+                            } else {
+                                println!("The program has stopped.");
+                                self.program.print_state(&interrupt);
+                                return Ok(true);
+                            }
+                            break;
+                        }
+                    }
+                }
+
+                self.print_location();
+            }
+            ("b" | "break", [location]) => {
+                if let Some((path, line)) = location.rsplit_once(':') {
+                    let line = parse_u64(line)?;
+                    let Ok(line) = u32::try_from(line) else {
+                        bail!("invalid line number: {line}");
+                    };
+
+                    let list = polkavm::debugger::find_source_line(self.program.instance.module(), path, line);
+                    if list.is_empty() {
+                        bail!("no code found for {location}");
+                    }
+
+                    for pc in list {
+                        println!("Breakpoint added at #{pc}");
+                        self.breakpoints.insert(pc);
+                    }
+                } else {
+                    let pc = parse_pc(location)?;
+                    if !self.instructions.contains_key(&pc) {
+                        bail!("there is no instruction at #{pc}");
+                    }
+
+                    println!("Breakpoint added at #{pc}");
+                    self.breakpoints.insert(pc);
+                }
+            }
+            ("d" | "delete", [pc]) => {
+                let pc = parse_pc(pc)?;
+                if !self.breakpoints.remove(&pc) {
+                    bail!("there is no breakpoint at #{pc}");
+                }
+            }
+            ("breakpoints", []) => {
+                for pc in &self.breakpoints {
+                    println!("#{pc}");
+                }
+            }
+            ("watch" | "rwatch" | "awatch", [address]) | ("watch" | "rwatch" | "awatch", [address, _]) => {
+                let (address, length) = parse_range(address, args.get(1))?;
+                let kind = match command {
+                    "watch" => WatchpointKind::Write,
+                    "rwatch" => WatchpointKind::Read,
+                    _ => WatchpointKind::ReadWrite,
+                };
+
+                if let Err(error) = self.program.instance.add_watchpoint(address, length, kind) {
+                    bail!("failed to add a watchpoint: {error}");
+                }
+
+                println!("Watchpoint added at 0x{address:08x} ({length} bytes)");
+            }
+            ("unwatch", [address]) | ("unwatch", [address, _]) => {
+                let (address, length) = parse_range(address, args.get(1))?;
+                let mut removed = false;
+                for kind in [WatchpointKind::Write, WatchpointKind::Read, WatchpointKind::ReadWrite] {
+                    removed |= self.program.instance.remove_watchpoint(address, length, kind);
+                }
+
+                if !removed {
+                    bail!("there is no watchpoint at 0x{address:08x} ({length} bytes)");
+                }
+            }
+            ("r" | "regs", []) => print_registers(&self.program.instance),
+            ("x", [address]) | ("x", [address, _]) => {
+                let Ok(address) = u32::try_from(parse_u64(address)?) else {
+                    bail!("invalid address: {address}");
+                };
+
+                let length = match args.get(1) {
+                    Some(length) => parse_u64(length)?.min(0x10000) as u32,
+                    None => 64,
+                };
+
+                let data = match self.program.instance.read_memory(address, length) {
+                    Ok(data) => data,
+                    Err(error) => {
+                        bail!("failed to read memory: {error}");
+                    }
+                };
+
+                print_hexdump(address, &data);
+            }
+            ("w" | "where", []) => match self.current_pc() {
+                Some(pc) => print!("{}", polkavm::debugger::describe_location(self.program.instance.module(), pc)),
+                None => bail!("the program is not running"),
+            },
+            ("bt" | "backtrace", []) => {
+                if self.program.instance.program_counter().is_none() {
+                    bail!("the program is not running");
+                }
+
+                print!("{}", polkavm::backtrace::Backtrace::capture(&self.program.instance));
+            }
+            ("h" | "help", []) => println!("{HELP}"),
+            ("q" | "quit", []) => return Ok(true),
+            _ => bail!("unknown command or invalid arguments; type 'help' for a list of commands"),
+        }
+
+        Ok(false)
+    }
+
+    fn repl(&mut self) -> Result<(), String> {
+        self.print_location();
+
+        let stdin = std::io::stdin();
+        let mut lines = stdin.lock().lines();
+        let mut last_command = String::new();
+        loop {
+            print!("(polkavm) ");
+            let _ = std::io::stdout().flush();
+
+            let Some(line) = lines.next() else { return Ok(()) };
+            let line = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    bail!("failed to read from stdin: {error}");
+                }
+            };
+
+            let line = if line.trim().is_empty() {
+                last_command.clone()
+            } else {
+                last_command = line.clone();
+                line
+            };
+
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else { continue };
+            let args: Vec<&str> = words.collect();
+            match self.run_command(command, &args) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(error) => println!("ERROR: {error}"),
+            }
+        }
+    }
+}
+
+fn parse_pc(input: &str) -> Result<ProgramCounter, String> {
+    let input = input.strip_prefix('#').unwrap_or(input);
+    match u32::try_from(parse_u64(input)?) {
+        Ok(pc) => Ok(ProgramCounter(pc)),
+        Err(_) => Err(format!("invalid program counter: {input}")),
+    }
+}
+
+fn parse_range(address: &str, length: Option<&&str>) -> Result<(u32, u32), String> {
+    let Ok(address) = u32::try_from(parse_u64(address)?) else {
+        bail!("invalid address: {address}");
+    };
+
+    let length = match length {
+        Some(length) => match u32::try_from(parse_u64(length)?) {
+            Ok(length) if length > 0 => length,
+            _ => bail!("invalid length: {length}"),
+        },
+        None => 4,
+    };
+
+    Ok((address, length))
+}
+
+fn print_hexdump(address: u32, data: &[u8]) {
+    for (index, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<_> = chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() { char::from(byte) } else { '.' })
+            .collect();
+        let row_address = u64::from(address) + index as u64 * 16;
+        println!("0x{row_address:08x}: {:<47} |{ascii}|", hex.join(" "));
+    }
+}
+
+pub fn main_profile(mut args: RunArgs, output: PathBuf, format: ProfileFormat, weight: ProfileWeight) -> Result<(), String> {
+    if args.gas.is_none() {
+        // We always want to know how much gas was consumed.
+        args.gas = Some(i64::MAX);
+    }
+
+    let mut program = Program::new(&args, true, false)?;
+    let mut profiler = polkavm::profiler::Profiler::new(program.instance.module()).map_err(|error| error.to_string())?;
+    let interrupt = loop {
+        let interrupt = match program.instance.run() {
+            Ok(interrupt) => interrupt,
+            Err(error) => {
+                bail!("failed to run the program: {error}");
+            }
+        };
+
+        profiler.on_interrupt(&program.instance, &interrupt);
+        match interrupt {
+            InterruptKind::Step => continue,
+            InterruptKind::Ecalli(index) if program.handle_import(index) => continue,
+            interrupt => break interrupt,
+        }
+    };
+
+    program.print_state(&interrupt);
+
+    let profile = profiler.profile();
+    let functions = profile.functions();
+    println!();
+    println!(
+        "{:>12} {:>12} {:>12} {:>12}  Function",
+        "Self gas", "Total gas", "Self insns", "Total insns"
+    );
+    for function in functions.iter().take(20) {
+        println!(
+            "{:>12} {:>12} {:>12} {:>12}  {}",
+            function.self_gas, function.total_gas, function.self_instructions, function.total_instructions, function.name
+        );
+    }
+
+    let fp = match std::fs::File::create(&output) {
+        Ok(fp) => fp,
+        Err(error) => {
+            bail!("failed to create output file {output:?}: {error}");
+        }
+    };
+
+    let mut fp = std::io::BufWriter::new(fp);
+    let result = match format {
+        ProfileFormat::Folded => {
+            let weight = match weight {
+                ProfileWeight::Instructions => polkavm::profiler::ProfileWeight::Instructions,
+                ProfileWeight::Gas => polkavm::profiler::ProfileWeight::Gas,
+            };
+
+            profile.write_folded(&mut fp, weight)
+        }
+        ProfileFormat::Pprof => profile.write_pprof(&mut fp),
+    };
+
+    if let Err(error) = result.and_then(|()| fp.flush()) {
+        bail!("failed to write the profile to {output:?}: {error}");
+    }
+
+    Ok(())
+}
+
+pub fn main_gas_report(mut args: RunArgs, dynamic: bool, group_by: GasReportGrouping) -> Result<(), String> {
+    if args.gas.is_none() {
+        // Gas metering needs to be enabled for the dynamic costs to match what the program actually consumed.
+        args.gas = Some(i64::MAX);
+    }
+
+    let mut program = Program::new(&args, dynamic, false)?;
+    let mut report = polkavm::gas_report::GasReport::new(program.instance.module());
+    if dynamic {
+        let interrupt = loop {
+            let interrupt = match program.instance.run() {
+                Ok(interrupt) => interrupt,
+                Err(error) => {
+                    bail!("failed to run the program: {error}");
+                }
+            };
+
+            report.on_interrupt(&program.instance, &interrupt);
+            match interrupt {
+                InterruptKind::Step => continue,
+                InterruptKind::Ecalli(index) if program.handle_import(index) => continue,
+                interrupt => break interrupt,
+            }
+        };
+
+        program.print_state(&interrupt);
+        println!();
+    }
+
+    match group_by {
+        GasReportGrouping::Function | GasReportGrouping::Line => {
+            let entries = match group_by {
+                GasReportGrouping::Function => report.by_function(),
+                _ => report.by_line(),
+            };
+
+            println!(
+                "{:>12} {:>12} {:>12} {:>8}  Name",
+                "Dynamic gas", "Executions", "Static gas", "Blocks"
+            );
+            for entry in entries {
+                println!(
+                    "{:>12} {:>12} {:>12} {:>8}  {}",
+                    entry.dynamic_cost, entry.executions, entry.static_cost, entry.blocks, entry.name
+                );
+            }
+        }
+        GasReportGrouping::Block => {
+            println!("{:>12} {:>12} {:>8}  Block", "Dynamic gas", "Executions", "Cost");
+            for block in report.blocks() {
+                let location = match block.frames().last() {
+                    Some(frame) => match (&frame.path, frame.line) {
+                        (Some(path), Some(line)) => format!("{} ({path}:{line})", frame.function),
+                        (Some(path), None) => format!("{} ({path})", frame.function),
+                        _ => frame.function.clone(),
+                    },
+                    None => String::new(),
+                };
+                println!(
+                    "{:>12} {:>12} {:>8}  {}..{} {location}",
+                    block.dynamic_cost(),
+                    block.executions(),
+                    block.cost(),
+                    block.start(),
+                    block.end()
+                );
+            }
+        }
+    }
+
+    if dynamic {
+        println!();
+        println!("Total gas: {}", report.total_dynamic_cost());
+    }
+
+    Ok(())
+}
@@ -367,6 +367,9 @@ fn main_generate() {
                     final_pc = instance.program_counter().unwrap();
                     continue;
                 }
+                InterruptKind::LimitReached(..) => unreachable!(),
+                InterruptKind::Interrupted => unreachable!(),
+                InterruptKind::Watchpoint { .. } => unreachable!(),
             }
         };
 